@@ -0,0 +1,46 @@
+# Printer profile of a CoreXY machine with a 250 x 210 mm bed
+
+[bed]
+# Corner of the print area closest to the endstops, and its size from there. In millimeters
+origin = 0, 0, 0
+size = 250, 210, 210
+max_temp = 120
+# Proportional, integral and derivative gains of the temperature loop, as set by M304
+pid = 10, 0.023, 305.4
+
+[axes]
+# X, Y, Z, E
+steps_per_unit = 80, 80, 400, 280
+max_feedrate = 500, 500, 12, 120
+max_acceleration = 5000, 5000, 200, 5000
+jerk = 8, 8, 0.4, 2.5
+
+[motion]
+print_acceleration = 2000
+retract_acceleration = 1500
+travel_acceleration = 3000
+junction_deviation = 0.02
+min_feedrate = 0
+min_travel_feedrate = 0
+
+[extruder]
+count = 1
+max_temp = 300
+# Proportional, integral and derivative gains of the temperature loop, as set by M301
+pid = 22.2, 1.08, 114
+# Filament pushed ahead per mm/s of extrusion speed, which is averaged over `smooth_time` seconds. Zero turns it off
+pressure_advance = 0
+smooth_time = 0.04
+
+[kinematics]
+type = corexy
+
+[input_shaper]
+# X, Y. zv, mzv, ei or 2hump_ei, tuned to the resonance frequency of the axis in Hz. A frequency of zero leaves the axis unshaped
+type = mzv, mzv
+frequency = 0, 0
+damping = 0.1, 0.1
+
+[firmware]
+# generic, marlin, prusa, klipper or reprapfirmware. Decides the commands jobs can use
+dialect = marlin
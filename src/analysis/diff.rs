@@ -0,0 +1,204 @@
+use std::collections::BTreeSet;
+
+use crate::error::PrintResult;
+use crate::gcode::GcodeLine;
+use crate::system::{Location, SystemConfig};
+
+use super::{Bounds, JobMetadata, JobSchedule, JobStats, LayerStats, ScheduleChange};
+
+/// Durations closer than this are taken as the same, in seconds
+const DURATION_TOLERANCE: f32 = 0.01;
+/// Heights, extrusions and bounds closer than this are taken as the same, in millimeters
+const LENGTH_TOLERANCE: f32 = 1e-3;
+
+/// Layer that changed between two jobs. None on the side of the job without the layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerDiff {
+    index: usize,
+    before: Option<LayerStats>,
+    after: Option<LayerStats>,
+}
+
+/// Slicer setting that changed between two jobs. None on the side of the job without the setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingDiff {
+    name: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+/// What changed from one job to another, once both are resolved into moves. Only what the machine
+/// does and the settings the slicer wrote down are compared, so changes of whitespace, comments or
+/// the way values are written don't show up
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobDiff {
+    before_stats: JobStats,
+    after_stats: JobStats,
+    layers: Vec<LayerDiff>,
+    /// Temperature and fan changes only the job before makes
+    removed_changes: Vec<ScheduleChange>,
+    /// Temperature and fan changes only the job after makes
+    added_changes: Vec<ScheduleChange>,
+    settings: Vec<SettingDiff>,
+}
+
+impl LayerDiff {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn before(&self) -> Option<&LayerStats> {
+        self.before.as_ref()
+    }
+
+    pub fn after(&self) -> Option<&LayerStats> {
+        self.after.as_ref()
+    }
+}
+
+impl SettingDiff {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn before(&self) -> Option<&str> {
+        self.before.as_deref()
+    }
+
+    pub fn after(&self) -> Option<&str> {
+        self.after.as_deref()
+    }
+}
+
+impl JobDiff {
+    /// Replays both jobs on copies of the configuration and compares them layer by layer
+    pub fn from_lines(
+        before: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+        after: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+        config: &SystemConfig,
+    ) -> PrintResult<JobDiff> {
+        let before = before.into_iter().collect::<PrintResult<Vec<_>>>()?;
+        let after = after.into_iter().collect::<PrintResult<Vec<_>>>()?;
+
+        let before_stats = JobStats::from_lines(lines(&before), config)?;
+        let after_stats = JobStats::from_lines(lines(&after), config)?;
+        let before_schedule = JobSchedule::from_lines(lines(&before), config)?;
+        let after_schedule = JobSchedule::from_lines(lines(&after), config)?;
+        let before_metadata = JobMetadata::from_lines(lines(&before))?;
+        let after_metadata = JobMetadata::from_lines(lines(&after))?;
+
+        let layer_count = before_stats.layers().len().max(after_stats.layers().len());
+        let layers = (0..layer_count)
+            .map(|index| LayerDiff {
+                index,
+                before: before_stats.layers().get(index).cloned(),
+                after: after_stats.layers().get(index).cloned(),
+            })
+            .filter(|layer| match (&layer.before, &layer.after) {
+                (Some(before), Some(after)) => !same_layer(before, after),
+                _ => true,
+            })
+            .collect();
+
+        let only_in = |changes: &JobSchedule, others: &JobSchedule| {
+            changes
+                .changes()
+                .iter()
+                .filter(|change| !others.changes().contains(change))
+                .copied()
+                .collect()
+        };
+
+        let names: BTreeSet<&String> = before_metadata
+            .settings()
+            .keys()
+            .chain(after_metadata.settings().keys())
+            .collect();
+        let settings = names
+            .into_iter()
+            .map(|name| SettingDiff {
+                name: name.clone(),
+                before: before_metadata.setting(name).map(str::to_string),
+                after: after_metadata.setting(name).map(str::to_string),
+            })
+            .filter(|setting| setting.before != setting.after)
+            .collect();
+
+        Ok(JobDiff {
+            removed_changes: only_in(&before_schedule, &after_schedule),
+            added_changes: only_in(&after_schedule, &before_schedule),
+            before_stats,
+            after_stats,
+            layers,
+            settings,
+        })
+    }
+
+    pub fn before_stats(&self) -> &JobStats {
+        &self.before_stats
+    }
+
+    pub fn after_stats(&self) -> &JobStats {
+        &self.after_stats
+    }
+
+    /// Layers taking another time, extruding another amount or covering another area, by index.
+    /// Layers only one of the jobs has are included too
+    pub fn layers(&self) -> &[LayerDiff] {
+        &self.layers
+    }
+
+    /// Temperature and fan changes only the job before makes, like the ones moved to another layer
+    pub fn removed_changes(&self) -> &[ScheduleChange] {
+        &self.removed_changes
+    }
+
+    /// Temperature and fan changes only the job after makes
+    pub fn added_changes(&self) -> &[ScheduleChange] {
+        &self.added_changes
+    }
+
+    /// Slicer settings with other values, by name
+    pub fn settings(&self) -> &[SettingDiff] {
+        &self.settings
+    }
+
+    /// True if both jobs print the same, with the same settings
+    pub fn is_empty(&self) -> bool {
+        (self.before_stats.duration() - self.after_stats.duration()).abs() <= DURATION_TOLERANCE
+            && (self.before_stats.filament() - self.after_stats.filament()).abs()
+                <= LENGTH_TOLERANCE
+            && same_bounds(self.before_stats.bounds(), self.after_stats.bounds())
+            && self.layers.is_empty()
+            && self.removed_changes.is_empty()
+            && self.added_changes.is_empty()
+            && self.settings.is_empty()
+    }
+}
+
+/// Lines read up front, handed out again for each analysis
+fn lines(lines: &[GcodeLine]) -> impl Iterator<Item = PrintResult<GcodeLine>> + '_ {
+    lines.iter().cloned().map(Ok)
+}
+
+fn same_layer(before: &LayerStats, after: &LayerStats) -> bool {
+    (before.z() - after.z()).abs() <= LENGTH_TOLERANCE
+        && (before.duration() - after.duration()).abs() <= DURATION_TOLERANCE
+        && (before.extrusion() - after.extrusion()).abs() <= LENGTH_TOLERANCE
+        && same_bounds(before.bounds(), after.bounds())
+}
+
+fn same_bounds(before: Option<Bounds>, after: Option<Bounds>) -> bool {
+    let same_location = |before: Location, after: Location| {
+        (before.x() - after.x()).abs() <= LENGTH_TOLERANCE
+            && (before.y() - after.y()).abs() <= LENGTH_TOLERANCE
+            && (before.z() - after.z()).abs() <= LENGTH_TOLERANCE
+    };
+
+    match (before, after) {
+        (Some(before), Some(after)) => {
+            same_location(before.min(), after.min()) && same_location(before.max(), after.max())
+        }
+        (before, after) => before.is_none() && after.is_none(),
+    }
+}
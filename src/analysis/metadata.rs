@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use crate::error::PrintResult;
+use crate::gcode::GcodeLine;
+
+/// Information the slicer left in the comments of a job
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct JobMetadata {
+    /// Slicer and version that generated the job
+    generator: Option<String>,
+    /// Slicer settings, by name
+    settings: BTreeMap<String, String>,
+}
+
+impl JobMetadata {
+    /// Collects the metadata out of the comments of a job. Two layouts are understood:
+    /// `; name = value` anywhere in the job, like PrusaSlicer writes them at the end, and
+    /// `;NAME:value` in the header before the first command, like Cura does
+    pub fn from_lines(
+        lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    ) -> PrintResult<JobMetadata> {
+        let mut metadata = JobMetadata::default();
+        let mut in_header = true;
+
+        for line in lines {
+            let line = line?;
+            if line.command().is_some() {
+                in_header = false;
+                continue;
+            }
+            let Some(comment) = line.comment() else {
+                continue;
+            };
+
+            if let Some(generator) = generator_of(comment) {
+                metadata.generator.get_or_insert(generator);
+            } else if let Some((name, value)) = comment.split_once(" = ") {
+                metadata
+                    .settings
+                    .insert(name.trim().to_string(), value.trim().to_string());
+            } else if let Some((name, value)) = comment.split_once(':').filter(|_| in_header) {
+                metadata
+                    .settings
+                    .insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn generator(&self) -> Option<&str> {
+        self.generator.as_deref()
+    }
+
+    pub fn settings(&self) -> &BTreeMap<String, String> {
+        &self.settings
+    }
+
+    pub fn setting(&self, name: &str) -> Option<&str> {
+        self.settings.get(name).map(String::as_str)
+    }
+}
+
+/// Slicer named by comments like `generated by PrusaSlicer 2.2.0 on 2020-02-04` or
+/// `Generated with Cura_SteamEngine 5.4.0`, without the date
+fn generator_of(comment: &str) -> Option<String> {
+    let lowercase = comment.to_ascii_lowercase();
+    let start = ["generated by ", "generated with "]
+        .iter()
+        .find_map(|prefix| lowercase.starts_with(prefix).then_some(prefix.len()))?;
+
+    let generator = &comment[start..];
+    let generator = generator.split(" on ").next().unwrap_or(generator);
+    Some(generator.trim().to_string())
+}
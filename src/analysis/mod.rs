@@ -0,0 +1,342 @@
+mod diff;
+mod metadata;
+mod schedule;
+
+use std::collections::VecDeque;
+
+use crate::error::PrintResult;
+use crate::export::{Layer, replay_job};
+use crate::gcode::GcodeLine;
+use crate::motion::{PlannedSegment, Planner};
+use crate::system::{Location, ResolvedMove, SystemConfig};
+use crate::types::{ExtrudeAmountType, LocationType};
+
+pub use diff::{JobDiff, LayerDiff, SettingDiff};
+pub use metadata::JobMetadata;
+pub use schedule::{JobSchedule, ScheduleChange, ScheduledOutput};
+
+/// Smallest box holding a set of locations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    min: Location,
+    max: Location,
+}
+
+/// Summary of a single layer of a job
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerStats {
+    index: usize,
+    z: LocationType,
+    /// Seconds
+    duration: f32,
+    extrusion: ExtrudeAmountType,
+    /// Box holding every extrusion of the layer. None if nothing was extruded
+    bounds: Option<Bounds>,
+}
+
+/// Summary of a whole job, found by replaying it through the state tracker and the planner
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct JobStats {
+    /// Seconds, accelerations included
+    duration: f32,
+    /// Millimeters of filament pushed, retractions discounted
+    filament: ExtrudeAmountType,
+    moves: usize,
+    layers: Vec<LayerStats>,
+    /// Box holding every extrusion of the job. None if nothing was extruded
+    bounds: Option<Bounds>,
+}
+
+impl Bounds {
+    fn new(location: Location) -> Self {
+        Self {
+            min: location,
+            max: location,
+        }
+    }
+
+    pub fn min(&self) -> Location {
+        self.min
+    }
+
+    pub fn max(&self) -> Location {
+        self.max
+    }
+
+    /// Grows the box until it holds the location
+    fn include(&mut self, location: Location) {
+        self.min = Location::new(
+            self.min.x().min(location.x()),
+            self.min.y().min(location.y()),
+            self.min.z().min(location.z()),
+        );
+        self.max = Location::new(
+            self.max.x().max(location.x()),
+            self.max.y().max(location.y()),
+            self.max.z().max(location.z()),
+        );
+    }
+}
+
+/// Adds the ends of an extrusion to a box, creating it if needed
+fn include_move(bounds: &mut Option<Bounds>, resolved_move: &ResolvedMove) {
+    for location in [resolved_move.start(), resolved_move.end()] {
+        match bounds {
+            Some(bounds) => bounds.include(location),
+            None => *bounds = Some(Bounds::new(location)),
+        }
+    }
+}
+
+impl LayerStats {
+    fn new(index: usize) -> Self {
+        Self {
+            index,
+            z: 0.0,
+            duration: 0.0,
+            extrusion: 0.0,
+            bounds: None,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Height of the layer, in millimeters
+    pub fn z(&self) -> LocationType {
+        self.z
+    }
+
+    /// Seconds
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// Millimeters of filament pushed, retractions discounted
+    pub fn extrusion(&self) -> ExtrudeAmountType {
+        self.extrusion
+    }
+
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.bounds
+    }
+}
+
+impl JobStats {
+    /// Replays a job on a copy of the configuration. Durations come from the planner, so they take
+    /// the accelerations and junction speeds into account, following any limit changed by the job
+    pub fn from_lines(
+        lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+        config: &SystemConfig,
+    ) -> PrintResult<JobStats> {
+        let mut stats = JobStats::default();
+        let mut planner = Planner::new();
+        //  Planner releases the segments in the same order the moves were pushed, so the layers follow them
+        let mut pending_layers = VecDeque::new();
+
+        replay_job(lines, config, |_, resolved_move, layer, state| {
+            stats.add_move(resolved_move, layer);
+            pending_layers.push_back(layer.index);
+            for segment in planner.push(resolved_move.clone(), state.motion_limits()) {
+                stats.add_segment(&segment, pending_layers.pop_front());
+            }
+            Ok(())
+        })?;
+
+        for segment in planner.flush() {
+            stats.add_segment(&segment, pending_layers.pop_front());
+        }
+
+        Ok(stats)
+    }
+
+    /// Seconds, accelerations included
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// Millimeters of filament pushed, retractions discounted
+    pub fn filament(&self) -> ExtrudeAmountType {
+        self.filament
+    }
+
+    /// Amount of resolved moves. Arcs count one per segment
+    pub fn moves(&self) -> usize {
+        self.moves
+    }
+
+    pub fn layers(&self) -> &[LayerStats] {
+        &self.layers
+    }
+
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.bounds
+    }
+
+    fn add_move(&mut self, resolved_move: &ResolvedMove, layer: Layer) {
+        while self.layers.len() <= layer.index {
+            self.layers.push(LayerStats::new(self.layers.len()));
+        }
+        let layer_stats = &mut self.layers[layer.index];
+        layer_stats.z = layer.z;
+        layer_stats.extrusion += resolved_move.extrusion();
+
+        self.moves += 1;
+        self.filament += resolved_move.extrusion();
+        if resolved_move.prints() {
+            include_move(&mut layer_stats.bounds, resolved_move);
+            include_move(&mut self.bounds, resolved_move);
+        }
+    }
+
+    fn add_segment(&mut self, segment: &PlannedSegment, layer: Option<usize>) {
+        self.duration += segment.duration();
+        if let Some(layer_stats) = layer.and_then(|index| self.layers.get_mut(index)) {
+            layer_stats.duration += segment.duration();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::{
+        JobDiff, JobMetadata, JobSchedule, JobStats, ScheduleChange, ScheduledOutput,
+    };
+    use crate::gcode::GcodeReader;
+    use crate::system::{Location, SystemConfig};
+
+    #[test]
+    fn stats_by_layer() {
+        let source = "M83\nG1 Z0.2 F1200\nG1 X10 Y10\nG1 X20 Y10 E1\nG1 X20 Y20 E1\nG1 E-0.5\n\
+                      G1 Z0.4\nG1 E0.5\nG1 X10 Y20 E2\n";
+        let stats = JobStats::from_lines(
+            GcodeReader::new(source.as_bytes()),
+            &SystemConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(stats.moves(), 8);
+        assert_eq!(stats.filament(), 4.0);
+        assert_eq!(stats.layers().len(), 2);
+        //  Priming after the Z change still belongs to the layer below, until something is printed
+        assert_eq!(stats.layers()[0].extrusion(), 2.0);
+        assert_eq!(stats.layers()[1].z(), 0.4);
+        assert_eq!(stats.layers()[1].extrusion(), 2.0);
+
+        let bounds = stats.bounds().unwrap();
+        assert_eq!(bounds.min(), Location::new(10.0, 10.0, 0.2));
+        assert_eq!(bounds.max(), Location::new(20.0, 20.0, 0.4));
+
+        //  Accelerations make the job slower than moving at the feedrate all along
+        let nominal: f32 = [0.2, 14.1421, 10.0, 10.0, 0.5, 0.2, 0.5, 10.0]
+            .iter()
+            .map(|length| length / 20.0)
+            .sum();
+        let layers_duration: f32 = stats.layers().iter().map(|layer| layer.duration()).sum();
+        assert!(stats.duration() > nominal);
+        assert!((layers_duration - stats.duration()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn metadata_from_comments() {
+        let source = std::fs::read_to_string("small_example.gcode").unwrap();
+        let metadata = JobMetadata::from_lines(GcodeReader::new(source.as_bytes())).unwrap();
+
+        assert_eq!(
+            metadata.generator(),
+            Some("PrusaSlicer 2.2.0-alpha3+184-win64")
+        );
+        assert_eq!(metadata.settings().len(), 6);
+        assert_eq!(
+            metadata.setting("first layer extrusion width"),
+            Some("0.42mm")
+        );
+
+        let cura = ";FLAVOR:Marlin\n;Generated with Cura_SteamEngine 5.4.0\nG28\n;TYPE:SKIRT\n";
+        let metadata = JobMetadata::from_lines(GcodeReader::new(cura.as_bytes())).unwrap();
+        assert_eq!(metadata.generator(), Some("Cura_SteamEngine 5.4.0"));
+        assert_eq!(metadata.setting("FLAVOR"), Some("Marlin"));
+        //  Markers past the header describe the moves, not the job
+        assert_eq!(metadata.setting("TYPE"), None);
+    }
+
+    #[test]
+    fn schedule_by_layer() {
+        let source = "M140 S60\nM104 S215\nM83\nG1 Z0.2 F1200\nM106 S0\nG1 X10 Y10\nG1 X20 Y10 E1\n\
+                      M104 S210\nG1 Z0.4\nM106 S255\nG1 X10 Y10 E1\nM104 S0\nM140 S0\nM107\n";
+        let schedule = JobSchedule::from_lines(
+            GcodeReader::new(source.as_bytes()),
+            &SystemConfig::default(),
+        )
+        .unwrap();
+
+        //  Settings go with the layer printed next, and the ones after the last print with the last layer
+        let changes: Vec<_> = schedule
+            .changes()
+            .iter()
+            .map(|change| (change.layer(), change.output(), change.value()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (0, ScheduledOutput::Hotend(0), 215),
+                (0, ScheduledOutput::Bed, 60),
+                (1, ScheduledOutput::Hotend(0), 210),
+                (1, ScheduledOutput::Fan, 255),
+                (1, ScheduledOutput::Hotend(0), 0),
+                (1, ScheduledOutput::Bed, 0),
+                (1, ScheduledOutput::Fan, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_between_jobs() {
+        let job = |header: &str, second_layer: &str| {
+            format!(
+                "; layer_height = {header}\nM104 S215\nM83\nG1 Z0.2 F1200\nG1 X10 Y10\n\
+                 G1 X20 Y10 E1\nG1 Z0.4\n{second_layer}\nM104 S0\n"
+            )
+        };
+        let diff = |before: &str, after: &str| {
+            JobDiff::from_lines(
+                GcodeReader::new(before.as_bytes()),
+                GcodeReader::new(after.as_bytes()),
+                &SystemConfig::default(),
+            )
+            .unwrap()
+        };
+        let before = job("0.2", "M106 S255\nG1 X10 Y10 E1");
+
+        //  Formatting, comments and the way values are written don't matter
+        let reformatted = "; layer_height = 0.2\nM104 S215.0 ; heat up\n\nM83\nG1 Z.2 F1200.000\n\
+                           G1   X10.000 Y10\n;perimeter\nG1 X20 Y10 E1.00\nG1 Z0.4\nM106 S255\n\
+                           G1 X10 Y10 E1\nM104 S0\n";
+        assert!(diff(&before, reformatted).is_empty());
+
+        //  Fan turned on a layer earlier, printing slower on the second layer, and another setting
+        let after =
+            job("0.3", "G1 X10 Y10 E1.5 F600").replace("M104 S215\n", "M104 S215\nM106 S255\n");
+        let diff = diff(&before, &after);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.layers().len(), 1);
+        let layer = &diff.layers()[0];
+        assert_eq!(layer.index(), 1);
+        assert_eq!(layer.before().unwrap().extrusion(), 1.0);
+        assert_eq!(layer.after().unwrap().extrusion(), 1.5);
+        assert!(layer.after().unwrap().duration() > layer.before().unwrap().duration());
+
+        let layers_of = |changes: &[ScheduleChange]| -> Vec<usize> {
+            changes.iter().map(|change| change.layer()).collect()
+        };
+        assert_eq!(layers_of(diff.removed_changes()), [1]);
+        assert_eq!(layers_of(diff.added_changes()), [0]);
+        assert_eq!(diff.removed_changes()[0].output(), ScheduledOutput::Fan);
+
+        assert_eq!(diff.settings().len(), 1);
+        assert_eq!(diff.settings()[0].name(), "layer_height");
+        assert_eq!(diff.settings()[0].before(), Some("0.2"));
+        assert_eq!(diff.settings()[0].after(), Some("0.3"));
+    }
+}
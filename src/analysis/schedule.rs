@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use crate::error::PrintResult;
+use crate::export::LayerTracker;
+use crate::gcode::GcodeLine;
+use crate::recovery::ModalState;
+use crate::system::SystemConfig;
+
+/// Heater or fan a job sets as it goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ScheduledOutput {
+    /// Hotend of a tool
+    Hotend(u8),
+    Bed,
+    /// Part cooling fan
+    Fan,
+}
+
+/// Setting a job gives a heater or the fan, from some layer on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleChange {
+    layer: usize,
+    output: ScheduledOutput,
+    /// Target in °C for heaters, zero if off. Speed from 0 to 255 for the fan
+    value: u16,
+}
+
+/// Target temperatures and fan speeds of a job, layer by layer
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JobSchedule {
+    changes: Vec<ScheduleChange>,
+}
+
+impl std::fmt::Display for ScheduledOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduledOutput::Hotend(tool) => write!(f, "hotend {tool}"),
+            ScheduledOutput::Bed => write!(f, "bed"),
+            ScheduledOutput::Fan => write!(f, "fan"),
+        }
+    }
+}
+
+impl ScheduleChange {
+    /// Layer the setting is for, counted like the layers of `JobStats`
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+
+    pub fn output(&self) -> ScheduledOutput {
+        self.output
+    }
+
+    /// Target in °C for heaters, zero if off. Speed from 0 to 255 for the fan
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+}
+
+impl JobSchedule {
+    /// Replays a job, noting every target temperature and fan speed it changes. Slicers set them up right
+    /// before the layer they're meant for, so a change belongs to the layer of the next move printing,
+    /// or to the last layer if nothing is printed after it. Settings changed back before anything is
+    /// printed are left out
+    pub fn from_lines(
+        lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+        config: &SystemConfig,
+    ) -> PrintResult<JobSchedule> {
+        let mut state = config.clone();
+        let mut modal_state = ModalState::new(config);
+        let mut layers = LayerTracker::default();
+        let mut last_layer = 0;
+        //  Settings in effect as of the last change noted, and as of the last command
+        let mut scheduled = outputs(&modal_state);
+        let mut current = scheduled.clone();
+        let mut schedule = JobSchedule::default();
+
+        for line in lines {
+            let line = line?;
+            let Some(command) = line.command() else {
+                continue;
+            };
+
+            modal_state.apply_command(command);
+            current = outputs(&modal_state);
+            let resolved_moves = state
+                .apply_command(command)
+                .map_err(|error| error.in_line(line.line_number()))?;
+            for resolved_move in resolved_moves {
+                let layer = layers.layer_of(&resolved_move);
+                if resolved_move.prints() {
+                    last_layer = layer.index;
+                    schedule.note_changes(&mut scheduled, &current, layer.index);
+                }
+            }
+        }
+        schedule.note_changes(&mut scheduled, &current, last_layer);
+
+        Ok(schedule)
+    }
+
+    /// Changes in the order the job makes them, by layer
+    pub fn changes(&self) -> &[ScheduleChange] {
+        &self.changes
+    }
+
+    fn note_changes(
+        &mut self,
+        scheduled: &mut BTreeMap<ScheduledOutput, u16>,
+        current: &BTreeMap<ScheduledOutput, u16>,
+        layer: usize,
+    ) {
+        for (output, value) in current {
+            if scheduled.insert(*output, *value) != Some(*value) {
+                self.changes.push(ScheduleChange {
+                    layer,
+                    output: *output,
+                    value: *value,
+                });
+            }
+        }
+    }
+}
+
+/// Setting of every heater and of the fan
+fn outputs(modal_state: &ModalState) -> BTreeMap<ScheduledOutput, u16> {
+    let hotends = modal_state
+        .hotend_temperatures()
+        .iter()
+        .enumerate()
+        .map(|(tool, temperature)| (ScheduledOutput::Hotend(tool as u8), *temperature));
+
+    hotends
+        .chain([
+            (ScheduledOutput::Bed, modal_state.bed_temperature()),
+            (ScheduledOutput::Fan, modal_state.fan_speed() as u16),
+        ])
+        .collect()
+}
@@ -0,0 +1,457 @@
+use std::f32::consts::TAU;
+use std::io::Write;
+
+use crate::error::PrintResult;
+use crate::gcode::{G1Move, G2ArcMove, GcodeCommand, GcodeLine, GcodeWriter};
+use crate::system::{Location, SystemConfig};
+use crate::types::{ExtrudeAmountType, FeedrateAmountType, LocationType};
+
+/// Fewest straight moves replaced by an arc. Same as ArcWelder
+const MIN_ARC_MOVES: usize = 3;
+/// Widest radius fitted, in millimeters. Flatter curves stay as straight moves, since firmwares lose
+/// precision on arcs whose center is that far away
+const MAX_ARC_RADIUS: LocationType = 1000.0;
+/// Extrusion per millimeter of the moves replaced by an arc can differ this much, relative to the first one.
+/// Same as ArcWelder
+const EXTRUSION_RATE_TOLERANCE: f32 = 0.05;
+/// Length of an arc can differ this much from the moves it replaces, relative to them. Any three points
+/// are on some circle, so corners like the ones of a square would be fitted without it. Same as ArcWelder
+const PATH_LENGTH_TOLERANCE: LocationType = 0.05;
+
+/// What the arc fitting did to a job
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ArcFitReport {
+    lines_read: usize,
+    lines_written: usize,
+    arcs_written: usize,
+    /// Straight moves replaced by the arcs
+    moves_replaced: usize,
+    /// Furthest any replaced move was from its arc, in millimeters
+    max_deviation: LocationType,
+}
+
+/// Straight move on the XY plane, waiting to find out if it's part of an arc
+#[derive(Debug, Clone)]
+struct FitMove {
+    command: G1Move,
+    comment: Option<String>,
+    start: Location,
+    end: Location,
+    extrusion: ExtrudeAmountType,
+    /// Millimeters per minute
+    feedrate: FeedrateAmountType,
+}
+
+/// Circle going through every point of a run of moves
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FittedArc {
+    center_x: LocationType,
+    center_y: LocationType,
+    clockwise: bool,
+    /// Furthest the moves are from the circle, in millimeters
+    deviation: LocationType,
+}
+
+/// Replaces runs of straight moves lying on a circle with G2 and G3 arcs. The moves must be within
+/// `tolerance` millimeters of the arc all along. Moves of a run
+/// stay on the same height, feedrate and extrusion rate, and the arc extrudes as much as all of them
+/// together. Anything else is written as it was read, and arcs never span over it
+pub fn fit_arcs<W: Write>(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    writer: &mut GcodeWriter<W>,
+    tolerance: LocationType,
+) -> PrintResult<ArcFitReport> {
+    let mut fitter = ArcFitter::new(tolerance);
+
+    for line in lines {
+        let line = line?;
+        fitter.report.lines_read += 1;
+        match line.command() {
+            Some(command) => fitter
+                .fit_command(command, line.comment(), writer)
+                .map_err(|error| error.in_line(line.line_number()))?,
+            None => {
+                fitter.flush(writer)?;
+                fitter.report.lines_written += 1;
+                writer.write_line(&line)?;
+            }
+        }
+    }
+    fitter.flush(writer)?;
+
+    Ok(fitter.report)
+}
+
+impl ArcFitReport {
+    pub fn lines_read(&self) -> usize {
+        self.lines_read
+    }
+
+    pub fn lines_written(&self) -> usize {
+        self.lines_written
+    }
+
+    pub fn arcs_written(&self) -> usize {
+        self.arcs_written
+    }
+
+    /// Straight moves replaced by the arcs
+    pub fn moves_replaced(&self) -> usize {
+        self.moves_replaced
+    }
+
+    /// Lines written per line read. Ex: 0.25 for a job written in a quarter of its lines
+    pub fn compression_ratio(&self) -> f32 {
+        match self.lines_read {
+            0 => 1.0,
+            lines_read => self.lines_written as f32 / lines_read as f32,
+        }
+    }
+
+    /// Furthest any replaced move was from its arc, in millimeters
+    pub fn max_deviation(&self) -> LocationType {
+        self.max_deviation
+    }
+}
+
+struct ArcFitter {
+    tolerance: LocationType,
+    /// State of the original job
+    state: SystemConfig,
+    /// Moves lying on the same circle so far
+    run: Vec<FitMove>,
+    report: ArcFitReport,
+}
+
+impl ArcFitter {
+    fn new(tolerance: LocationType) -> Self {
+        Self {
+            tolerance,
+            state: SystemConfig::default(),
+            run: vec![],
+            report: ArcFitReport::default(),
+        }
+    }
+
+    fn fit_command<W: Write>(
+        &mut self,
+        command: &GcodeCommand,
+        comment: Option<&str>,
+        writer: &mut GcodeWriter<W>,
+    ) -> PrintResult<()> {
+        let start = self.state.current_location();
+        let start_extrusion = self.state.extruder_position();
+        //  Units and modes change how the run is written, so it goes out before the state follows them
+        if !matches!(command, GcodeCommand::G1(_)) {
+            self.flush(writer)?;
+        }
+        if command.changes_position() {
+            self.state.apply_command(command)?;
+        }
+
+        let next = match command {
+            GcodeCommand::G1(linear_move) => FitMove {
+                command: linear_move.clone(),
+                comment: comment.map(str::to_string),
+                start,
+                end: self.state.current_location(),
+                extrusion: self.state.extruder_position() - start_extrusion,
+                feedrate: self.state.feedrate(),
+            },
+            command => {
+                self.report.lines_written += 1;
+                return writer.write_command(command, comment);
+            }
+        };
+        let is_planar = next.start.z == next.end.z
+            && (next.start.x != next.end.x || next.start.y != next.end.y)
+            && next.command.laser_power.is_none();
+        if !is_planar {
+            self.flush(writer)?;
+            return self.write_move(&next, writer);
+        }
+
+        //  Moves that can't go on the arc close it. Runs too short for an arc give away their first
+        //  move, since the rest of them might still start one
+        while !self.run.is_empty() && !self.extends_run(&next) {
+            if self.run.len() >= MIN_ARC_MOVES {
+                self.flush(writer)?;
+            } else {
+                let first = self.run.remove(0);
+                self.write_move(&first, writer)?;
+            }
+        }
+        self.run.push(next);
+
+        Ok(())
+    }
+
+    /// True if the move goes on along the circle of the run, at the same height, feedrate and extrusion rate.
+    /// Moves commented otherwise than the first one never join it, since the comment would be lost
+    fn extends_run(&self, next: &FitMove) -> bool {
+        let first = &self.run[0];
+        let rate = first.extrusion / first.start.distance_to(&first.end);
+        let next_rate = next.extrusion / next.start.distance_to(&next.end);
+        if first.start.z != next.end.z
+            || first.feedrate != next.feedrate
+            || (next.comment.is_some() && next.comment != first.comment)
+            || (rate - next_rate).abs() > EXTRUSION_RATE_TOLERANCE * rate.abs()
+            || (rate == 0.0) != (next_rate == 0.0)
+        {
+            return false;
+        }
+
+        let mut run = self.run.clone();
+        run.push(next.clone());
+        fit_circle(&run, self.tolerance).is_some()
+    }
+
+    /// Writes the run, as an arc if it's long enough and as the moves it holds otherwise
+    fn flush<W: Write>(&mut self, writer: &mut GcodeWriter<W>) -> PrintResult<()> {
+        let run = std::mem::take(&mut self.run);
+        let arc = match run.len() >= MIN_ARC_MOVES {
+            true => fit_circle(&run, self.tolerance),
+            false => None,
+        };
+        let Some(arc) = arc else {
+            for fit_move in &run {
+                self.write_move(fit_move, writer)?;
+            }
+            return Ok(());
+        };
+
+        let first = &run[0];
+        let last = &run[run.len() - 1];
+        let factor = self.state.to_millimeters(1.0);
+        let (x_target, y_target) = match self.state.is_relative_positioning() {
+            true => (last.end.x - first.start.x, last.end.y - first.start.y),
+            false => (last.end.x, last.end.y),
+        };
+        //  Absolute extrusion ends where the last move does, and relative extrusion adds up every move
+        let amount_to_extrude = match self.state.is_relative_extrusion() {
+            true => run
+                .iter()
+                .filter_map(|fit_move| fit_move.command.amount_to_extrude)
+                .reduce(|total, amount| total + amount),
+            false => run
+                .iter()
+                .rev()
+                .find_map(|fit_move| fit_move.command.amount_to_extrude),
+        };
+        let arc_move = G2ArcMove {
+            x_target: Some(x_target / factor),
+            y_target: Some(y_target / factor),
+            z_target: None,
+            i_offset: Some((arc.center_x - first.start.x) / factor),
+            j_offset: Some((arc.center_y - first.start.y) / factor),
+            radius: None,
+            amount_to_extrude,
+            feedrate_per_minute: first.command.feedrate_per_minute,
+        };
+        let command = match arc.clockwise {
+            true => GcodeCommand::G2(arc_move),
+            false => GcodeCommand::G3(arc_move),
+        };
+
+        self.report.arcs_written += 1;
+        self.report.moves_replaced += run.len();
+        self.report.max_deviation = self.report.max_deviation.max(arc.deviation);
+        self.report.lines_written += 1;
+        writer.write_command(&command, first.comment.as_deref())
+    }
+
+    fn write_move<W: Write>(
+        &mut self,
+        fit_move: &FitMove,
+        writer: &mut GcodeWriter<W>,
+    ) -> PrintResult<()> {
+        self.report.lines_written += 1;
+        writer.write_command(
+            &GcodeCommand::G1(fit_move.command.clone()),
+            fit_move.comment.as_deref(),
+        )
+    }
+}
+
+/// Circle through the start, the middle and the end of a run of moves, if the moves are within the
+/// tolerance of it all along. The moves must go around the center in the same direction, without
+/// closing the circle, and be about as long as the arc
+fn fit_circle(run: &[FitMove], tolerance: LocationType) -> Option<FittedArc> {
+    let points: Vec<Location> = [run[0].start]
+        .into_iter()
+        .chain(run.iter().map(|fit_move| fit_move.end))
+        .collect();
+    let (center_x, center_y) = circumcenter(
+        &points[0],
+        &points[points.len() / 2],
+        &points[points.len() - 1],
+    )?;
+    let radius = (points[0].x - center_x).hypot(points[0].y - center_y);
+    if radius > MAX_ARC_RADIUS {
+        return None;
+    }
+
+    let distance_to_circle =
+        |x: LocationType, y: LocationType| ((x - center_x).hypot(y - center_y) - radius).abs();
+    let mut deviation: LocationType = 0.0;
+    let mut sweep = 0.0;
+    let mut length = 0.0;
+    let mut clockwise = None;
+    for pair in points.windows(2) {
+        let (from_x, from_y) = (pair[0].x - center_x, pair[0].y - center_y);
+        let (to_x, to_y) = (pair[1].x - center_x, pair[1].y - center_y);
+        let cross = from_x * to_y - from_y * to_x;
+        if cross == 0.0 || *clockwise.get_or_insert(cross < 0.0) != (cross < 0.0) {
+            return None;
+        }
+        sweep += cross.atan2(from_x * to_x + from_y * to_y).abs();
+        length += (to_x - from_x).hypot(to_y - from_y);
+
+        //  The middle of a move is where it's furthest from the arc, unless the point is off it
+        deviation =
+            deviation
+                .max(distance_to_circle(pair[1].x, pair[1].y))
+                .max(distance_to_circle(
+                    (pair[0].x + pair[1].x) / 2.0,
+                    (pair[0].y + pair[1].y) / 2.0,
+                ));
+    }
+
+    let is_same_length = (radius * sweep - length).abs() <= PATH_LENGTH_TOLERANCE * length;
+    match deviation <= tolerance && sweep < TAU && is_same_length {
+        true => Some(FittedArc {
+            center_x,
+            center_y,
+            clockwise: clockwise?,
+            deviation,
+        }),
+        false => None,
+    }
+}
+
+/// Center of the circle going through three points of the XY plane. None if they're on a straight line
+fn circumcenter(a: &Location, b: &Location, c: &Location) -> Option<(LocationType, LocationType)> {
+    //  Worked out from the first point, which keeps the products small
+    let (bx, by) = (b.x - a.x, b.y - a.y);
+    let (cx, cy) = (c.x - a.x, c.y - a.y);
+    let determinant = 2.0 * (bx * cy - by * cx);
+    if determinant.abs() <= f32::EPSILON {
+        return None;
+    }
+
+    let b_squared = bx * bx + by * by;
+    let c_squared = cx * cx + cy * cy;
+    Some((
+        a.x + (cy * b_squared - by * c_squared) / determinant,
+        a.y + (bx * c_squared - cx * b_squared) / determinant,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::JobStats;
+    use crate::arc_fit::{ArcFitReport, fit_arcs};
+    use crate::error::PrintResult;
+    use crate::gcode::{GcodeReader, rewrite_source};
+    use crate::system::SystemConfig;
+
+    fn fit_source(source: &str, tolerance: f32) -> PrintResult<(String, ArcFitReport)> {
+        rewrite_source(source, |lines, writer| fit_arcs(lines, writer, tolerance))
+    }
+
+    /// Polyline along a circle centered on X50 Y50, from one angle to another in degrees
+    fn polyline(radius: f32, from: f32, to: f32, segments: usize, extrusion: f32) -> String {
+        (1..=segments)
+            .map(|segment| {
+                let angle = (from + (to - from) * segment as f32 / segments as f32).to_radians();
+                let round = |value: f32| (value * 1000.0).round() / 1000.0;
+                format!(
+                    "G1 X{} Y{} E{extrusion}\n",
+                    round(50.0 + radius * angle.cos()),
+                    round(50.0 + radius * angle.sin())
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn polylines_become_arcs() {
+        let source = format!(
+            "M83\nG1 X70 Y50 F1200\n{}G1 X50 Y90 E1\n{}",
+            polyline(20.0, 0.0, 90.0, 18, 0.05),
+            polyline(40.0, 90.0, 0.0, 30, 0.1),
+        );
+        let (fitted, report) = fit_source(&source, 0.05).unwrap();
+
+        //  Counterclockwise from the right of the center, and then clockwise from the top of it
+        assert_eq!(
+            fitted,
+            "M83\nG1 X70 Y50 F1200\nG3 X50 Y70 I-20.00047 J-0.00047 E0.9\nG1 X50 Y90 E1\n\
+             G2 X90 Y50 I-0.00094 J-40.00094 E3\n"
+        );
+        assert_eq!(report.lines_read(), 51);
+        assert_eq!(report.lines_written(), 5);
+        assert_eq!(report.arcs_written(), 2);
+        assert_eq!(report.moves_replaced(), 48);
+        assert!(report.compression_ratio() < 0.1);
+        assert!(report.max_deviation() > 0.0 && report.max_deviation() <= 0.05);
+
+        //  Moves sagging further than the tolerance from the arc stay as they were
+        let (fitted, report) = fit_source(&source, 0.01).unwrap();
+        assert_eq!(fitted, source);
+        assert_eq!(report.arcs_written(), 0);
+        assert_eq!(report.compression_ratio(), 1.0);
+    }
+
+    #[test]
+    fn lines_and_other_commands_stay() {
+        //  Straight lines, corners, travels among extrusions and moves up never become arcs.
+        //  Neither do arcs over other commands or comments
+        let source = format!(
+            "G1 X10 Y0 E1 F600\nG1 X20 Y0 E2\nG1 X30 Y0 E3\nG1 X30 Y10 E4\nG1 X20 Y10 E5\n\
+             G1 X70 Y50\n{}M106 S255\n{}; wall\n{}G1 Z1\n{}",
+            polyline(20.0, 0.0, 5.0, 2, 0.1),
+            polyline(20.0, 5.0, 10.0, 2, 0.1),
+            polyline(20.0, 10.0, 15.0, 2, 0.1),
+            polyline(20.0, 15.0, 20.0, 2, 0.1),
+        );
+        let (fitted, report) = fit_source(&source, 0.05).unwrap();
+
+        assert_eq!(fitted, source);
+        assert_eq!(report.arcs_written(), 0);
+    }
+
+    #[test]
+    fn fitted_job_moves_the_same() {
+        let mut source = String::from("G28\nM82\nG92 E0\nG1 Z0.2 F600\nG1 X70 Y50 F1800\n");
+        let mut extrusion = 0.0;
+        for (from, to) in [(0.0, 120.0), (120.0, 240.0), (240.0, 360.0)] {
+            for point in polyline(20.0, from, to, 40, 0.0).lines() {
+                extrusion += 0.1;
+                let point = point.trim_end_matches(" E0");
+                source.push_str(&format!("{point} E{extrusion:.3}\n"));
+            }
+        }
+        let (fitted, report) = fit_source(&source, 0.05).unwrap();
+
+        //  A whole circle can't be a single arc, so its last move stays
+        assert_eq!(report.arcs_written(), 1);
+        assert!(fitted.ends_with("E11.9\nG1 X70 Y50 E12\n"), "{fitted}");
+
+        let config = SystemConfig::default();
+        let original = JobStats::from_lines(GcodeReader::new(source.as_bytes()), &config).unwrap();
+        let fitted = JobStats::from_lines(GcodeReader::new(fitted.as_bytes()), &config).unwrap();
+        assert!((original.filament() - fitted.filament()).abs() < 1e-3);
+        assert!((original.duration() - fitted.duration()).abs() < 0.01 * original.duration());
+        let (original, fitted) = (original.bounds().unwrap(), fitted.bounds().unwrap());
+        for (original, fitted) in [
+            (original.min(), fitted.min()),
+            (original.max(), fitted.max()),
+        ] {
+            assert!(
+                original.distance_to(&fitted) < 0.05,
+                "{original:?} {fitted:?}"
+            );
+        }
+    }
+}
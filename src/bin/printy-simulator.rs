@@ -0,0 +1,103 @@
+//  Pseudoterminals are only opened on Linux. Elsewhere the simulator only reports it can't run
+#[cfg(target_os = "linux")]
+use std::io::{BufRead, BufReader, Write};
+use std::process::ExitCode;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use printy::{Pseudoterminal, SystemConfig, VirtualPrinter};
+
+#[cfg(target_os = "linux")]
+const USAGE: &str = "\
+Usage: printy-simulator [--profile <profile>] [--speed <factor>]
+
+Opens a pseudoterminal and answers any host connecting to it like a printer running Marlin would.
+
+Options:
+  --profile <profile>    Printer profile to simulate. Defaults to the built in configuration
+  --speed <factor>       How many times faster than real time the printer runs. Defaults to 1,
+                         0 answers right away";
+
+#[cfg(not(target_os = "linux"))]
+fn main() -> ExitCode {
+    eprintln!(
+        "printy-simulator: unsupported platform, pseudoterminals are only available on Linux"
+    );
+    ExitCode::FAILURE
+}
+
+#[cfg(target_os = "linux")]
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("printy-simulator: {message}\n\n{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run() -> Result<(), String> {
+    let mut profile = None;
+    let mut speed = 1.0;
+
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        let mut value = || {
+            arguments
+                .next()
+                .ok_or_else(|| format!("missing value of option `{argument}`"))
+        };
+        match argument.as_str() {
+            "--profile" => profile = Some(value()?),
+            "--speed" => {
+                let factor = value()?;
+                speed = match factor.parse::<f32>() {
+                    Ok(speed) if speed >= 0.0 && speed.is_finite() => speed,
+                    _ => return Err(format!("invalid speed `{factor}`")),
+                };
+            }
+            "help" | "--help" | "-h" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            argument => return Err(format!("unknown argument `{argument}`")),
+        }
+    }
+
+    let config = match profile {
+        Some(path) => {
+            SystemConfig::load_profile(&path).map_err(|error| format!("{path}: {error}"))?
+        }
+        None => SystemConfig::default(),
+    };
+    let mut printer = VirtualPrinter::new(config);
+    let terminal = Pseudoterminal::open().map_err(|error| error.to_string())?;
+    println!("Listening on {}", terminal.slave_path().display());
+
+    let mut output = terminal.master();
+    let send = |output: &mut &std::fs::File, text: &str| {
+        output
+            .write_all(format!("{text}\n").as_bytes())
+            .map_err(|error| error.to_string())
+    };
+    send(&mut output, printer.greeting())?;
+
+    for line in BufReader::new(terminal.master()).lines() {
+        let line = line.map_err(|error| error.to_string())?;
+        //  Messages are sent once the simulated time they wait for has gone by
+        let mut sent_at = 0.0;
+        for message in printer.handle_line(&line) {
+            if speed > 0.0 {
+                let wait = (message.delay() - sent_at) / speed;
+                std::thread::sleep(Duration::from_secs_f32(wait.max(0.0)));
+            }
+            sent_at = message.delay();
+            send(&mut output, message.text())?;
+        }
+    }
+
+    Ok(())
+}
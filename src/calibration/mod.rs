@@ -0,0 +1,370 @@
+mod probing;
+mod simulation;
+
+use crate::error::{Error, PrintResult};
+use crate::motion::{Axis, AxisSteps, Kinematics, StepperDriver};
+use crate::system::{Location, SystemConfig};
+
+pub use probing::{BedProbe, probe_mesh};
+pub use simulation::SimulatedAxes;
+
+/// Limit switches at both ends of the X, Y and Z axes
+pub trait Endstops {
+    /// True while the switch at one end of an axis is pressed
+    fn is_triggered(&mut self, axis: Axis, end: AxisEnd) -> PrintResult<bool>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AxisEnd {
+    /// Closest to the origin
+    Min,
+    /// Furthest from the origin
+    Max,
+}
+
+/// Speeds and distances used to find the endstops
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomingConfig {
+    /// Speed of the first approach to a switch, in mm/s
+    fast_feedrate: f32,
+    /// Speed of the second approach to a switch, where the precision is taken from, in mm/s
+    slow_feedrate: f32,
+    /// Distance moved away from a switch after the first approach, in millimeters
+    backoff_distance: f32,
+    /// Longest distance an axis moves looking for a switch before giving up, in millimeters
+    max_travel: f32,
+}
+
+/// Result of the calibration, already written to the bed config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BedCalibration {
+    origin: Location,
+    limit: Location,
+}
+
+impl Default for HomingConfig {
+    fn default() -> Self {
+        Self {
+            fast_feedrate: 50.0,
+            slow_feedrate: 5.0,
+            backoff_distance: 5.0,
+            max_travel: 500.0,
+        }
+    }
+}
+
+impl HomingConfig {
+    pub fn new(
+        fast_feedrate: f32,
+        slow_feedrate: f32,
+        backoff_distance: f32,
+        max_travel: f32,
+    ) -> Self {
+        Self {
+            fast_feedrate,
+            slow_feedrate,
+            backoff_distance,
+            max_travel,
+        }
+    }
+}
+
+impl BedCalibration {
+    pub fn origin(&self) -> Location {
+        self.origin
+    }
+
+    pub fn limit(&self) -> Location {
+        self.limit
+    }
+}
+
+/// Boot calibration. Homes every axis to its min switch, which becomes the origin, and then measures the travel
+/// up to the max switch, which becomes the limit of the print area. Both are written to the bed config, and the
+/// machine is left at the origin
+pub fn calibrate_bed(
+    config: &mut SystemConfig,
+    driver: &mut impl StepperDriver,
+    endstops: &mut dyn Endstops,
+    homing: &HomingConfig,
+) -> PrintResult<BedCalibration> {
+    let mut travel = [0.0; 3];
+
+    for (index, axis) in [Axis::X, Axis::Y, Axis::Z].into_iter().enumerate() {
+        home_axis(driver, endstops, axis, AxisEnd::Min, config, homing)?;
+        travel[index] = measure_travel(driver, endstops, axis, config, homing)?;
+    }
+
+    let calibration = BedCalibration {
+        origin: Location::default(),
+        limit: Location::new(travel[0], travel[1], travel[2]),
+    };
+    config.set_bed(calibration.origin, calibration.limit);
+    config.set_current_location(calibration.origin);
+
+    Ok(calibration)
+}
+
+/// Finds the switch at one end of an axis: a fast approach until it triggers, a short back off,
+/// and a slow approach for precision. The axis is left right where the switch triggers.
+/// The toolhead moves along the axis, with the motors the kinematics need for it, like both motors
+/// of a CoreXY for X. Deltas home their towers instead, which isn't supported
+pub fn home_axis(
+    driver: &mut impl StepperDriver,
+    endstops: &mut dyn Endstops,
+    axis: Axis,
+    end: AxisEnd,
+    config: &SystemConfig,
+    homing: &HomingConfig,
+) -> PrintResult<()> {
+    approach(
+        driver,
+        endstops,
+        axis,
+        end,
+        homing.fast_feedrate,
+        homing.max_travel,
+        config,
+    )?;
+    back_off(driver, endstops, axis, end, config, homing)?;
+    approach(
+        driver,
+        endstops,
+        axis,
+        end,
+        homing.slow_feedrate,
+        homing.backoff_distance * 2.0,
+        config,
+    )?;
+
+    Ok(())
+}
+
+/// Measures the distance between both switches of an axis, in millimeters.
+/// The axis must be homed to its min switch, and it's moved back there once measured
+pub fn measure_travel(
+    driver: &mut impl StepperDriver,
+    endstops: &mut dyn Endstops,
+    axis: Axis,
+    config: &SystemConfig,
+    homing: &HomingConfig,
+) -> PrintResult<f32> {
+    let mut position = approach(
+        driver,
+        endstops,
+        axis,
+        AxisEnd::Max,
+        homing.fast_feedrate,
+        homing.max_travel,
+        config,
+    )?;
+    position += back_off(driver, endstops, axis, AxisEnd::Max, config, homing)?;
+    position += approach(
+        driver,
+        endstops,
+        axis,
+        AxisEnd::Max,
+        homing.slow_feedrate,
+        homing.backoff_distance * 2.0,
+        config,
+    )?;
+
+    let axis_steps_per_unit = config.steps_per_unit().get(axis);
+    driver.move_steps(
+        &actuator_steps(axis, -position, config)?,
+        position as f32 / (homing.fast_feedrate * axis_steps_per_unit),
+    )?;
+
+    Ok(position as f32 / axis_steps_per_unit)
+}
+
+/// Moves an axis one step at a time towards one of its ends, until the switch there triggers.
+/// Returns the signed amount of steps taken along the axis
+fn approach(
+    driver: &mut impl StepperDriver,
+    endstops: &mut dyn Endstops,
+    axis: Axis,
+    end: AxisEnd,
+    feedrate: f32,
+    max_distance: f32,
+    config: &SystemConfig,
+) -> PrintResult<i32> {
+    let direction = end.direction();
+    let steps_per_unit = config.steps_per_unit().get(axis);
+    let max_steps = (max_distance * steps_per_unit).ceil() as i32;
+    let step_duration = 1.0 / (feedrate * steps_per_unit);
+    let step = actuator_steps(axis, direction, config)?;
+    let mut taken = 0;
+
+    while !endstops.is_triggered(axis, end)? {
+        if taken >= max_steps {
+            return Err(Error::HomingFailed(axis));
+        }
+        driver.move_steps(&step, step_duration)?;
+        taken += 1;
+    }
+
+    Ok(taken * direction)
+}
+
+/// Moves an axis away from a triggered switch, which must be released afterwards.
+/// Returns the signed amount of steps taken
+fn back_off(
+    driver: &mut impl StepperDriver,
+    endstops: &mut dyn Endstops,
+    axis: Axis,
+    end: AxisEnd,
+    config: &SystemConfig,
+    homing: &HomingConfig,
+) -> PrintResult<i32> {
+    let steps = -end.direction()
+        * (homing.backoff_distance * config.steps_per_unit().get(axis)).round() as i32;
+    driver.move_steps(
+        &actuator_steps(axis, steps, config)?,
+        homing.backoff_distance / homing.fast_feedrate,
+    )?;
+
+    if endstops.is_triggered(axis, end)? {
+        return Err(Error::HomingFailed(axis));
+    }
+
+    Ok(steps)
+}
+
+/// Steps of each motor moving the toolhead some steps along an axis. The kinematics must be linear,
+/// so the motors move the same for the same distance anywhere on the bed
+fn actuator_steps(axis: Axis, steps: i32, config: &SystemConfig) -> PrintResult<AxisSteps> {
+    let (kinematics, steps_per_unit) = (config.kinematics(), config.steps_per_unit());
+    if !kinematics.is_linear() {
+        return Err(Error::HomingNotSupported);
+    }
+
+    let distance = steps as f32 / steps_per_unit.get(axis);
+    let offset = match axis {
+        Axis::X => Location::new(distance, 0.0, 0.0),
+        Axis::Y => Location::new(0.0, distance, 0.0),
+        Axis::Z => Location::new(0.0, 0.0, distance),
+        Axis::E => return Ok(AxisSteps::default()),
+    };
+    let actuators = kinematics.to_actuators(&offset)?;
+    let to_steps = |distance: f32, axis: Axis| (distance * steps_per_unit.get(axis)).round() as i32;
+
+    Ok(AxisSteps::new(
+        to_steps(actuators.a(), Axis::X),
+        to_steps(actuators.b(), Axis::Y),
+        to_steps(actuators.c(), Axis::Z),
+        0,
+    ))
+}
+
+impl AxisEnd {
+    /// Sign of the steps moving towards this end
+    fn direction(&self) -> i32 {
+        match self {
+            AxisEnd::Min => -1,
+            AxisEnd::Max => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::calibration::{AxisEnd, HomingConfig, SimulatedAxes, calibrate_bed, home_axis};
+    use crate::error::Error;
+    use crate::motion::{Axis, CoreXYKinematics, LinearDeltaKinematics, MachineKinematics};
+    use crate::system::{Location, SystemConfig};
+
+    #[test]
+    fn calibrate_finds_origin_and_limit() {
+        let mut config = SystemConfig::default();
+        let axes = SimulatedAxes::new(
+            Location::new(-12.0, -3.5, -1.0),
+            Location::new(238.0, 206.5, 179.0),
+            Location::new(40.0, 80.0, 20.0),
+            config.steps_per_unit(),
+        );
+
+        let calibration = calibrate_bed(
+            &mut config,
+            &mut axes.clone(),
+            &mut axes.clone(),
+            &HomingConfig::default(),
+        )
+        .unwrap();
+
+        //  Distance between switches, within a step of precision
+        let expected = Location::new(250.0, 210.0, 180.0);
+        assert!(calibration.limit().distance_to(&expected) < 1.0 / 80.0);
+        assert_eq!(config.bed_limit(), Some(calibration.limit()));
+        assert_eq!(config.bed_origin(), Some(Location::default()));
+        assert!(config.is_bed_configured());
+
+        //  Machine is left at the min switches
+        let location = axes.location(config.steps_per_unit());
+        assert!(location.distance_to(&Location::new(-12.0, -3.5, -1.0)) < 1.0 / 80.0);
+    }
+
+    #[test]
+    fn homing_fails_without_switch() {
+        let config = SystemConfig::default();
+        let axes = SimulatedAxes::new(
+            Location::new(-1000.0, 0.0, 0.0),
+            Location::new(200.0, 200.0, 200.0),
+            Location::new(10.0, 10.0, 10.0),
+            config.steps_per_unit(),
+        );
+
+        let result = home_axis(
+            &mut axes.clone(),
+            &mut axes.clone(),
+            Axis::X,
+            AxisEnd::Min,
+            &config,
+            &HomingConfig::new(50.0, 5.0, 5.0, 300.0),
+        );
+
+        assert!(matches!(result, Err(Error::HomingFailed(Axis::X))));
+    }
+
+    #[test]
+    fn calibrate_corexy_moves_both_motors() {
+        let mut config = SystemConfig::default();
+        config.set_kinematics(MachineKinematics::CoreXY(CoreXYKinematics));
+        let axes = SimulatedAxes::new(
+            Location::new(-12.0, -3.5, -1.0),
+            Location::new(238.0, 206.5, 179.0),
+            Location::new(40.0, 80.0, 20.0),
+            config.steps_per_unit(),
+        )
+        .with_kinematics(*config.kinematics())
+        .unwrap();
+
+        let calibration = calibrate_bed(
+            &mut config,
+            &mut axes.clone(),
+            &mut axes.clone(),
+            &HomingConfig::default(),
+        )
+        .unwrap();
+
+        //  Homing X alone would have moved Y along with it, and found the wrong travel
+        let expected = Location::new(250.0, 210.0, 180.0);
+        assert!(calibration.limit().distance_to(&expected) < 1.0 / 40.0);
+        let location = axes.location(config.steps_per_unit());
+        assert!(location.distance_to(&Location::new(-12.0, -3.5, -1.0)) < 1.0 / 40.0);
+
+        //  Delta towers don't move along the axes
+        config.set_kinematics(MachineKinematics::LinearDelta(LinearDeltaKinematics::new(
+            250.0, 120.0, 100.0,
+        )));
+        assert!(matches!(
+            calibrate_bed(
+                &mut config,
+                &mut axes.clone(),
+                &mut axes.clone(),
+                &HomingConfig::default(),
+            ),
+            Err(Error::HomingNotSupported)
+        ));
+    }
+}
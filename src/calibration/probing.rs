@@ -0,0 +1,93 @@
+use crate::error::{Error, PrintResult};
+use crate::gcode::G29ProbeMesh;
+use crate::motion::{Axis, AxisSteps, Kinematics, StepperDriver};
+use crate::system::{BedMesh, Location, SystemConfig};
+use crate::types::LocationType;
+
+use super::HomingConfig;
+
+/// Points probed along each axis when G29 doesn't say otherwise
+const DEFAULT_MESH_POINTS: usize = 3;
+
+/// Sensor measuring the height of the bed under the toolhead, like an inductive probe or a BLTouch
+pub trait BedProbe {
+    /// Distance between the bed and its nominal height at the location of the toolhead, which is
+    /// already over the given point. Positive where the bed is higher. The toolhead is left at the
+    /// height it had before probing
+    fn measure_z_offset(&mut self, x: LocationType, y: LocationType) -> PrintResult<LocationType>;
+}
+
+/// G29. Probes a grid of points over the bed, going back and forth along X to keep the travels short.
+/// The mesh is stored in the config and the leveling turned on. The toolhead is left over the last point
+pub fn probe_mesh(
+    config: &mut SystemConfig,
+    driver: &mut impl StepperDriver,
+    probe: &mut dyn BedProbe,
+    probe_mesh: &G29ProbeMesh,
+    homing: &HomingConfig,
+) -> PrintResult<BedMesh> {
+    let (Some(origin), Some(limit)) = (config.bed_origin(), config.bed_limit()) else {
+        return Err(Error::BedNotConfigured);
+    };
+
+    let mut mesh = BedMesh::new(
+        probe_mesh.left.unwrap_or(origin.x()),
+        probe_mesh.front.unwrap_or(origin.y()),
+        probe_mesh.right.unwrap_or(limit.x()),
+        probe_mesh.back.unwrap_or(limit.y()),
+        probe_mesh.columns.unwrap_or(DEFAULT_MESH_POINTS),
+        probe_mesh.rows.unwrap_or(DEFAULT_MESH_POINTS),
+    )?;
+
+    let mut location = config.current_location();
+    for row in 0..mesh.rows() {
+        for step in 0..mesh.columns() {
+            let column = match row % 2 {
+                0 => step,
+                _ => mesh.columns() - 1 - step,
+            };
+            let target = Location::new(mesh.column_x(column), mesh.row_y(row), location.z());
+            travel(config, driver, &location, &target, homing)?;
+            location = target;
+
+            let z_offset = probe.measure_z_offset(target.x(), target.y())?;
+            mesh.set_point(column, row, z_offset)?;
+        }
+    }
+
+    config.set_current_location(location);
+    config.set_bed_mesh(mesh.clone());
+
+    Ok(mesh)
+}
+
+/// Straight travel between two probed points, at the fast homing feedrate
+fn travel(
+    config: &SystemConfig,
+    driver: &mut impl StepperDriver,
+    from: &Location,
+    to: &Location,
+    homing: &HomingConfig,
+) -> PrintResult<()> {
+    let kinematics = config.kinematics();
+    let steps_per_unit = config.steps_per_unit();
+    let from_actuators = kinematics.to_actuators(from)?;
+    let to_actuators = kinematics.to_actuators(to)?;
+
+    //  Steps are taken from the absolute positions, so the rounding doesn't pile up between points
+    let steps = |from: f32, to: f32, axis: Axis| {
+        let steps_per_unit = steps_per_unit.get(axis);
+        ((to * steps_per_unit).round() - (from * steps_per_unit).round()) as i32
+    };
+    let axis_steps = AxisSteps::new(
+        steps(from_actuators.a, to_actuators.a, Axis::X),
+        steps(from_actuators.b, to_actuators.b, Axis::Y),
+        steps(from_actuators.c, to_actuators.c, Axis::Z),
+        0,
+    );
+
+    if axis_steps.is_empty() {
+        return Ok(());
+    }
+    driver.move_steps(&axis_steps, from.distance_to(to) / homing.fast_feedrate)
+}
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::error::PrintResult;
+use crate::motion::{
+    ActuatorPosition, Axis, AxisSteps, Kinematics, MachineKinematics, StepperDriver, StepsPerUnit,
+};
+use crate::system::Location;
+
+use super::{AxisEnd, Endstops};
+
+/// Axes without hardware, with switches placed at configurable positions.
+/// Clones share the same axes, so one clone can be used as driver and another one as endstops
+#[derive(Debug, Clone)]
+pub struct SimulatedAxes {
+    state: Rc<RefCell<SimulatedState>>,
+}
+
+#[derive(Debug)]
+struct SimulatedState {
+    /// Steps of each motor away from the physical zero
+    position: [i64; 3],
+    /// Steps from the physical zero where the min switch of each axis triggers
+    min_switches: [i64; 3],
+    /// Steps from the physical zero where the max switch of each axis triggers
+    max_switches: [i64; 3],
+    /// How the motors move the toolhead. Switches are triggered by the toolhead, not the motors
+    kinematics: MachineKinematics,
+    steps_per_unit: StepsPerUnit,
+}
+
+impl SimulatedAxes {
+    /// Every location is measured in millimeters from an arbitrary physical zero.
+    /// Each motor moves a single axis, unless other kinematics are given with `with_kinematics()`
+    pub fn new(
+        min_switches: Location,
+        max_switches: Location,
+        start: Location,
+        steps_per_unit: &StepsPerUnit,
+    ) -> Self {
+        let to_steps = |location: Location| {
+            [
+                (location.x * steps_per_unit.get(Axis::X)).round() as i64,
+                (location.y * steps_per_unit.get(Axis::Y)).round() as i64,
+                (location.z * steps_per_unit.get(Axis::Z)).round() as i64,
+            ]
+        };
+
+        Self {
+            state: Rc::new(RefCell::new(SimulatedState {
+                position: to_steps(start),
+                min_switches: to_steps(min_switches),
+                max_switches: to_steps(max_switches),
+                kinematics: MachineKinematics::default(),
+                steps_per_unit: *steps_per_unit,
+            })),
+        }
+    }
+
+    /// Moves the toolhead with the motors of other kinematics, like the two belts of a CoreXY.
+    /// The toolhead stays where it was
+    pub fn with_kinematics(self, kinematics: MachineKinematics) -> PrintResult<Self> {
+        {
+            let mut state = self.state.borrow_mut();
+            let location = state.location(&state.steps_per_unit)?;
+            let actuators = kinematics.to_actuators(&location)?;
+            state.position = state.actuator_steps(&actuators);
+            state.kinematics = kinematics;
+        }
+
+        Ok(self)
+    }
+
+    /// Current location of the toolhead, measured from the physical zero. Locations the kinematics
+    /// can't reach are reported at the physical zero
+    pub fn location(&self, steps_per_unit: &StepsPerUnit) -> Location {
+        self.state
+            .borrow()
+            .location(steps_per_unit)
+            .unwrap_or_default()
+    }
+}
+
+impl SimulatedState {
+    fn location(&self, steps_per_unit: &StepsPerUnit) -> PrintResult<Location> {
+        let actuators = ActuatorPosition::new(
+            self.position[0] as f32 / steps_per_unit.get(Axis::X),
+            self.position[1] as f32 / steps_per_unit.get(Axis::Y),
+            self.position[2] as f32 / steps_per_unit.get(Axis::Z),
+        );
+        self.kinematics.to_cartesian(&actuators)
+    }
+
+    fn actuator_steps(&self, actuators: &ActuatorPosition) -> [i64; 3] {
+        [
+            (actuators.a() * self.steps_per_unit.get(Axis::X)).round() as i64,
+            (actuators.b() * self.steps_per_unit.get(Axis::Y)).round() as i64,
+            (actuators.c() * self.steps_per_unit.get(Axis::Z)).round() as i64,
+        ]
+    }
+}
+
+impl StepperDriver for SimulatedAxes {
+    fn move_steps(&mut self, steps: &AxisSteps, _duration: f32) -> PrintResult<()> {
+        let mut state = self.state.borrow_mut();
+        for (index, axis) in [Axis::X, Axis::Y, Axis::Z].into_iter().enumerate() {
+            state.position[index] += steps.get(axis) as i64;
+        }
+
+        Ok(())
+    }
+}
+
+impl Endstops for SimulatedAxes {
+    fn is_triggered(&mut self, axis: Axis, end: AxisEnd) -> PrintResult<bool> {
+        let state = self.state.borrow();
+        let location = state.location(&state.steps_per_unit)?;
+        let (index, position) = match axis {
+            Axis::X => (0, location.x),
+            Axis::Y => (1, location.y),
+            Axis::Z => (2, location.z),
+            Axis::E => return Ok(false),
+        };
+        let position = (position * state.steps_per_unit.get(axis)).round() as i64;
+
+        Ok(match end {
+            AxisEnd::Min => position <= state.min_switches[index],
+            AxisEnd::Max => position >= state.max_switches[index],
+        })
+    }
+}
@@ -1,3 +1,9 @@
+use crate::gcode::Dialect;
+use crate::heater::HeaterKind;
+use crate::motion::Axis;
+use crate::recovery::ResumePoint;
+use crate::system::Location;
+
 pub type PrintResult<T> = Result<T, Error>;
 
 #[derive(Debug)]
@@ -5,5 +11,158 @@ pub enum Error {
     //  Command name, line number
     InvalidCommandInLine(Option<String>, Option<usize>),
     UnsupportedCommand(String),
+    //  Command the firmware of the target printer doesn't have, its dialect, line number
+    UnsupportedByDialect(String, Dialect, Option<usize>),
+    //  Parameter, line number
+    InvalidParameterInLine(String, Option<usize>),
     InputOutputError(std::io::Error),
+    //  Location the machine can't get to, line number
+    UnreachableLocation(Location, Option<usize>),
+    //  Moves can't be executed until the bed origin and limit are configured
+    BedNotConfigured,
+    //  Homing or calibration was requested but no endstops were attached to the executor
+    EndstopsNotAttached,
+    //  Axis whose endstop didn't behave as expected while homing
+    HomingFailed(Axis),
+    //  Homing asked of kinematics not moving the toolhead along straight lines, like deltas
+    HomingNotSupported,
+    //  Mesh edited before one was probed or loaded
+    MeshNotConfigured,
+    //  Column and row of a point outside the bed mesh
+    InvalidMeshPoint(usize, usize),
+    //  Columns and rows of a mesh without two points along each axis, or without any area between them
+    InvalidMeshSize(usize, usize),
+    //  Settings were saved or loaded but no storage was attached to the executor
+    StorageNotAttached,
+    //  Storage doesn't hold any saved settings
+    NoStoredSettings,
+    //  Layout version of settings saved by another version of the crate
+    SettingsVersionMismatch(u16),
+    //  Saved settings don't match their checksum, or were cut short
+    CorruptSettings,
+    //  Description of the problem, line number of the printer profile
+    InvalidProfileLine(String, usize),
+    //  Arcs can only be transformed by the same scale along X and Y, line number
+    NonUniformArcScale(usize),
+    //  Line number of the emergency stop that aborted the job
+    EmergencyStop(Option<usize>),
+    //  Line or layer a job was asked to resume from, which the job ends before reaching
+    ResumePointNotReached(ResumePoint),
+    //  Recovery journal doesn't match its checksum, or was cut short
+    CorruptJournal,
+    //  Heater a command needs, which wasn't attached to the executor
+    HeaterNotAttached(HeaterKind),
+    //  Heater whose temperature didn't rise while heating
+    HeatingFailed(HeaterKind),
+    //  Heater whose temperature fell away from the target it had reached
+    ThermalRunaway(HeaterKind),
+    //  Heater whose fault aborted the job, reported on every call after the fault
+    HeaterFault(HeaterKind),
+    //  Heater the autotune couldn't measure, because it overshot or stopped oscillating
+    AutotuneFailed(HeaterKind),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        //  Line numbers are appended whenever they're known
+        let at_line = |line_number: &Option<usize>| match line_number {
+            Some(line_number) => format!(" at line {line_number}"),
+            None => String::new(),
+        };
+
+        match self {
+            Error::InvalidCommandInLine(command, line_number) => match command {
+                Some(command) => write!(f, "invalid command `{command}`{}", at_line(line_number)),
+                None => write!(f, "invalid command{}", at_line(line_number)),
+            },
+            Error::UnsupportedCommand(command) => write!(f, "unsupported command `{command}`"),
+            Error::UnsupportedByDialect(command, dialect, line_number) => write!(
+                f,
+                "{dialect} doesn't support `{command}`{}",
+                at_line(line_number)
+            ),
+            Error::InvalidParameterInLine(parameter, line_number) => {
+                write!(f, "invalid parameter `{parameter}`{}", at_line(line_number))
+            }
+            Error::InputOutputError(error) => write!(f, "input/output error: {error}"),
+            Error::UnreachableLocation(location, line_number) => write!(
+                f,
+                "unreachable location X{} Y{} Z{}{}",
+                location.x(),
+                location.y(),
+                location.z(),
+                at_line(line_number)
+            ),
+            Error::BedNotConfigured => write!(f, "bed origin and limit are not configured"),
+            Error::EndstopsNotAttached => write!(f, "no endstops attached"),
+            Error::HomingFailed(axis) => write!(f, "homing failed on axis {axis:?}"),
+            Error::HomingNotSupported => {
+                write!(
+                    f,
+                    "homing isn't supported with non linear kinematics, like deltas"
+                )
+            }
+            Error::MeshNotConfigured => write!(f, "no bed mesh configured"),
+            Error::InvalidMeshPoint(column, row) => {
+                write!(
+                    f,
+                    "mesh point at column {column}, row {row} is out of the mesh"
+                )
+            }
+            Error::InvalidMeshSize(columns, rows) => {
+                write!(f, "invalid mesh of {columns} columns and {rows} rows")
+            }
+            Error::StorageNotAttached => write!(f, "no storage attached"),
+            Error::NoStoredSettings => write!(f, "storage holds no settings"),
+            Error::SettingsVersionMismatch(version) => {
+                write!(f, "stored settings have unsupported version {version}")
+            }
+            Error::CorruptSettings => write!(f, "stored settings are corrupt"),
+            Error::InvalidProfileLine(problem, line_number) => {
+                write!(f, "{problem} at line {line_number} of the profile")
+            }
+            Error::NonUniformArcScale(line_number) => write!(
+                f,
+                "arc at line {line_number} can't be scaled differently along X and Y"
+            ),
+            Error::EmergencyStop(line_number) => {
+                write!(f, "emergency stop{}", at_line(line_number))
+            }
+            Error::ResumePointNotReached(point) => write!(f, "job ends before reaching {point}"),
+            Error::CorruptJournal => write!(f, "recovery journal is corrupt"),
+            Error::HeaterNotAttached(heater) => write!(f, "no {} heater attached", heater.name()),
+            Error::HeatingFailed(heater) => {
+                write!(f, "heating failed, the {} isn't warming up", heater.name())
+            }
+            Error::ThermalRunaway(heater) => write!(f, "thermal runaway on the {}", heater.name()),
+            Error::HeaterFault(heater) => {
+                write!(f, "job aborted by a fault of the {}", heater.name())
+            }
+            Error::AutotuneFailed(heater) => write!(f, "autotune of the {} failed", heater.name()),
+        }
+    }
 }
+
+impl Error {
+    /// Same error, placed at a line if it doesn't tell where it happened yet. Errors found while resolving
+    /// commands, past the parser, only learn their line from the caller
+    pub(crate) fn in_line(self, line_number: usize) -> Self {
+        match self {
+            Error::InvalidCommandInLine(command, None) => {
+                Error::InvalidCommandInLine(command, Some(line_number))
+            }
+            Error::UnsupportedByDialect(command, dialect, None) => {
+                Error::UnsupportedByDialect(command, dialect, Some(line_number))
+            }
+            Error::InvalidParameterInLine(parameter, None) => {
+                Error::InvalidParameterInLine(parameter, Some(line_number))
+            }
+            Error::UnreachableLocation(location, None) => {
+                Error::UnreachableLocation(location, Some(line_number))
+            }
+            error => error,
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use crate::error::PrintResult;
+use crate::gcode::ExtendedCommand;
+use crate::system::SystemConfig;
+
+/// Action the executor runs for every extended command with a given name, like a Klipper macro.
+/// Closures taking the command and the config can be used as handlers
+pub trait ExtendedCommandHandler {
+    fn handle(&mut self, command: &ExtendedCommand, config: &mut SystemConfig) -> PrintResult<()>;
+}
+
+impl<F> ExtendedCommandHandler for F
+where
+    F: FnMut(&ExtendedCommand, &mut SystemConfig) -> PrintResult<()>,
+{
+    fn handle(&mut self, command: &ExtendedCommand, config: &mut SystemConfig) -> PrintResult<()> {
+        self(command, config)
+    }
+}
+
+/// Handlers of the extended commands, by name. Names ignore the case, like Klipper does
+#[derive(Default)]
+pub(crate) struct HandlerRegistry {
+    handlers: HashMap<String, Box<dyn ExtendedCommandHandler>>,
+}
+
+impl HandlerRegistry {
+    /// Adds the handler of a name, replacing the previous one if any
+    pub(crate) fn register(&mut self, name: &str, handler: Box<dyn ExtendedCommandHandler>) {
+        self.handlers.insert(name.to_ascii_uppercase(), handler);
+    }
+
+    /// Runs the handler of the command. Returns false if no handler was registered for its name
+    pub(crate) fn handle(
+        &mut self,
+        command: &ExtendedCommand,
+        config: &mut SystemConfig,
+    ) -> PrintResult<bool> {
+        match self.handlers.get_mut(&command.name().to_ascii_uppercase()) {
+            Some(handler) => handler.handle(command, config).map(|_| true),
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,826 @@
+mod handlers;
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+
+use crate::calibration::{self, AxisEnd, BedCalibration, BedProbe, Endstops, HomingConfig};
+use crate::error::{Error, PrintResult};
+use crate::gcode::{G29ProbeMesh, GcodeCommand, GcodeLine, M303PidAutotune};
+use crate::heater::{Heater, HeaterKind, HeaterOutput, PidGains, TemperatureSensor};
+use crate::motion::{
+    Axis, Kinematics, MotionQueue, PlannedSegment, Planner, StepConverter, StepperDriver,
+};
+use crate::recovery::{ModalState, RecoveryJournal};
+use crate::storage::Storage;
+use crate::system::{Location, SystemConfig};
+use crate::types::LineNumberType;
+
+use handlers::HandlerRegistry;
+
+pub use handlers::ExtendedCommandHandler;
+
+/// Amount of commands read ahead of the one being executed
+const DEFAULT_QUEUE_SIZE: usize = 16;
+/// Seconds the heaters are regulated between checks, while M109 and M190 wait for their temperature
+const HEATING_WAIT_PERIOD: f32 = 0.5;
+/// Degrees around the target M109 and M190 take as reached
+const TEMPERATURE_WINDOW: f32 = 1.0;
+/// Seconds between checks of the temperature falling, while M109 and M190 wait for a heater to cool.
+/// Same as Marlin
+const COOLING_CHECK_PERIOD: f32 = 60.0;
+/// Least degrees the hotend and the bed must cool by between checks to keep waiting. Same as Marlin
+const MIN_HOTEND_COOLING: f32 = 1.5;
+const MIN_BED_COOLING: f32 = 1.0;
+/// Cycles M303 measures when C is left out. Same as Marlin
+const DEFAULT_AUTOTUNE_CYCLES: u8 = 5;
+
+/// Takes the commands read from a gcode source, queues them, and executes them in order.
+/// Emergency commands skip the queue and are handled as soon as they're submitted
+pub struct Executor {
+    config: SystemConfig,
+    planner: Planner,
+    /// Segments released by the planner wait here while the pressure advance or the input shapers are on
+    motion_queue: MotionQueue,
+    step_converter: StepConverter,
+    queue: VecDeque<QueuedCommand>,
+    queue_size: usize,
+    /// Switches used by G28 and the bed calibration. Without them, G28 only resets the position
+    endstops: Option<Box<dyn Endstops>>,
+    homing_config: HomingConfig,
+    /// Probe used by G29. Without it, G29 keeps the mesh already in the config
+    probe: Option<Box<dyn BedProbe>>,
+    /// Where M500 saves the settings and M501 loads them from
+    storage: Option<Box<dyn Storage>>,
+    /// Heaters set by M104, M109, M140 and M190. Temperature commands for heaters not attached
+    /// are left to the host
+    hotend: Option<Heater>,
+    bed: Option<Heater>,
+    /// What extended commands do. Commands without a handler are skipped
+    handlers: HandlerRegistry,
+    /// Where the progress of the job is recorded, to resume it after a power loss
+    journal: Option<RecoveryJournal>,
+    /// Temperatures, fan and tool set by the job, written to the journal along with the position
+    modal_state: ModalState,
+    /// End of the last move sent to the planner, after compensating the bed mesh
+    leveled_location: Location,
+    job_state: JobState,
+    /// What aborted the job, if anything
+    aborted_by: Option<AbortCause>,
+    events: Vec<ExecutorEvent>,
+}
+
+/// Command waiting in the queue for its turn to be executed
+#[derive(Debug)]
+pub struct QueuedCommand {
+    line_number: LineNumberType,
+    /// Bytes from the start of the source to the line of the command, if known
+    offset: Option<u64>,
+    command: GcodeCommand,
+}
+
+/// What aborted a job, reported again by every call until the executor is reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AbortCause {
+    /// Line of the M112
+    EmergencyStop(LineNumberType),
+    HeaterFault(HeaterKind),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Nothing was submitted yet
+    #[default]
+    Idle,
+    Running,
+    /// Stopped by an emergency stop or a heater fault. Nothing else will be executed until the executor is reset
+    Aborted,
+}
+
+/// Relevant things that happened during the execution of a job
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecutorEvent {
+    /// M112 received. The job was aborted and every queued command was dropped, along with the
+    /// segments already planned. A single command, like an arc, can be planned into many segments
+    EmergencyStop {
+        line_number: LineNumberType,
+        dropped_commands: usize,
+        dropped_segments: usize,
+    },
+    /// M410 received. Queued moves were dropped along with the segments already planned, so the
+    /// machine position can't be trusted until homed again
+    QuickStop {
+        line_number: LineNumberType,
+        dropped_moves: usize,
+        dropped_segments: usize,
+    },
+    /// Heater failing to warm up, running away, or whose sensor or output failed. Every heater was
+    /// turned off, and the job was aborted along with every queued command
+    HeaterFault { heater: HeaterKind },
+    /// M503 received. The report is gcode that restores the settings when run
+    SettingsReport {
+        line_number: LineNumberType,
+        report: String,
+    },
+    /// M303 finished. The gains are only in use if the command had U1
+    PidAutotuned {
+        line_number: LineNumberType,
+        heater: HeaterKind,
+        gains: PidGains,
+    },
+    /// Extended command without a registered handler. It was skipped
+    UnhandledCommand {
+        line_number: LineNumberType,
+        name: String,
+    },
+}
+
+impl Executor {
+    pub fn new(config: SystemConfig) -> Self {
+        Self {
+            modal_state: ModalState::new(&config),
+            config,
+            planner: Planner::new(),
+            motion_queue: MotionQueue::default(),
+            step_converter: StepConverter::new(),
+            queue: VecDeque::with_capacity(DEFAULT_QUEUE_SIZE),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            endstops: None,
+            homing_config: HomingConfig::default(),
+            probe: None,
+            storage: None,
+            hotend: None,
+            bed: None,
+            handlers: HandlerRegistry::default(),
+            journal: None,
+            leveled_location: Location::default(),
+            job_state: JobState::Idle,
+            aborted_by: None,
+            events: vec![],
+        }
+    }
+
+    pub fn config(&self) -> &SystemConfig {
+        &self.config
+    }
+
+    pub fn job_state(&self) -> JobState {
+        self.job_state
+    }
+
+    /// Amount of commands waiting to be executed
+    pub fn queued_commands(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// True if there's no room left in the queue, and commands need to be executed before submitting new ones
+    pub fn is_queue_full(&self) -> bool {
+        self.queue.len() >= self.queue_size
+    }
+
+    /// Attaches the switches used to home the axes
+    pub fn attach_endstops(&mut self, endstops: Box<dyn Endstops>, homing_config: HomingConfig) {
+        self.endstops = Some(endstops);
+        self.homing_config = homing_config;
+    }
+
+    /// Attaches the probe used by G29 to measure the bed mesh
+    pub fn attach_probe(&mut self, probe: Box<dyn BedProbe>) {
+        self.probe = Some(probe);
+    }
+
+    /// Attaches the storage used by M500 and M501
+    pub fn attach_storage(&mut self, storage: Box<dyn Storage>) {
+        self.storage = Some(storage);
+    }
+
+    /// Attaches the sensor and the output of a heater, regulated with the PID gains of the config
+    pub fn attach_heater(
+        &mut self,
+        heater: HeaterKind,
+        sensor: Box<dyn TemperatureSensor>,
+        output: Box<dyn HeaterOutput>,
+    ) {
+        let gains = self.config.pid_gains(heater);
+        let attached = Some(Heater::new(heater, sensor, output, gains));
+        match heater {
+            HeaterKind::Hotend => self.hotend = attached,
+            HeaterKind::Bed => self.bed = attached,
+        }
+    }
+
+    /// Attaches the journal recording the progress of the job. Only commands submitted along with
+    /// their offset in the source, like the ones read by `run()`, are recorded, and only once every
+    /// move before them was sent to the driver
+    pub fn attach_journal(&mut self, journal: RecoveryJournal) {
+        self.journal = Some(journal);
+    }
+
+    /// Registers what an extended command does, replacing the previous handler of the name if any.
+    /// Names ignore the case. Ex: `PRINT_START`
+    pub fn register_handler(&mut self, name: &str, handler: Box<dyn ExtendedCommandHandler>) {
+        self.handlers.register(name, handler);
+    }
+
+    /// Runs the boot calibration with the attached endstops, writing the bed origin and limit to the config.
+    /// Must be run before any move, since printing isn't allowed without a configured bed
+    pub fn calibrate(&mut self, driver: &mut impl StepperDriver) -> PrintResult<BedCalibration> {
+        self.check_not_aborted()?;
+        let Some(endstops) = self.endstops.as_deref_mut() else {
+            return Err(Error::EndstopsNotAttached);
+        };
+
+        let calibration =
+            calibration::calibrate_bed(&mut self.config, driver, endstops, &self.homing_config)?;
+        self.sync_position()?;
+
+        Ok(calibration)
+    }
+
+    /// Runs the control loop of every attached heater for some seconds, keeping the temperatures of
+    /// the config up to date. Meant to be called by the firmware on a timer while the job runs.
+    /// A heater failing to warm up or running away aborts the job, with every heater turned off
+    pub fn regulate_heaters(&mut self, seconds: f32) -> PrintResult<()> {
+        let mut fault = None;
+        for heater in [self.hotend.as_mut(), self.bed.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            let result = heater.regulate(seconds);
+            self.config
+                .set_temperature(heater.kind(), heater.temperature());
+            if let Err(error) = result {
+                fault = Some((heater.kind(), error));
+                break;
+            }
+        }
+
+        match fault {
+            Some((heater, error)) => {
+                self.abort_heating(heater);
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns every event since the last call, leaving the list empty
+    pub fn drain_events(&mut self) -> Vec<ExecutorEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Hands a command over to the executor. Regular commands are queued, while emergency ones are handled right away
+    pub fn submit(
+        &mut self,
+        command: GcodeCommand,
+        line_number: LineNumberType,
+    ) -> PrintResult<()> {
+        self.submit_at(command, line_number, None)
+    }
+
+    /// Same as `submit()`, taking the command from a line read from a source. The offset of the line
+    /// is kept, so the journal can point at it. Lines without a command are skipped
+    pub fn submit_line(&mut self, line: GcodeLine) -> PrintResult<()> {
+        let line_number = line.line_number();
+        let offset = line.offset();
+        match line.into_command() {
+            Some(command) => self.submit_at(command, line_number, offset),
+            None => Ok(()),
+        }
+    }
+
+    fn submit_at(
+        &mut self,
+        command: GcodeCommand,
+        line_number: LineNumberType,
+        offset: Option<u64>,
+    ) -> PrintResult<()> {
+        self.check_not_aborted()?;
+        self.job_state = JobState::Running;
+
+        match command {
+            GcodeCommand::M112 => {
+                self.emergency_stop(line_number);
+                self.check_not_aborted()
+            }
+            GcodeCommand::M410 => {
+                self.quick_stop(line_number);
+                Ok(())
+            }
+            command => {
+                self.queue.push_back(QueuedCommand {
+                    line_number,
+                    offset,
+                    command,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Executes the next queued command. Moves go through the planner, and the segments leaving it
+    /// are sent to the driver. Returns the executed command, or None if the queue is empty
+    pub fn step(&mut self, driver: &mut impl StepperDriver) -> PrintResult<Option<QueuedCommand>> {
+        self.check_not_aborted()?;
+
+        let Some(queued) = self.queue.pop_front() else {
+            return Ok(None);
+        };
+
+        //  Journal holds the state before the command, so resuming runs the command again
+        self.modal_state.follow(&self.config);
+        if let (Some(journal), Some(offset)) = (self.journal.as_mut(), queued.offset) {
+            journal.update(queued.line_number, offset, &self.modal_state)?;
+        }
+        self.modal_state.apply_command(&queued.command);
+
+        match &queued.command {
+            GcodeCommand::G28(home) => self.home(&home.axes(), driver)?,
+            GcodeCommand::G29(probe_mesh) => self.probe_mesh(probe_mesh, driver)?,
+            GcodeCommand::M500 => {
+                let storage = self.storage.as_deref_mut();
+                self.config
+                    .save_settings(storage.ok_or(Error::StorageNotAttached)?)?
+            }
+            //  Planned moves are finished with the settings they were planned with
+            GcodeCommand::M501 => {
+                self.flush_planner(driver)?;
+                let storage = self.storage.as_deref_mut();
+                self.config
+                    .load_settings(storage.ok_or(Error::StorageNotAttached)?)?
+            }
+            //  Like Marlin, the steps per unit, the pressure advance and the extruder change once the
+            //  machine stops
+            GcodeCommand::M92(_) | GcodeCommand::M502 | GcodeCommand::M900(_) => {
+                self.flush_planner(driver)?
+            }
+            GcodeCommand::Passthrough(..) if queued.command.tool_change().is_some() => {
+                self.flush_planner(driver)?
+            }
+            GcodeCommand::M303(autotune) => self.autotune(autotune, queued.line_number, driver)?,
+            GcodeCommand::Passthrough(name, _)
+                if matches!(name.as_str(), "M104" | "M109" | "M140" | "M190") =>
+            {
+                self.set_heater_target(&queued.command, driver)?
+            }
+            GcodeCommand::M503 => self.events.push(ExecutorEvent::SettingsReport {
+                line_number: queued.line_number,
+                report: self.config.report_settings(),
+            }),
+            GcodeCommand::Passthrough(name, _) if name == "M413" => {
+                if let (Some(journal), Some(enabled)) =
+                    (self.journal.as_mut(), queued.command.parameter_value('S'))
+                {
+                    journal.set_enabled(enabled != 0.0)?;
+                }
+            }
+            GcodeCommand::Extended(extended) => {
+                let handled = self.handlers.handle(extended, &mut self.config)?;
+                if !handled {
+                    self.events.push(ExecutorEvent::UnhandledCommand {
+                        line_number: queued.line_number,
+                        name: extended.name().to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        let resolved_moves = self
+            .config
+            .apply_command(&queued.command)
+            .map_err(|error| error.in_line(queued.line_number))?;
+        match &queued.command {
+            //  Homed axes were moved to the origin in the config, without taking any step.
+            //  New steps per unit and leveling change where the steps taken so far leave the toolhead
+            GcodeCommand::G28(_)
+            | GcodeCommand::M92(_)
+            | GcodeCommand::M501
+            | GcodeCommand::M502 => self.sync_position()?,
+            //  The toolhead doesn't move, but its coordinates do
+            GcodeCommand::G92(_) => {
+                self.leveled_location = self
+                    .config
+                    .leveled_location(&self.config.current_location())
+            }
+            _ => {}
+        }
+        if matches!(
+            queued.command,
+            GcodeCommand::M301(_) | GcodeCommand::M304(_) | GcodeCommand::M501 | GcodeCommand::M502
+        ) {
+            self.sync_heater_gains();
+        }
+
+        for resolved_move in resolved_moves {
+            if !self.config.is_bed_configured() {
+                return Err(Error::BedNotConfigured);
+            }
+            if !self.config.is_reachable(&resolved_move.end()) {
+                return Err(Error::UnreachableLocation(
+                    resolved_move.end(),
+                    Some(queued.line_number),
+                ));
+            }
+
+            //  Moves start where the previous one left the toolhead, so changes in the compensation,
+            //  like turning the leveling on, are applied by the next move
+            for (index, leveled_move) in self
+                .config
+                .level_move(&resolved_move)
+                .into_iter()
+                .enumerate()
+            {
+                let leveled_move = match index {
+                    0 => leveled_move.with_ends(self.leveled_location, leveled_move.end()),
+                    _ => leveled_move,
+                };
+                self.leveled_location = leveled_move.end();
+
+                let released = self.planner.push_at(
+                    leveled_move,
+                    self.config.motion_limits(),
+                    queued.line_number,
+                    queued.offset,
+                );
+                self.execute_segments(released, driver)?;
+            }
+        }
+
+        Ok(Some(queued))
+    }
+
+    /// Executes every queued command, and then every segment left in the planner, bringing the machine to a stop
+    pub fn finish(&mut self, driver: &mut impl StepperDriver) -> PrintResult<()> {
+        while self.step(driver)?.is_some() {}
+
+        self.flush_planner(driver)
+    }
+
+    /// Finishes every planned move, and homes the axes to their min switches if endstops are attached
+    fn home(&mut self, axes: &[Axis], driver: &mut impl StepperDriver) -> PrintResult<()> {
+        self.flush_planner(driver)?;
+
+        if let Some(endstops) = self.endstops.as_deref_mut() {
+            for axis in axes {
+                calibration::home_axis(
+                    driver,
+                    endstops,
+                    *axis,
+                    AxisEnd::Min,
+                    &self.config,
+                    &self.homing_config,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finishes every planned move and probes the bed mesh, if a probe is attached
+    fn probe_mesh(
+        &mut self,
+        probe_mesh: &G29ProbeMesh,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        self.flush_planner(driver)?;
+
+        let Some(probe) = self.probe.as_deref_mut() else {
+            return Ok(());
+        };
+        calibration::probe_mesh(
+            &mut self.config,
+            driver,
+            probe,
+            probe_mesh,
+            &self.homing_config,
+        )?;
+
+        //  The probing took the toolhead over the last point without going through the planner
+        self.sync_position()
+    }
+
+    /// M104, M109, M140 and M190. S and R set the target, which can't go over the hottest the heater
+    /// can be set to. Waiting commands finish the planned moves, and regulate every heater until the
+    /// temperature is reached: S only waits while heating, and R while either heating or cooling.
+    /// Like Marlin, cooling stops being waited for once the temperature stops falling, since targets
+    /// like the ones below ambient are never reached
+    fn set_heater_target(
+        &mut self,
+        command: &GcodeCommand,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        let (kind, wait) = match command.name() {
+            "M104" => (HeaterKind::Hotend, false),
+            "M109" => (HeaterKind::Hotend, true),
+            "M140" => (HeaterKind::Bed, false),
+            _ => (HeaterKind::Bed, true),
+        };
+        let (target, heating_only) =
+            match (command.parameter_value('S'), command.parameter_value('R')) {
+                (Some(target), _) => (target, true),
+                (None, Some(target)) => (target, false),
+                (None, None) => return Ok(()),
+            };
+        let max_temp = match kind {
+            HeaterKind::Hotend => self.config.hotend_max_temp(),
+            HeaterKind::Bed => self.config.bed_max_temp(),
+        };
+
+        let Some(heater) = self.heater_mut(kind) else {
+            return Ok(());
+        };
+        heater.set_target(target.min(max_temp as f32))?;
+        if !wait || heater.target() <= 0.0 {
+            return Ok(());
+        }
+
+        let min_cooling = match kind {
+            HeaterKind::Hotend => MIN_HOTEND_COOLING,
+            HeaterKind::Bed => MIN_BED_COOLING,
+        };
+        //  Seconds since the last cooling check, and the temperature then
+        let (mut since_check, mut checked_temperature) = (0.0, heater.temperature());
+
+        self.flush_planner(driver)?;
+        loop {
+            self.regulate_heaters(HEATING_WAIT_PERIOD)?;
+            let Some(heater) = self.heater_mut(kind) else {
+                return Ok(());
+            };
+            if heater.is_at_target(TEMPERATURE_WINDOW, heating_only) {
+                return Ok(());
+            }
+
+            since_check += HEATING_WAIT_PERIOD;
+            let cooling = heater.temperature() > heater.target();
+            if cooling && since_check < COOLING_CHECK_PERIOD {
+                continue;
+            }
+            if cooling && checked_temperature - heater.temperature() < min_cooling {
+                return Ok(());
+            }
+            (since_check, checked_temperature) = (0.0, heater.temperature());
+        }
+    }
+
+    /// M303. Finishes the planned moves and tunes the heater, which is left off once done
+    fn autotune(
+        &mut self,
+        autotune: &M303PidAutotune,
+        line_number: LineNumberType,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        self.flush_planner(driver)?;
+
+        let kind = autotune.heater;
+        let target = autotune.target.unwrap_or(kind.autotune_target());
+        let max_temp = match kind {
+            HeaterKind::Hotend => self.config.hotend_max_temp(),
+            HeaterKind::Bed => self.config.bed_max_temp(),
+        };
+        if target > max_temp as f32 {
+            return Err(Error::InvalidParameterInLine(
+                format!("S{target}"),
+                Some(line_number),
+            ));
+        }
+
+        let heater = self
+            .heater_mut(kind)
+            .ok_or(Error::HeaterNotAttached(kind))?;
+        let gains = heater.autotune(target, autotune.cycles.unwrap_or(DEFAULT_AUTOTUNE_CYCLES))?;
+        let temperature = heater.temperature();
+        if autotune.use_result {
+            heater.set_gains(gains);
+            self.config.set_pid_gains(kind, gains);
+        }
+        self.config.set_temperature(kind, temperature);
+
+        self.events.push(ExecutorEvent::PidAutotuned {
+            line_number,
+            heater: kind,
+            gains,
+        });
+        Ok(())
+    }
+
+    fn heater_mut(&mut self, kind: HeaterKind) -> Option<&mut Heater> {
+        match kind {
+            HeaterKind::Hotend => self.hotend.as_mut(),
+            HeaterKind::Bed => self.bed.as_mut(),
+        }
+    }
+
+    /// Hands the gains of the config over to the heaters, after they were set or loaded
+    fn sync_heater_gains(&mut self) {
+        for heater in [self.hotend.as_mut(), self.bed.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            heater.set_gains(self.config.pid_gains(heater.kind()));
+        }
+    }
+
+    /// Turns every heater off after a heater fault, and aborts the job like an emergency stop would.
+    /// Heaters that can't be turned off are already failing, so their errors are dropped
+    fn abort_heating(&mut self, faulty: HeaterKind) {
+        for heater in [self.hotend.as_mut(), self.bed.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = heater.turn_off();
+        }
+        self.queue.clear();
+        self.planner.clear();
+        self.motion_queue.clear();
+        self.job_state = JobState::Aborted;
+        self.aborted_by = Some(AbortCause::HeaterFault(faulty));
+        self.events
+            .push(ExecutorEvent::HeaterFault { heater: faulty });
+    }
+
+    /// Matches the steps taken so far with the position in the config, after it was set without moving
+    fn sync_position(&mut self) -> PrintResult<()> {
+        let actuators = self
+            .config
+            .kinematics()
+            .to_actuators(&self.config.current_location())?;
+        self.step_converter
+            .reset(&actuators, self.config.steps_per_unit());
+        self.leveled_location = self.config.current_location();
+
+        Ok(())
+    }
+
+    /// Executes every segment left in the planner, bringing the machine to a stop
+    fn flush_planner(&mut self, driver: &mut impl StepperDriver) -> PrintResult<()> {
+        let remaining = self.planner.flush();
+        self.execute_segments(remaining, driver)?;
+        self.execute_adjusted(true, driver)?;
+
+        match self.journal.as_mut() {
+            Some(journal) => journal.reached(None),
+            None => Ok(()),
+        }
+    }
+
+    fn execute_segments(
+        &mut self,
+        segments: Vec<PlannedSegment>,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        let pressure_advance = self.config.pressure_advance(self.config.active_extruder());
+        let shapers = self.config.input_shapers();
+        if !pressure_advance.is_enabled() && shapers.is_empty() && !self.motion_queue.is_active() {
+            for segment in segments {
+                if let (Some(journal), Some(offset)) = (self.journal.as_mut(), segment.offset()) {
+                    journal.reached(Some(offset))?;
+                }
+                segment.execute(
+                    &mut self.step_converter,
+                    self.config.kinematics(),
+                    self.config.steps_per_unit(),
+                    driver,
+                )?;
+            }
+            return Ok(());
+        }
+
+        for segment in segments {
+            self.motion_queue.push(segment, pressure_advance, shapers);
+        }
+        self.execute_adjusted(false, driver)
+    }
+
+    /// Sends the segments whose adjusted motion is known to the driver. Flushing sends all of them,
+    /// and then settles the toolhead and the extruder where the job left them
+    fn execute_adjusted(
+        &mut self,
+        flush: bool,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        let kinematics = self.config.kinematics();
+        let steps_per_unit = self.config.steps_per_unit();
+
+        for (segment, start_time) in self.motion_queue.release(flush) {
+            if let (Some(journal), Some(offset)) = (self.journal.as_mut(), segment.offset()) {
+                journal.reached(Some(offset))?;
+            }
+            let motion_queue = &mut self.motion_queue;
+            segment.execute_adjusted(
+                &mut self.step_converter,
+                kinematics,
+                steps_per_unit,
+                driver,
+                |piece, time| motion_queue.adjust(piece, start_time + time),
+            )?;
+        }
+
+        if flush && let Some((settle, duration)) = self.motion_queue.settle() {
+            let steps = self
+                .step_converter
+                .convert(&settle, kinematics, steps_per_unit)?;
+            if !steps.is_empty() {
+                driver.move_steps(&steps, duration)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the full source and executes it, keeping the queue filled as the commands are read.
+    /// Stops at the first error, including the ones caused by emergency stops.
+    /// The journal, if attached, is cleared once the job is done
+    pub fn run(
+        &mut self,
+        lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        for line in lines {
+            let line = line?;
+            let Some(command) = line.command() else {
+                continue;
+            };
+
+            //  Make room for the next command before submitting it. Emergency commands never wait for room
+            if !command.is_emergency() {
+                while self.is_queue_full() {
+                    self.step(driver)?;
+                }
+            }
+            self.submit_line(line)?;
+        }
+
+        //  Flush whatever is left in the queue and the planner
+        self.finish(driver)?;
+        match self.journal.as_mut() {
+            Some(journal) => journal.clear(),
+            None => Ok(()),
+        }
+    }
+
+    /// Leaves the aborted state after an emergency stop, so a new job can be submitted
+    pub fn reset(&mut self) {
+        self.queue.clear();
+        self.planner.clear();
+        self.motion_queue.clear();
+        if let Some(journal) = self.journal.as_mut() {
+            journal.discard_pending();
+        }
+        self.job_state = JobState::Idle;
+        self.aborted_by = None;
+    }
+
+    /// Aborts the job and drops every command in the queue, along with the moves already in the planner
+    fn emergency_stop(&mut self, line_number: LineNumberType) {
+        let dropped_commands = self.queue.len();
+        let dropped_segments = self.planner.clear() + self.motion_queue.clear();
+        self.queue.clear();
+        self.job_state = JobState::Aborted;
+        self.aborted_by = Some(AbortCause::EmergencyStop(line_number));
+        self.events.push(ExecutorEvent::EmergencyStop {
+            line_number,
+            dropped_commands,
+            dropped_segments,
+        });
+    }
+
+    /// Drops the queued moves only, both in the queue and in the planner.
+    /// Any other command stays in the queue and the job keeps running
+    fn quick_stop(&mut self, line_number: LineNumberType) {
+        let queued = self.queue.len();
+        self.queue.retain(|queued| !queued.command.is_move());
+        let dropped_moves = queued - self.queue.len();
+        let dropped_segments = self.planner.clear() + self.motion_queue.clear();
+        self.events.push(ExecutorEvent::QuickStop {
+            line_number,
+            dropped_moves,
+            dropped_segments,
+        });
+    }
+
+    fn check_not_aborted(&self) -> PrintResult<()> {
+        if self.job_state == JobState::Aborted {
+            return Err(match self.aborted_by {
+                Some(AbortCause::HeaterFault(heater)) => Error::HeaterFault(heater),
+                Some(AbortCause::EmergencyStop(line_number)) => {
+                    Error::EmergencyStop(Some(line_number))
+                }
+                None => Error::EmergencyStop(None),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl QueuedCommand {
+    pub fn line_number(&self) -> LineNumberType {
+        self.line_number
+    }
+
+    pub fn command(&self) -> &GcodeCommand {
+        &self.command
+    }
+}
@@ -0,0 +1,547 @@
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::calibration::{BedProbe, HomingConfig, SimulatedAxes};
+    use crate::error::Error;
+    use crate::executor::{Executor, ExecutorEvent, JobState};
+    use crate::gcode::{ExtendedCommand, GcodeLine, GcodeReader};
+    use crate::heater::{HeaterKind, ThermalPlant};
+    use crate::motion::mock::MockDriver;
+    use crate::motion::{Axis, AxisSteps, InputShaper, ShaperType};
+    use crate::storage::MemoryEeprom;
+    use crate::system::{Location, SystemConfig};
+
+    /// Config with a 250 x 210 x 200 bed
+    fn configured_system() -> SystemConfig {
+        let mut config = SystemConfig::default();
+        config.set_bed(Location::default(), Location::new(250.0, 210.0, 200.0));
+        config
+    }
+
+    /// Bed rising 0.2 mm every 100 mm along X
+    struct TiltedBed;
+
+    impl BedProbe for TiltedBed {
+        fn measure_z_offset(&mut self, x: f32, _y: f32) -> Result<f32, Error> {
+            Ok(x * 0.002)
+        }
+    }
+
+    fn run_source(source: &str) -> (Executor, Result<(), Error>) {
+        let mut executor = Executor::new(configured_system());
+        let result = executor.run(
+            GcodeReader::new(source.as_bytes()),
+            &mut MockDriver::default(),
+        );
+        (executor, result)
+    }
+
+    #[test]
+    fn run_small_file_ok() {
+        let file = std::fs::File::open("small_example.gcode").unwrap();
+        let mut executor = Executor::new(SystemConfig::default());
+
+        assert!(
+            executor
+                .run(GcodeReader::from_file(file), &mut MockDriver::default())
+                .is_ok()
+        );
+        assert!(executor.drain_events().is_empty());
+    }
+
+    #[test]
+    fn emergency_stop_skips_queue() {
+        let mut executor = Executor::new(SystemConfig::default());
+        let source = "G1 X1\nG1 X2\nM104 S200\nM112\nG1 X3\n";
+        let mut commands = GcodeReader::new(source.as_bytes())
+            .map(|line| line.unwrap())
+            .filter_map(|line| Some((line.line_number(), line.into_command()?)));
+
+        //  Queue the first three commands without executing them
+        for _ in 0..3 {
+            let (line_number, command) = commands.next().unwrap();
+            executor.submit(command, line_number).unwrap();
+        }
+        assert_eq!(executor.queued_commands(), 3);
+
+        let (line_number, command) = commands.next().unwrap();
+        let result = executor.submit(command, line_number);
+
+        assert!(matches!(result, Err(Error::EmergencyStop(Some(4)))));
+        assert_eq!(executor.job_state(), JobState::Aborted);
+        assert_eq!(executor.queued_commands(), 0);
+        assert_eq!(
+            executor.drain_events(),
+            vec![ExecutorEvent::EmergencyStop {
+                line_number: 4,
+                dropped_commands: 3,
+                dropped_segments: 0
+            }]
+        );
+
+        //  Nothing else gets in or out until the executor is reset
+        let (line_number, command) = commands.next().unwrap();
+        assert!(matches!(
+            executor.submit(command, line_number),
+            Err(Error::EmergencyStop(Some(4)))
+        ));
+        let mut driver = MockDriver::default();
+        assert!(matches!(
+            executor.step(&mut driver),
+            Err(Error::EmergencyStop(Some(4)))
+        ));
+
+        executor.reset();
+        assert_eq!(executor.job_state(), JobState::Idle);
+        assert!(executor.step(&mut driver).unwrap().is_none());
+        assert!(driver.moves.is_empty());
+    }
+
+    #[test]
+    fn run_aborts_on_emergency_stop() {
+        let (mut executor, result) = run_source("G1 X1\nM112\nG1 X2\n");
+
+        assert!(matches!(result, Err(Error::EmergencyStop(Some(2)))));
+        assert_eq!(executor.job_state(), JobState::Aborted);
+        assert!(matches!(
+            executor.drain_events()[..],
+            [ExecutorEvent::EmergencyStop { line_number: 2, .. }]
+        ));
+    }
+
+    #[test]
+    fn quick_stop_drops_moves_only() {
+        let mut executor = Executor::new(SystemConfig::default());
+        let source = "G1 X1\nM104 S200\nG0 X2\nM410\n";
+        for line in GcodeReader::new(source.as_bytes()) {
+            let line = line.unwrap();
+            let line_number = line.line_number();
+            executor
+                .submit(line.into_command().unwrap(), line_number)
+                .unwrap();
+        }
+
+        assert_eq!(executor.job_state(), JobState::Running);
+        assert_eq!(executor.queued_commands(), 1);
+        let mut driver = MockDriver::default();
+        assert_eq!(
+            executor.step(&mut driver).unwrap().unwrap().line_number(),
+            2
+        );
+        assert!(driver.moves.is_empty());
+        assert_eq!(
+            executor.drain_events(),
+            vec![ExecutorEvent::QuickStop {
+                line_number: 4,
+                dropped_moves: 2,
+                dropped_segments: 0
+            }]
+        );
+
+        //  An arc already executed is a single command, but many planned segments
+        let mut executor = Executor::new(configured_system());
+        for line in GcodeReader::new("G2 X20 I10\nM410\n".as_bytes()) {
+            executor.submit_line(line.unwrap()).unwrap();
+            executor.step(&mut driver).unwrap();
+        }
+        let [
+            ExecutorEvent::QuickStop {
+                dropped_moves: 0,
+                dropped_segments,
+                ..
+            },
+        ] = executor.drain_events()[..]
+        else {
+            panic!("expected a quick stop");
+        };
+        assert!(dropped_segments > 1, "{dropped_segments}");
+    }
+
+    #[test]
+    fn moves_are_sent_to_driver_as_steps() {
+        let mut executor = Executor::new(configured_system());
+        let mut driver = MockDriver::default();
+        let source = "M92 X100 Y100\nG1 X10 F600\nG1 F1200\nG1 Y5 E1\n";
+
+        executor
+            .run(GcodeReader::new(source.as_bytes()), &mut driver)
+            .unwrap();
+
+        assert_eq!(driver.position(Axis::X), 1000);
+        assert_eq!(driver.position(Axis::Y), 500);
+        assert_eq!(driver.position(Axis::E), 93);
+        //  Accelerations make the moves take longer than their nominal 1.25 seconds
+        assert!(driver.elapsed() > 1.25);
+    }
+
+    #[test]
+    fn steps_per_unit_change_once_planned_moves_are_done() {
+        let mut executor = Executor::new(configured_system());
+        let mut driver = MockDriver::default();
+        let source = "M92 X80\nG1 X10 F600\nM92 X100\nG1 X20\n";
+
+        executor
+            .run(GcodeReader::new(source.as_bytes()), &mut driver)
+            .unwrap();
+
+        //  First 10 mm at 80 steps/mm, and the next 10 mm at 100 steps/mm
+        assert_eq!(driver.position(Axis::X), 800 + 1000);
+    }
+
+    #[test]
+    fn pressure_advance_pushes_filament_ahead() {
+        let mut executor = Executor::new(configured_system());
+        let mut driver = MockDriver::default();
+        let source = "M92 X100 Y100 E100\nM900 K0.1\nG1 X10 E2 F1200\nG1 X20 E4\nG1 X20 Y10\n";
+
+        executor
+            .run(GcodeReader::new(source.as_bytes()), &mut driver)
+            .unwrap();
+
+        //  Extruder runs ahead of the toolhead while accelerating, and pulls back while slowing down
+        let mut x = 0;
+        let mut e = 0;
+        let mut max_lead = 0;
+        for (steps, _) in &driver.moves {
+            x += steps.get(Axis::X);
+            e += steps.get(Axis::E);
+            max_lead = max_lead.max(e - x * 2);
+        }
+        assert!(max_lead > 10);
+        assert!(driver.moves.iter().any(|(steps, _)| steps.get(Axis::E) < 0));
+        //  Nothing is left ahead once the job is done
+        assert_eq!(driver.position(Axis::E), 400);
+        assert_eq!(driver.position(Axis::Y), 1000);
+    }
+
+    #[test]
+    fn input_shapers_smooth_the_steps() {
+        let source = "M92 X100 Y100\nG1 X20 F6000\nG1 X20 Y20\nG1 X0 Y20\n";
+        let run = |shaper: Option<InputShaper>| {
+            let mut config = configured_system();
+            config.set_input_shaper(Axis::X, shaper);
+            config.set_input_shaper(Axis::Y, shaper);
+            let mut driver = MockDriver::default();
+            Executor::new(config)
+                .run(GcodeReader::new(source.as_bytes()), &mut driver)
+                .unwrap();
+            driver
+        };
+
+        let planned = run(None);
+        let shaped = run(Some(InputShaper::new(ShaperType::Mzv, 40.0, 0.1)));
+
+        //  Planned moves go along one axis at a time, while shaped corners blend both of them
+        let diagonal =
+            |(steps, _): &(AxisSteps, f32)| steps.get(Axis::X) != 0 && steps.get(Axis::Y) != 0;
+        assert!(!planned.moves.iter().any(diagonal));
+        assert!(shaped.moves.iter().any(diagonal));
+        assert_ne!(planned.moves.len(), shaped.moves.len());
+        //  Shaping only changes the way there, the toolhead still ends where the job asked
+        for axis in [Axis::X, Axis::Y] {
+            assert_eq!(shaped.position(axis), planned.position(axis));
+        }
+        assert_eq!(shaped.position(Axis::Y), 2000);
+    }
+
+    #[test]
+    fn moves_need_configured_bed() {
+        let mut executor = Executor::new(SystemConfig::default());
+        let result = executor.run(
+            GcodeReader::new("M83\nG1 X10\n".as_bytes()),
+            &mut MockDriver::default(),
+        );
+        assert!(matches!(result, Err(Error::BedNotConfigured)));
+
+        let (_, result) = run_source("G1 X10\nG1 X300\n");
+        assert!(matches!(
+            result,
+            Err(Error::UnreachableLocation(_, Some(2)))
+        ));
+    }
+
+    #[test]
+    fn resolve_errors_tell_their_line() {
+        //  Radius too small to reach the end of the arc
+        let (_, result) = run_source("G1 X10\nG2 X30 Y0 R5\n");
+        assert!(matches!(
+            result,
+            Err(Error::InvalidParameterInLine(_, Some(2)))
+        ));
+
+        //  Extruder that doesn't exist
+        let (_, result) = run_source("G1 X10\nM83\nM900 K0.05 T3\n");
+        assert!(matches!(
+            result,
+            Err(Error::InvalidParameterInLine(parameter, Some(3))) if parameter == "T3"
+        ));
+    }
+
+    #[test]
+    fn calibrate_and_home_with_endstops() {
+        let mut config = SystemConfig::default();
+        let axes = SimulatedAxes::new(
+            Location::new(-5.0, -5.0, -1.0),
+            Location::new(245.0, 205.0, 199.0),
+            Location::new(30.0, 30.0, 10.0),
+            config.steps_per_unit(),
+        );
+        config.set_bed(Location::default(), Location::new(1.0, 1.0, 1.0));
+        let mut executor = Executor::new(config);
+        let mut driver = axes.clone();
+
+        assert!(matches!(
+            executor.calibrate(&mut driver),
+            Err(Error::EndstopsNotAttached)
+        ));
+        executor.attach_endstops(Box::new(axes.clone()), HomingConfig::default());
+        let calibration = executor.calibrate(&mut driver).unwrap();
+        assert!(calibration.limit().x() > 249.9);
+
+        let source = "G1 X100 Y50 Z5 F6000\nG28 X\nG1 Y60\n";
+        executor
+            .run(GcodeReader::new(source.as_bytes()), &mut driver)
+            .unwrap();
+
+        //  X went back to its switch, while Y kept moving from where it was
+        let location = axes.location(executor.config().steps_per_unit());
+        assert!(location.distance_to(&Location::new(-5.0, 55.0, 4.0)) < 0.02);
+        assert_eq!(
+            executor.config().current_location(),
+            Location::new(0.0, 60.0, 5.0)
+        );
+    }
+
+    #[test]
+    fn probed_mesh_compensates_moves() {
+        let mut executor = Executor::new(configured_system());
+        let mut driver = MockDriver::default();
+        executor.attach_probe(Box::new(TiltedBed));
+
+        let source = "G28\nG29 P2\nG1 X125 Y0 Z0.2 F6000\nG92 X0\nG1 X10\n";
+        executor
+            .run(GcodeReader::new(source.as_bytes()), &mut driver)
+            .unwrap();
+
+        let mesh = executor.config().bed_mesh().unwrap();
+        assert_eq!((mesh.columns(), mesh.rows()), (2, 2));
+        assert_eq!(mesh.point(1, 1), Some(0.5));
+        assert!(executor.config().is_leveling_active());
+        //  Probing went to X250 and back, and the move stopped halfway through the bed
+        assert_eq!(driver.position(Axis::X), 135 * 80);
+        //  0.2 mm of layer height and 0.25 mm of compensation at X125, plus the 0.02 mm the compensation
+        //  grows from X0 to X10 once G92 resets the coordinates
+        assert_eq!(driver.position(Axis::Z), 188);
+        assert_eq!(
+            executor.config().current_location(),
+            Location::new(10.0, 0.0, 0.2)
+        );
+    }
+
+    #[test]
+    fn settings_saved_and_loaded() {
+        let mut executor = Executor::new(configured_system());
+        let mut driver = MockDriver::default();
+        let source = "M92 X100\nM500\nM502\nM503\nM501\nG1 X10\n";
+
+        assert!(matches!(
+            executor.run(GcodeReader::new(source.as_bytes()), &mut driver),
+            Err(Error::StorageNotAttached)
+        ));
+
+        let mut executor = Executor::new(configured_system());
+        let eeprom = MemoryEeprom::default();
+        executor.attach_storage(Box::new(eeprom.clone()));
+        executor
+            .run(GcodeReader::new(source.as_bytes()), &mut driver)
+            .unwrap();
+
+        //  The report was taken after the reset, and the move after loading the saved settings
+        let events = executor.drain_events();
+        assert!(matches!(
+            &events[..],
+            [ExecutorEvent::SettingsReport { line_number: 4, report }] if report.contains("M92 X80 ")
+        ));
+        assert_eq!(executor.config().steps_per_unit().get(Axis::X), 100.0);
+        assert_eq!(driver.position(Axis::X), 1000);
+
+        let mut restarted = SystemConfig::default();
+        restarted.load_settings(&mut eeprom.clone()).unwrap();
+        assert_eq!(restarted.steps_per_unit().get(Axis::X), 100.0);
+    }
+
+    #[test]
+    fn extended_commands_run_handlers() {
+        let mut executor = Executor::new(configured_system());
+        let bed_temperature = Rc::new(Cell::new(0.0));
+        let temperature = bed_temperature.clone();
+        executor.register_handler(
+            "print_start",
+            Box::new(move |command: &ExtendedCommand, _: &mut SystemConfig| {
+                temperature.set(command.number("bed")?.unwrap_or_default());
+                Ok(())
+            }),
+        );
+        //  Macros can be turned into regular commands
+        executor.register_handler(
+            "SET_VELOCITY_LIMIT",
+            Box::new(|command: &ExtendedCommand, config: &mut SystemConfig| {
+                let accel = command.parameter("ACCEL").unwrap_or("3000");
+                if let Some(command) =
+                    GcodeLine::parse(&format!("M204 S{accel}"), 0)?.into_command()
+                {
+                    config.apply_command(&command)?;
+                }
+                Ok(())
+            }),
+        );
+
+        let source = "PRINT_START BED=60 EXTRUDER=210\nSET_VELOCITY_LIMIT ACCEL=1200\nRESPOND MSG=\"Print started\"\nG1 X10\n";
+        executor
+            .run(
+                GcodeReader::new(source.as_bytes()),
+                &mut MockDriver::default(),
+            )
+            .unwrap();
+
+        assert_eq!(bed_temperature.get(), 60.0);
+        assert_eq!(
+            executor.config().motion_limits().print_acceleration(),
+            1200.0
+        );
+        assert_eq!(
+            executor.drain_events(),
+            vec![ExecutorEvent::UnhandledCommand {
+                line_number: 3,
+                name: "RESPOND".to_string()
+            }]
+        );
+    }
+
+    /// Hotend heating about 2.3 °C/s from cold, with the sensor two seconds behind
+    fn attach_hotend(executor: &mut Executor) -> ThermalPlant {
+        let plant = ThermalPlant::new(25.0, 350.0, 150.0, 2.0);
+        executor.attach_heater(
+            HeaterKind::Hotend,
+            Box::new(plant.clone()),
+            Box::new(plant.clone()),
+        );
+        plant
+    }
+
+    #[test]
+    fn heaters_wait_for_their_temperature() {
+        let mut executor = Executor::new(configured_system());
+        let plant = attach_hotend(&mut executor);
+        //  The bed isn't attached, so its commands are left to the host
+        let source = "M140 S60\nM104 S150\nG1 X10\nM109 S200\nG1 X20\n";
+        executor
+            .run(
+                GcodeReader::new(source.as_bytes()),
+                &mut MockDriver::default(),
+            )
+            .unwrap();
+
+        let temperature = executor.config().temperature(HeaterKind::Hotend);
+        assert!((temperature - 200.0).abs() <= 1.0, "{temperature}");
+        assert_eq!(executor.config().temperature(HeaterKind::Bed), 0.0);
+        assert!(plant.elapsed() > 60.0);
+
+        //  The hotend keeps its temperature while the firmware regulates it
+        executor.regulate_heaters(120.0).unwrap();
+        let temperature = executor.config().temperature(HeaterKind::Hotend);
+        assert!((temperature - 200.0).abs() <= 1.0, "{temperature}");
+    }
+
+    #[test]
+    fn cooling_waits_end_once_the_temperature_stops_falling() {
+        let mut executor = Executor::new(configured_system());
+        let plant = attach_hotend(&mut executor);
+
+        //  The room is at 25 °C, so the hotend never gets down to 20 °C
+        executor
+            .run(
+                GcodeReader::new(
+                    "M109 S200
+M109 R20
+G1 X10
+"
+                    .as_bytes(),
+                ),
+                &mut MockDriver::default(),
+            )
+            .unwrap();
+
+        let temperature = executor.config().temperature(HeaterKind::Hotend);
+        assert!(temperature < 35.0, "{temperature}");
+        assert!(plant.elapsed() < 1800.0);
+    }
+
+    #[test]
+    fn heater_faults_abort_the_job() {
+        let mut executor = Executor::new(configured_system());
+        let plant = attach_hotend(&mut executor);
+        plant.disconnect_heater();
+
+        let result = executor.run(
+            GcodeReader::new("M109 S200\nG1 X10\n".as_bytes()),
+            &mut MockDriver::default(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::HeatingFailed(HeaterKind::Hotend))
+        ));
+        assert_eq!(executor.job_state(), JobState::Aborted);
+        assert_eq!(plant.power(), 0.0);
+
+        //  The host is told which heater aborted the job, and so is every later call
+        assert_eq!(
+            executor.drain_events(),
+            vec![ExecutorEvent::HeaterFault {
+                heater: HeaterKind::Hotend
+            }]
+        );
+        assert!(matches!(
+            executor.step(&mut MockDriver::default()),
+            Err(Error::HeaterFault(HeaterKind::Hotend))
+        ));
+    }
+
+    #[test]
+    fn autotune_sets_the_gains() {
+        let mut executor = Executor::new(configured_system());
+        let mut driver = MockDriver::default();
+        assert!(matches!(
+            executor.run(GcodeReader::new("M303 E-1 S60".as_bytes()), &mut driver),
+            Err(Error::HeaterNotAttached(HeaterKind::Bed))
+        ));
+
+        let mut executor = Executor::new(configured_system());
+        let plant = attach_hotend(&mut executor);
+        executor
+            .run(
+                GcodeReader::new("M303 S200 C5 U1\nM503\n".as_bytes()),
+                &mut driver,
+            )
+            .unwrap();
+
+        let events = executor.drain_events();
+        let [
+            ExecutorEvent::PidAutotuned {
+                line_number: 1,
+                heater: HeaterKind::Hotend,
+                gains,
+            },
+            ExecutorEvent::SettingsReport { report, .. },
+        ] = &events[..]
+        else {
+            panic!("unexpected events {events:?}");
+        };
+        assert_eq!(executor.config().pid_gains(HeaterKind::Hotend), *gains);
+        assert!(report.contains(&format!("M301 P{} ", gains.proportional())));
+        assert_eq!(plant.power(), 0.0);
+    }
+}
@@ -0,0 +1,79 @@
+mod moves;
+mod svg;
+
+use crate::error::PrintResult;
+use crate::gcode::GcodeLine;
+use crate::system::{ResolvedMove, SystemConfig};
+use crate::types::LocationType;
+
+pub use moves::{MoveTableFormat, export_moves};
+pub use svg::{SvgLayer, export_svg_layers, render_svg_layers};
+
+/// Heights closer than this belong to the same layer, in millimeters
+const LAYER_TOLERANCE: LocationType = 1e-3;
+
+/// Layer a replayed move belongs to
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Layer {
+    /// Starts at 0. Moves before the first extrusion belong to the first layer
+    pub(crate) index: usize,
+    /// Height of the first extrusion of the layer. Zero until something is extruded
+    pub(crate) z: LocationType,
+}
+
+/// Tells the layers of a job apart while it's replayed. A layer starts with the first move extruding
+/// above the previous layer, so travels and Z hops stay in the layer they leave from
+#[derive(Debug, Default)]
+pub(crate) struct LayerTracker {
+    layer: Option<Layer>,
+}
+
+impl LayerTracker {
+    pub(crate) fn layer_of(&mut self, resolved_move: &ResolvedMove) -> Layer {
+        let end = resolved_move.end();
+        //  Extruding without moving, like after a retraction, doesn't start a layer
+        if resolved_move.prints() {
+            self.layer = match self.layer {
+                None => Some(Layer {
+                    index: 0,
+                    z: end.z(),
+                }),
+                Some(layer) if end.z() > layer.z + LAYER_TOLERANCE => Some(Layer {
+                    index: layer.index + 1,
+                    z: end.z(),
+                }),
+                layer => layer,
+            };
+        }
+
+        self.layer.unwrap_or_default()
+    }
+}
+
+/// Replays a job on a copy of the configuration, handing every resolved move to `visit`
+/// along with the line it came from, its layer and the state once the whole command is applied
+pub(crate) fn replay_job(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    mut visit: impl FnMut(&GcodeLine, &ResolvedMove, Layer, &SystemConfig) -> PrintResult<()>,
+) -> PrintResult<()> {
+    let mut state = config.clone();
+    let mut layers = LayerTracker::default();
+
+    for line in lines {
+        let line = line?;
+        let Some(command) = line.command() else {
+            continue;
+        };
+
+        let resolved_moves = state
+            .apply_command(command)
+            .map_err(|error| error.in_line(line.line_number()))?;
+        for resolved_move in resolved_moves {
+            let layer = layers.layer_of(&resolved_move);
+            visit(&line, &resolved_move, layer, &state)?;
+        }
+    }
+
+    Ok(())
+}
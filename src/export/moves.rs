@@ -0,0 +1,207 @@
+use std::io::Write;
+
+use crate::error::{Error, PrintResult};
+use crate::gcode::{GcodeLine, format_number};
+use crate::system::{ResolvedMove, SystemConfig};
+
+use super::{Layer, replay_job};
+
+/// Max amount of decimals of the values written
+const PRECISION: usize = 5;
+/// Name of every field of a record, in the order they're written
+const FIELDS: [&str; 13] = [
+    "line",
+    "command",
+    "start_x",
+    "start_y",
+    "start_z",
+    "end_x",
+    "end_y",
+    "end_z",
+    "extrusion",
+    "feedrate",
+    "layer",
+    "feature",
+    "duration",
+];
+
+/// Layout of the records written by `export_moves()`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MoveTableFormat {
+    /// Comma separated values, with a header line naming the fields
+    #[default]
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+}
+
+/// Value of a field of a record
+enum Field<'a> {
+    Number(String),
+    Text(Option<&'a str>),
+}
+
+/// Replays a job on a copy of the configuration, writing one record per resolved move as soon as
+/// it's resolved. Arcs write one record per segment. Records hold the line and command the move
+/// came from, its ends in millimeters, the filament extruded, the feedrate in millimeters per minute,
+/// the layer, the comment of the line as feature, and the duration in seconds at nominal feedrate.
+/// Returns the amount of records written
+pub fn export_moves<W: Write>(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    format: MoveTableFormat,
+    mut output: W,
+) -> PrintResult<usize> {
+    if format == MoveTableFormat::Csv {
+        writeln!(output, "{}", FIELDS.join(",")).map_err(Error::InputOutputError)?;
+    }
+
+    let mut records = 0;
+    replay_job(lines, config, |line, resolved_move, layer, _| {
+        let fields = record(line, resolved_move, layer);
+        let text = match format {
+            MoveTableFormat::Csv => csv_record(&fields),
+            MoveTableFormat::JsonLines => json_record(&fields),
+        };
+        records += 1;
+        writeln!(output, "{text}").map_err(Error::InputOutputError)
+    })?;
+
+    output.flush().map_err(Error::InputOutputError)?;
+    Ok(records)
+}
+
+fn record<'a>(line: &'a GcodeLine, resolved_move: &ResolvedMove, layer: Layer) -> [Field<'a>; 13] {
+    let number = |value: f32| Field::Number(format_number(value, PRECISION));
+    let (start, end) = (resolved_move.start(), resolved_move.end());
+
+    [
+        Field::Number(line.line_number().to_string()),
+        Field::Text(line.command().map(|command| command.name())),
+        number(start.x()),
+        number(start.y()),
+        number(start.z()),
+        number(end.x()),
+        number(end.y()),
+        number(end.z()),
+        number(resolved_move.extrusion()),
+        number(resolved_move.feedrate()),
+        Field::Number(layer.index.to_string()),
+        Field::Text(line.comment()),
+        number(resolved_move.nominal_duration()),
+    ]
+}
+
+/// Text with commas, quotes or line breaks is quoted, doubling its quotes. Missing text is left empty
+fn csv_record(fields: &[Field]) -> String {
+    let values: Vec<String> = fields
+        .iter()
+        .map(|field| match field {
+            Field::Number(number) => number.clone(),
+            Field::Text(None) => String::new(),
+            Field::Text(Some(text)) if text.contains([',', '"', '\n', '\r']) => {
+                format!("\"{}\"", text.replace('"', "\"\""))
+            }
+            Field::Text(Some(text)) => text.to_string(),
+        })
+        .collect();
+
+    values.join(",")
+}
+
+/// Missing text is written as null
+fn json_record(fields: &[Field]) -> String {
+    let members: Vec<String> = FIELDS
+        .iter()
+        .zip(fields)
+        .map(|(name, field)| {
+            let value = match field {
+                Field::Number(number) => number.clone(),
+                Field::Text(None) => "null".to_string(),
+                Field::Text(Some(text)) => json_string(text),
+            };
+            format!("\"{name}\":{value}")
+        })
+        .collect();
+
+    format!("{{{}}}", members.join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            character if character.is_control() => {
+                escaped.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => escaped.push(character),
+        }
+    }
+    escaped.push('"');
+
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use crate::export::{MoveTableFormat, export_moves};
+    use crate::gcode::GcodeReader;
+    use crate::system::SystemConfig;
+
+    const SOURCE: &str = "M83\nG1 Z0.2 F600\nG1 X10 E0.5 ; skirt, first pass\nM104 S200\nG1 Y10 E0.5 ; \"perimeter\"\n";
+
+    fn export(format: MoveTableFormat) -> (usize, String) {
+        let mut output = vec![];
+        let records = export_moves(
+            GcodeReader::new(SOURCE.as_bytes()),
+            &SystemConfig::default(),
+            format,
+            &mut output,
+        )
+        .unwrap();
+        (records, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn export_csv() {
+        let (records, csv) = export(MoveTableFormat::Csv);
+        let rows: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(records, 3);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0],
+            "line,command,start_x,start_y,start_z,end_x,end_y,end_z,extrusion,feedrate,layer,feature,duration"
+        );
+        assert_eq!(rows[1], "2,G1,0,0,0,0,0,0.2,0,600,0,,0.02");
+        assert_eq!(
+            rows[2],
+            "3,G1,0,0,0.2,10,0,0.2,0.5,600,0,\"skirt, first pass\",1"
+        );
+        assert_eq!(
+            rows[3],
+            "5,G1,10,0,0.2,10,10,0.2,0.5,600,0,\"\"\"perimeter\"\"\",1"
+        );
+    }
+
+    #[test]
+    fn export_json_lines() {
+        let (records, json) = export(MoveTableFormat::JsonLines);
+        let rows: Vec<&str> = json.lines().collect();
+
+        assert_eq!(records, rows.len());
+        assert_eq!(
+            rows[1],
+            "{\"line\":3,\"command\":\"G1\",\"start_x\":0,\"start_y\":0,\"start_z\":0.2,\"end_x\":10,\"end_y\":0,\
+             \"end_z\":0.2,\"extrusion\":0.5,\"feedrate\":600,\"layer\":0,\"feature\":\"skirt, first pass\",\"duration\":1}"
+        );
+        assert!(rows[0].contains("\"feature\":null"));
+        assert!(rows[2].contains("\"feature\":\"\\\"perimeter\\\"\""));
+    }
+}
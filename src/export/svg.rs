@@ -0,0 +1,314 @@
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, PrintResult};
+use crate::gcode::{GcodeLine, format_number};
+use crate::system::{Location, ResolvedMove, SystemConfig};
+use crate::types::LocationType;
+
+use super::{Layer, replay_job};
+
+/// Room left around the bed frame, in millimeters
+const MARGIN: LocationType = 5.0;
+/// Max amount of decimals of the coordinates written
+const PRECISION: usize = 3;
+
+/// Single layer of a job drawn as a standalone SVG document. Units are millimeters,
+/// with the Y axis pointing up like on the bed
+#[derive(Debug, Clone, PartialEq)]
+pub struct SvgLayer {
+    index: usize,
+    z: LocationType,
+    document: String,
+}
+
+/// Layer being drawn. Travels and extrusions go in separate paths, so each one gets its own style
+struct LayerDrawing {
+    layer: Layer,
+    travels: PathData,
+    extrusions: PathData,
+    /// Points of the canvas where the filament was retracted
+    retractions: Vec<(LocationType, LocationType)>,
+}
+
+/// Data of a SVG path, drawn one segment at a time
+#[derive(Default)]
+struct PathData {
+    data: String,
+    /// Where the last segment ended. Segments starting somewhere else need to jump there first
+    end: Option<(LocationType, LocationType)>,
+}
+
+/// Bed frame the layers are drawn in
+struct Canvas {
+    origin: Location,
+    limit: Location,
+}
+
+impl SvgLayer {
+    /// Starts at 0, counting from the first layer with extrusions
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Height of the layer, in millimeters
+    pub fn z(&self) -> LocationType {
+        self.z
+    }
+
+    pub fn document(&self) -> &str {
+        &self.document
+    }
+}
+
+/// Replays a job on a copy of the configuration and draws every layer in the range.
+/// The bed must be configured, since its bounds are drawn as the frame of every layer
+pub fn render_svg_layers(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    layers: impl RangeBounds<usize>,
+) -> PrintResult<Vec<SvgLayer>> {
+    let mut rendered = vec![];
+    draw_layers(lines, config, layers, |layer| {
+        rendered.push(layer);
+        Ok(())
+    })?;
+
+    Ok(rendered)
+}
+
+/// Same as `render_svg_layers()`, but each layer is written to the directory as soon as it's drawn,
+/// as `layer_0000.svg`, `layer_0001.svg`, etc. Returns the paths of the written files
+pub fn export_svg_layers(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    layers: impl RangeBounds<usize>,
+    directory: impl AsRef<Path>,
+) -> PrintResult<Vec<PathBuf>> {
+    let directory = directory.as_ref();
+    std::fs::create_dir_all(directory).map_err(Error::InputOutputError)?;
+
+    let mut paths = vec![];
+    draw_layers(lines, config, layers, |layer| {
+        let path = directory.join(format!("layer_{:04}.svg", layer.index));
+        std::fs::write(&path, &layer.document).map_err(Error::InputOutputError)?;
+        paths.push(path);
+        Ok(())
+    })?;
+
+    Ok(paths)
+}
+
+/// Hands every drawn layer over to `finished` as soon as the job moves on to the next one
+fn draw_layers(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    layers: impl RangeBounds<usize>,
+    mut finished: impl FnMut(SvgLayer) -> PrintResult<()>,
+) -> PrintResult<()> {
+    let (Some(origin), Some(limit)) = (config.bed_origin(), config.bed_limit()) else {
+        return Err(Error::BedNotConfigured);
+    };
+    let canvas = Canvas { origin, limit };
+    let mut drawing: Option<LayerDrawing> = None;
+
+    replay_job(lines, config, |_, resolved_move, layer, _| {
+        if let Some(current) = drawing.take_if(|current| current.layer.index != layer.index) {
+            finished(current.finish(&canvas))?;
+        }
+        if layers.contains(&layer.index) {
+            drawing
+                .get_or_insert_with(|| LayerDrawing::new(layer))
+                .draw(resolved_move, layer, &canvas);
+        }
+        Ok(())
+    })?;
+
+    match drawing {
+        Some(current) => finished(current.finish(&canvas)),
+        None => Ok(()),
+    }
+}
+
+impl LayerDrawing {
+    fn new(layer: Layer) -> Self {
+        Self {
+            layer,
+            travels: PathData::default(),
+            extrusions: PathData::default(),
+            retractions: vec![],
+        }
+    }
+
+    /// Moves along Z alone aren't visible from the top, so only their retractions are drawn
+    fn draw(&mut self, resolved_move: &ResolvedMove, layer: Layer, canvas: &Canvas) {
+        //  The height of the first layer is only known after its first extrusion
+        self.layer = layer;
+
+        let start = canvas.flip(resolved_move.start());
+        let end = canvas.flip(resolved_move.end());
+        if resolved_move.extrusion() < 0.0 {
+            self.retractions.push(start);
+        }
+
+        if start != end {
+            match resolved_move.extrusion() > 0.0 {
+                true => self.extrusions.line(start, end),
+                false => self.travels.line(start, end),
+            }
+        }
+    }
+
+    fn finish(self, canvas: &Canvas) -> SvgLayer {
+        let width = canvas.limit.x() - canvas.origin.x();
+        let height = canvas.limit.y() - canvas.origin.y();
+        let number = |value: f32| format_number(value, PRECISION);
+
+        let mut document = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\" width=\"{}mm\" height=\"{}mm\">\n",
+            number(canvas.origin.x() - MARGIN),
+            number(canvas.origin.y() - MARGIN),
+            number(width + 2.0 * MARGIN),
+            number(height + 2.0 * MARGIN),
+            number(width + 2.0 * MARGIN),
+            number(height + 2.0 * MARGIN),
+        );
+        document.push_str(&format!(
+            "  <title>Layer {} at Z {}</title>\n",
+            self.layer.index,
+            number(self.layer.z)
+        ));
+        document.push_str(&format!(
+            "  <rect class=\"bed\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#808080\" stroke-width=\"0.5\"/>\n",
+            number(canvas.origin.x()),
+            number(canvas.origin.y()),
+            number(width),
+            number(height),
+        ));
+        if !self.extrusions.data.is_empty() {
+            document.push_str(&format!(
+                "  <path class=\"extrusion\" fill=\"none\" stroke=\"#ff7f0e\" stroke-width=\"0.45\" stroke-linecap=\"round\" stroke-linejoin=\"round\" d=\"{}\"/>\n",
+                self.extrusions.data.trim_end()
+            ));
+        }
+        if !self.travels.data.is_empty() {
+            document.push_str(&format!(
+                "  <path class=\"travel\" fill=\"none\" stroke=\"#1f77b4\" stroke-width=\"0.2\" stroke-dasharray=\"1 1\" d=\"{}\"/>\n",
+                self.travels.data.trim_end()
+            ));
+        }
+        for (x, y) in self.retractions {
+            document.push_str(&format!(
+                "  <circle class=\"retraction\" cx=\"{}\" cy=\"{}\" r=\"0.6\" fill=\"#d62728\"/>\n",
+                number(x),
+                number(y)
+            ));
+        }
+        document.push_str("</svg>\n");
+
+        SvgLayer {
+            index: self.layer.index,
+            z: self.layer.z,
+            document,
+        }
+    }
+}
+
+impl PathData {
+    fn line(&mut self, start: (LocationType, LocationType), end: (LocationType, LocationType)) {
+        if self.end != Some(start) {
+            self.point('M', start);
+        }
+        self.point('L', end);
+        self.end = Some(end);
+    }
+
+    fn point(&mut self, command: char, (x, y): (LocationType, LocationType)) {
+        self.data.push_str(&format!(
+            "{command}{},{} ",
+            format_number(x, PRECISION),
+            format_number(y, PRECISION)
+        ));
+    }
+}
+
+impl Canvas {
+    /// SVG has the Y axis pointing down, so the bed is drawn upside down to look like seen from above
+    fn flip(&self, location: Location) -> (LocationType, LocationType) {
+        (
+            location.x(),
+            self.origin.y() + self.limit.y() - location.y(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::export::{export_svg_layers, render_svg_layers};
+    use crate::gcode::GcodeReader;
+    use crate::system::{Location, SystemConfig};
+
+    const SOURCE: &str = "M83\nG1 Z0.2 F3000\nG1 X10 Y10\nG1 X20 Y10 E1 ; perimeter\nG1 E-0.8\nG1 X30 Y30\n\
+                          G1 E0.8\nG1 X40 Y30 E1\nG1 Z0.4\nG1 X10 Y10\nG1 X20 Y20 E1\n";
+
+    fn bed_config() -> SystemConfig {
+        let mut config = SystemConfig::default();
+        config.set_bed(
+            Location::new(0.0, 0.0, 0.0),
+            Location::new(100.0, 100.0, 100.0),
+        );
+        config
+    }
+
+    #[test]
+    fn layers_are_drawn_apart() {
+        let layers =
+            render_svg_layers(GcodeReader::new(SOURCE.as_bytes()), &bed_config(), ..).unwrap();
+
+        assert_eq!(layers.len(), 2);
+        assert_eq!((layers[0].index(), layers[0].z()), (0, 0.2));
+        assert_eq!((layers[1].index(), layers[1].z()), (1, 0.4));
+
+        let first = layers[0].document();
+        assert!(first.contains("<rect class=\"bed\" x=\"0\" y=\"0\" width=\"100\" height=\"100\""));
+        //  Y is flipped, so the bed looks like seen from above
+        assert!(first.contains("d=\"M10,90 L20,90 M30,70 L40,70\""));
+        assert!(first.contains("<circle class=\"retraction\" cx=\"20\" cy=\"90\""));
+        assert!(first.contains("class=\"travel\""));
+        assert!(!layers[1].document().contains("retraction"));
+    }
+
+    #[test]
+    fn layer_range_and_files() {
+        let config = bed_config();
+        let layers = render_svg_layers(GcodeReader::new(SOURCE.as_bytes()), &config, 1..).unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].index(), 1);
+
+        let directory = std::env::temp_dir().join(format!("printy_svg_{}", std::process::id()));
+        let paths = export_svg_layers(
+            GcodeReader::new(SOURCE.as_bytes()),
+            &config,
+            ..=0,
+            &directory,
+        )
+        .unwrap();
+        assert_eq!(paths, vec![directory.join("layer_0000.svg")]);
+        assert!(
+            std::fs::read_to_string(&paths[0])
+                .unwrap()
+                .starts_with("<svg")
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+
+        assert!(matches!(
+            render_svg_layers(
+                GcodeReader::new(SOURCE.as_bytes()),
+                &SystemConfig::default(),
+                ..
+            ),
+            Err(Error::BedNotConfigured)
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+use std::f32::consts::PI;
+
+use super::pid::{PID_MAX, PidGains};
+
+/// Shortest time the relay stays on one side, in seconds. Keeps noise around the target from flipping it
+const MIN_SWITCH_TIME: f32 = 5.0;
+/// Bias of the relay is kept this far from both ends of the output
+const MIN_BIAS: f32 = 20.0;
+/// Cycles measured at the least. The first ones are spent settling the bias
+const MIN_AUTOTUNE_CYCLES: u8 = 3;
+
+/// Relay autotune, the same one Marlin runs for M303. The heater is driven high while below the target and
+/// low while above it, so the temperature oscillates around the target. Each cycle moves the bias of the relay
+/// towards symmetric oscillations, and the gains come from their amplitude and period by Ziegler-Nichols
+#[derive(Debug, Clone)]
+pub(crate) struct PidAutotune {
+    target: f32,
+    cycles: u8,
+    completed_cycles: u8,
+    heating: bool,
+    /// Output the relay oscillates around, from 0 to 255
+    bias: f32,
+    /// Output the relay moves away from the bias, from 0 to 255
+    amplitude: f32,
+    /// Seconds since the start
+    time: f32,
+    /// Times the relay last switched off and on
+    switched_off_at: f32,
+    switched_on_at: f32,
+    high_time: f32,
+    low_time: f32,
+    max_temperature: f32,
+    min_temperature: f32,
+    gains: Option<PidGains>,
+}
+
+impl PidAutotune {
+    pub(crate) fn new(target: f32, cycles: u8) -> Self {
+        Self {
+            target,
+            cycles: cycles.max(MIN_AUTOTUNE_CYCLES),
+            completed_cycles: 0,
+            heating: true,
+            bias: PID_MAX / 2.0,
+            amplitude: PID_MAX / 2.0,
+            time: 0.0,
+            switched_off_at: 0.0,
+            switched_on_at: 0.0,
+            high_time: 0.0,
+            low_time: 0.0,
+            max_temperature: target,
+            min_temperature: target,
+            gains: None,
+        }
+    }
+
+    /// Gains measured by the last cycle. None until the requested cycles are done
+    pub(crate) fn result(&self) -> Option<PidGains> {
+        match self.completed_cycles > self.cycles {
+            true => self.gains,
+            false => None,
+        }
+    }
+
+    /// Seconds since the relay last switched. Autotunes that stop switching never finish
+    pub(crate) fn time_since_switch(&self) -> f32 {
+        self.time - self.switched_on_at.max(self.switched_off_at)
+    }
+
+    /// Power the heater is driven at until the next update, from 0 to 1, out of the temperature just read
+    pub(crate) fn update(&mut self, temperature: f32, seconds: f32) -> f32 {
+        self.time += seconds;
+        self.max_temperature = self.max_temperature.max(temperature);
+        self.min_temperature = self.min_temperature.min(temperature);
+
+        if self.heating
+            && temperature > self.target
+            && self.time - self.switched_on_at > MIN_SWITCH_TIME
+        {
+            self.heating = false;
+            self.switched_off_at = self.time;
+            self.high_time = self.switched_off_at - self.switched_on_at;
+            self.max_temperature = self.target;
+        }
+
+        if !self.heating
+            && temperature < self.target
+            && self.time - self.switched_off_at > MIN_SWITCH_TIME
+        {
+            self.heating = true;
+            self.switched_on_at = self.time;
+            self.low_time = self.switched_on_at - self.switched_off_at;
+            if self.completed_cycles > 0 {
+                self.measure_cycle();
+            }
+            self.completed_cycles += 1;
+            self.min_temperature = self.target;
+        }
+
+        let output = match self.heating {
+            true => self.bias + self.amplitude,
+            false => self.bias - self.amplitude,
+        };
+        output / PID_MAX
+    }
+
+    /// Moves the bias towards equal times on both sides, and takes the gains out of the last oscillation
+    fn measure_cycle(&mut self) {
+        let period = self.high_time + self.low_time;
+        self.bias += self.amplitude * (self.high_time - self.low_time) / period;
+        self.bias = self.bias.clamp(MIN_BIAS, PID_MAX - MIN_BIAS);
+        self.amplitude = match self.bias > PID_MAX / 2.0 {
+            true => PID_MAX - 1.0 - self.bias,
+            false => self.bias,
+        };
+
+        if self.completed_cycles > 2 {
+            let oscillation = (self.max_temperature - self.min_temperature) / 2.0;
+            let ultimate_gain = 4.0 * self.amplitude / (PI * oscillation);
+            let proportional = 0.6 * ultimate_gain;
+            self.gains = Some(PidGains::new(
+                proportional,
+                2.0 * proportional / period,
+                proportional * period / 8.0,
+            ));
+        }
+    }
+}
@@ -0,0 +1,299 @@
+mod autotune;
+mod pid;
+mod plant;
+mod runaway;
+
+use crate::error::{Error, PrintResult};
+
+use autotune::PidAutotune;
+use pid::PidController;
+use runaway::RunawayMonitor;
+
+pub use pid::PidGains;
+pub use plant::ThermalPlant;
+
+/// Seconds between reads of the sensor while regulating a heater
+const CONTROL_PERIOD: f32 = 0.1;
+/// Autotunes fail when the temperature goes this far above the target, in °C. Same as Marlin
+const MAX_AUTOTUNE_OVERSHOOT: f32 = 30.0;
+/// Autotunes fail when the relay doesn't switch for this long, in seconds. Same as Marlin
+const MAX_AUTOTUNE_SWITCH_TIME: f32 = 1200.0;
+
+/// Thermistor or thermocouple measuring the temperature of a heater
+pub trait TemperatureSensor {
+    /// Current temperature, in °C
+    fn read_temperature(&mut self) -> PrintResult<f32>;
+}
+
+/// Abstraction over the hardware that drives a heater, usually with PWM
+pub trait HeaterOutput {
+    /// Drives the heater at a power from 0 to 1 for the given seconds, returning once they're over
+    fn apply_power(&mut self, power: f32, seconds: f32) -> PrintResult<()>;
+}
+
+/// Heaters the machine can regulate. Every tool shares the hotend heater
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HeaterKind {
+    #[default]
+    Hotend,
+    Bed,
+}
+
+/// Heater regulated by a PID loop, watched for thermal runaways
+pub(crate) struct Heater {
+    kind: HeaterKind,
+    sensor: Box<dyn TemperatureSensor>,
+    output: Box<dyn HeaterOutput>,
+    controller: PidController,
+    monitor: RunawayMonitor,
+    /// °C. Zero while the heater is off
+    target: f32,
+    /// Last temperature read, in °C
+    temperature: f32,
+}
+
+impl HeaterKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeaterKind::Hotend => "hotend",
+            HeaterKind::Bed => "bed",
+        }
+    }
+
+    /// Gains used until M301 or M304 sets others. Same defaults as Marlin
+    pub fn default_gains(&self) -> PidGains {
+        match self {
+            HeaterKind::Hotend => PidGains::new(22.2, 1.08, 114.0),
+            HeaterKind::Bed => PidGains::new(10.0, 0.023, 305.4),
+        }
+    }
+
+    /// Temperature M303 tunes at when S is left out, in °C. Same as the PLA preheat of Marlin
+    pub fn autotune_target(&self) -> f32 {
+        match self {
+            HeaterKind::Hotend => 180.0,
+            HeaterKind::Bed => 70.0,
+        }
+    }
+}
+
+impl Heater {
+    pub(crate) fn new(
+        kind: HeaterKind,
+        sensor: Box<dyn TemperatureSensor>,
+        output: Box<dyn HeaterOutput>,
+        gains: PidGains,
+    ) -> Self {
+        Self {
+            kind,
+            sensor,
+            output,
+            controller: PidController::new(gains),
+            monitor: RunawayMonitor::new(kind),
+            target: 0.0,
+            temperature: 0.0,
+        }
+    }
+
+    pub(crate) fn kind(&self) -> HeaterKind {
+        self.kind
+    }
+
+    /// °C. Zero while the heater is off
+    pub(crate) fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Last temperature read, in °C
+    pub(crate) fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub(crate) fn set_gains(&mut self, gains: PidGains) {
+        self.controller.set_gains(gains);
+    }
+
+    /// Sets the temperature to regulate at, or turns the heater off with zero
+    pub(crate) fn set_target(&mut self, target: f32) -> PrintResult<()> {
+        self.temperature = self.sensor.read_temperature()?;
+        self.target = target.max(0.0);
+        self.controller.reset();
+        self.monitor.start(self.target, self.temperature);
+
+        Ok(())
+    }
+
+    /// True once the temperature is within a window around the target. Heating only waits while below it
+    pub(crate) fn is_at_target(&self, window: f32, heating_only: bool) -> bool {
+        match heating_only {
+            true => self.temperature >= self.target - window,
+            false => (self.temperature - self.target).abs() <= window,
+        }
+    }
+
+    /// Runs the control loop for some seconds. A thermal runaway turns the heater off and fails
+    pub(crate) fn regulate(&mut self, seconds: f32) -> PrintResult<()> {
+        let mut remaining = seconds;
+        while remaining > 0.0 {
+            let period = remaining.min(CONTROL_PERIOD);
+            remaining -= period;
+
+            self.temperature = self.sensor.read_temperature()?;
+            if let Err(error) = self.monitor.check(self.target, self.temperature, period) {
+                self.turn_off()?;
+                return Err(error);
+            }
+            let power = self
+                .controller
+                .update(self.target, self.temperature, period);
+            self.output.apply_power(power, period)?;
+        }
+
+        Ok(())
+    }
+
+    /// M303. Measures the gains that regulate the heater at a target, oscillating around it for some cycles.
+    /// The heater is left off once done
+    pub(crate) fn autotune(&mut self, target: f32, cycles: u8) -> PrintResult<PidGains> {
+        let mut autotune = PidAutotune::new(target, cycles);
+
+        let result = loop {
+            self.temperature = self.sensor.read_temperature()?;
+            if self.temperature > target + MAX_AUTOTUNE_OVERSHOOT
+                || autotune.time_since_switch() > MAX_AUTOTUNE_SWITCH_TIME
+            {
+                break Err(Error::AutotuneFailed(self.kind));
+            }
+
+            let power = autotune.update(self.temperature, CONTROL_PERIOD);
+            if let Some(gains) = autotune.result() {
+                break Ok(gains);
+            }
+            self.output.apply_power(power, CONTROL_PERIOD)?;
+        };
+
+        self.turn_off()?;
+        result
+    }
+
+    /// Drops the target and cuts the power right away
+    pub(crate) fn turn_off(&mut self) -> PrintResult<()> {
+        self.target = 0.0;
+        self.controller.reset();
+        self.monitor.start(0.0, self.temperature);
+        self.output.apply_power(0.0, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+
+    use super::{Heater, HeaterKind, PidGains, ThermalPlant};
+
+    /// Hotend heating about 2.3 °C/s from cold, with the sensor two seconds behind
+    fn hotend_plant() -> ThermalPlant {
+        ThermalPlant::new(25.0, 350.0, 150.0, 2.0)
+    }
+
+    fn attached_heater(plant: &ThermalPlant, gains: PidGains) -> Heater {
+        Heater::new(
+            HeaterKind::Hotend,
+            Box::new(plant.clone()),
+            Box::new(plant.clone()),
+            gains,
+        )
+    }
+
+    /// Regulates until the time is over, returning the highest temperature reached
+    fn regulate(heater: &mut Heater, seconds: f32) -> f32 {
+        let mut max_temperature = heater.temperature();
+        for _ in 0..(seconds as usize) {
+            heater.regulate(1.0).unwrap();
+            max_temperature = max_temperature.max(heater.temperature());
+        }
+        max_temperature
+    }
+
+    #[test]
+    fn default_gains_hold_the_target() {
+        let plant = hotend_plant();
+        let mut heater = attached_heater(&plant, HeaterKind::Hotend.default_gains());
+
+        heater.set_target(200.0).unwrap();
+        let max_temperature = regulate(&mut heater, 400.0);
+
+        assert!(max_temperature < 210.0, "overshot to {max_temperature}");
+        assert!(heater.is_at_target(1.0, false), "{}", heater.temperature());
+        assert!(plant.power() > 0.0 && plant.power() < 1.0);
+    }
+
+    #[test]
+    fn autotuned_gains_hold_the_target() {
+        let plant = hotend_plant();
+        let mut heater = attached_heater(&plant, PidGains::new(1.0, 0.0, 0.0));
+
+        let gains = heater.autotune(200.0, 5).unwrap();
+        assert!(gains.proportional() > 0.0 && gains.integral() > 0.0 && gains.derivative() > 0.0);
+        assert_eq!(plant.power(), 0.0);
+
+        heater.set_gains(gains);
+        heater.set_target(200.0).unwrap();
+        let max_temperature = regulate(&mut heater, 400.0);
+
+        assert!(max_temperature < 215.0, "overshot to {max_temperature}");
+        assert!(heater.is_at_target(1.0, false), "{}", heater.temperature());
+    }
+
+    #[test]
+    fn autotune_fails_without_heating() {
+        let plant = hotend_plant();
+        plant.disconnect_heater();
+        let mut heater = attached_heater(&plant, HeaterKind::Hotend.default_gains());
+
+        assert!(matches!(
+            heater.autotune(200.0, 5),
+            Err(Error::AutotuneFailed(HeaterKind::Hotend))
+        ));
+    }
+
+    #[test]
+    fn heater_not_warming_up_is_stopped() {
+        let plant = hotend_plant();
+        let mut heater = attached_heater(&plant, HeaterKind::Hotend.default_gains());
+        plant.disconnect_heater();
+
+        heater.set_target(200.0).unwrap();
+        let result = heater.regulate(60.0);
+
+        assert!(matches!(
+            result,
+            Err(Error::HeatingFailed(HeaterKind::Hotend))
+        ));
+        //  Found on the first watch period, and the heater was turned off
+        assert!(plant.elapsed() <= 21.0);
+        assert_eq!(plant.power(), 0.0);
+        assert_eq!(heater.target(), 0.0);
+    }
+
+    #[test]
+    fn temperature_falling_at_target_is_a_runaway() {
+        let plant = hotend_plant();
+        let mut heater = attached_heater(&plant, HeaterKind::Hotend.default_gains());
+        heater.set_target(200.0).unwrap();
+        regulate(&mut heater, 300.0);
+
+        plant.disconnect_heater();
+        let start = plant.elapsed();
+        let result = heater.regulate(300.0);
+
+        assert!(matches!(
+            result,
+            Err(Error::ThermalRunaway(HeaterKind::Hotend))
+        ));
+        //  It takes a few seconds to fall below the hysteresis, and then the period runs out
+        let detected_after = plant.elapsed() - start;
+        assert!(detected_after > 40.0 && detected_after < 60.0);
+        assert_eq!(plant.power(), 0.0);
+    }
+}
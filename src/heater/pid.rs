@@ -0,0 +1,148 @@
+/// Output of the controller at full power. Same scale as Marlin, so its gains can be used as they are
+pub(crate) const PID_MAX: f32 = 255.0;
+/// Further than this from the target, in °C, the heater is driven fully on or off instead of regulated.
+/// Keeps the integral from winding up while heating from cold, like Marlin
+const FUNCTIONAL_RANGE: f32 = 10.0;
+/// Seconds the speed of the temperature is averaged over before going into the derivative term. The readings
+/// of a sensor change in small jumps, which the derivative would turn into spikes of the output. Close to Marlin
+const DERIVATIVE_SMOOTH_TIME: f32 = 3.0;
+
+/// Gains of a PID loop, with the time in seconds and the output from 0 to 255. Ex: `M301 P22.2 I1.08 D114`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidGains {
+    proportional: f32,
+    integral: f32,
+    derivative: f32,
+}
+
+/// PID loop regulating the power of a heater
+#[derive(Debug, Clone)]
+pub(crate) struct PidController {
+    gains: PidGains,
+    /// Integral term, kept within the output range so it never winds up
+    integral_term: f32,
+    /// Temperature of the last update. None right after a reset
+    last_temperature: Option<f32>,
+    /// Speed the temperature falls at, smoothed, in °C/s
+    falling_speed: f32,
+}
+
+impl PidGains {
+    pub fn new(proportional: f32, integral: f32, derivative: f32) -> Self {
+        Self {
+            proportional,
+            integral,
+            derivative,
+        }
+    }
+
+    /// Output per °C below the target
+    pub fn proportional(&self) -> f32 {
+        self.proportional
+    }
+
+    /// Output per °C·s below the target
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// Output per °C/s the temperature falls
+    pub fn derivative(&self) -> f32 {
+        self.derivative
+    }
+
+    /// Gains with the values given, keeping the current ones for the rest
+    pub(crate) fn with_values(
+        &self,
+        proportional: Option<f32>,
+        integral: Option<f32>,
+        derivative: Option<f32>,
+    ) -> Self {
+        Self {
+            proportional: proportional.unwrap_or(self.proportional),
+            integral: integral.unwrap_or(self.integral),
+            derivative: derivative.unwrap_or(self.derivative),
+        }
+    }
+}
+
+impl PidController {
+    pub(crate) fn new(gains: PidGains) -> Self {
+        Self {
+            gains,
+            integral_term: 0.0,
+            last_temperature: None,
+            falling_speed: 0.0,
+        }
+    }
+
+    /// Changing the gains starts the loop over, since the integral term was built with the old ones
+    pub(crate) fn set_gains(&mut self, gains: PidGains) {
+        if self.gains != gains {
+            self.gains = gains;
+            self.reset();
+        }
+    }
+
+    /// Forgets the history of the loop, like when the target changes
+    pub(crate) fn reset(&mut self) {
+        self.integral_term = 0.0;
+        self.last_temperature = None;
+        self.falling_speed = 0.0;
+    }
+
+    /// Power the heater is driven at until the next update, from 0 to 1, out of the temperature just read.
+    /// The derivative follows the temperature instead of the error, so changes of target don't kick the output
+    pub(crate) fn update(&mut self, target: f32, temperature: f32, seconds: f32) -> f32 {
+        let error = target - temperature;
+        let last_temperature = self.last_temperature.replace(temperature);
+        if target <= 0.0 || error.abs() > FUNCTIONAL_RANGE {
+            self.integral_term = 0.0;
+            return match target > 0.0 && error > 0.0 {
+                true => 1.0,
+                false => 0.0,
+            };
+        }
+
+        if let (Some(last_temperature), true) = (last_temperature, seconds > 0.0) {
+            let falling_speed = (last_temperature - temperature) / seconds;
+            let weight = seconds / (DERIVATIVE_SMOOTH_TIME + seconds);
+            self.falling_speed += (falling_speed - self.falling_speed) * weight;
+        }
+        self.integral_term =
+            (self.integral_term + self.gains.integral * error * seconds).clamp(0.0, PID_MAX);
+        let output = self.gains.proportional * error
+            + self.integral_term
+            + self.gains.derivative * self.falling_speed;
+
+        (output / PID_MAX).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PID_MAX, PidController, PidGains};
+
+    #[test]
+    fn output_follows_the_error() {
+        let mut controller = PidController::new(PidGains::new(20.0, 1.0, 100.0));
+
+        //  Far from the target the heater is just turned fully on or off
+        assert_eq!(controller.update(200.0, 25.0, 0.1), 1.0);
+        assert_eq!(controller.update(200.0, 230.0, 0.1), 0.0);
+        assert_eq!(controller.update(0.0, 25.0, 0.1), 0.0);
+
+        //  Close to it, falling temperatures push the output up through the derivative
+        controller.reset();
+        let steady = controller.update(200.0, 198.0, 0.1);
+        let falling = controller.update(200.0, 197.9, 0.1);
+        assert!(steady > 0.0 && steady < 1.0);
+        assert!(falling > steady);
+
+        //  The integral alone never asks for more than full power
+        for _ in 0..10_000 {
+            controller.update(200.0, 195.0, 0.1);
+        }
+        assert!(controller.integral_term <= PID_MAX);
+    }
+}
@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::error::PrintResult;
+
+use super::{HeaterOutput, TemperatureSensor};
+
+/// Longest step the model is integrated with, in seconds
+const PLANT_STEP: f32 = 0.05;
+
+/// Heater without hardware, following a first order model: the temperature closes in on the one reached at
+/// the current power, faster the further it is. The sensor reads it with a dead time, like a thermistor
+/// a few millimeters away from the heater cartridge. Clones share the same heater, so one clone can be
+/// attached as sensor, another one as output, and another one kept to inspect or break the heater
+#[derive(Debug, Clone)]
+pub struct ThermalPlant {
+    state: Rc<RefCell<PlantState>>,
+}
+
+#[derive(Debug)]
+struct PlantState {
+    temperature: f32,
+    /// Temperature of the room, reached with the heater off, in °C
+    ambient: f32,
+    /// Degrees above the room the heater settles at on full power
+    max_rise: f32,
+    /// Seconds it takes to cover about 63% of the way to the settling temperature
+    time_constant: f32,
+    /// Seconds the sensor lags behind the heater
+    dead_time: f32,
+    /// Seconds simulated so far
+    elapsed: f32,
+    /// Power the heater was last driven at, from 0 to 1
+    power: f32,
+    /// Disconnected heaters don't warm up, whatever the power they're driven at
+    disconnected: bool,
+    /// Temperatures over the last dead time, along with the time they were reached at
+    history: VecDeque<(f32, f32)>,
+}
+
+impl ThermalPlant {
+    pub fn new(ambient: f32, max_rise: f32, time_constant: f32, dead_time: f32) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(PlantState {
+                temperature: ambient,
+                ambient,
+                max_rise,
+                time_constant,
+                dead_time,
+                elapsed: 0.0,
+                power: 0.0,
+                disconnected: false,
+                history: VecDeque::from([(0.0, ambient)]),
+            })),
+        }
+    }
+
+    /// Actual temperature of the heater, ahead of what the sensor reads, in °C
+    pub fn temperature(&self) -> f32 {
+        self.state.borrow().temperature
+    }
+
+    /// Seconds simulated so far
+    pub fn elapsed(&self) -> f32 {
+        self.state.borrow().elapsed
+    }
+
+    /// Power the heater was last driven at, from 0 to 1
+    pub fn power(&self) -> f32 {
+        self.state.borrow().power
+    }
+
+    /// Breaks the wiring of the heater, which cools down to the room from now on
+    pub fn disconnect_heater(&self) {
+        self.state.borrow_mut().disconnected = true;
+    }
+}
+
+impl TemperatureSensor for ThermalPlant {
+    fn read_temperature(&mut self) -> PrintResult<f32> {
+        let state = self.state.borrow();
+        let read_time = state.elapsed - state.dead_time;
+        let reading = state
+            .history
+            .iter()
+            .rev()
+            .find(|(time, _)| *time <= read_time)
+            .or(state.history.front())
+            .map_or(state.temperature, |(_, temperature)| *temperature);
+
+        Ok(reading)
+    }
+}
+
+impl HeaterOutput for ThermalPlant {
+    fn apply_power(&mut self, power: f32, seconds: f32) -> PrintResult<()> {
+        let mut state = self.state.borrow_mut();
+        state.power = power.clamp(0.0, 1.0);
+        let goal = match state.disconnected {
+            true => state.ambient,
+            false => state.ambient + state.power * state.max_rise,
+        };
+
+        let mut remaining = seconds;
+        while remaining > 0.0 {
+            let step = remaining.min(PLANT_STEP);
+            remaining -= step;
+            state.temperature =
+                goal + (state.temperature - goal) * (-step / state.time_constant).exp();
+            state.elapsed += step;
+
+            let reading = (state.elapsed, state.temperature);
+            state.history.push_back(reading);
+            //  One reading older than the dead time is kept, it's the one the sensor sees
+            let oldest_needed = state.elapsed - state.dead_time;
+            while state
+                .history
+                .get(1)
+                .is_some_and(|(time, _)| *time <= oldest_needed)
+            {
+                state.history.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+}
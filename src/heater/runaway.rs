@@ -0,0 +1,117 @@
+use crate::error::{Error, PrintResult};
+
+use super::HeaterKind;
+
+/// Limits a heater is watched against, the same defaults Marlin uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ProtectionLimits {
+    /// Seconds the temperature may stay too far below the target once reached
+    period: f32,
+    /// Degrees below the target still taken as at target
+    hysteresis: f32,
+    /// Seconds the temperature has to rise `watch_increase` degrees in while heating
+    watch_period: f32,
+    watch_increase: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunawayState {
+    /// Heater off
+    Idle,
+    /// Heating towards the target. The temperature must have risen past the reference by the deadline
+    Heating { deadline: f32, reference: f32 },
+    /// Target reached. Time the temperature fell too far below it, if it did
+    AtTarget { below_since: Option<f32> },
+}
+
+/// Watches the temperature of a heater for thermal runaways: heaters that don't warm up when driven,
+/// like a heater cartridge come loose, and temperatures falling away from a target already reached,
+/// like a thermistor out of its block
+#[derive(Debug, Clone)]
+pub(crate) struct RunawayMonitor {
+    heater: HeaterKind,
+    limits: ProtectionLimits,
+    state: RunawayState,
+    /// Seconds watched so far
+    time: f32,
+}
+
+impl RunawayMonitor {
+    pub(crate) fn new(heater: HeaterKind) -> Self {
+        let limits = match heater {
+            HeaterKind::Hotend => ProtectionLimits {
+                period: 40.0,
+                hysteresis: 4.0,
+                watch_period: 20.0,
+                watch_increase: 2.0,
+            },
+            HeaterKind::Bed => ProtectionLimits {
+                period: 20.0,
+                hysteresis: 2.0,
+                watch_period: 60.0,
+                watch_increase: 2.0,
+            },
+        };
+
+        Self {
+            heater,
+            limits,
+            state: RunawayState::Idle,
+            time: 0.0,
+        }
+    }
+
+    /// Starts watching a new target, out of the temperature when it was set
+    pub(crate) fn start(&mut self, target: f32, temperature: f32) {
+        self.state = if target <= 0.0 {
+            RunawayState::Idle
+        } else if temperature < target - self.limits.hysteresis {
+            RunawayState::Heating {
+                deadline: self.time + self.limits.watch_period,
+                reference: temperature,
+            }
+        } else {
+            RunawayState::AtTarget { below_since: None }
+        };
+    }
+
+    /// Checks the temperature read some seconds after the last check
+    pub(crate) fn check(&mut self, target: f32, temperature: f32, seconds: f32) -> PrintResult<()> {
+        self.time += seconds;
+        let at_target = temperature >= target - self.limits.hysteresis;
+
+        self.state = match self.state {
+            RunawayState::Idle => RunawayState::Idle,
+            RunawayState::Heating { .. } if at_target => {
+                RunawayState::AtTarget { below_since: None }
+            }
+            RunawayState::Heating {
+                deadline,
+                reference,
+            } if self.time >= deadline => {
+                if temperature < reference + self.limits.watch_increase {
+                    return Err(Error::HeatingFailed(self.heater));
+                }
+                RunawayState::Heating {
+                    deadline: self.time + self.limits.watch_period,
+                    reference: temperature,
+                }
+            }
+            heating @ RunawayState::Heating { .. } => heating,
+            RunawayState::AtTarget { .. } if at_target => {
+                RunawayState::AtTarget { below_since: None }
+            }
+            RunawayState::AtTarget { below_since } => {
+                let below_since = below_since.unwrap_or(self.time);
+                if self.time - below_since >= self.limits.period {
+                    return Err(Error::ThermalRunaway(self.heater));
+                }
+                RunawayState::AtTarget {
+                    below_since: Some(below_since),
+                }
+            }
+        };
+
+        Ok(())
+    }
+}
@@ -1,11 +1,45 @@
+pub(crate) mod analysis;
+pub(crate) mod arc_fit;
+pub(crate) mod calibration;
 pub mod error;
+pub(crate) mod executor;
+pub(crate) mod export;
+pub(crate) mod heater;
+pub(crate) mod motion;
+pub(crate) mod optimize;
 pub(crate) mod parser;
+pub(crate) mod recovery;
+pub(crate) mod simulator;
+pub(crate) mod storage;
 pub(crate) mod system;
+pub(crate) mod transform;
 pub(crate) mod types;
 
 //  Re exports
+pub use analysis::{
+    Bounds, JobDiff, JobMetadata, JobSchedule, JobStats, LayerDiff, LayerStats, ScheduleChange,
+    ScheduledOutput, SettingDiff,
+};
+pub use arc_fit::{ArcFitReport, fit_arcs};
+pub use calibration::{
+    AxisEnd, BedCalibration, BedProbe, Endstops, HomingConfig, SimulatedAxes, calibrate_bed,
+    home_axis, measure_travel, probe_mesh,
+};
+pub use executor::{Executor, ExecutorEvent, ExtendedCommandHandler, JobState, QueuedCommand};
+pub use export::{MoveTableFormat, SvgLayer, export_moves, export_svg_layers, render_svg_layers};
+pub use heater::{HeaterKind, HeaterOutput, PidGains, TemperatureSensor, ThermalPlant};
+pub use motion::{
+    ActuatorPosition, Axis, AxisSteps, CartesianKinematics, CoreXYKinematics, CoreXZKinematics,
+    ExtruderSample, InputShaper, Kinematics, LinearDeltaKinematics, MachineKinematics,
+    MotionLimits, MotionPhase, PlannedSegment, Planner, PressureAdvance, ShaperType, ShapingSample,
+    StepConverter, StepperDriver, StepsPerUnit,
+};
+pub use optimize::{OptimizationReport, optimize_job};
 pub use parser::gcode;
-
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+pub use recovery::{ModalState, RecoveryJournal, RecoveryPoint, ResumePoint, ResumedJob};
+#[cfg(target_os = "linux")]
+pub use simulator::Pseudoterminal;
+pub use simulator::{SimulatorMessage, VirtualPrinter, simulate_extruder, simulate_shaping};
+pub use storage::{FileStorage, MemoryEeprom, Storage};
+pub use system::{BedMesh, Location, ResolvedMove, SETTINGS_VERSION, SystemConfig};
+pub use transform::{Transform, transform_job};
@@ -0,0 +1,741 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::ExitCode;
+
+use printy::gcode::{
+    Dialect, GcodeReader, GcodeWriter, LineEnding, ObjectFilter, WriterOptions, validate_file,
+    validate_file_with_config, validate_file_with_dialect,
+};
+use printy::{
+    Axis, Bounds, InputShaper, JobDiff, JobMetadata, JobStats, PressureAdvance, ScheduleChange,
+    ScheduledOutput, ShaperType, SystemConfig, Transform, fit_arcs, optimize_job,
+    simulate_extruder, simulate_shaping, transform_job,
+};
+
+const USAGE: &str = "\
+Usage: printy <command> [options]
+
+Commands:
+  lint <file> [options]                 Checks every line, and every move against the profile if given
+  stats <file> [--profile <profile>]    Prints the estimated time, filament, layers and bounds
+  convert <input> <output> [options]    Rewrites a file in canonical form, optionally transformed
+  optimize <input> <output> [options]   Rewrites a file into fewer lines with the same motion
+  arcs <input> <output> [options]       Rewrites the moves of a file lying on circles as G2 and G3 arcs
+  info <file>                           Prints the slicer metadata
+  diff <before> <after> [options]       Prints what a file prints differently than another, layer by layer
+  extruder <file> [options]             Prints the extruder motion over time as CSV, pressure advance included
+  shaping <file> [options]              Prints the acceleration of X and Y over time as CSV, before and after shaping
+
+Lint options:
+  --profile <profile>       Printer profile to check the moves and the commands against
+  --dialect <dialect>       Firmware to check the commands against, overriding the one of the profile.
+                            One of generic, marlin, prusa, klipper or reprapfirmware
+
+Convert options:
+  --precision <decimals>    Max decimals of every value. Defaults to 5
+  --no-comments             Drops every comment
+  --checksums               Frames every line with a line number and a checksum
+  --crlf                    Ends lines with CR LF
+  --translate <x>,<y>       Moves the job on the bed
+  --rotate <degrees>        Rotates the job counterclockwise around the origin
+  --scale <x>[,<y>]         Scales the job from the origin
+  --mirror-x <x>            Mirrors the job across the vertical line going through x
+  --mirror-y <y>            Mirrors the job across the horizontal line going through y
+  --z-offset <z>            Moves the job along Z
+  --exclude <object>        Skips the extrusions of an object, by name or id. Can be repeated
+
+Optimize options:
+  --tolerance <mm>          Furthest a corner can be from the move merging it away. Defaults to 0.01
+  --precision <decimals>    Max decimals of every value. Defaults to 5
+
+Arcs options:
+  --tolerance <mm>          Furthest a point of the moves can be from the arc replacing them. Defaults to 0.05
+  --precision <decimals>    Max decimals of every value. Defaults to 5
+
+Diff options:
+  --profile <profile>       Printer profile to replay both files on
+
+Extruder options:
+  --profile <profile>       Printer profile to simulate
+  --factor <k>              Pressure advance factor, overriding the one of the profile. M900 in the file still wins
+  --interval <seconds>      Time between samples. Defaults to 0.01
+
+Shaping options:
+  --profile <profile>       Printer profile to simulate
+  --shaper <type>           zv, mzv, ei or 2hump_ei, used by --frequency. Defaults to mzv
+  --frequency <x>[,<y>]     Resonance frequencies of X and Y in Hz, overriding the shapers of the profile
+  --damping <ratio>         Damping ratio used by --frequency. Defaults to 0.1
+  --interval <seconds>      Time between samples. Defaults to 0.001
+
+Exit codes: 0 if everything went fine, 1 if lint found problems or diff found differences, 2 if the command couldn't run";
+
+/// Lint found problems in the file, or diff found differences between the files
+const EXIT_PROBLEMS: u8 = 1;
+/// Wrong arguments, or files that couldn't be read or written
+const EXIT_FAILURE: u8 = 2;
+/// Tolerance of the optimizer when --tolerance isn't given, in millimeters
+const DEFAULT_OPTIMIZE_TOLERANCE: f32 = 0.01;
+/// Tolerance of the arc fitting when --tolerance isn't given, in millimeters. Same as ArcWelder
+const DEFAULT_ARC_TOLERANCE: f32 = 0.05;
+
+fn main() -> ExitCode {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, arguments)) = arguments.split_first() else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(EXIT_FAILURE);
+    };
+
+    let result = match command.as_str() {
+        "lint" => lint(arguments),
+        "stats" => stats(arguments),
+        "convert" => convert(arguments),
+        "optimize" => optimize(arguments),
+        "arcs" => arcs(arguments),
+        "info" => info(arguments),
+        "diff" => diff(arguments),
+        "extruder" => extruder(arguments),
+        "shaping" => shaping(arguments),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        command => Err(format!("unknown command `{command}`")),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(message) => {
+            eprintln!("printy: {message}\nRun `printy help` for usage");
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+/// Arguments of a command, split into positional ones and `--name value` options
+struct Arguments<'a> {
+    positional: Vec<&'a str>,
+    options: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> Arguments<'a> {
+    /// Options listed in `flags` don't take a value
+    fn parse(arguments: &'a [String], flags: &[&str]) -> Result<Self, String> {
+        let mut parsed = Self {
+            positional: vec![],
+            options: vec![],
+        };
+
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            match argument.strip_prefix("--") {
+                Some(name) if flags.contains(&name) => parsed.options.push((name, None)),
+                Some(name) => match arguments.next() {
+                    Some(value) => parsed.options.push((name, Some(value))),
+                    None => return Err(format!("missing value of option `--{name}`")),
+                },
+                None => parsed.positional.push(argument),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Positional arguments must match the expected amount exactly
+    fn positional<const N: usize>(&self) -> Result<[&'a str; N], String> {
+        self.positional
+            .clone()
+            .try_into()
+            .map_err(|_| format!("expected {N} file argument(s)"))
+    }
+
+    /// Fails on any option not listed in `known`
+    fn check_options(&self, known: &[&str]) -> Result<(), String> {
+        match self.options.iter().find(|(name, _)| !known.contains(name)) {
+            Some((name, _)) => Err(format!("unknown option `--{name}`")),
+            None => Ok(()),
+        }
+    }
+
+    fn value(&self, name: &str) -> Option<&'a str> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == name)
+            .and_then(|(_, value)| *value)
+    }
+
+    /// Every value of an option that can be repeated, in order
+    fn values(&self, name: &str) -> Vec<&'a str> {
+        self.options
+            .iter()
+            .filter(|(option, _)| *option == name)
+            .filter_map(|(_, value)| *value)
+            .collect()
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| *option == name)
+    }
+}
+
+fn lint(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["profile", "dialect"])?;
+    let [path] = arguments.positional()?;
+
+    let dialect = match arguments.value("dialect") {
+        Some(name) => {
+            Some(Dialect::from_name(name).ok_or_else(|| format!("unknown dialect `{name}`"))?)
+        }
+        None => None,
+    };
+    let file = open(path)?;
+    let problems = match (arguments.value("profile"), dialect) {
+        (Some(profile), dialect) => {
+            let mut config = load_profile(profile)?;
+            if let Some(dialect) = dialect {
+                config.set_dialect(dialect);
+            }
+            validate_file_with_config(&file, &config)
+        }
+        (None, Some(dialect)) => validate_file_with_dialect(&file, dialect),
+        (None, None) => validate_file(&file),
+    }
+    .map_err(|error| format!("{path}: {error}"))?;
+
+    for problem in &problems {
+        println!("{path}: {problem}");
+    }
+
+    match problems.len() {
+        0 => {
+            println!("{path}: ok");
+            Ok(ExitCode::SUCCESS)
+        }
+        count => {
+            println!("{path}: {count} problem(s) found");
+            Ok(ExitCode::from(EXIT_PROBLEMS))
+        }
+    }
+}
+
+fn stats(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["profile"])?;
+    let [path] = arguments.positional()?;
+
+    let config = match arguments.value("profile") {
+        Some(profile) => load_profile(profile)?,
+        None => SystemConfig::default(),
+    };
+    let stats = JobStats::from_lines(GcodeReader::from_file(open(path)?), &config)
+        .map_err(|error| format!("{path}: {error}"))?;
+
+    println!("Estimated time: {}", format_duration(stats.duration()));
+    println!("Filament: {:.2} mm", stats.filament());
+    println!("Layers: {}", stats.layers().len());
+    println!("Moves: {}", stats.moves());
+    println!("Bounds: {}", format_bounds(stats.bounds()));
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn convert(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &["no-comments", "checksums", "crlf"])?;
+    arguments.check_options(&[
+        "precision",
+        "no-comments",
+        "checksums",
+        "crlf",
+        "translate",
+        "rotate",
+        "scale",
+        "mirror-x",
+        "mirror-y",
+        "z-offset",
+        "exclude",
+    ])?;
+    let [input, output] = arguments.positional()?;
+
+    let precision = match arguments.value("precision") {
+        Some(precision) => precision
+            .parse()
+            .map_err(|_| format!("invalid precision `{precision}`"))?,
+        None => WriterOptions::default().precision(),
+    };
+    let line_ending = match arguments.flag("crlf") {
+        true => LineEnding::CrLf,
+        false => LineEnding::Lf,
+    };
+    let options = WriterOptions::new(
+        precision,
+        !arguments.flag("no-comments"),
+        arguments.flag("checksums"),
+        line_ending,
+    );
+
+    //  Transforms are applied in a fixed order, no matter the order of the options
+    let mut transform = Transform::identity();
+    if let Some(x) = arguments.value("mirror-x") {
+        transform = transform.mirrored_x(number(x)?);
+    }
+    if let Some(y) = arguments.value("mirror-y") {
+        transform = transform.mirrored_y(number(y)?);
+    }
+    if let Some(scale) = arguments.value("scale") {
+        let factors = numbers(scale)?;
+        match factors[..] {
+            [factor] => transform = transform.scaled(factor, factor),
+            [x, y] => transform = transform.scaled(x, y),
+            _ => return Err(format!("invalid scale `{scale}`")),
+        }
+    }
+    if let Some(degrees) = arguments.value("rotate") {
+        transform = transform.rotated(number(degrees)?);
+    }
+    if let Some(offset) = arguments.value("translate") {
+        match numbers(offset)?[..] {
+            [x, y] => transform = transform.translated(x, y),
+            _ => return Err(format!("invalid translation `{offset}`")),
+        }
+    }
+    if let Some(z) = arguments.value("z-offset") {
+        transform = transform.with_z_offset(number(z)?);
+    }
+
+    let mut reader = ObjectFilter::new(GcodeReader::from_file(open(input)?));
+    for object in arguments.values("exclude") {
+        reader.exclude(object);
+    }
+    let file = File::create(output).map_err(|error| format!("{output}: {error}"))?;
+    let mut writer = GcodeWriter::new(BufWriter::new(file), options);
+    transform_job(reader, &mut writer, &transform)
+        .and_then(|_| writer.into_inner().map(|_| ()))
+        .map_err(|error| format!("{input}: {error}"))?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn optimize(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["tolerance", "precision"])?;
+    let [input, output] = arguments.positional()?;
+
+    let tolerance = match arguments.value("tolerance") {
+        Some(tolerance) => match number(tolerance)? {
+            tolerance if tolerance < 0.0 => return Err(format!("invalid tolerance `{tolerance}`")),
+            tolerance => tolerance,
+        },
+        None => DEFAULT_OPTIMIZE_TOLERANCE,
+    };
+    let precision = match arguments.value("precision") {
+        Some(precision) => precision
+            .parse()
+            .map_err(|_| format!("invalid precision `{precision}`"))?,
+        None => WriterOptions::default().precision(),
+    };
+
+    let file = File::create(output).map_err(|error| format!("{output}: {error}"))?;
+    let options = WriterOptions::new(precision, true, false, LineEnding::Lf);
+    let mut writer = GcodeWriter::new(BufWriter::new(file), options);
+    let report = optimize_job(GcodeReader::from_file(open(input)?), &mut writer, tolerance)
+        .and_then(|report| writer.into_inner().map(|_| report))
+        .map_err(|error| format!("{input}: {error}"))?;
+
+    println!(
+        "Lines: {} read, {} written",
+        report.lines_read(),
+        report.lines_written()
+    );
+    println!("Commands removed: {}", report.removed_commands());
+    println!("Moves merged: {}", report.merged_moves());
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn arcs(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["tolerance", "precision"])?;
+    let [input, output] = arguments.positional()?;
+
+    let tolerance = match arguments.value("tolerance") {
+        Some(tolerance) => match number(tolerance)? {
+            tolerance if tolerance < 0.0 => return Err(format!("invalid tolerance `{tolerance}`")),
+            tolerance => tolerance,
+        },
+        None => DEFAULT_ARC_TOLERANCE,
+    };
+    let precision = match arguments.value("precision") {
+        Some(precision) => precision
+            .parse()
+            .map_err(|_| format!("invalid precision `{precision}`"))?,
+        None => WriterOptions::default().precision(),
+    };
+
+    let file = File::create(output).map_err(|error| format!("{output}: {error}"))?;
+    let options = WriterOptions::new(precision, true, false, LineEnding::Lf);
+    let mut writer = GcodeWriter::new(BufWriter::new(file), options);
+    let report = fit_arcs(GcodeReader::from_file(open(input)?), &mut writer, tolerance)
+        .and_then(|report| writer.into_inner().map(|_| report))
+        .map_err(|error| format!("{input}: {error}"))?;
+
+    println!(
+        "Lines: {} read, {} written ({:.1}%)",
+        report.lines_read(),
+        report.lines_written(),
+        report.compression_ratio() * 100.0
+    );
+    println!(
+        "Arcs: {}, replacing {} moves",
+        report.arcs_written(),
+        report.moves_replaced()
+    );
+    println!("Max deviation: {:.4} mm", report.max_deviation());
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn info(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&[])?;
+    let [path] = arguments.positional()?;
+
+    let metadata = JobMetadata::from_lines(GcodeReader::from_file(open(path)?))
+        .map_err(|error| format!("{path}: {error}"))?;
+
+    //  Settings can be many, so a closed output, like `head` being done, fails instead of panicking
+    let mut output = std::io::stdout().lock();
+    let generator = metadata.generator().unwrap_or("unknown");
+    writeln!(output, "Generator: {generator}").map_err(output_error)?;
+    for (name, value) in metadata.settings() {
+        writeln!(output, "{name} = {value}").map_err(output_error)?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["profile"])?;
+    let [before, after] = arguments.positional()?;
+
+    let config = match arguments.value("profile") {
+        Some(profile) => load_profile(profile)?,
+        None => SystemConfig::default(),
+    };
+    //  Read up front, so errors tell which file they come from
+    let read = |path: &str| {
+        GcodeReader::from_file(open(path)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| format!("{path}: {error}"))
+    };
+    let (before_lines, after_lines) = (read(before)?, read(after)?);
+    let diff = JobDiff::from_lines(
+        before_lines.into_iter().map(Ok),
+        after_lines.into_iter().map(Ok),
+        &config,
+    )
+    .map_err(|error| format!("{before} -> {after}: {error}"))?;
+
+    if diff.is_empty() {
+        println!("No differences");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let (before_stats, after_stats) = (diff.before_stats(), diff.after_stats());
+    println!(
+        "Estimated time: {} -> {}",
+        format_duration(before_stats.duration()),
+        format_duration(after_stats.duration())
+    );
+    println!(
+        "Filament: {:.2} mm -> {:.2} mm",
+        before_stats.filament(),
+        after_stats.filament()
+    );
+    println!(
+        "Layers: {} -> {}",
+        before_stats.layers().len(),
+        after_stats.layers().len()
+    );
+    println!(
+        "Bounds: {} -> {}",
+        format_bounds(before_stats.bounds()),
+        format_bounds(after_stats.bounds())
+    );
+
+    for layer in diff.layers() {
+        match (layer.before(), layer.after()) {
+            (Some(before), Some(after)) => println!(
+                "Layer {} at Z {:.2} -> {:.2}: time {:.1} s -> {:.1} s, filament {:.2} mm -> {:.2} mm, \
+                 bounds {} -> {}",
+                layer.index(),
+                before.z(),
+                after.z(),
+                before.duration(),
+                after.duration(),
+                before.extrusion(),
+                after.extrusion(),
+                format_bounds(before.bounds()),
+                format_bounds(after.bounds())
+            ),
+            (Some(before), None) => {
+                println!(
+                    "Layer {} at Z {:.2}: only before",
+                    layer.index(),
+                    before.z()
+                )
+            }
+            (None, Some(after)) => {
+                println!("Layer {} at Z {:.2}: only after", layer.index(), after.z())
+            }
+            (None, None) => {}
+        }
+    }
+
+    for (sign, changes) in [("-", diff.removed_changes()), ("+", diff.added_changes())] {
+        for change in changes {
+            println!("{sign} {}", format_change(change));
+        }
+    }
+
+    for setting in diff.settings() {
+        println!(
+            "Setting {}: {} -> {}",
+            setting.name(),
+            setting.before().unwrap_or("unset"),
+            setting.after().unwrap_or("unset")
+        );
+    }
+
+    Ok(ExitCode::from(EXIT_PROBLEMS))
+}
+
+fn extruder(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["profile", "factor", "interval"])?;
+    let [path] = arguments.positional()?;
+
+    let mut config = match arguments.value("profile") {
+        Some(profile) => load_profile(profile)?,
+        None => SystemConfig::default(),
+    };
+    if let Some(factor) = arguments.value("factor") {
+        let factor = number(factor)?;
+        if factor < 0.0 {
+            return Err(format!("invalid factor `{factor}`"));
+        }
+        for extruder in 0..config.extruder_count() {
+            let smooth_time = config.pressure_advance(extruder).smooth_time();
+            config.set_pressure_advance(extruder, PressureAdvance::new(factor, smooth_time));
+        }
+    }
+    let interval = match arguments.value("interval") {
+        Some(interval) => match number(interval)? {
+            seconds if seconds > 0.0 => seconds,
+            _ => return Err(format!("invalid interval `{interval}`")),
+        },
+        None => 0.01,
+    };
+
+    let samples = simulate_extruder(GcodeReader::from_file(open(path)?), &config, interval)
+        .map_err(|error| format!("{path}: {error}"))?;
+
+    println!("time,nominal_position,advance,nominal_speed,speed");
+    for sample in samples {
+        println!(
+            "{:.4},{:.5},{:.5},{:.5},{:.5}",
+            sample.time(),
+            sample.nominal_position(),
+            sample.advance(),
+            sample.nominal_speed(),
+            sample.speed()
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn shaping(arguments: &[String]) -> Result<ExitCode, String> {
+    let arguments = Arguments::parse(arguments, &[])?;
+    arguments.check_options(&["profile", "shaper", "frequency", "damping", "interval"])?;
+    let [path] = arguments.positional()?;
+
+    let mut config = match arguments.value("profile") {
+        Some(profile) => load_profile(profile)?,
+        None => SystemConfig::default(),
+    };
+    let shaper_type = match arguments.value("shaper") {
+        Some(name) => {
+            ShaperType::from_name(name).ok_or_else(|| format!("unknown shaper `{name}`"))?
+        }
+        None => ShaperType::default(),
+    };
+    let damping = match arguments.value("damping") {
+        Some(damping) => match number(damping)? {
+            ratio if (0.0..1.0).contains(&ratio) => ratio,
+            _ => return Err(format!("invalid damping `{damping}`")),
+        },
+        None => 0.1,
+    };
+    if let Some(frequencies) = arguments.value("frequency") {
+        let (x, y) = frequencies
+            .split_once(',')
+            .unwrap_or((frequencies, frequencies));
+        for (axis, frequency) in [(Axis::X, x), (Axis::Y, y)] {
+            let hertz = number(frequency)?;
+            if hertz < 0.0 {
+                return Err(format!("invalid frequency `{frequency}`"));
+            }
+            let shaper = (hertz > 0.0).then(|| InputShaper::new(shaper_type, hertz, damping));
+            config.set_input_shaper(axis, shaper);
+        }
+    }
+    let interval = match arguments.value("interval") {
+        Some(interval) => match number(interval)? {
+            seconds if seconds > 0.0 => seconds,
+            _ => return Err(format!("invalid interval `{interval}`")),
+        },
+        None => 0.001,
+    };
+
+    let samples = simulate_shaping(GcodeReader::from_file(open(path)?), &config, interval)
+        .map_err(|error| format!("{path}: {error}"))?;
+
+    println!("time,x_acceleration,x_shaped,y_acceleration,y_shaped");
+    for sample in samples {
+        println!(
+            "{:.4},{:.2},{:.2},{:.2},{:.2}",
+            sample.time(),
+            sample.acceleration(Axis::X),
+            sample.shaped_acceleration(Axis::X),
+            sample.acceleration(Axis::Y),
+            sample.shaped_acceleration(Axis::Y)
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn open(path: &str) -> Result<File, String> {
+    File::open(path).map_err(|error| format!("{path}: {error}"))
+}
+
+fn output_error(error: std::io::Error) -> String {
+    format!("standard output: {error}")
+}
+
+fn load_profile(path: &str) -> Result<SystemConfig, String> {
+    SystemConfig::load_profile(path).map_err(|error| format!("{path}: {error}"))
+}
+
+fn number(value: &str) -> Result<f32, String> {
+    match value.trim().parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => Err(format!("invalid number `{value}`")),
+    }
+}
+
+/// Comma separated numbers. Ex: `10,-5.5`
+fn numbers(values: &str) -> Result<Vec<f32>, String> {
+    values.split(',').map(number).collect()
+}
+
+/// Ex: `X 10.00 to 190.00, Y 5.00 to 95.00, Z 0.20 to 20.00` or `nothing extruded`
+fn format_bounds(bounds: Option<Bounds>) -> String {
+    match bounds {
+        Some(bounds) => format!(
+            "X {:.2} to {:.2}, Y {:.2} to {:.2}, Z {:.2} to {:.2}",
+            bounds.min().x(),
+            bounds.max().x(),
+            bounds.min().y(),
+            bounds.max().y(),
+            bounds.min().z(),
+            bounds.max().z()
+        ),
+        None => "nothing extruded".to_string(),
+    }
+}
+
+/// Ex: `layer 3: fan at 100%` or `layer 0: hotend 0 at 215 °C`
+fn format_change(change: &ScheduleChange) -> String {
+    match change.output() {
+        ScheduledOutput::Fan => format!(
+            "layer {}: fan at {}%",
+            change.layer(),
+            (change.value() as f32 * 100.0 / 255.0).round()
+        ),
+        output => format!(
+            "layer {}: {output} at {} °C",
+            change.layer(),
+            change.value()
+        ),
+    }
+}
+
+/// Ex: `1h 02m 03s`
+fn format_duration(seconds: f32) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}h {:02}m {:02}s",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::ExitCode;
+
+    use super::{Arguments, EXIT_PROBLEMS, lint};
+
+    fn strings(arguments: &[&str]) -> Vec<String> {
+        arguments
+            .iter()
+            .map(|argument| argument.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn arguments_split_into_positional_and_options() {
+        let arguments = strings(&[
+            "in.gcode",
+            "--exclude",
+            "cube",
+            "--no-comments",
+            "out.gcode",
+            "--exclude",
+            "cone",
+        ]);
+        let parsed = Arguments::parse(&arguments, &["no-comments"]).unwrap();
+
+        assert_eq!(parsed.positional::<2>().unwrap(), ["in.gcode", "out.gcode"]);
+        assert!(parsed.flag("no-comments"));
+        assert_eq!(parsed.value("exclude"), Some("cube"));
+        assert_eq!(parsed.values("exclude"), ["cube", "cone"]);
+        assert!(parsed.check_options(&["exclude", "no-comments"]).is_ok());
+        assert!(parsed.check_options(&["exclude"]).is_err());
+        assert!(parsed.positional::<1>().is_err());
+
+        //  Options taking a value can't end the arguments
+        assert!(Arguments::parse(&strings(&["in.gcode", "--profile"]), &[]).is_err());
+    }
+
+    #[test]
+    fn lint_exit_status() {
+        let path = std::env::temp_dir().join(format!("printy_lint_{}.gcode", std::process::id()));
+        let arguments = strings(&[path.to_str().unwrap()]);
+
+        std::fs::write(&path, "G28\nG1 X10 Y10 F1200\n").unwrap();
+        assert_eq!(lint(&arguments), Ok(ExitCode::SUCCESS));
+
+        std::fs::write(&path, "G28\nG1 X10 Yabc\n").unwrap();
+        assert_eq!(lint(&arguments), Ok(ExitCode::from(EXIT_PROBLEMS)));
+
+        //  Problems running the command are errors, which main turns into EXIT_FAILURE
+        assert!(lint(&strings(&[path.to_str().unwrap(), "--dialect", "unknown"])).is_err());
+
+        std::fs::remove_file(path).unwrap();
+        assert!(lint(&arguments).is_err());
+    }
+}
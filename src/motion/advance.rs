@@ -0,0 +1,281 @@
+use super::planner::PlannedSegment;
+use super::timeline::{MotionTimeline, TimelinePhase};
+
+/// Window the extrusion speed is averaged over when the profile doesn't say otherwise, in seconds. Same as Klipper
+const DEFAULT_SMOOTH_TIME: f32 = 0.04;
+
+/// Pressure advance of an extruder. While the extrusion speeds up, filament is pushed ahead of the
+/// nominal amount to build up the pressure in the nozzle, and it's pulled back as the extrusion slows down.
+/// The filament ahead is the factor times the extrusion speed, averaged over the smoothing window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PressureAdvance {
+    /// M900 K. Millimeters of filament per mm/s of extrusion speed. Zero turns the advance off
+    factor: f32,
+    /// Seconds. Zero follows the speed changes instantly
+    smooth_time: f32,
+}
+
+/// Extruder motion at a point in time of a job, as the extruder stepper sees it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtruderSample {
+    /// Seconds since the first move
+    time: f32,
+    /// Filament pushed by the job so far, retractions discounted, in millimeters
+    nominal_position: f32,
+    /// Filament pushed ahead of the nominal position by the pressure advance, in millimeters
+    advance: f32,
+    /// Extrusion speed requested by the job, in mm/s of filament
+    nominal_speed: f32,
+    /// Speed the extruder actually moves at, pressure advance included, in mm/s of filament
+    speed: f32,
+}
+
+impl Default for PressureAdvance {
+    fn default() -> Self {
+        Self {
+            factor: 0.0,
+            smooth_time: DEFAULT_SMOOTH_TIME,
+        }
+    }
+}
+
+impl PressureAdvance {
+    pub fn new(factor: f32, smooth_time: f32) -> Self {
+        Self {
+            factor,
+            smooth_time,
+        }
+    }
+
+    /// Millimeters of filament per mm/s of extrusion speed
+    pub fn factor(&self) -> f32 {
+        self.factor
+    }
+
+    /// Seconds
+    pub fn smooth_time(&self) -> f32 {
+        self.smooth_time
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.factor > 0.0
+    }
+
+    pub(crate) fn set_factor(&mut self, factor: f32) {
+        self.factor = factor;
+    }
+
+    pub(crate) fn set_smooth_time(&mut self, smooth_time: f32) {
+        self.smooth_time = smooth_time;
+    }
+}
+
+impl ExtruderSample {
+    /// Seconds since the first move
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// Filament pushed by the job so far, in millimeters
+    pub fn nominal_position(&self) -> f32 {
+        self.nominal_position
+    }
+
+    /// Filament pushed ahead of the nominal position, in millimeters
+    pub fn advance(&self) -> f32 {
+        self.advance
+    }
+
+    /// Position of the extruder, pressure advance included, in millimeters
+    pub fn position(&self) -> f32 {
+        self.nominal_position + self.advance
+    }
+
+    /// mm/s of filament
+    pub fn nominal_speed(&self) -> f32 {
+        self.nominal_speed
+    }
+
+    /// mm/s of filament, pressure advance included
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+}
+
+impl TimelinePhase {
+    pub(super) fn integral_at(&self, elapsed: f32) -> f32 {
+        self.start_integral + self.factor * self.ratio * self.distance_at(elapsed)
+    }
+
+    /// Advance before smoothing, proportional to the extrusion speed
+    fn raw_advance_at(&self, elapsed: f32) -> f32 {
+        self.factor * self.ratio * self.speed_at(elapsed)
+    }
+}
+
+impl MotionTimeline {
+    fn integral_at(&self, time: f32) -> f32 {
+        match self.phase_at(time) {
+            Some((phase, elapsed)) => phase.integral_at(elapsed),
+            None => self
+                .phases
+                .front()
+                .map_or(0.0, |phase| phase.start_integral),
+        }
+    }
+
+    fn raw_advance_at(&self, time: f32) -> f32 {
+        self.moving_phase_at(time)
+            .map_or(0.0, |(phase, elapsed)| phase.raw_advance_at(elapsed))
+    }
+
+    /// Filament pushed ahead at a time, in millimeters. The advance follows the extrusion speed
+    /// averaged over a window centered on the time
+    pub(crate) fn advance_at(&self, time: f32, smooth_time: f32) -> f32 {
+        if smooth_time <= 0.0 {
+            return self.raw_advance_at(time);
+        }
+
+        let half_window = smooth_time / 2.0;
+        (self.integral_at(time + half_window) - self.integral_at(time - half_window)) / smooth_time
+    }
+
+    /// Speed the advance changes at, in mm/s of filament. Added to the nominal speed, it gives the speed of the extruder
+    pub(crate) fn advance_speed_at(&self, time: f32, smooth_time: f32) -> f32 {
+        if smooth_time <= 0.0 {
+            return self.moving_phase_at(time).map_or(0.0, |(phase, _)| {
+                phase.factor * phase.ratio * phase.acceleration()
+            });
+        }
+
+        let half_window = smooth_time / 2.0;
+        (self.raw_advance_at(time + half_window) - self.raw_advance_at(time - half_window))
+            / smooth_time
+    }
+}
+
+/// Samples the extrusion of planned segments every `interval` seconds, the end included. Each segment
+/// comes with the pressure advance it's planned with
+pub(crate) fn sample_extrusion(
+    segments: impl IntoIterator<Item = (PlannedSegment, PressureAdvance)>,
+    interval: f32,
+) -> Vec<ExtruderSample> {
+    let mut timeline = MotionTimeline::default();
+    //  Times where the smoothing window changes, with the window used from each one on
+    let mut windows = vec![];
+    for (segment, pressure_advance) in segments {
+        let start_time = timeline.push(&segment, pressure_advance.factor());
+        if windows
+            .last()
+            .is_none_or(|(_, smooth_time)| *smooth_time != pressure_advance.smooth_time())
+        {
+            windows.push((start_time, pressure_advance.smooth_time()));
+        }
+    }
+
+    //  The advance keeps settling for half a window after the last move
+    let last_window = windows.last().map_or(0.0, |(_, smooth_time)| *smooth_time);
+    let end_time = timeline.end_time() + last_window / 2.0;
+    let samples = (end_time / interval).ceil() as usize;
+
+    (0..=samples)
+        .map(|sample| {
+            let time = (sample as f32 * interval).min(end_time);
+            let smooth_time = windows
+                .iter()
+                .rev()
+                .find(|(start_time, _)| *start_time <= time)
+                .map_or(0.0, |(_, smooth_time)| *smooth_time);
+            let nominal_speed = timeline.nominal_speed_at(time);
+
+            ExtruderSample {
+                time,
+                nominal_position: timeline.position_at(time),
+                advance: timeline.advance_at(time, smooth_time),
+                nominal_speed,
+                speed: nominal_speed + timeline.advance_speed_at(time, smooth_time),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::motion::plan_source;
+
+    use super::{MotionTimeline, PressureAdvance, sample_extrusion};
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{value} != {expected}");
+    }
+
+    #[test]
+    fn advance_follows_extrusion_speed() {
+        //  0.05 mm of filament per mm of path
+        let (_, segments) = plan_source("G1 X10 E0.5 F600");
+        let segment = &segments[0];
+        let acceleration = segment.acceleration();
+        let accelerate_time = segment.phases()[0].duration();
+
+        let mut timeline = MotionTimeline::default();
+        timeline.push(segment, 0.1);
+
+        //  Without smoothing, the advance is the factor times the extrusion speed
+        let time = accelerate_time / 2.0;
+        assert_close(
+            timeline.advance_at(time, 0.0),
+            0.1 * 0.05 * acceleration * time,
+        );
+        assert_close(
+            timeline.advance_speed_at(time, 0.0),
+            0.1 * 0.05 * acceleration,
+        );
+        assert_close(timeline.advance_at(timeline.end_time(), 0.0), 0.0);
+
+        //  While cruising far from any speed change, smoothing changes nothing
+        let cruise_time = timeline.end_time() / 2.0;
+        assert_close(timeline.advance_at(cruise_time, 0.04), 0.1 * 0.05 * 10.0);
+
+        //  Smoothing starts building up the advance before the move, and ends it after
+        assert!(timeline.advance_at(-0.01, 0.04) > 0.0);
+        assert!(timeline.advance_at(timeline.end_time() + 0.01, 0.04) > 0.0);
+        assert_close(timeline.advance_at(timeline.end_time() + 0.02, 0.04), 0.0);
+    }
+
+    #[test]
+    fn travels_and_retractions_are_not_advanced() {
+        let (_, segments) = plan_source("M83\nG1 X10 F600\nG1 E-1 F1800\nG1 E1\nG1 X20 E0.5");
+        let samples = sample_extrusion(
+            segments
+                .into_iter()
+                .map(|segment| (segment, PressureAdvance::new(0.1, 0.0))),
+            0.001,
+        );
+
+        let last_travel = samples
+            .iter()
+            .take_while(|sample| sample.nominal_position() == 0.0)
+            .count();
+        assert!(
+            samples[..last_travel]
+                .iter()
+                .all(|sample| sample.advance() == 0.0)
+        );
+        //  Retraction and unretraction move the filament as requested
+        assert!(samples.iter().any(|sample| sample.nominal_speed() < 0.0
+            && sample.advance() == 0.0
+            && sample.speed() == sample.nominal_speed()));
+
+        //  The advance is gone once the extrusion stops, leaving the filament where the job wants it
+        let last = samples.last().unwrap();
+        assert_close(last.advance(), 0.0);
+        assert_close(last.position(), 0.5);
+        assert!(
+            samples
+                .iter()
+                .map(|sample| sample.advance())
+                .fold(0.0, f32::max)
+                > 0.0
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use crate::error::{Error, PrintResult};
+use crate::system::Location;
+
+/// Position of the three motion actuators, in millimeters. Which motor each one is depends on the kinematics.
+/// For a cartesian machine they're the X, Y and Z motors, while for a delta they're the heights of the carriages
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ActuatorPosition {
+    pub(crate) a: f32,
+    pub(crate) b: f32,
+    pub(crate) c: f32,
+}
+
+/// Converts between the cartesian location of the toolhead and the position of the actuators moving it
+pub trait Kinematics {
+    /// Actuator positions that place the toolhead at a location.
+    /// Returns error if the location can't be reached
+    fn to_actuators(&self, location: &Location) -> PrintResult<ActuatorPosition>;
+
+    /// Toolhead location for a set of actuator positions. Inverse of `to_actuators()`, used to report positions
+    fn to_cartesian(&self, actuators: &ActuatorPosition) -> PrintResult<Location>;
+
+    /// True if the machine can physically put the toolhead at a location
+    fn is_reachable(&self, location: &Location) -> bool;
+
+    /// True if straight toolhead moves are straight actuator moves too. Non linear kinematics, like deltas,
+    /// need moves to be split into short pieces to follow straight lines
+    fn is_linear(&self) -> bool {
+        true
+    }
+}
+
+/// Each motor moves a single axis
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CartesianKinematics;
+
+/// Both A and B motors move X and Y together. A = X + Y, B = X - Y, and C moves Z alone
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CoreXYKinematics;
+
+/// Both A and C motors move X and Z together. A = X + Z, C = X - Z, and B moves Y alone
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CoreXZKinematics;
+
+/// Three vertical towers with a carriage each, joined to the effector by arms of the same length
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearDeltaKinematics {
+    /// Length of the arms, from carriage joint to effector joint
+    diagonal_rod: f32,
+    /// Horizontal distance from the center of the bed to the towers, with the effector offsets already subtracted
+    radius: f32,
+    /// Max horizontal distance from the center the toolhead can print at
+    print_radius: f32,
+    /// X and Y of each tower
+    towers: [(f32, f32); 3],
+}
+
+/// Kinematics configured for the machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineKinematics {
+    Cartesian(CartesianKinematics),
+    CoreXY(CoreXYKinematics),
+    CoreXZ(CoreXZKinematics),
+    LinearDelta(LinearDeltaKinematics),
+}
+
+impl ActuatorPosition {
+    pub fn new(a: f32, b: f32, c: f32) -> Self {
+        Self { a, b, c }
+    }
+
+    pub fn a(&self) -> f32 {
+        self.a
+    }
+
+    pub fn b(&self) -> f32 {
+        self.b
+    }
+
+    pub fn c(&self) -> f32 {
+        self.c
+    }
+}
+
+impl Kinematics for CartesianKinematics {
+    fn to_actuators(&self, location: &Location) -> PrintResult<ActuatorPosition> {
+        Ok(ActuatorPosition::new(location.x, location.y, location.z))
+    }
+
+    fn to_cartesian(&self, actuators: &ActuatorPosition) -> PrintResult<Location> {
+        Ok(Location::new(actuators.a, actuators.b, actuators.c))
+    }
+
+    /// Any location is reachable as far as the kinematics are concerned. Bed limits are checked on their own
+    fn is_reachable(&self, _location: &Location) -> bool {
+        true
+    }
+}
+
+impl Kinematics for CoreXYKinematics {
+    fn to_actuators(&self, location: &Location) -> PrintResult<ActuatorPosition> {
+        Ok(ActuatorPosition::new(
+            location.x + location.y,
+            location.x - location.y,
+            location.z,
+        ))
+    }
+
+    fn to_cartesian(&self, actuators: &ActuatorPosition) -> PrintResult<Location> {
+        Ok(Location::new(
+            (actuators.a + actuators.b) / 2.0,
+            (actuators.a - actuators.b) / 2.0,
+            actuators.c,
+        ))
+    }
+
+    fn is_reachable(&self, _location: &Location) -> bool {
+        true
+    }
+}
+
+impl Kinematics for CoreXZKinematics {
+    fn to_actuators(&self, location: &Location) -> PrintResult<ActuatorPosition> {
+        Ok(ActuatorPosition::new(
+            location.x + location.z,
+            location.y,
+            location.x - location.z,
+        ))
+    }
+
+    fn to_cartesian(&self, actuators: &ActuatorPosition) -> PrintResult<Location> {
+        Ok(Location::new(
+            (actuators.a + actuators.c) / 2.0,
+            actuators.b,
+            (actuators.a - actuators.c) / 2.0,
+        ))
+    }
+
+    fn is_reachable(&self, _location: &Location) -> bool {
+        true
+    }
+}
+
+impl LinearDeltaKinematics {
+    /// Towers are placed at 210, 330 and 90 degrees, which puts tower A at the front left
+    pub fn new(diagonal_rod: f32, radius: f32, print_radius: f32) -> Self {
+        let tower = |degrees: f32| {
+            let radians = degrees.to_radians();
+            (radius * radians.cos(), radius * radians.sin())
+        };
+
+        Self {
+            diagonal_rod,
+            radius,
+            print_radius,
+            towers: [tower(210.0), tower(330.0), tower(90.0)],
+        }
+    }
+
+    pub fn diagonal_rod(&self) -> f32 {
+        self.diagonal_rod
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn print_radius(&self) -> f32 {
+        self.print_radius
+    }
+
+    /// Height of a carriage over the toolhead, or None if the arm is too short to get there
+    fn carriage_height(&self, tower: (f32, f32), location: &Location) -> Option<f32> {
+        let horizontal_squared = (tower.0 - location.x).powi(2) + (tower.1 - location.y).powi(2);
+        let vertical_squared = self.diagonal_rod.powi(2) - horizontal_squared;
+
+        (vertical_squared >= 0.0).then(|| location.z + vertical_squared.sqrt())
+    }
+}
+
+impl Kinematics for LinearDeltaKinematics {
+    fn to_actuators(&self, location: &Location) -> PrintResult<ActuatorPosition> {
+        if !self.is_reachable(location) {
+            return Err(Error::UnreachableLocation(*location, None));
+        }
+
+        let heights = self
+            .towers
+            .map(|tower| self.carriage_height(tower, location).unwrap_or_default());
+        Ok(ActuatorPosition::new(heights[0], heights[1], heights[2]))
+    }
+
+    /// Trilateration of the three spheres centered in the carriage joints, with the arms as radius.
+    /// The toolhead hangs from the lower of the two intersections
+    fn to_cartesian(&self, actuators: &ActuatorPosition) -> PrintResult<Location> {
+        let points = [
+            [self.towers[0].0, self.towers[0].1, actuators.a],
+            [self.towers[1].0, self.towers[1].1, actuators.b],
+            [self.towers[2].0, self.towers[2].1, actuators.c],
+        ]
+        .map(|point| point.map(f64::from));
+
+        let p12 = subtract(points[1], points[0]);
+        let p13 = subtract(points[2], points[0]);
+        let d = norm(p12);
+        let ex = scale(p12, 1.0 / d);
+        let i = dot(ex, p13);
+        let ey_unnormalized = subtract(p13, scale(ex, i));
+        let ey = scale(ey_unnormalized, 1.0 / norm(ey_unnormalized));
+        let ez = cross(ex, ey);
+        let j = dot(ey, p13);
+
+        //  Every sphere has the same radius, which simplifies the general trilateration formulas
+        let x = d / 2.0;
+        let y = ((i * i + j * j) / 2.0 - i * x) / j;
+        let z_squared = (self.diagonal_rod as f64).powi(2) - x * x - y * y;
+        if z_squared < 0.0 || !z_squared.is_finite() {
+            let location = Location::new(actuators.a, actuators.b, actuators.c);
+            return Err(Error::UnreachableLocation(location, None));
+        }
+        let z = -z_squared.sqrt();
+
+        let result =
+            [0, 1, 2].map(|index| points[0][index] + x * ex[index] + y * ey[index] + z * ez[index]);
+        Ok(Location::new(
+            result[0] as f32,
+            result[1] as f32,
+            result[2] as f32,
+        ))
+    }
+
+    fn is_reachable(&self, location: &Location) -> bool {
+        let within_print_radius =
+            location.x.powi(2) + location.y.powi(2) <= self.print_radius.powi(2);
+
+        within_print_radius
+            && self
+                .towers
+                .iter()
+                .all(|tower| self.carriage_height(*tower, location).is_some())
+    }
+
+    fn is_linear(&self) -> bool {
+        false
+    }
+}
+
+fn subtract(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f64; 3], factor: f64) -> [f64; 3] {
+    a.map(|value| value * factor)
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn norm(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+impl Default for MachineKinematics {
+    fn default() -> Self {
+        MachineKinematics::Cartesian(CartesianKinematics)
+    }
+}
+
+impl MachineKinematics {
+    fn as_kinematics(&self) -> &dyn Kinematics {
+        match self {
+            MachineKinematics::Cartesian(kinematics) => kinematics,
+            MachineKinematics::CoreXY(kinematics) => kinematics,
+            MachineKinematics::CoreXZ(kinematics) => kinematics,
+            MachineKinematics::LinearDelta(kinematics) => kinematics,
+        }
+    }
+}
+
+impl Kinematics for MachineKinematics {
+    fn to_actuators(&self, location: &Location) -> PrintResult<ActuatorPosition> {
+        self.as_kinematics().to_actuators(location)
+    }
+
+    fn to_cartesian(&self, actuators: &ActuatorPosition) -> PrintResult<Location> {
+        self.as_kinematics().to_cartesian(actuators)
+    }
+
+    fn is_reachable(&self, location: &Location) -> bool {
+        self.as_kinematics().is_reachable(location)
+    }
+
+    fn is_linear(&self) -> bool {
+        self.as_kinematics().is_linear()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::motion::{
+        ActuatorPosition, CoreXYKinematics, CoreXZKinematics, Kinematics, LinearDeltaKinematics,
+    };
+    use crate::system::Location;
+
+    fn assert_round_trip(kinematics: &impl Kinematics, location: Location) {
+        let actuators = kinematics.to_actuators(&location).unwrap();
+        let back = kinematics.to_cartesian(&actuators).unwrap();
+
+        assert!(
+            location.distance_to(&back) < 1e-3,
+            "{location:?} came back as {back:?}"
+        );
+    }
+
+    #[test]
+    fn corexy_transforms() {
+        let actuators = CoreXYKinematics
+            .to_actuators(&Location::new(10.0, 4.0, 2.0))
+            .unwrap();
+
+        assert_eq!(actuators, ActuatorPosition::new(14.0, 6.0, 2.0));
+        assert_round_trip(&CoreXYKinematics, Location::new(-3.5, 120.25, 7.0));
+        assert_round_trip(&CoreXZKinematics, Location::new(35.0, 20.0, 0.2));
+    }
+
+    #[test]
+    fn delta_transforms() {
+        let delta = LinearDeltaKinematics::new(250.0, 120.0, 90.0);
+
+        //  At the center every carriage sits at the same height
+        let center = delta.to_actuators(&Location::new(0.0, 0.0, 0.0)).unwrap();
+        let expected = (250.0_f32.powi(2) - 120.0_f32.powi(2)).sqrt();
+        assert!((center.a() - expected).abs() < 1e-3);
+        assert_eq!(center.a(), center.b());
+        assert_eq!(center.b(), center.c());
+
+        assert_round_trip(&delta, Location::new(0.0, 0.0, 10.0));
+        assert_round_trip(&delta, Location::new(45.5, -60.25, 0.3));
+        assert_round_trip(&delta, Location::new(-80.0, 20.0, 150.0));
+    }
+
+    #[test]
+    fn delta_reachability() {
+        let delta = LinearDeltaKinematics::new(250.0, 120.0, 90.0);
+
+        assert!(delta.is_reachable(&Location::new(60.0, 60.0, 0.0)));
+        assert!(!delta.is_reachable(&Location::new(80.0, 80.0, 0.0)));
+        assert!(delta.to_actuators(&Location::new(0.0, 95.0, 0.0)).is_err());
+    }
+}
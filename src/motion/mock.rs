@@ -0,0 +1,34 @@
+use crate::error::PrintResult;
+
+use super::stepper::{AXIS_COUNT, Axis, AxisSteps, StepperDriver};
+
+/// Driver that moves nothing, but keeps track of everything it was asked to do
+#[derive(Debug, Default)]
+pub(crate) struct MockDriver {
+    pub(crate) moves: Vec<(AxisSteps, f32)>,
+    position: [i32; AXIS_COUNT],
+    elapsed: f32,
+}
+
+impl StepperDriver for MockDriver {
+    fn move_steps(&mut self, steps: &AxisSteps, duration: f32) -> PrintResult<()> {
+        for axis in Axis::ALL {
+            self.position[axis.index()] += steps.get(axis);
+        }
+        self.elapsed += duration;
+        self.moves.push((*steps, duration));
+        Ok(())
+    }
+}
+
+impl MockDriver {
+    /// Sum of every step received by an axis
+    pub(crate) fn position(&self, axis: Axis) -> i32 {
+        self.position[axis.index()]
+    }
+
+    /// Sum of the durations of every move received, in seconds
+    pub(crate) fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+}
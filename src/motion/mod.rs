@@ -0,0 +1,22 @@
+mod advance;
+mod kinematics;
+#[cfg(test)]
+pub(crate) mod mock;
+mod planner;
+mod shaper;
+mod stepper;
+mod timeline;
+
+pub(crate) use advance::sample_extrusion;
+pub use advance::{ExtruderSample, PressureAdvance};
+pub use kinematics::{
+    ActuatorPosition, CartesianKinematics, CoreXYKinematics, CoreXZKinematics, Kinematics,
+    LinearDeltaKinematics, MachineKinematics,
+};
+#[cfg(test)]
+pub(crate) use planner::plan_source;
+pub use planner::{MotionLimits, MotionPhase, PlannedSegment, Planner};
+pub(crate) use shaper::{DEFAULT_DAMPING, InputShapers, sample_shaping};
+pub use shaper::{InputShaper, ShaperType, ShapingSample};
+pub use stepper::{Axis, AxisSteps, StepConverter, StepperDriver, StepsPerUnit};
+pub(crate) use timeline::MotionQueue;
@@ -0,0 +1,852 @@
+use std::collections::VecDeque;
+
+use crate::error::PrintResult;
+use crate::gcode::{AxisParameters, M204Acceleration, M205AdvancedSettings};
+use crate::system::ResolvedMove;
+use crate::types::LineNumberType;
+
+use super::kinematics::Kinematics;
+use super::stepper::{AXIS_COUNT, Axis, StepConverter, StepperDriver, StepsPerUnit};
+
+/// Amount of segments the planner looks ahead before releasing the oldest one
+const DEFAULT_LOOKAHEAD_SIZE: usize = 16;
+/// Slowest speed at which a junction can be crossed, in mm/s. Used for full reversals of direction
+const MINIMUM_PLANNER_SPEED: f32 = 0.05;
+/// Longest piece a phase is split into when the kinematics aren't linear, in millimeters
+const NON_LINEAR_SEGMENT_LENGTH: f32 = 0.5;
+/// Longest piece a phase is split into when the pressure advance or the input shapers are applied, in seconds
+const ADJUSTED_PIECE_DURATION: f32 = 0.005;
+/// Directions closer than this to being parallel are treated as straight lines or full reversals
+const PARALLEL_THRESHOLD: f32 = 0.999_999;
+
+/// Limits the planner works with. Set through M201, M203, M204 and M205
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionLimits {
+    /// M201. Max acceleration of each axis, in mm/s²
+    max_acceleration: [f32; AXIS_COUNT],
+    /// M203. Max feedrate of each axis, in mm/s
+    max_feedrate: [f32; AXIS_COUNT],
+    /// M204 P. Acceleration of moves that extrude, in mm/s²
+    print_acceleration: f32,
+    /// M204 R. Acceleration of the extruder moving alone, in mm/s²
+    retract_acceleration: f32,
+    /// M204 T. Acceleration of moves that don't extrude, in mm/s²
+    travel_acceleration: f32,
+    /// M205 X Y Z E. Max instant speed change of each axis, in mm/s. Used only if junction deviation is zero
+    jerk: [f32; AXIS_COUNT],
+    /// M205 J. Max distance between the junction of two moves and the arc the toolhead would follow through it
+    junction_deviation: f32,
+    /// M205 S. Min feedrate for moves that extrude, in mm/s
+    min_feedrate: f32,
+    /// M205 T. Min feedrate for travel moves, in mm/s
+    min_travel_feedrate: f32,
+}
+
+/// Move already planned, with the speeds it enters, cruises and leaves at
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedSegment {
+    resolved_move: ResolvedMove,
+    /// Portion of the segment length moved by each axis. Negative towards the origin
+    direction: [f32; AXIS_COUNT],
+    /// Millimeters
+    length: f32,
+    /// mm/s²
+    acceleration: f32,
+    /// Speed requested by the feedrate after applying the axis limits, in mm/s
+    nominal_speed: f32,
+    /// Fastest the segment can be entered, given the junction with the previous one. mm/s
+    max_entry_speed: f32,
+    entry_speed: f32,
+    exit_speed: f32,
+    /// Line of the command the move comes from, if pushed with `push_at()`
+    line_number: Option<LineNumberType>,
+    /// Bytes from the start of the source to the line of the command, if known
+    offset: Option<u64>,
+}
+
+/// Part of a segment moved with a constant acceleration. Every segment has up to three of them:
+/// acceleration, cruise and deceleration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionPhase {
+    /// Distance from the start of the segment where the phase begins, in millimeters
+    start_distance: f32,
+    /// Distance from the start of the segment where the phase ends, in millimeters
+    end_distance: f32,
+    /// mm/s
+    start_speed: f32,
+    /// mm/s
+    end_speed: f32,
+    /// Seconds
+    duration: f32,
+}
+
+/// Queues the resolved moves and plans their speed profiles, looking ahead at the incoming ones.
+/// A segment is released once enough segments are queued after it, or when the planner is flushed
+#[derive(Debug)]
+pub struct Planner {
+    segments: VecDeque<PlannedSegment>,
+    lookahead_size: usize,
+    /// Last segment pushed, kept to compute the junction with the next one even after being released
+    previous: Option<PlannedSegment>,
+    /// Exit speed of the last segment released. The first queued segment must enter at this speed
+    released_exit_speed: f32,
+}
+
+impl Default for MotionLimits {
+    /// Common values for a cartesian printer
+    fn default() -> Self {
+        Self {
+            max_acceleration: [3000.0, 3000.0, 100.0, 10000.0],
+            max_feedrate: [300.0, 300.0, 5.0, 25.0],
+            print_acceleration: 3000.0,
+            retract_acceleration: 3000.0,
+            travel_acceleration: 3000.0,
+            jerk: [10.0, 10.0, 0.3, 5.0],
+            junction_deviation: 0.013,
+            min_feedrate: 0.0,
+            min_travel_feedrate: 0.0,
+        }
+    }
+}
+
+impl MotionLimits {
+    pub fn max_acceleration(&self, axis: Axis) -> f32 {
+        self.max_acceleration[axis.index()]
+    }
+
+    pub fn max_feedrate(&self, axis: Axis) -> f32 {
+        self.max_feedrate[axis.index()]
+    }
+
+    pub fn print_acceleration(&self) -> f32 {
+        self.print_acceleration
+    }
+
+    pub fn retract_acceleration(&self) -> f32 {
+        self.retract_acceleration
+    }
+
+    pub fn travel_acceleration(&self) -> f32 {
+        self.travel_acceleration
+    }
+
+    pub fn jerk(&self, axis: Axis) -> f32 {
+        self.jerk[axis.index()]
+    }
+
+    pub fn junction_deviation(&self) -> f32 {
+        self.junction_deviation
+    }
+
+    pub fn min_feedrate(&self) -> f32 {
+        self.min_feedrate
+    }
+
+    pub fn min_travel_feedrate(&self) -> f32 {
+        self.min_travel_feedrate
+    }
+
+    pub(crate) fn set_max_acceleration(&mut self, parameters: &AxisParameters) {
+        set_axis_values(&mut self.max_acceleration, parameters);
+    }
+
+    pub(crate) fn set_max_feedrate(&mut self, parameters: &AxisParameters) {
+        set_axis_values(&mut self.max_feedrate, parameters);
+    }
+
+    pub(crate) fn set_acceleration(&mut self, acceleration: &M204Acceleration) {
+        //  Legacy S goes first, so P and T on the same line take precedence
+        if let Some(legacy) = acceleration.legacy {
+            self.print_acceleration = legacy;
+            self.travel_acceleration = legacy;
+        }
+        if let Some(print) = acceleration.print {
+            self.print_acceleration = print;
+        }
+        if let Some(retract) = acceleration.retract {
+            self.retract_acceleration = retract;
+        }
+        if let Some(travel) = acceleration.travel {
+            self.travel_acceleration = travel;
+        }
+    }
+
+    pub(crate) fn set_advanced_settings(&mut self, settings: &M205AdvancedSettings) {
+        set_axis_values(&mut self.jerk, &settings.jerk);
+        if let Some(junction_deviation) = settings.junction_deviation {
+            self.junction_deviation = junction_deviation;
+        }
+        if let Some(min_feedrate) = settings.min_feedrate {
+            self.min_feedrate = min_feedrate;
+        }
+        if let Some(min_travel_feedrate) = settings.min_travel_feedrate {
+            self.min_travel_feedrate = min_travel_feedrate;
+        }
+    }
+}
+
+fn set_axis_values(values: &mut [f32; AXIS_COUNT], parameters: &AxisParameters) {
+    for (axis, value) in parameters.by_axis() {
+        if let Some(value) = value {
+            values[axis.index()] = value;
+        }
+    }
+}
+
+impl PlannedSegment {
+    /// Builds the segment applying the axis limits to its feedrate and acceleration.
+    /// Entry and exit speeds are left at zero until the planner recalculates them
+    fn new(resolved_move: ResolvedMove, limits: &MotionLimits) -> Self {
+        let (start, end) = (resolved_move.start(), resolved_move.end());
+        let length = resolved_move.length();
+        let deltas = [
+            end.x - start.x,
+            end.y - start.y,
+            end.z - start.z,
+            resolved_move.extrusion(),
+        ];
+        let direction = deltas.map(|delta| delta / length);
+        let is_travel = resolved_move.extrusion() == 0.0;
+
+        let (mut acceleration, min_speed) = if start == end {
+            (limits.retract_acceleration, 0.0)
+        } else if is_travel {
+            (limits.travel_acceleration, limits.min_travel_feedrate)
+        } else {
+            (limits.print_acceleration, limits.min_feedrate)
+        };
+        let mut nominal_speed = (resolved_move.feedrate() / 60.0).max(min_speed);
+
+        //  The axis with the largest share of the move is the first to reach its limits
+        for axis in Axis::ALL {
+            let share = direction[axis.index()].abs();
+            if share > 0.0 {
+                acceleration = acceleration.min(limits.max_acceleration(axis) / share);
+                nominal_speed = nominal_speed.min(limits.max_feedrate(axis) / share);
+            }
+        }
+
+        Self {
+            resolved_move,
+            direction,
+            length,
+            acceleration,
+            nominal_speed,
+            max_entry_speed: 0.0,
+            entry_speed: 0.0,
+            exit_speed: 0.0,
+            line_number: None,
+            offset: None,
+        }
+    }
+
+    pub fn resolved_move(&self) -> &ResolvedMove {
+        &self.resolved_move
+    }
+
+    pub fn length(&self) -> f32 {
+        self.length
+    }
+
+    pub fn acceleration(&self) -> f32 {
+        self.acceleration
+    }
+
+    pub fn nominal_speed(&self) -> f32 {
+        self.nominal_speed
+    }
+
+    pub fn entry_speed(&self) -> f32 {
+        self.entry_speed
+    }
+
+    pub fn exit_speed(&self) -> f32 {
+        self.exit_speed
+    }
+
+    /// Line of the command the move comes from, if pushed with `push_at()`
+    pub fn line_number(&self) -> Option<LineNumberType> {
+        self.line_number
+    }
+
+    /// Bytes from the start of the source to the line of the command, if known
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// True if the segment only moves the extruder
+    fn is_extruder_only(&self) -> bool {
+        self.direction[..3].iter().all(|share| *share == 0.0)
+    }
+
+    /// Fastest speed the segment can be entered from a previous one, without exceeding the junction limits
+    fn junction_speed(&self, previous: &PlannedSegment, limits: &MotionLimits) -> f32 {
+        let max_speed = self.nominal_speed.min(previous.nominal_speed);
+
+        //  Retractions and moves following them always start from a stop
+        if self.is_extruder_only() || previous.is_extruder_only() {
+            return MINIMUM_PLANNER_SPEED.min(max_speed);
+        }
+
+        if limits.junction_deviation > 0.0 {
+            self.junction_deviation_speed(previous, limits.junction_deviation)
+                .min(max_speed)
+        } else {
+            self.classic_jerk_speed(previous, max_speed, limits)
+        }
+    }
+
+    /// Speed at which the toolhead could go through the junction following an arc tangent to both segments,
+    /// without straying further than the junction deviation from the corner
+    fn junction_deviation_speed(&self, previous: &PlannedSegment, junction_deviation: f32) -> f32 {
+        let length_xyz = |segment: &PlannedSegment| {
+            segment.direction[..3]
+                .iter()
+                .map(|share| share * share)
+                .sum::<f32>()
+                .sqrt()
+        };
+        let (previous_length, length) = (length_xyz(previous), length_xyz(self));
+        let cos_theta = -(0..3)
+            .map(|index| {
+                (previous.direction[index] / previous_length) * (self.direction[index] / length)
+            })
+            .sum::<f32>();
+
+        if cos_theta > PARALLEL_THRESHOLD {
+            return MINIMUM_PLANNER_SPEED;
+        }
+        if cos_theta < -PARALLEL_THRESHOLD {
+            return f32::MAX;
+        }
+
+        let sin_theta_half = (0.5 * (1.0 - cos_theta)).sqrt();
+        let acceleration = self.acceleration.min(previous.acceleration);
+        (acceleration * junction_deviation * sin_theta_half / (1.0 - sin_theta_half))
+            .sqrt()
+            .max(MINIMUM_PLANNER_SPEED)
+    }
+
+    /// Speed at which no axis changes its own speed more than its jerk limit when going through the junction
+    fn classic_jerk_speed(
+        &self,
+        previous: &PlannedSegment,
+        max_speed: f32,
+        limits: &MotionLimits,
+    ) -> f32 {
+        let mut factor: f32 = 1.0;
+        for axis in Axis::ALL {
+            let index = axis.index();
+            let speed_change =
+                (self.direction[index] - previous.direction[index]).abs() * max_speed;
+            if speed_change > limits.jerk(axis) {
+                factor = factor.min(limits.jerk(axis) / speed_change);
+            }
+        }
+
+        (max_speed * factor).max(MINIMUM_PLANNER_SPEED.min(max_speed))
+    }
+
+    /// Fastest speed reachable at the end of the segment when starting at `start_speed`
+    fn reachable_speed(&self, start_speed: f32) -> f32 {
+        (start_speed * start_speed + 2.0 * self.acceleration * self.length).sqrt()
+    }
+
+    /// Splits the segment into its acceleration, cruise and deceleration phases.
+    /// Phases with no length are left out. Segments too short to reach their nominal speed have no cruise
+    pub fn phases(&self) -> Vec<MotionPhase> {
+        let acceleration = self.acceleration;
+        let (entry, exit) = (self.entry_speed, self.exit_speed);
+        let mut peak_speed = self.nominal_speed;
+        let mut accelerate_distance = (peak_speed.powi(2) - entry.powi(2)) / (2.0 * acceleration);
+        let mut decelerate_distance = (peak_speed.powi(2) - exit.powi(2)) / (2.0 * acceleration);
+
+        //  Nominal speed can't be reached, so the profile becomes a triangle
+        if accelerate_distance + decelerate_distance > self.length {
+            accelerate_distance =
+                ((exit.powi(2) - entry.powi(2)) / (2.0 * acceleration) + self.length) / 2.0;
+            accelerate_distance = accelerate_distance.clamp(0.0, self.length);
+            decelerate_distance = self.length - accelerate_distance;
+            peak_speed = (entry.powi(2) + 2.0 * acceleration * accelerate_distance).sqrt();
+        }
+        let cruise_distance = self.length - accelerate_distance - decelerate_distance;
+
+        let candidates = [
+            (accelerate_distance, entry, peak_speed),
+            (cruise_distance, peak_speed, peak_speed),
+            (decelerate_distance, peak_speed, exit),
+        ];
+
+        let mut phases = vec![];
+        let mut start_distance = 0.0;
+        for (distance, start_speed, end_speed) in candidates {
+            if distance <= 0.0 {
+                continue;
+            }
+            //  Average speed of a constant acceleration is the mean of both ends
+            let duration = distance / ((start_speed + end_speed) / 2.0);
+            phases.push(MotionPhase {
+                start_distance,
+                end_distance: start_distance + distance,
+                start_speed,
+                end_speed,
+                duration,
+            });
+            start_distance += distance;
+        }
+
+        //  Avoid floating point leftovers, the last phase always ends at the end of the segment
+        if let Some(last) = phases.last_mut() {
+            last.end_distance = self.length;
+        }
+
+        phases
+    }
+
+    /// Total time it takes to complete the segment, in seconds
+    pub fn duration(&self) -> f32 {
+        self.phases().iter().map(|phase| phase.duration).sum()
+    }
+
+    /// Sends the segment to the driver, one set of steps per phase. With non linear kinematics,
+    /// phases are split in short pieces so the toolhead follows a straight line
+    pub fn execute(
+        &self,
+        converter: &mut StepConverter,
+        kinematics: &impl Kinematics,
+        steps_per_unit: &StepsPerUnit,
+        driver: &mut impl StepperDriver,
+    ) -> PrintResult<()> {
+        self.execute_pieces(
+            converter,
+            kinematics,
+            steps_per_unit,
+            driver,
+            f32::INFINITY,
+            |piece, _| piece,
+        )
+    }
+
+    /// Same as `execute()`, but phases are split in short pieces and `adjust` changes each one before it's
+    /// sent, given the time it ends at from the start of the segment. Used by the pressure advance and the input shapers
+    pub(crate) fn execute_adjusted(
+        &self,
+        converter: &mut StepConverter,
+        kinematics: &impl Kinematics,
+        steps_per_unit: &StepsPerUnit,
+        driver: &mut impl StepperDriver,
+        adjust: impl FnMut(ResolvedMove, f32) -> ResolvedMove,
+    ) -> PrintResult<()> {
+        self.execute_pieces(
+            converter,
+            kinematics,
+            steps_per_unit,
+            driver,
+            ADJUSTED_PIECE_DURATION,
+            adjust,
+        )
+    }
+
+    fn execute_pieces(
+        &self,
+        converter: &mut StepConverter,
+        kinematics: &impl Kinematics,
+        steps_per_unit: &StepsPerUnit,
+        driver: &mut impl StepperDriver,
+        max_piece_duration: f32,
+        mut adjust: impl FnMut(ResolvedMove, f32) -> ResolvedMove,
+    ) -> PrintResult<()> {
+        let mut phase_start_time = 0.0;
+        for phase in self.phases() {
+            let length_pieces = match kinematics.is_linear() {
+                true => 1.0,
+                false => (phase.end_distance - phase.start_distance) / NON_LINEAR_SEGMENT_LENGTH,
+            };
+            let pieces = length_pieces
+                .max(phase.duration / max_piece_duration)
+                .ceil()
+                .max(1.0) as usize;
+
+            let mut previous_distance = phase.start_distance;
+            let mut previous_time = 0.0;
+            for piece in 1..=pieces {
+                let distance = if piece == pieces {
+                    phase.end_distance
+                } else {
+                    phase.start_distance
+                        + (phase.end_distance - phase.start_distance) * piece as f32 / pieces as f32
+                };
+                let time = phase.time_at(distance - phase.start_distance);
+
+                let portion = self
+                    .resolved_move
+                    .portion(previous_distance / self.length, distance / self.length);
+                let portion = adjust(portion, phase_start_time + time);
+                let steps = converter.convert(&portion, kinematics, steps_per_unit)?;
+                if !steps.is_empty() {
+                    driver.move_steps(&steps, time - previous_time)?;
+                }
+
+                previous_distance = distance;
+                previous_time = time;
+            }
+            phase_start_time += phase.duration;
+        }
+
+        Ok(())
+    }
+}
+
+impl MotionPhase {
+    pub fn start_distance(&self) -> f32 {
+        self.start_distance
+    }
+
+    pub fn end_distance(&self) -> f32 {
+        self.end_distance
+    }
+
+    pub fn start_speed(&self) -> f32 {
+        self.start_speed
+    }
+
+    pub fn end_speed(&self) -> f32 {
+        self.end_speed
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// Constant acceleration along the phase, in mm/s². Negative when decelerating
+    pub fn acceleration(&self) -> f32 {
+        (self.end_speed - self.start_speed) / self.duration
+    }
+
+    /// Time it takes to move a distance from the start of the phase, in seconds
+    pub fn time_at(&self, distance: f32) -> f32 {
+        let length = self.end_distance - self.start_distance;
+        if distance >= length {
+            return self.duration;
+        }
+
+        let acceleration = self.acceleration();
+        if acceleration.abs() < f32::EPSILON {
+            return distance / self.start_speed;
+        }
+
+        //  Solving distance = v0 * t + a * t² / 2 for t
+        let speed = (self.start_speed.powi(2) + 2.0 * acceleration * distance)
+            .max(0.0)
+            .sqrt();
+        (speed - self.start_speed) / acceleration
+    }
+}
+
+impl Default for Planner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Planner {
+    pub fn new() -> Self {
+        Self {
+            segments: VecDeque::with_capacity(DEFAULT_LOOKAHEAD_SIZE + 1),
+            lookahead_size: DEFAULT_LOOKAHEAD_SIZE,
+            previous: None,
+            released_exit_speed: 0.0,
+        }
+    }
+
+    /// Amount of segments waiting for more moves to be planned
+    pub fn queued_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Queues a move and replans the queue. Returns the segments that left the lookahead window,
+    /// whose speeds won't change anymore
+    pub fn push(
+        &mut self,
+        resolved_move: ResolvedMove,
+        limits: &MotionLimits,
+    ) -> Vec<PlannedSegment> {
+        self.push_segment(PlannedSegment::new(resolved_move, limits), limits)
+    }
+
+    /// Same as `push()`, noting on the segment the line of the command the move comes from and its
+    /// offset in the source. Like the file position Marlin stores in each block, it tells how far the
+    /// job got once the segment reaches the driver
+    pub fn push_at(
+        &mut self,
+        resolved_move: ResolvedMove,
+        limits: &MotionLimits,
+        line_number: LineNumberType,
+        offset: Option<u64>,
+    ) -> Vec<PlannedSegment> {
+        let segment = PlannedSegment {
+            line_number: Some(line_number),
+            offset,
+            ..PlannedSegment::new(resolved_move, limits)
+        };
+        self.push_segment(segment, limits)
+    }
+
+    fn push_segment(
+        &mut self,
+        mut segment: PlannedSegment,
+        limits: &MotionLimits,
+    ) -> Vec<PlannedSegment> {
+        if let Some(previous) = &self.previous {
+            segment.max_entry_speed = segment.junction_speed(previous, limits);
+        }
+
+        self.previous = Some(segment.clone());
+        self.segments.push_back(segment);
+        self.recalculate();
+
+        let mut released = vec![];
+        while self.segments.len() > self.lookahead_size {
+            if let Some(segment) = self.segments.pop_front() {
+                self.released_exit_speed = segment.exit_speed;
+                released.push(segment);
+            }
+        }
+
+        released
+    }
+
+    /// Releases every queued segment, planning the last one to come to a full stop
+    pub fn flush(&mut self) -> Vec<PlannedSegment> {
+        self.previous = None;
+        self.released_exit_speed = 0.0;
+        self.segments.drain(..).collect()
+    }
+
+    /// Drops every queued segment without executing them. Returns the amount of segments dropped
+    pub fn clear(&mut self) -> usize {
+        let dropped = self.segments.len();
+        self.previous = None;
+        self.released_exit_speed = 0.0;
+        self.segments.clear();
+        dropped
+    }
+
+    /// Plans the speeds of the queued segments. The reverse pass makes sure every segment can slow down
+    /// in time for the next ones, assuming the last one ends at a stop. The forward pass makes sure every
+    /// segment can actually speed up to what the reverse pass allowed
+    fn recalculate(&mut self) {
+        let mut next_entry_speed = 0.0;
+        for (index, segment) in self.segments.iter_mut().enumerate().rev() {
+            segment.exit_speed = next_entry_speed;
+            segment.entry_speed = if index == 0 {
+                //  The previous segment is already gone, so the entry can't change anymore
+                self.released_exit_speed
+            } else {
+                segment
+                    .max_entry_speed
+                    .min(segment.reachable_speed(next_entry_speed))
+            };
+            next_entry_speed = segment.entry_speed;
+        }
+
+        for index in 0..self.segments.len() {
+            let segment = &mut self.segments[index];
+            segment.exit_speed = segment
+                .exit_speed
+                .min(segment.reachable_speed(segment.entry_speed));
+            let exit_speed = segment.exit_speed;
+
+            if let Some(next) = self.segments.get_mut(index + 1) {
+                next.entry_speed = exit_speed;
+            }
+        }
+    }
+}
+
+/// Plans the full source with the default config, flushing the planner at the end. Returns the
+/// config as the source left it, along with the segments
+#[cfg(test)]
+pub(crate) fn plan_source(source: &str) -> (crate::system::SystemConfig, Vec<PlannedSegment>) {
+    let mut config = crate::system::SystemConfig::default();
+    let mut planner = Planner::new();
+    let mut segments = vec![];
+
+    for line in crate::gcode::GcodeReader::new(source.as_bytes()) {
+        let Some(command) = line.unwrap().into_command() else {
+            continue;
+        };
+        for resolved_move in config.apply_command(&command).unwrap() {
+            segments.extend(planner.push(resolved_move, config.motion_limits()));
+        }
+    }
+    segments.extend(planner.flush());
+
+    (config, segments)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::GcodeReader;
+    use crate::motion::mock::MockDriver;
+    use crate::motion::{
+        Axis, Kinematics, LinearDeltaKinematics, MotionLimits, Planner, StepConverter, plan_source,
+    };
+    use crate::system::SystemConfig;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-3, "{value} != {expected}");
+    }
+
+    #[test]
+    fn single_move_trapezoid() {
+        //  100 mm/s with 1000 mm/s² takes 5 mm to accelerate and another 5 to stop
+        let (_, segments) = plan_source("M204 T1000\nG0 X100 F6000");
+        let phases = segments[0].phases();
+
+        assert_eq!(phases.len(), 3);
+        assert_close(phases[0].end_distance(), 5.0);
+        assert_close(phases[0].duration(), 0.1);
+        assert_close(phases[1].end_distance(), 95.0);
+        assert_close(phases[1].duration(), 0.9);
+        assert_close(phases[2].end_speed(), 0.0);
+        assert_close(segments[0].duration(), 1.1);
+    }
+
+    #[test]
+    fn short_move_triangle() {
+        //  Over 2 mm at 1000 mm/s² the peak is sqrt(2 * 1000 * 1) mm/s, far from the 100 mm/s requested
+        let (_, segments) = plan_source("M204 T1000\nG0 X2 F6000");
+        let phases = segments[0].phases();
+
+        assert_eq!(phases.len(), 2);
+        assert_close(phases[0].end_speed(), 2000.0_f32.sqrt());
+        assert_close(phases[1].start_distance(), 1.0);
+    }
+
+    #[test]
+    fn collinear_moves_keep_speed() {
+        let (_, segments) = plan_source("M204 T1000\nG0 X50 F6000\nG0 X100");
+
+        assert_close(segments[0].exit_speed(), 100.0);
+        assert_close(segments[1].entry_speed(), 100.0);
+        assert_eq!(segments[0].phases().len(), 2);
+    }
+
+    #[test]
+    fn corners_slow_down() {
+        let (_, segments) = plan_source("M204 T1000\nM205 J0.02\nG0 X50 F6000\nG0 Y50");
+
+        //  At 90 degrees, sin(theta / 2) is sqrt(0.5)
+        let sin_theta_half = 0.5_f32.sqrt();
+        let expected = (1000.0 * 0.02 * sin_theta_half / (1.0 - sin_theta_half)).sqrt();
+        assert_close(segments[0].exit_speed(), expected);
+        assert_close(segments[1].entry_speed(), expected);
+
+        //  A full reversal needs a stop
+        let (_, segments) = plan_source("G0 X50 F6000\nG0 X0");
+        assert!(segments[0].exit_speed() <= 0.05);
+    }
+
+    #[test]
+    fn classic_jerk_when_junction_deviation_disabled() {
+        let (_, segments) = plan_source("M205 J0 X5 Y5\nG0 X50 F6000\nG0 Y50");
+
+        //  Both X and Y change their speed by the junction speed, which can't exceed 5 mm/s
+        assert_close(segments[0].exit_speed(), 5.0);
+    }
+
+    #[test]
+    fn axis_limits_are_respected() {
+        let (config, segments) = plan_source("M203 Z5\nM201 Z100\nG0 Z10 F6000");
+
+        assert_close(segments[0].nominal_speed(), 5.0);
+        assert_close(segments[0].acceleration(), 100.0);
+        assert_eq!(config.motion_limits().max_feedrate(Axis::Z), 5.0);
+    }
+
+    #[test]
+    fn lookahead_window_releases_segments() {
+        let mut config = SystemConfig::default();
+        let mut planner = Planner::new();
+        let mut released = 0;
+        let mut source = String::new();
+        for index in 1..=20 {
+            source.push_str(&format!("G0 X{index} F6000\n"));
+        }
+
+        for line in GcodeReader::new(source.as_bytes()) {
+            let command = line.unwrap().into_command().unwrap();
+            let resolved_move = config.apply_command(&command).unwrap().remove(0);
+            released += planner.push(resolved_move, &MotionLimits::default()).len();
+        }
+
+        assert_eq!(released, 4);
+        assert_eq!(planner.queued_segments(), 16);
+        assert_eq!(planner.flush().last().unwrap().exit_speed(), 0.0);
+    }
+
+    #[test]
+    fn segments_run_against_mock_driver() {
+        let (config, segments) =
+            plan_source("M204 P500 T1000\nG0 X10 F3000\nG1 X20 Y10 E1.5\nG1 X0 Y0 E3.0");
+        let mut converter = StepConverter::new();
+        let mut driver = MockDriver::default();
+        for segment in &segments {
+            segment
+                .execute(
+                    &mut converter,
+                    config.kinematics(),
+                    config.steps_per_unit(),
+                    &mut driver,
+                )
+                .unwrap();
+        }
+
+        //  Every step reaches the driver, and the speeds never go over the nominal ones
+        assert_eq!(driver.position(Axis::X), 0);
+        assert_eq!(driver.position(Axis::E), (3.0_f32 * 93.0).round() as i32);
+        assert!(
+            driver.elapsed()
+                > segments
+                    .iter()
+                    .map(|s| s.resolved_move().nominal_duration())
+                    .sum()
+        );
+        for segment in &segments {
+            for phase in segment.phases() {
+                assert!(phase.start_speed() <= segment.nominal_speed() + 1e-3);
+                assert!(phase.acceleration().abs() <= segment.acceleration() + 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    fn delta_segments_are_split() {
+        let (config, segments) = plan_source("G0 X10 F3000\nG0 X-10 Y10");
+        let delta = LinearDeltaKinematics::new(250.0, 120.0, 90.0);
+        let mut converter = StepConverter::new();
+        let mut driver = MockDriver::default();
+        let start = delta
+            .to_actuators(&segments[0].resolved_move().start())
+            .unwrap();
+        converter.reset(&start, config.steps_per_unit());
+
+        for segment in &segments {
+            segment
+                .execute(&mut converter, &delta, config.steps_per_unit(), &mut driver)
+                .unwrap();
+        }
+
+        //  More pieces than phases, and the same total time the planner expected
+        let phases: usize = segments.iter().map(|s| s.phases().len()).sum();
+        assert!(driver.moves.len() > phases * 10);
+        let planned: f32 = segments.iter().map(|s| s.duration()).sum();
+        assert!((driver.elapsed() - planned).abs() < 1e-3);
+
+        let reported = converter
+            .reported_location(&delta, config.steps_per_unit())
+            .unwrap();
+        assert!(reported.distance_to(&config.current_location()) < 0.05);
+    }
+}
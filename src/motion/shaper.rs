@@ -0,0 +1,370 @@
+use std::f32::consts::{PI, SQRT_2, TAU};
+
+use crate::system::Location;
+
+use super::planner::PlannedSegment;
+use super::stepper::Axis;
+use super::timeline::MotionTimeline;
+
+/// Vibration tolerated by the extra insensitive shapers at their own frequency. Same as Klipper
+const EI_VIBRATION_TOLERANCE: f32 = 0.05;
+/// Damping ratio shapers are built for when the profile doesn't say otherwise. Same as Klipper
+pub(crate) const DEFAULT_DAMPING: f32 = 0.1;
+
+/// Families of input shapers. Shapers with more impulses cancel a wider band of frequencies around
+/// the one they're tuned to, at the cost of smoothing the motion for longer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ShaperType {
+    /// Zero vibration. Two impulses, the shortest but also the narrowest
+    Zv,
+    /// Modified zero vibration. Three impulses, like Klipper's default
+    #[default]
+    Mzv,
+    /// Extra insensitive. Three impulses, tolerating a 5% vibration to widen the band
+    Ei,
+    /// Two hump extra insensitive. Four impulses, for resonances that drift a lot
+    TwoHumpEi,
+}
+
+/// Filter canceling the ringing of an axis at a resonance frequency. Every position of the axis is
+/// split into impulses, each one sent a bit later with part of the motion, so the vibrations started
+/// by each one cancel each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputShaper {
+    shaper_type: ShaperType,
+    /// Resonance frequency of the axis, in Hz
+    frequency: f32,
+    /// Damping ratio of the resonance. Usually around 0.1
+    damping: f32,
+}
+
+/// Shapers of the X and Y axes. Axes without a shaper move as planned
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct InputShapers {
+    pub(crate) x: Option<InputShaper>,
+    pub(crate) y: Option<InputShaper>,
+}
+
+/// Acceleration of the X and Y axes at a point in time of a job, before and after the input shapers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShapingSample {
+    /// Seconds since the first move
+    time: f32,
+    /// Planned acceleration of X and Y, in mm/s²
+    acceleration: [f32; 2],
+    /// Acceleration of X and Y once shaped, in mm/s²
+    shaped_acceleration: [f32; 2],
+}
+
+impl ShaperType {
+    pub const ALL: [ShaperType; 4] = [
+        ShaperType::Zv,
+        ShaperType::Mzv,
+        ShaperType::Ei,
+        ShaperType::TwoHumpEi,
+    ];
+
+    /// Name used by printer profiles. Ex: `2hump_ei`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShaperType::Zv => "zv",
+            ShaperType::Mzv => "mzv",
+            ShaperType::Ei => "ei",
+            ShaperType::TwoHumpEi => "2hump_ei",
+        }
+    }
+
+    /// Finds a shaper type by its name, ignoring the case
+    pub fn from_name(name: &str) -> Option<ShaperType> {
+        let name = name.trim().to_ascii_lowercase();
+        Self::ALL.into_iter().find(|shaper| shaper.name() == name)
+    }
+}
+
+impl InputShaper {
+    pub fn new(shaper_type: ShaperType, frequency: f32, damping: f32) -> Self {
+        Self {
+            shaper_type,
+            frequency,
+            damping,
+        }
+    }
+
+    pub fn shaper_type(&self) -> ShaperType {
+        self.shaper_type
+    }
+
+    /// Hz
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn damping(&self) -> f32 {
+        self.damping
+    }
+
+    /// Amplitudes and delays of the impulses, following the formulas of Klipper. Amplitudes add up to one,
+    /// and delays are centered on their weighted average, so the shaped motion is neither ahead nor behind.
+    /// The shaped position at a time is the sum of the planned positions at each delay before it, weighted
+    /// by the amplitudes
+    pub fn impulses(&self) -> Vec<(f32, f32)> {
+        let damped = (1.0 - self.damping.powi(2)).sqrt();
+        let k = (-self.damping * PI / damped).exp();
+        let half_period = 0.5 / (self.frequency * damped);
+
+        let (amplitudes, delays) = match self.shaper_type {
+            ShaperType::Zv => (vec![1.0, k], vec![0.0, half_period]),
+            ShaperType::Mzv => {
+                let k = (-0.75 * self.damping * PI / damped).exp();
+                let a1 = 1.0 - 1.0 / SQRT_2;
+                (
+                    vec![a1, (SQRT_2 - 1.0) * k, a1 * k * k],
+                    vec![0.0, 0.75 * half_period, 1.5 * half_period],
+                )
+            }
+            ShaperType::Ei => {
+                let a1 = 0.25 * (1.0 + EI_VIBRATION_TOLERANCE);
+                let a2 = 0.5 * (1.0 - EI_VIBRATION_TOLERANCE) * k;
+                (
+                    vec![a1, a2, a1 * k * k],
+                    vec![0.0, half_period, 2.0 * half_period],
+                )
+            }
+            ShaperType::TwoHumpEi => {
+                let tolerance = EI_VIBRATION_TOLERANCE.powi(2);
+                let x = (tolerance * ((1.0 - tolerance).sqrt() + 1.0)).cbrt();
+                let a1 = (3.0 * x * x + 2.0 * x + 3.0 * tolerance) / (16.0 * x);
+                let a2 = (0.5 - a1) * k;
+                (
+                    vec![a1, a2, a2 * k, a1 * k.powi(3)],
+                    vec![0.0, half_period, 2.0 * half_period, 3.0 * half_period],
+                )
+            }
+        };
+
+        let total: f32 = amplitudes.iter().sum();
+        let center: f32 = amplitudes
+            .iter()
+            .zip(&delays)
+            .map(|(amplitude, delay)| amplitude * delay)
+            .sum::<f32>()
+            / total;
+        amplitudes
+            .into_iter()
+            .zip(delays)
+            .map(|(amplitude, delay)| (amplitude / total, delay - center))
+            .collect()
+    }
+
+    /// Seconds between the first and the last impulse
+    pub fn duration(&self) -> f32 {
+        let impulses = self.impulses();
+        impulses.last().map_or(0.0, |(_, delay)| *delay) - impulses[0].1
+    }
+
+    /// Portion of the vibration left by the shaper on a resonance at some frequency, with the damping
+    /// of the shaper. Zero means the ringing is gone, one means it's untouched
+    pub fn vibration_ratio(&self, frequency: f32) -> f32 {
+        let omega = TAU * frequency;
+        let damped_omega = omega * (1.0 - self.damping.powi(2)).sqrt();
+        let impulses = self.impulses();
+        let last_delay = impulses.last().map_or(0.0, |(_, delay)| *delay);
+
+        let (sine, cosine) =
+            impulses
+                .iter()
+                .fold((0.0, 0.0), |(sine, cosine), (amplitude, delay)| {
+                    let weight = amplitude * (-self.damping * omega * (last_delay - delay)).exp();
+                    (
+                        sine + weight * (damped_omega * delay).sin(),
+                        cosine + weight * (damped_omega * delay).cos(),
+                    )
+                });
+        sine.hypot(cosine)
+    }
+}
+
+impl InputShapers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.x.is_none() && self.y.is_none()
+    }
+
+    fn impulses(&self) -> impl Iterator<Item = (f32, f32)> {
+        [self.x, self.y]
+            .into_iter()
+            .flatten()
+            .flat_map(|shaper| shaper.impulses())
+    }
+
+    /// Seconds of motion after a point in time the shaped motion depends on
+    pub(crate) fn lookahead(&self) -> f32 {
+        self.impulses().map(|(_, delay)| -delay).fold(0.0, f32::max)
+    }
+
+    /// Seconds of motion before a point in time the shaped motion depends on
+    pub(crate) fn lookbehind(&self) -> f32 {
+        self.impulses().map(|(_, delay)| delay).fold(0.0, f32::max)
+    }
+
+    /// Toolhead location at a time once X and Y are shaped. Z is never shaped
+    pub(crate) fn shaped_location_at(&self, timeline: &MotionTimeline, time: f32) -> Location {
+        let mut location = timeline.location_at(time);
+        if let Some(shaper) = self.x {
+            location.x = shape(&shaper, |time| timeline.location_at(time).x, time);
+        }
+        if let Some(shaper) = self.y {
+            location.y = shape(&shaper, |time| timeline.location_at(time).y, time);
+        }
+        location
+    }
+}
+
+impl ShapingSample {
+    /// Seconds since the first move
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// Planned acceleration of an axis, in mm/s². Only X and Y are sampled
+    pub fn acceleration(&self, axis: Axis) -> f32 {
+        match axis {
+            Axis::X => self.acceleration[0],
+            Axis::Y => self.acceleration[1],
+            Axis::Z | Axis::E => 0.0,
+        }
+    }
+
+    /// Acceleration of an axis once shaped, in mm/s². Only X and Y are sampled
+    pub fn shaped_acceleration(&self, axis: Axis) -> f32 {
+        match axis {
+            Axis::X => self.shaped_acceleration[0],
+            Axis::Y => self.shaped_acceleration[1],
+            Axis::Z | Axis::E => 0.0,
+        }
+    }
+}
+
+impl MotionTimeline {
+    /// Planned acceleration of a cartesian axis at a time, in mm/s². Zero while not moving
+    fn acceleration_at(&self, axis: usize, time: f32) -> f32 {
+        self.moving_phase_at(time).map_or(0.0, |(phase, _)| {
+            phase.direction[axis] * phase.acceleration()
+        })
+    }
+}
+
+/// Value of a shaped signal at a time, out of the signal before shaping
+fn shape(shaper: &InputShaper, signal: impl Fn(f32) -> f32, time: f32) -> f32 {
+    shaper
+        .impulses()
+        .into_iter()
+        .map(|(amplitude, delay)| amplitude * signal(time - delay))
+        .sum()
+}
+
+/// Samples the acceleration of X and Y every `interval` seconds, before and after the shapers.
+/// Sampling starts before the first move and ends after the last one, covering the shaped motion
+pub(crate) fn sample_shaping(
+    segments: impl IntoIterator<Item = PlannedSegment>,
+    shapers: InputShapers,
+    interval: f32,
+) -> Vec<ShapingSample> {
+    let mut timeline = MotionTimeline::default();
+    for segment in segments {
+        timeline.push(&segment, 0.0);
+    }
+
+    let start_time = -shapers.lookahead();
+    let end_time = timeline.end_time() + shapers.lookbehind();
+    let samples = match interval > 0.0 {
+        true => ((end_time - start_time) / interval).ceil() as usize,
+        false => 0,
+    };
+
+    (0..=samples)
+        .map(|sample| {
+            let time = (start_time + sample as f32 * interval).min(end_time);
+            let acceleration = [0, 1].map(|axis| timeline.acceleration_at(axis, time));
+            let shaped_acceleration = [(0, shapers.x), (1, shapers.y)].map(|(axis, shaper)| {
+                let acceleration = |time| timeline.acceleration_at(axis, time);
+                match shaper {
+                    Some(shaper) => shape(&shaper, acceleration, time),
+                    None => acceleration(time),
+                }
+            });
+
+            ShapingSample {
+                time,
+                acceleration,
+                shaped_acceleration,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::motion::{Axis, plan_source};
+
+    use super::{InputShaper, InputShapers, ShaperType, sample_shaping};
+
+    #[test]
+    fn shapers_cancel_their_frequency() {
+        for shaper_type in ShaperType::ALL {
+            let shaper = InputShaper::new(shaper_type, 40.0, 0.1);
+            let impulses = shaper.impulses();
+
+            let total: f32 = impulses.iter().map(|(amplitude, _)| amplitude).sum();
+            assert!((total - 1.0).abs() < 1e-5);
+            let center: f32 = impulses
+                .iter()
+                .map(|(amplitude, delay)| amplitude * delay)
+                .sum();
+            assert!(center.abs() < 1e-6);
+
+            //  Extra insensitive shapers leave a small vibration on purpose, in exchange for a wider band
+            let ratio = shaper.vibration_ratio(40.0);
+            assert!(ratio < 0.06, "{shaper_type:?} leaves {ratio}");
+        }
+
+        //  Away from the tuned frequency, wider shapers keep canceling more
+        let off_tune = |shaper_type| InputShaper::new(shaper_type, 40.0, 0.1).vibration_ratio(48.0);
+        assert!(off_tune(ShaperType::Ei) < off_tune(ShaperType::Zv));
+        assert_eq!(
+            ShaperType::from_name("2HUMP_EI"),
+            Some(ShaperType::TwoHumpEi)
+        );
+    }
+
+    #[test]
+    fn shaped_acceleration_is_smoothed() {
+        let (_, segments) = plan_source("G1 X20 F6000\nG1 X20 Y20\n");
+
+        let shapers = InputShapers {
+            x: Some(InputShaper::new(ShaperType::Mzv, 40.0, 0.1)),
+            y: None,
+        };
+        let samples = sample_shaping(segments, shapers, 0.0005);
+        let peak = |acceleration: fn(&super::ShapingSample) -> f32| {
+            samples
+                .iter()
+                .map(|sample| acceleration(sample).abs())
+                .fold(0.0, f32::max)
+        };
+
+        //  Shaping spreads the acceleration steps of X over time, so the peaks never grow
+        let planned = peak(|sample| sample.acceleration(Axis::X));
+        let shaped = peak(|sample| sample.shaped_acceleration(Axis::X));
+        assert!(shaped <= planned + 1e-3);
+        //  Steps get ramps, so X accelerates while the plan says it doesn't yet
+        assert!(samples.iter().any(|sample| {
+            sample.acceleration(Axis::X) == 0.0 && sample.shaped_acceleration(Axis::X) != 0.0
+        }));
+        //  Y has no shaper
+        assert!(
+            samples
+                .iter()
+                .all(|sample| sample.acceleration(Axis::Y) == sample.shaped_acceleration(Axis::Y))
+        );
+    }
+}
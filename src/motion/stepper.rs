@@ -0,0 +1,240 @@
+use crate::error::PrintResult;
+use crate::system::{Location, ResolvedMove};
+use crate::types::StepCountType;
+
+use super::kinematics::{ActuatorPosition, Kinematics};
+
+pub(crate) const AXIS_COUNT: usize = 4;
+
+/// Axes driven by a stepper motor. E is the extruder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+    E,
+}
+
+/// Abstraction over the hardware that pulses the stepper motors.
+/// The firmware implements it for its drivers, and the crate feeds it with already calibrated step counts
+pub trait StepperDriver {
+    /// Moves every axis the given signed amount of steps, all of them starting and finishing together
+    /// in `duration` seconds
+    fn move_steps(&mut self, steps: &AxisSteps, duration: f32) -> PrintResult<()>;
+}
+
+/// Signed amount of steps for each axis. Negative values move towards the origin
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AxisSteps {
+    steps: [StepCountType; AXIS_COUNT],
+}
+
+/// Amount of steps each axis needs to move a single millimeter. Set with M92
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepsPerUnit {
+    values: [f32; AXIS_COUNT],
+}
+
+/// Turns resolved moves into step counts. The fraction of a step that can't be taken on a move is carried
+/// over to the next one, so the rounding errors never pile up along the print
+#[derive(Debug, Default)]
+pub struct StepConverter {
+    /// Fraction of a step pending for each axis. Always between -0.5 and 0.5
+    residuals: [f64; AXIS_COUNT],
+    /// Sum of every step taken by each axis since the last reset
+    position: [i64; AXIS_COUNT],
+}
+
+impl Axis {
+    pub const ALL: [Axis; AXIS_COUNT] = [Axis::X, Axis::Y, Axis::Z, Axis::E];
+
+    pub(crate) fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl AxisSteps {
+    pub fn new(x: StepCountType, y: StepCountType, z: StepCountType, e: StepCountType) -> Self {
+        Self {
+            steps: [x, y, z, e],
+        }
+    }
+
+    /// Steps for a single axis, leaving the rest still
+    pub fn for_axis(axis: Axis, steps: StepCountType) -> Self {
+        let mut axis_steps = Self::default();
+        axis_steps.steps[axis.index()] = steps;
+        axis_steps
+    }
+
+    pub fn get(&self, axis: Axis) -> StepCountType {
+        self.steps[axis.index()]
+    }
+
+    /// True if no axis needs to move
+    pub fn is_empty(&self) -> bool {
+        self.steps.iter().all(|steps| *steps == 0)
+    }
+}
+
+impl Default for StepsPerUnit {
+    /// Common values for a cartesian printer with 16 microsteps
+    fn default() -> Self {
+        Self {
+            values: [80.0, 80.0, 400.0, 93.0],
+        }
+    }
+}
+
+impl StepsPerUnit {
+    pub fn get(&self, axis: Axis) -> f32 {
+        self.values[axis.index()]
+    }
+
+    pub(crate) fn set(&mut self, axis: Axis, value: f32) {
+        self.values[axis.index()] = value;
+    }
+}
+
+impl StepConverter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Converts a move into the steps each actuator needs to take, carrying over the fractions left behind.
+    /// X, Y and Z steps go to the actuators A, B and C of the kinematics
+    pub fn convert(
+        &mut self,
+        resolved_move: &ResolvedMove,
+        kinematics: &impl Kinematics,
+        steps_per_unit: &StepsPerUnit,
+    ) -> PrintResult<AxisSteps> {
+        let start = kinematics.to_actuators(&resolved_move.start())?;
+        let end = kinematics.to_actuators(&resolved_move.end())?;
+        //  Deltas are computed in f64, where subtracting two f32 values is exact. This way the deltas of
+        //  consecutive moves add up exactly to the total travel
+        let deltas = [
+            end.a as f64 - start.a as f64,
+            end.b as f64 - start.b as f64,
+            end.c as f64 - start.c as f64,
+            resolved_move.extrusion() as f64,
+        ];
+
+        let mut steps = AxisSteps::default();
+        for axis in Axis::ALL {
+            let index = axis.index();
+            let exact_steps =
+                self.residuals[index] + deltas[index] * steps_per_unit.get(axis) as f64;
+            let taken_steps = exact_steps.round();
+
+            self.residuals[index] = exact_steps - taken_steps;
+            self.position[index] += taken_steps as i64;
+            steps.steps[index] = taken_steps as StepCountType;
+        }
+
+        Ok(steps)
+    }
+
+    /// Location of the toolhead according to the steps actually taken, rather than the one requested by the gcode
+    pub fn reported_location(
+        &self,
+        kinematics: &impl Kinematics,
+        steps_per_unit: &StepsPerUnit,
+    ) -> PrintResult<Location> {
+        let [a, b, c] = [Axis::X, Axis::Y, Axis::Z].map(|axis| {
+            (self.position[axis.index()] as f64 / steps_per_unit.get(axis) as f64) as f32
+        });
+
+        kinematics.to_cartesian(&ActuatorPosition::new(a, b, c))
+    }
+
+    /// Drops the pending fractions and sets the actuators at a known position. Used after homing
+    pub fn reset(&mut self, actuators: &ActuatorPosition, steps_per_unit: &StepsPerUnit) {
+        self.residuals = [0.0; AXIS_COUNT];
+        self.position = [
+            (actuators.a * steps_per_unit.get(Axis::X)).round() as i64,
+            (actuators.b * steps_per_unit.get(Axis::Y)).round() as i64,
+            (actuators.c * steps_per_unit.get(Axis::Z)).round() as i64,
+            0,
+        ];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::GcodeReader;
+    use crate::motion::{Axis, CoreXYKinematics, StepConverter, StepsPerUnit};
+    use crate::system::SystemConfig;
+
+    #[test]
+    fn fractional_steps_are_not_lost() {
+        let mut config = SystemConfig::default();
+        let mut converter = StepConverter::new();
+        let mut total_x = 0;
+        let mut total_e = 0;
+
+        //  With 80 steps/mm, 0.0123 mm is 0.984 steps. A thousand of them add up to 984 steps
+        let mut source = String::from("M83\n");
+        for move_number in 1..=1000 {
+            source.push_str(&format!("G1 X{:.4} E0.0123\n", move_number as f32 * 0.0123));
+        }
+        for line in GcodeReader::new(source.as_bytes()) {
+            let Some(command) = line.unwrap().into_command() else {
+                continue;
+            };
+            for resolved_move in config.apply_command(&command).unwrap() {
+                let steps = converter
+                    .convert(&resolved_move, config.kinematics(), config.steps_per_unit())
+                    .unwrap();
+                total_x += steps.get(Axis::X);
+                total_e += steps.get(Axis::E);
+            }
+        }
+
+        let expected_x = (config.current_location().x() * 80.0).round() as i32;
+        assert_eq!(total_x, expected_x);
+        assert_eq!(total_e, (12.3_f32 * 93.0).round() as i32);
+    }
+
+    #[test]
+    fn steps_per_unit_from_m92() {
+        let mut config = SystemConfig::default();
+        let source = "M92 X100 E415.5\n";
+        for line in GcodeReader::new(source.as_bytes()) {
+            config
+                .apply_command(&line.unwrap().into_command().unwrap())
+                .unwrap();
+        }
+
+        let steps_per_unit = config.steps_per_unit();
+        assert_eq!(steps_per_unit.get(Axis::X), 100.0);
+        assert_eq!(
+            steps_per_unit.get(Axis::Y),
+            StepsPerUnit::default().get(Axis::Y)
+        );
+        assert_eq!(steps_per_unit.get(Axis::E), 415.5);
+    }
+
+    #[test]
+    fn corexy_steps_and_reported_location() {
+        let mut config = SystemConfig::default();
+        let mut converter = StepConverter::new();
+        let source = "G1 X10 Y5\nG1 X20.0125 Y-3.333\n";
+        for line in GcodeReader::new(source.as_bytes()) {
+            let command = line.unwrap().into_command().unwrap();
+            let resolved_move = config.apply_command(&command).unwrap().remove(0);
+            let steps = converter
+                .convert(&resolved_move, &CoreXYKinematics, config.steps_per_unit())
+                .unwrap();
+            if resolved_move.start().x() == 0.0 {
+                //  A moves X + Y and B moves X - Y, at 80 steps/mm
+                assert_eq!((steps.get(Axis::X), steps.get(Axis::Y)), (1200, 400));
+            }
+        }
+
+        let reported = converter
+            .reported_location(&CoreXYKinematics, config.steps_per_unit())
+            .unwrap();
+        assert!(reported.distance_to(&config.current_location()) < 1.0 / 80.0);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+
+use crate::system::{Location, ResolvedMove};
+
+use super::advance::PressureAdvance;
+use super::planner::PlannedSegment;
+use super::shaper::InputShapers;
+
+/// Part of a planned segment moved with a constant acceleration, placed on the timeline of the job
+#[derive(Debug, Clone, Copy)]
+pub(super) struct TimelinePhase {
+    pub(super) start_time: f32,
+    pub(super) duration: f32,
+    /// Toolhead speed at the start, in mm/s
+    pub(super) start_speed: f32,
+    /// Toolhead speed at the end, in mm/s
+    pub(super) end_speed: f32,
+    pub(super) start_location: Location,
+    /// Millimeters each cartesian axis moves per millimeter of path. Zero for moves of the extruder alone
+    pub(super) direction: [f32; 3],
+    /// Millimeters of filament per millimeter of path. Negative for retractions
+    pub(super) ratio: f32,
+    /// Pressure advance factor of the phase. Zero for moves it doesn't apply to, like travels and retractions
+    pub(super) factor: f32,
+    /// Nominal filament position at the start, in millimeters
+    pub(super) start_position: f32,
+    /// Integral of the advance over time up to the start, before smoothing. The smoothed advance is
+    /// the difference of this integral across the window, divided by the window
+    pub(super) start_integral: f32,
+}
+
+/// Planned segments laid out on time. Both the pressure advance and the input shapers look at the
+/// motion around a point in time, so they're found from here
+#[derive(Debug, Default)]
+pub(crate) struct MotionTimeline {
+    pub(super) phases: VecDeque<TimelinePhase>,
+    end_time: f32,
+    end_location: Location,
+    end_position: f32,
+    end_integral: f32,
+}
+
+/// Segments on their way to the driver, held back until the motion after them is known well enough
+/// to apply the pressure advance and the input shapers to them
+#[derive(Debug, Default)]
+pub(crate) struct MotionQueue {
+    timeline: MotionTimeline,
+    /// Segments not sent to the driver yet, along with the time they start at
+    pending: VecDeque<(PlannedSegment, f32)>,
+    pressure_advance: PressureAdvance,
+    shapers: InputShapers,
+    /// Filament already pushed ahead, in millimeters
+    applied: f32,
+    /// Where the shaped motion left the toolhead. None until a segment is sent
+    shaped_location: Option<Location>,
+    /// Last segment released. The machine settles at its end
+    last_move: Option<ResolvedMove>,
+}
+
+impl TimelinePhase {
+    pub(super) fn end_time(&self) -> f32 {
+        self.start_time + self.duration
+    }
+
+    /// Toolhead speed some time after the start of the phase
+    pub(super) fn speed_at(&self, elapsed: f32) -> f32 {
+        let elapsed = elapsed.clamp(0.0, self.duration);
+        self.start_speed + (self.end_speed - self.start_speed) * elapsed / self.duration
+    }
+
+    /// Constant toolhead acceleration along the phase, in mm/s². Negative when decelerating
+    pub(super) fn acceleration(&self) -> f32 {
+        (self.end_speed - self.start_speed) / self.duration
+    }
+
+    /// Toolhead distance moved some time after the start of the phase
+    pub(super) fn distance_at(&self, elapsed: f32) -> f32 {
+        let elapsed = elapsed.clamp(0.0, self.duration);
+        (self.start_speed + self.speed_at(elapsed)) / 2.0 * elapsed
+    }
+
+    fn location_at(&self, elapsed: f32) -> Location {
+        let distance = self.distance_at(elapsed);
+        let [x, y, z] = self.direction;
+        Location::new(
+            self.start_location.x + x * distance,
+            self.start_location.y + y * distance,
+            self.start_location.z + z * distance,
+        )
+    }
+
+    pub(super) fn position_at(&self, elapsed: f32) -> f32 {
+        self.start_position + self.ratio * self.distance_at(elapsed)
+    }
+}
+
+impl MotionTimeline {
+    /// Places the phases of a segment after the last one. Returns the time the segment starts at.
+    /// Like Klipper, only moves extruding along the toolhead path are advanced
+    pub(crate) fn push(&mut self, segment: &PlannedSegment, factor: f32) -> f32 {
+        let segment_start = self.end_time;
+        let resolved_move = segment.resolved_move();
+        let (start, end) = (resolved_move.start(), resolved_move.end());
+        let advanced = resolved_move.extrusion() > 0.0 && (start.x, start.y) != (end.x, end.y);
+        let direction = [end.x - start.x, end.y - start.y, end.z - start.z]
+            .map(|delta| delta / segment.length());
+
+        for phase in segment.phases() {
+            let distance = phase.start_distance();
+            let start_location = Location::new(
+                start.x + direction[0] * distance,
+                start.y + direction[1] * distance,
+                start.z + direction[2] * distance,
+            );
+            let phase = TimelinePhase {
+                start_time: self.end_time,
+                duration: phase.duration(),
+                start_speed: phase.start_speed(),
+                end_speed: phase.end_speed(),
+                start_location,
+                direction,
+                ratio: resolved_move.extrusion() / segment.length(),
+                factor: if advanced { factor } else { 0.0 },
+                start_position: self.end_position,
+                start_integral: self.end_integral,
+            };
+            self.end_time = phase.end_time();
+            self.end_position = phase.position_at(phase.duration);
+            self.end_integral = phase.integral_at(phase.duration);
+            self.phases.push_back(phase);
+        }
+        //  Ends are kept exact, without the rounding errors of the phases
+        self.end_location = end;
+
+        segment_start
+    }
+
+    /// Time the last phase ends at, in seconds
+    pub(crate) fn end_time(&self) -> f32 {
+        self.end_time
+    }
+
+    /// Phase started most recently at a time, along with the time elapsed since its start.
+    /// None before the first phase
+    pub(super) fn phase_at(&self, time: f32) -> Option<(&TimelinePhase, f32)> {
+        self.phases
+            .iter()
+            .rev()
+            .find(|phase| phase.start_time <= time)
+            .map(|phase| (phase, time - phase.start_time))
+    }
+
+    /// Same as `phase_at()`, but only while the phase is still going on
+    pub(super) fn moving_phase_at(&self, time: f32) -> Option<(&TimelinePhase, f32)> {
+        self.phase_at(time)
+            .filter(|(phase, elapsed)| *elapsed < phase.duration)
+    }
+
+    /// Nominal toolhead location at a time
+    pub(crate) fn location_at(&self, time: f32) -> Location {
+        if time >= self.end_time {
+            return self.end_location;
+        }
+
+        match self.phase_at(time) {
+            Some((phase, elapsed)) => phase.location_at(elapsed),
+            None => self
+                .phases
+                .front()
+                .map_or(self.end_location, |phase| phase.start_location),
+        }
+    }
+
+    /// Nominal filament position at a time, in millimeters
+    pub(crate) fn position_at(&self, time: f32) -> f32 {
+        match self.phase_at(time) {
+            Some((phase, elapsed)) => phase.position_at(elapsed),
+            None => self
+                .phases
+                .front()
+                .map_or(0.0, |phase| phase.start_position),
+        }
+    }
+
+    /// Nominal extrusion speed at a time, in mm/s of filament. Zero once the last phase is over
+    pub(crate) fn nominal_speed_at(&self, time: f32) -> f32 {
+        self.moving_phase_at(time).map_or(0.0, |(phase, elapsed)| {
+            phase.ratio * phase.speed_at(elapsed)
+        })
+    }
+
+    /// Drops the phases over before a time. The timeline can't be asked about them anymore
+    pub(crate) fn trim(&mut self, time: f32) {
+        while self
+            .phases
+            .front()
+            .is_some_and(|phase| phase.end_time() < time)
+        {
+            self.phases.pop_front();
+        }
+    }
+}
+
+impl MotionQueue {
+    /// True while segments are held back, or the motion sent differs from the nominal one
+    pub(crate) fn is_active(&self) -> bool {
+        !self.pending.is_empty() || self.applied != 0.0 || self.shaped_location.is_some()
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        segment: PlannedSegment,
+        pressure_advance: PressureAdvance,
+        shapers: InputShapers,
+    ) {
+        self.pressure_advance = pressure_advance;
+        self.shapers = shapers;
+        let start_time = self.timeline.push(&segment, pressure_advance.factor());
+        self.pending.push_back((segment, start_time));
+    }
+
+    /// Seconds of motion needed after a point in time to find the motion sent at that time
+    fn lookahead(&self) -> f32 {
+        self.advance_window().max(self.shapers.lookahead())
+    }
+
+    /// Seconds of motion needed before a point in time to find the motion sent at that time
+    fn lookbehind(&self) -> f32 {
+        self.advance_window().max(self.shapers.lookbehind())
+    }
+
+    /// Half the smoothing window of the pressure advance, which reaches as far on both sides
+    fn advance_window(&self) -> f32 {
+        match self.pressure_advance.is_enabled() {
+            true => self.pressure_advance.smooth_time() / 2.0,
+            false => 0.0,
+        }
+    }
+
+    /// Takes the segments whose motion is known, which are the ones followed by enough motion for
+    /// the pressure advance and the input shapers. Flushing takes every segment
+    pub(crate) fn release(&mut self, flush: bool) -> Vec<(PlannedSegment, f32)> {
+        let (lookahead, lookbehind) = (self.lookahead(), self.lookbehind());
+        let mut released = vec![];
+
+        while let Some((segment, start_time)) = self.pending.front() {
+            if !flush && self.timeline.end_time() < start_time + segment.duration() + lookahead {
+                break;
+            }
+            self.timeline.trim(start_time - lookbehind);
+            released.extend(self.pending.pop_front());
+        }
+
+        if let Some((segment, _)) = released.last() {
+            self.last_move = Some(segment.resolved_move().clone());
+        }
+        released
+    }
+
+    /// Turns a piece of a released segment, ending at a time of the timeline, into the piece actually
+    /// sent: the toolhead follows the shaped motion, and the extruder pushes the filament ahead
+    pub(crate) fn adjust(&mut self, piece: ResolvedMove, time: f32) -> ResolvedMove {
+        let piece = match self.shapers.is_empty() {
+            true => piece,
+            false => {
+                let start = self.shaped_location.unwrap_or(piece.start());
+                let end = self.shapers.shaped_location_at(&self.timeline, time);
+                self.shaped_location = Some(end);
+                piece.with_ends(start, end)
+            }
+        };
+
+        let advance = self
+            .timeline
+            .advance_at(time, self.pressure_advance.smooth_time());
+        let extra = advance - self.applied;
+        self.applied = advance;
+        match extra != 0.0 {
+            true => piece.with_extrusion(piece.extrusion() + extra),
+            false => piece,
+        }
+    }
+
+    /// Move bringing the toolhead and the extruder to where the job left them once every segment was sent,
+    /// along with its duration. The machine comes to a stop, so the timeline starts over
+    pub(crate) fn settle(&mut self) -> Option<(ResolvedMove, f32)> {
+        let duration = self.lookbehind();
+        let applied = std::mem::take(&mut self.applied);
+        let shaped_location = self.shaped_location.take();
+        self.timeline = MotionTimeline::default();
+        let last_move = self.last_move.take()?;
+
+        let end = last_move.end();
+        let start = shaped_location.unwrap_or(end);
+        (applied != 0.0 || start != end).then(|| {
+            let settle = last_move.with_ends(start, end).with_extrusion(-applied);
+            (settle, duration)
+        })
+    }
+
+    /// Drops the segments held back, along with any adjustment. Returns the amount of segments dropped
+    pub(crate) fn clear(&mut self) -> usize {
+        let dropped = self.pending.len();
+        *self = Self {
+            pressure_advance: self.pressure_advance,
+            shapers: self.shapers,
+            ..Self::default()
+        };
+        dropped
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::error::PrintResult;
+use crate::gcode::{G1Move, GcodeCommand, GcodeLine, GcodeWriter};
+use crate::system::{Location, SystemConfig};
+use crate::types::{ExtrudeAmountType, FeedrateAmountType, LocationType};
+
+/// Extrusion per millimeter of moves merged together can differ this much, relative to the larger one.
+/// Slicers round E to a few decimals, so the short moves of a straight line never match exactly
+const EXTRUSION_RATE_TOLERANCE: f32 = 0.01;
+
+/// What the optimizer did to a job
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizationReport {
+    lines_read: usize,
+    lines_written: usize,
+    /// Commands dropped because they had no effect, like moves going nowhere or repeated modes
+    removed_commands: usize,
+    /// Moves folded into the move before them
+    merged_moves: usize,
+}
+
+/// State of the machine as left by the lines written so far. None while it can't be known,
+/// like at the start of the job, where the modes left by the previous job still hold
+#[derive(Debug, Default, Clone)]
+struct WrittenState {
+    /// Millimeters per minute
+    feedrate: Option<FeedrateAmountType>,
+    inches: Option<bool>,
+    relative_positioning: Option<bool>,
+    relative_extrusion: Option<bool>,
+    /// Speed of each fan, by the index given with P
+    fan_speeds: HashMap<u8, f32>,
+}
+
+/// Straight moves going the same way, waiting to be written as a single move
+#[derive(Debug, Clone)]
+struct PendingMove {
+    command: G1Move,
+    travel: bool,
+    comment: Option<String>,
+    start: Location,
+    end: Location,
+    /// Ends of every move folded so far but the last one
+    corners: Vec<Location>,
+    extrusion: ExtrudeAmountType,
+    /// Millimeters per minute
+    feedrate: FeedrateAmountType,
+}
+
+/// Rewrites a job into fewer lines with the same motion. Commands with no effect are dropped: moves that
+/// don't go anywhere, modes and fan speeds already set, and feedrates the machine already moves at.
+/// Consecutive straight moves are merged while every corner between them is within `tolerance`
+/// millimeters of the merged move, and they extrude at the same rate. Comments and lines the
+/// optimizer doesn't know stay where they were, and moves are never merged across them.
+/// Comments of the commands dropped go with them
+pub fn optimize_job<W: Write>(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    writer: &mut GcodeWriter<W>,
+    tolerance: LocationType,
+) -> PrintResult<OptimizationReport> {
+    let mut optimizer = JobOptimizer::new(tolerance);
+
+    for line in lines {
+        let line = line?;
+        optimizer.report.lines_read += 1;
+        match line.command() {
+            Some(command) => optimizer
+                .optimize_command(command, line.comment(), writer)
+                .map_err(|error| error.in_line(line.line_number()))?,
+            None => {
+                optimizer.flush(writer)?;
+                optimizer.write(writer, |writer| writer.write_line(&line))?;
+            }
+        }
+    }
+    optimizer.flush(writer)?;
+
+    Ok(optimizer.report)
+}
+
+impl OptimizationReport {
+    pub fn lines_read(&self) -> usize {
+        self.lines_read
+    }
+
+    pub fn lines_written(&self) -> usize {
+        self.lines_written
+    }
+
+    /// Commands dropped because they had no effect, like moves going nowhere or repeated modes
+    pub fn removed_commands(&self) -> usize {
+        self.removed_commands
+    }
+
+    /// Moves folded into the move before them
+    pub fn merged_moves(&self) -> usize {
+        self.merged_moves
+    }
+}
+
+struct JobOptimizer {
+    tolerance: LocationType,
+    /// State of the original job
+    state: SystemConfig,
+    written: WrittenState,
+    pending: Option<PendingMove>,
+    report: OptimizationReport,
+}
+
+impl JobOptimizer {
+    fn new(tolerance: LocationType) -> Self {
+        Self {
+            tolerance,
+            state: SystemConfig::default(),
+            written: WrittenState::default(),
+            pending: None,
+            report: OptimizationReport::default(),
+        }
+    }
+
+    fn optimize_command<W: Write>(
+        &mut self,
+        command: &GcodeCommand,
+        comment: Option<&str>,
+        writer: &mut GcodeWriter<W>,
+    ) -> PrintResult<()> {
+        if self.has_no_effect(command) {
+            self.report.removed_commands += 1;
+            return Ok(());
+        }
+
+        //  Units and modes change how the pending move is written, so it goes out before the state follows them
+        if !matches!(command, GcodeCommand::G0(_) | GcodeCommand::G1(_)) {
+            self.flush(writer)?;
+        }
+
+        let start = self.state.current_location();
+        let start_extrusion = self.state.extruder_position();
+        if command.changes_position() {
+            self.state.apply_command(command)?;
+        }
+
+        match command {
+            GcodeCommand::G0(linear_move) | GcodeCommand::G1(linear_move) => {
+                let next = PendingMove {
+                    command: linear_move.clone(),
+                    travel: matches!(command, GcodeCommand::G0(_)),
+                    comment: comment.map(str::to_string),
+                    start,
+                    end: self.state.current_location(),
+                    corners: vec![],
+                    extrusion: self.state.extruder_position() - start_extrusion,
+                    feedrate: self.state.feedrate(),
+                };
+                //  Moves only setting the feedrate hand it over to the next move written
+                if next.start == next.end && next.extrusion == 0.0 {
+                    self.report.removed_commands += 1;
+                    return Ok(());
+                }
+
+                match self.pending.as_mut() {
+                    Some(pending) if pending.can_merge(&next, self.tolerance) => {
+                        pending.merge(
+                            next,
+                            self.state.is_relative_positioning(),
+                            self.state.is_relative_extrusion(),
+                        );
+                        self.report.merged_moves += 1;
+                    }
+                    _ => {
+                        self.flush(writer)?;
+                        self.pending = Some(next);
+                    }
+                }
+                Ok(())
+            }
+            GcodeCommand::G2(arc_move) | GcodeCommand::G3(arc_move) => {
+                let mut arc_move = arc_move.clone();
+                arc_move.feedrate_per_minute = self.written_feedrate(self.state.feedrate());
+                let arc = match command {
+                    GcodeCommand::G2(_) => GcodeCommand::G2(arc_move),
+                    _ => GcodeCommand::G3(arc_move),
+                };
+                self.write(writer, |writer| writer.write_command(&arc, comment))
+            }
+            command => {
+                self.written.follow(command, &self.state);
+                self.write(writer, |writer| writer.write_command(command, comment))
+            }
+        }
+    }
+
+    /// True if the command leaves the machine as it was, like modes already set or fans already at its speed
+    fn has_no_effect(&self, command: &GcodeCommand) -> bool {
+        let written = &self.written;
+        match command {
+            GcodeCommand::G20 => written.inches == Some(true),
+            GcodeCommand::G21 => written.inches == Some(false),
+            GcodeCommand::G90 => {
+                written.relative_positioning == Some(false)
+                    && written.relative_extrusion == Some(false)
+            }
+            GcodeCommand::G91 => {
+                written.relative_positioning == Some(true)
+                    && written.relative_extrusion == Some(true)
+            }
+            GcodeCommand::M82 => written.relative_extrusion == Some(false),
+            GcodeCommand::M83 => written.relative_extrusion == Some(true),
+            GcodeCommand::Passthrough(..) => match fan_speed(command) {
+                Some((fan, speed)) => written.fan_speeds.get(&fan) == Some(&speed),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// F a written move at the feedrate needs, in the units of the job. None if the machine already moves at it
+    fn written_feedrate(&mut self, feedrate: FeedrateAmountType) -> Option<FeedrateAmountType> {
+        if self.written.feedrate == Some(feedrate) || feedrate <= 0.0 {
+            return None;
+        }
+
+        self.written.feedrate = Some(feedrate);
+        Some(feedrate / self.state.to_millimeters(1.0))
+    }
+
+    /// Writes the pending move, if any
+    fn flush<W: Write>(&mut self, writer: &mut GcodeWriter<W>) -> PrintResult<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        let mut linear_move = pending.command;
+        linear_move.feedrate_per_minute = self.written_feedrate(pending.feedrate);
+        let command = match pending.travel {
+            true => GcodeCommand::G0(linear_move),
+            false => GcodeCommand::G1(linear_move),
+        };
+
+        self.write(writer, |writer| {
+            writer.write_command(&command, pending.comment.as_deref())
+        })
+    }
+
+    fn write<W: Write>(
+        &mut self,
+        writer: &mut GcodeWriter<W>,
+        write: impl FnOnce(&mut GcodeWriter<W>) -> PrintResult<()>,
+    ) -> PrintResult<()> {
+        self.report.lines_written += 1;
+        write(writer)
+    }
+}
+
+impl WrittenState {
+    /// Takes what a written command leaves set. Tool changes and extended commands can run macros
+    /// doing anything, so nothing is known after them
+    fn follow(&mut self, command: &GcodeCommand, state: &SystemConfig) {
+        match command {
+            GcodeCommand::G20 | GcodeCommand::G21 => self.inches = Some(state.is_inches()),
+            GcodeCommand::G90 | GcodeCommand::G91 => {
+                self.relative_positioning = Some(state.is_relative_positioning());
+                self.relative_extrusion = Some(state.is_relative_extrusion());
+            }
+            GcodeCommand::M82 | GcodeCommand::M83 => {
+                self.relative_extrusion = Some(state.is_relative_extrusion())
+            }
+            //  Homing moves at its own feedrate, which firmwares don't always restore
+            GcodeCommand::G28(_) => self.feedrate = None,
+            GcodeCommand::Extended(_) => *self = Self::default(),
+            GcodeCommand::Passthrough(..) if command.tool_change().is_some() => {
+                *self = Self::default()
+            }
+            GcodeCommand::Passthrough(..) => {
+                if let Some((fan, speed)) = fan_speed(command) {
+                    self.fan_speeds.insert(fan, speed);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl PendingMove {
+    /// True if the next move goes on along the same straight line, at the same feedrate and extrusion rate.
+    /// Moves commented otherwise than the first one are never merged, since the comment would be lost
+    fn can_merge(&self, next: &PendingMove, tolerance: LocationType) -> bool {
+        let length = self.start.distance_to(&self.end);
+        let next_length = next.start.distance_to(&next.end);
+        if self.travel != next.travel
+            || self.feedrate != next.feedrate
+            || (next.comment.is_some() && next.comment != self.comment)
+            || self.command.laser_power.is_some()
+            || next.command.laser_power.is_some()
+            || length <= 0.0
+            || next_length <= 0.0
+        {
+            return false;
+        }
+
+        let rate = self.extrusion / length;
+        let next_rate = next.extrusion / next_length;
+        if (rate - next_rate).abs() > EXTRUSION_RATE_TOLERANCE * rate.abs().max(next_rate.abs()) {
+            return false;
+        }
+
+        //  Every corner must be close to the merged move, and come in the same order along it
+        let mut last_position = 0.0;
+        self.corners.iter().chain([&self.end]).all(|corner| {
+            match project(&self.start, &next.end, corner) {
+                Some((position, distance)) if position > last_position && position < 1.0 => {
+                    last_position = position;
+                    distance <= tolerance
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Folds the next move in. Values given by any of the moves are written, taking the last
+    /// value given in absolute mode, and adding the values up in relative mode
+    fn merge(&mut self, next: PendingMove, relative_positioning: bool, relative_extrusion: bool) {
+        let targets = [
+            (
+                &mut self.command.x_target,
+                next.command.x_target,
+                relative_positioning,
+            ),
+            (
+                &mut self.command.y_target,
+                next.command.y_target,
+                relative_positioning,
+            ),
+            (
+                &mut self.command.z_target,
+                next.command.z_target,
+                relative_positioning,
+            ),
+            (
+                &mut self.command.amount_to_extrude,
+                next.command.amount_to_extrude,
+                relative_extrusion,
+            ),
+        ];
+        for (target, next_target, relative) in targets {
+            *target = match (*target, next_target) {
+                (Some(value), Some(next_value)) if relative => Some(value + next_value),
+                (value, next_value) => next_value.or(value),
+            };
+        }
+
+        self.corners.push(self.end);
+        self.end = next.end;
+        self.extrusion += next.extrusion;
+    }
+}
+
+/// Position of a point along the line from `start` to `end`, from 0 at the start to 1 at the end,
+/// and its distance to the line. None if both ends are the same
+fn project(start: &Location, end: &Location, point: &Location) -> Option<(f32, LocationType)> {
+    let direction = [end.x - start.x, end.y - start.y, end.z - start.z];
+    let offset = [point.x - start.x, point.y - start.y, point.z - start.z];
+    let length_squared: f32 = direction.iter().map(|value| value * value).sum();
+    if length_squared <= 0.0 {
+        return None;
+    }
+
+    let position = direction
+        .iter()
+        .zip(offset)
+        .map(|(direction, offset)| direction * offset)
+        .sum::<f32>()
+        / length_squared;
+    let distance = direction
+        .iter()
+        .zip(offset)
+        .map(|(direction, offset)| (offset - direction * position).powi(2))
+        .sum::<f32>()
+        .sqrt();
+
+    Some((position, distance))
+}
+
+/// Fan and speed set by M106 or M107. P picks the fan, the first one if left out
+fn fan_speed(command: &GcodeCommand) -> Option<(u8, f32)> {
+    let fan = command.parameter_value('P').unwrap_or_default() as u8;
+    match command.name() {
+        "M106" => Some((
+            fan,
+            command
+                .parameter_value('S')
+                .map_or(255.0, |speed| speed.clamp(0.0, 255.0)),
+        )),
+        "M107" => Some((fan, 0.0)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::JobStats;
+    use crate::error::PrintResult;
+    use crate::gcode::{GcodeReader, rewrite_source};
+    use crate::optimize::{OptimizationReport, optimize_job};
+    use crate::system::SystemConfig;
+
+    fn optimize_source(source: &str, tolerance: f32) -> PrintResult<(String, OptimizationReport)> {
+        rewrite_source(source, |lines, writer| {
+            optimize_job(lines, writer, tolerance)
+        })
+    }
+
+    #[test]
+    fn drop_commands_without_effect() {
+        let source = "G90\nM83\nG1 F1200\nG1 X10 Y0 F1200.000 E1\nG1 F1200\nG1 X10 Y0\n\
+                      G1 X10 Y10 E1\nM106 S255\nM106\nM107\nM107\nG90\nM82\nG0 X0 F3000\n";
+        let (optimized, report) = optimize_source(source, 0.01).unwrap();
+
+        assert_eq!(
+            optimized,
+            "G90\nM83\nG1 X10 Y0 E1 F1200\nG1 X10 Y10 E1\nM106 S255\nM107\nG90\nG0 X0 F3000\n"
+        );
+        assert_eq!(report.lines_read(), 14);
+        assert_eq!(report.lines_written(), 8);
+        assert_eq!(report.removed_commands(), 6);
+        assert_eq!(report.merged_moves(), 0);
+    }
+
+    #[test]
+    fn merge_collinear_moves() {
+        let source = "M83\nG1 X0 Y5 F600\nG1 X10 Y5 E1\nG1 X20 Y5.005 E1\nG1 X30 Y5 E1.002\n\
+                      G1 X30 Y15 E1\nG1 X30 Y25 E2\nG1 X30 Y35 E1 ; wall\nG1 X30 Y45 E1\n\
+                      G1 X30 Y55 E1 ; wall\nG1 X30 Y65 E1 ; infill\n";
+        let (optimized, report) = optimize_source(source, 0.01).unwrap();
+
+        //  Corners, travels and moves extruding at other rates are kept apart.
+        //  Comments stay with the first move merged
+        assert_eq!(
+            optimized,
+            "M83\nG1 X0 Y5 F600\nG1 X30 Y5 E3.002\nG1 X30 Y15 E1\nG1 X30 Y25 E2\n\
+             G1 X30 Y55 E3 ; wall\nG1 X30 Y65 E1 ; infill\n"
+        );
+        assert_eq!(report.merged_moves(), 4);
+
+        let (optimized, _) = optimize_source(source, 0.001).unwrap();
+        assert!(optimized.contains("G1 X10 Y5 E1\nG1 X20 Y5.005 E1\nG1 X30 Y5 E1.002\n"));
+
+        //  Absolute extrusion keeps the last value, and going back along the line is never merged
+        let (optimized, _) =
+            optimize_source("M82\nG1 X10 E1\nG1 X20 E2\nG1 X15 E2.5\n", 0.01).unwrap();
+        assert_eq!(optimized, "M82\nG1 X20 E2\nG1 X15 E2.5\n");
+    }
+
+    #[test]
+    fn optimized_job_moves_the_same() {
+        let mut source = String::from("G90\nM83\nG28\nM107\nG1 Z0.2 F600\n");
+        for layer in 0..3 {
+            source.push_str(&format!(";LAYER:{layer}\nG1 F1800\n"));
+            for step in 0..20 {
+                source.push_str(&format!("G1 X{} Y20 E0.1\n", 20 + step));
+            }
+            source.push_str("G1 F1800\nG1 X40 Y40 E0.5\nM106 S200\nM106 S200\nG1 F1800\n");
+            for step in 0..20 {
+                source.push_str(&format!("G1 X{} Y40 E0.1\n", 40 - step));
+            }
+            source.push_str(&format!("G0 Z{} F600\n", 0.4 + 0.2 * layer as f32));
+        }
+        let (optimized, report) = optimize_source(&source, 0.01).unwrap();
+        assert!(report.lines_written() < report.lines_read() / 4);
+
+        let config = SystemConfig::default();
+        let original = JobStats::from_lines(GcodeReader::new(source.as_bytes()), &config).unwrap();
+        let optimized =
+            JobStats::from_lines(GcodeReader::new(optimized.as_bytes()), &config).unwrap();
+        assert!((original.filament() - optimized.filament()).abs() < 1e-4);
+        assert!((original.duration() - optimized.duration()).abs() < 1e-3);
+        assert_eq!(original.bounds(), optimized.bounds());
+        assert_eq!(original.layers().len(), optimized.layers().len());
+    }
+}
@@ -1,26 +1,507 @@
+use crate::error::{Error, PrintResult};
+use crate::heater::HeaterKind;
+use crate::motion::Axis;
 use crate::types::{ExtrudeAmountType, FeedrateAmountType, LocationType, PowerType};
-use std::fmt::Debug;
 
-pub(super) trait GcodeExecutable: Debug {}
+/// Any command recognized by the parser, ready to be handed over to the executor
+#[derive(Debug, Clone, PartialEq)]
+pub enum GcodeCommand {
+    /// Rapid move
+    G0(G0Move),
+    /// Linear move
+    G1(G1Move),
+    /// Clockwise arc
+    G2(G2ArcMove),
+    /// Counterclockwise arc
+    G3(G3ArcMove),
+    /// Set units to inches
+    G20,
+    /// Auto home
+    G28(G28Home),
+    /// Probe the bed mesh
+    G29(G29ProbeMesh),
+    /// Set units to millimeters
+    G21,
+    /// Absolute positioning, extruder included
+    G90,
+    /// Relative positioning, extruder included
+    G91,
+    /// Set position
+    G92(G92SetPosition),
+    /// Absolute extruder positioning
+    M82,
+    /// Relative extruder positioning
+    M83,
+    /// Set axis steps per unit
+    M92(M92StepsPerUnit),
+    /// Emergency stop. Kills the job as soon as it's read, ahead of anything already queued
+    M112,
+    /// Set max acceleration per axis
+    M201(M201MaxAcceleration),
+    /// Set max feedrate per axis
+    M203(M203MaxFeedrate),
+    /// Set starting acceleration for print, retract and travel moves
+    M204(M204Acceleration),
+    /// Set jerk, junction deviation and minimum feedrates
+    M205(M205AdvancedSettings),
+    /// Quick stop. Drops every queued move as soon as it's read, but the job stays alive
+    M410,
+    /// Enable or disable the bed mesh, and set its fade height
+    M420(M420LevelingState),
+    /// Set a single point of the bed mesh
+    M421(M421SetMeshPoint),
+    /// Save settings to the storage
+    M500,
+    /// Load settings from the storage
+    M501,
+    /// Reset settings to factory defaults
+    M502,
+    /// Report settings as gcode
+    M503,
+    /// Set the PID gains of the hotend
+    M301(M301PidGains),
+    /// Autotune the PID gains of a heater
+    M303(M303PidAutotune),
+    /// Set the PID gains of the bed
+    M304(M304PidGains),
+    /// Set the pressure advance factor of an extruder
+    M900(M900PressureAdvance),
+    /// Command accepted by the parser that has no effect on the machine yet.
+    /// Holds the command name and its raw parameters
+    Passthrough(String, Vec<String>),
+    /// Command named with a word, like the ones of Klipper and its macros
+    Extended(ExtendedCommand),
+}
+
+impl GcodeCommand {
+    /// Emergency commands must skip the queue and be handled right after being read
+    pub fn is_emergency(&self) -> bool {
+        matches!(self, GcodeCommand::M112 | GcodeCommand::M410)
+    }
+
+    /// True for commands that end up moving the steppers
+    pub fn is_move(&self) -> bool {
+        matches!(
+            self,
+            GcodeCommand::G0(_) | GcodeCommand::G1(_) | GcodeCommand::G2(_) | GcodeCommand::G3(_)
+        )
+    }
+
+    /// True for commands changing the position, or how the coordinates of the moves are read. Passes rewriting
+    /// a job only follow these, since any other one could fail without the machine the job was sliced for
+    pub(crate) fn changes_position(&self) -> bool {
+        matches!(
+            self,
+            GcodeCommand::G0(_)
+                | GcodeCommand::G1(_)
+                | GcodeCommand::G2(_)
+                | GcodeCommand::G3(_)
+                | GcodeCommand::G20
+                | GcodeCommand::G21
+                | GcodeCommand::G28(_)
+                | GcodeCommand::G90
+                | GcodeCommand::G91
+                | GcodeCommand::G92(_)
+                | GcodeCommand::M82
+                | GcodeCommand::M83
+        )
+    }
+
+    /// Name of the command, without parameters. Ex: `G1`
+    pub fn name(&self) -> &str {
+        match self {
+            GcodeCommand::G0(_) => "G0",
+            GcodeCommand::G1(_) => "G1",
+            GcodeCommand::G2(_) => "G2",
+            GcodeCommand::G3(_) => "G3",
+            GcodeCommand::G20 => "G20",
+            GcodeCommand::G28(_) => "G28",
+            GcodeCommand::G29(_) => "G29",
+            GcodeCommand::G21 => "G21",
+            GcodeCommand::G90 => "G90",
+            GcodeCommand::G91 => "G91",
+            GcodeCommand::G92(_) => "G92",
+            GcodeCommand::M82 => "M82",
+            GcodeCommand::M83 => "M83",
+            GcodeCommand::M92(_) => "M92",
+            GcodeCommand::M112 => "M112",
+            GcodeCommand::M201(_) => "M201",
+            GcodeCommand::M203(_) => "M203",
+            GcodeCommand::M204(_) => "M204",
+            GcodeCommand::M205(_) => "M205",
+            GcodeCommand::M410 => "M410",
+            GcodeCommand::M420(_) => "M420",
+            GcodeCommand::M421(_) => "M421",
+            GcodeCommand::M500 => "M500",
+            GcodeCommand::M501 => "M501",
+            GcodeCommand::M502 => "M502",
+            GcodeCommand::M503 => "M503",
+            GcodeCommand::M301(_) => "M301",
+            GcodeCommand::M303(_) => "M303",
+            GcodeCommand::M304(_) => "M304",
+            GcodeCommand::M900(_) => "M900",
+            GcodeCommand::Passthrough(name, _) => name,
+            GcodeCommand::Extended(extended) => extended.name(),
+        }
+    }
+
+    /// Value of a parameter of a passthrough command. Ex: 200 for the S of `M104 S200`
+    pub fn parameter_value(&self, letter: char) -> Option<f32> {
+        let GcodeCommand::Passthrough(_, parameters) = self else {
+            return None;
+        };
+
+        parameters.iter().rev().find_map(|parameter| {
+            parameter
+                .strip_prefix([letter.to_ascii_uppercase(), letter.to_ascii_lowercase()])
+                .and_then(|value| value.parse::<f32>().ok())
+        })
+    }
+
+    /// Extruder picked by a tool change. Ex: 1 for `T1`
+    pub fn tool_change(&self) -> Option<u8> {
+        match self {
+            GcodeCommand::Passthrough(name, _) => tool_number(name),
+            _ => None,
+        }
+    }
+}
+
+/// Number of the tool named by a tool change command. Ex: 1 for `T1`
+pub(crate) fn tool_number(name: &str) -> Option<u8> {
+    name.strip_prefix('T')?.parse().ok()
+}
 
-/// Rapid move
-pub(super) struct G0Move {}
+/// Rapid move. Takes the same parameters as the linear move
+pub type G0Move = G1Move;
 
 /// Linear move
-#[derive(Default, Debug)]
-pub(super) struct G1Move {
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct G1Move {
     /// Xnnn
-    x_target: Option<LocationType>,
+    pub(crate) x_target: Option<LocationType>,
     /// Ynnn
-    y_target: Option<LocationType>,
+    pub(crate) y_target: Option<LocationType>,
     /// Znnn
-    z_target: Option<LocationType>,
+    pub(crate) z_target: Option<LocationType>,
     /// Ennn
-    amount_to_extrude: Option<ExtrudeAmountType>,
+    pub(crate) amount_to_extrude: Option<ExtrudeAmountType>,
     /// Fnnn
-    feedrate_per_minute: Option<FeedrateAmountType>,
+    pub(crate) feedrate_per_minute: Option<FeedrateAmountType>,
     //  Hnnn and Rnnn not supported ATM
-    laser_power: Option<PowerType>,
+    /// Snnn
+    pub(crate) laser_power: Option<PowerType>,
+}
+
+/// Counterclockwise arc. Takes the same parameters as the clockwise arc
+pub type G3ArcMove = G2ArcMove;
+
+/// Clockwise arc on the XY plane. The center is given either as an offset from the start, or as a radius.
+/// Moving along Z at the same time makes a helix
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct G2ArcMove {
+    /// Xnnn
+    pub(crate) x_target: Option<LocationType>,
+    /// Ynnn
+    pub(crate) y_target: Option<LocationType>,
+    /// Znnn
+    pub(crate) z_target: Option<LocationType>,
+    /// Ennn
+    pub(crate) amount_to_extrude: Option<ExtrudeAmountType>,
+    /// Fnnn
+    pub(crate) feedrate_per_minute: Option<FeedrateAmountType>,
+    /// Innn. X offset of the center from the start
+    pub(crate) i_offset: Option<LocationType>,
+    /// Jnnn. Y offset of the center from the start
+    pub(crate) j_offset: Option<LocationType>,
+    /// Rnnn. Negative radiuses take the longer of the two possible arcs
+    pub(crate) radius: Option<LocationType>,
+}
+
+/// Set the current position to the given values, without moving the steppers
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct G92SetPosition {
+    /// Xnnn
+    pub(crate) x: Option<LocationType>,
+    /// Ynnn
+    pub(crate) y: Option<LocationType>,
+    /// Znnn
+    pub(crate) z: Option<LocationType>,
+    /// Ennn
+    pub(crate) e: Option<ExtrudeAmountType>,
 }
 
-impl GcodeExecutable for G1Move {}
+/// Value for each axis, shared by every command that configures the axes one by one
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct AxisParameters {
+    /// Xnnn
+    pub(crate) x: Option<f32>,
+    /// Ynnn
+    pub(crate) y: Option<f32>,
+    /// Znnn
+    pub(crate) z: Option<f32>,
+    /// Ennn
+    pub(crate) e: Option<f32>,
+}
+
+/// Home the given axes, or every axis if none is given
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct G28Home {
+    /// X
+    pub(crate) x: bool,
+    /// Y
+    pub(crate) y: bool,
+    /// Z
+    pub(crate) z: bool,
+    /// W. Prusa extension, skips the mesh bed leveling after homing
+    pub(crate) skip_mesh_leveling: bool,
+}
+
+impl G28Home {
+    /// Axes to home. No axis given means every axis
+    pub fn axes(&self) -> Vec<Axis> {
+        let requested = [(Axis::X, self.x), (Axis::Y, self.y), (Axis::Z, self.z)];
+        if requested.iter().all(|(_, requested)| !requested) {
+            return vec![Axis::X, Axis::Y, Axis::Z];
+        }
+
+        requested
+            .into_iter()
+            .filter_map(|(axis, requested)| requested.then_some(axis))
+            .collect()
+    }
+
+    pub fn skip_mesh_leveling(&self) -> bool {
+        self.skip_mesh_leveling
+    }
+}
+
+/// Probe a grid of points over the bed, building the mesh used to level it. Missing values
+/// fall back to a 3x3 grid covering the full bed
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct G29ProbeMesh {
+    /// Xnnn, or Pnnn for both axes. Amount of points along X
+    pub(crate) columns: Option<usize>,
+    /// Ynnn, or Pnnn for both axes. Amount of points along Y
+    pub(crate) rows: Option<usize>,
+    /// Lnnn. Lowest X probed
+    pub(crate) left: Option<LocationType>,
+    /// Rnnn. Highest X probed
+    pub(crate) right: Option<LocationType>,
+    /// Fnnn. Lowest Y probed
+    pub(crate) front: Option<LocationType>,
+    /// Bnnn. Highest Y probed
+    pub(crate) back: Option<LocationType>,
+}
+
+/// Turn the bed leveling on or off
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M420LevelingState {
+    /// Snnn. Any value other than zero turns the leveling on
+    pub(crate) enabled: Option<bool>,
+    /// Znnn. Height at which the compensation is faded out completely. Zero disables the fade
+    pub(crate) fade_height: Option<LocationType>,
+}
+
+/// Set the Z offset of a point of the bed mesh. The point is given either by its indexes or by the
+/// coordinates closest to it
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M421SetMeshPoint {
+    /// Innn. Index of the point along X
+    pub(crate) column: Option<usize>,
+    /// Jnnn. Index of the point along Y
+    pub(crate) row: Option<usize>,
+    /// Xnnn
+    pub(crate) x: Option<LocationType>,
+    /// Ynnn
+    pub(crate) y: Option<LocationType>,
+    /// Znnn. New offset of the point
+    pub(crate) z: Option<LocationType>,
+    /// Qnnn. Amount added to the current offset of the point
+    pub(crate) offset: Option<LocationType>,
+}
+
+/// Set how much filament is pushed ahead while the extruder speeds up, and pulled back while it slows down
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M900PressureAdvance {
+    /// Knnn. Millimeters of filament per mm/s of extrusion speed. Zero turns the advance off
+    pub(crate) factor: Option<f32>,
+    /// Tnnn. Extruder the factor is for. The active one when missing
+    pub(crate) tool: Option<u8>,
+}
+
+/// Set the gains of the PID loop regulating the hotend. Missing gains keep their value
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M301PidGains {
+    /// Pnnn
+    pub(crate) proportional: Option<f32>,
+    /// Innn
+    pub(crate) integral: Option<f32>,
+    /// Dnnn
+    pub(crate) derivative: Option<f32>,
+}
+
+/// Set the gains of the PID loop regulating the bed. Takes the same parameters as M301
+pub type M304PidGains = M301PidGains;
+
+/// Oscillate a heater around a temperature for some cycles, and measure the PID gains that regulate it
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M303PidAutotune {
+    /// Ennn. -1 picks the bed, any other value the hotend
+    pub(crate) heater: HeaterKind,
+    /// Snnn. Temperature to tune at, in °C
+    pub(crate) target: Option<f32>,
+    /// Cnnn. Cycles to measure
+    pub(crate) cycles: Option<u8>,
+    /// Unnn. Non zero applies the gains found
+    pub(crate) use_result: bool,
+}
+
+/// Set the amount of steps each axis needs to move a single millimeter
+pub type M92StepsPerUnit = AxisParameters;
+
+/// Set the max acceleration of each axis, in mm/s²
+pub type M201MaxAcceleration = AxisParameters;
+
+/// Set the max feedrate of each axis, in mm/s
+pub type M203MaxFeedrate = AxisParameters;
+
+/// Set the accelerations used when planning each kind of move, in mm/s²
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M204Acceleration {
+    /// Pnnn. Moves that extrude
+    pub(crate) print: Option<f32>,
+    /// Rnnn. Moves of the extruder alone
+    pub(crate) retract: Option<f32>,
+    /// Tnnn. Moves that don't extrude
+    pub(crate) travel: Option<f32>,
+    /// Snnn. Legacy parameter, sets both print and travel
+    pub(crate) legacy: Option<f32>,
+}
+
+/// Set the limits of the speed changes between consecutive moves
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct M205AdvancedSettings {
+    /// Xnnn, Ynnn, Znnn and Ennn. Max instant speed change of each axis, in mm/s
+    pub(crate) jerk: AxisParameters,
+    /// Jnnn. Junction deviation in millimeters. Zero switches to the classic jerk
+    pub(crate) junction_deviation: Option<f32>,
+    /// Snnn. Min feedrate for moves that extrude, in mm/s
+    pub(crate) min_feedrate: Option<f32>,
+    /// Tnnn. Min feedrate for travel moves, in mm/s
+    pub(crate) min_travel_feedrate: Option<f32>,
+}
+
+impl AxisParameters {
+    /// Pairs each value with its axis
+    pub(crate) fn by_axis(&self) -> [(Axis, Option<f32>); 4] {
+        [
+            (Axis::X, self.x),
+            (Axis::Y, self.y),
+            (Axis::Z, self.z),
+            (Axis::E, self.e),
+        ]
+    }
+}
+
+impl G1Move {
+    pub fn x_target(&self) -> Option<LocationType> {
+        self.x_target
+    }
+
+    pub fn y_target(&self) -> Option<LocationType> {
+        self.y_target
+    }
+
+    pub fn z_target(&self) -> Option<LocationType> {
+        self.z_target
+    }
+
+    pub fn amount_to_extrude(&self) -> Option<ExtrudeAmountType> {
+        self.amount_to_extrude
+    }
+
+    pub fn feedrate_per_minute(&self) -> Option<FeedrateAmountType> {
+        self.feedrate_per_minute
+    }
+
+    pub fn laser_power(&self) -> Option<PowerType> {
+        self.laser_power
+    }
+}
+
+impl G2ArcMove {
+    pub fn x_target(&self) -> Option<LocationType> {
+        self.x_target
+    }
+
+    pub fn y_target(&self) -> Option<LocationType> {
+        self.y_target
+    }
+
+    pub fn z_target(&self) -> Option<LocationType> {
+        self.z_target
+    }
+
+    pub fn amount_to_extrude(&self) -> Option<ExtrudeAmountType> {
+        self.amount_to_extrude
+    }
+
+    pub fn feedrate_per_minute(&self) -> Option<FeedrateAmountType> {
+        self.feedrate_per_minute
+    }
+
+    pub fn i_offset(&self) -> Option<LocationType> {
+        self.i_offset
+    }
+
+    pub fn j_offset(&self) -> Option<LocationType> {
+        self.j_offset
+    }
+
+    pub fn radius(&self) -> Option<LocationType> {
+        self.radius
+    }
+}
+
+/// Command named with a word instead of a letter and a number, taking `KEY=value` parameters.
+/// Ex: `PRINT_START BED=60 EXTRUDER=210`
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ExtendedCommand {
+    pub(crate) name: String,
+    /// Keys in uppercase and values without quotes, in the order they're written
+    pub(crate) parameters: Vec<(String, String)>,
+}
+
+impl ExtendedCommand {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn parameters(&self) -> &[(String, String)] {
+        &self.parameters
+    }
+
+    /// Value of a parameter, ignoring the case of the key. Repeated keys keep the last value
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .rev()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of a parameter read as a number. Fails if the value isn't a number
+    pub fn number(&self, key: &str) -> PrintResult<Option<f32>> {
+        match self.parameter(key) {
+            Some(value) => match value.parse::<f32>() {
+                Ok(number) if number.is_finite() => Ok(Some(number)),
+                _ => Err(Error::InvalidParameterInLine(
+                    format!("{}={value}", key.to_ascii_uppercase()),
+                    None,
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use super::commands::tool_number;
+
+/// Firmware a job is written for. Each one has its own set of commands, and a few parameters mean
+/// different things depending on it, like the accelerations of M204
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Accepts every command the parser knows, no matter the firmware. Parameters follow Marlin
+    #[default]
+    Generic,
+    Marlin,
+    /// Firmware of the Prusa MK3 family, which started as Marlin 1.0
+    Prusa,
+    Klipper,
+    RepRapFirmware,
+}
+
+impl Dialect {
+    pub const ALL: [Dialect; 5] = [
+        Dialect::Generic,
+        Dialect::Marlin,
+        Dialect::Prusa,
+        Dialect::Klipper,
+        Dialect::RepRapFirmware,
+    ];
+
+    /// Name used by printer profiles. Ex: `klipper`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dialect::Generic => "generic",
+            Dialect::Marlin => "marlin",
+            Dialect::Prusa => "prusa",
+            Dialect::Klipper => "klipper",
+            Dialect::RepRapFirmware => "reprapfirmware",
+        }
+    }
+
+    /// Finds a dialect by its name, ignoring the case. RepRapFirmware can also be named `rrf`
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        let name = name.trim().to_ascii_lowercase();
+        match name.as_str() {
+            "rrf" => Some(Dialect::RepRapFirmware),
+            name => Self::ALL.into_iter().find(|dialect| dialect.name() == name),
+        }
+    }
+
+    /// True if the firmware has the command. Subcommands belong to their base command, so `M862.3`
+    /// is checked as `M862`. Commands the parser doesn't know are left for the parser to report
+    pub fn supports(&self, command: &str) -> bool {
+        let command = command.split('.').next().unwrap_or(command);
+        match self {
+            Dialect::Generic => true,
+            Dialect::Marlin => !matches!(command, "M98" | "M566" | "M572" | "M862"),
+            Dialect::Prusa => !matches!(
+                command,
+                "G29" | "M98" | "M410" | "M413" | "M420" | "M421" | "M566" | "M572"
+            ),
+            //  Klipper configures the machine through extended commands, so only the basics are left.
+            //  Tool changes are macros every multi extruder configuration defines
+            Dialect::Klipper if tool_number(command).is_some() => true,
+            Dialect::Klipper => matches!(
+                command,
+                "G0" | "G1"
+                    | "G2"
+                    | "G3"
+                    | "G4"
+                    | "G10"
+                    | "G11"
+                    | "G21"
+                    | "G28"
+                    | "G90"
+                    | "G91"
+                    | "G92"
+                    | "M73"
+                    | "M82"
+                    | "M83"
+                    | "M84"
+                    | "M104"
+                    | "M106"
+                    | "M107"
+                    | "M109"
+                    | "M112"
+                    | "M115"
+                    | "M140"
+                    | "M190"
+                    | "M204"
+                    | "M221"
+            ),
+            Dialect::RepRapFirmware => !matches!(
+                command,
+                "G80" | "M410" | "M413" | "M420" | "M421" | "M600" | "M862" | "M900"
+            ),
+        }
+    }
+
+    /// True if commands can be named with words and take `KEY=value` parameters, like Klipper
+    /// commands and macros. Ex: `SET_PRESSURE_ADVANCE ADVANCE=0.05`
+    pub fn has_extended_commands(&self) -> bool {
+        matches!(self, Dialect::Generic | Dialect::Klipper)
+    }
+}
+
+impl std::fmt::Display for Dialect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dialect::Generic => write!(f, "generic gcode"),
+            Dialect::Marlin => write!(f, "Marlin"),
+            Dialect::Prusa => write!(f, "Prusa firmware"),
+            Dialect::Klipper => write!(f, "Klipper"),
+            Dialect::RepRapFirmware => write!(f, "RepRapFirmware"),
+        }
+    }
+}
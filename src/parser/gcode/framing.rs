@@ -0,0 +1,138 @@
+use crate::error::{Error, PrintResult};
+use crate::types::LineNumberType;
+
+use super::writer::line_checksum;
+
+/// Line as sent by a host, with its line number and checksum taken apart from the command.
+/// Ex: `N12 G1 X10*85`. Lines without framing hold only the command
+#[derive(Debug, Clone, PartialEq)]
+pub struct FramedLine<'a> {
+    line_number: Option<LineNumberType>,
+    command: &'a str,
+    checksum: Option<u8>,
+    /// Text covered by the checksum, which is everything before the `*`
+    checked_text: &'a str,
+}
+
+impl<'a> FramedLine<'a> {
+    /// Splits the framing of a line whose comment was already removed. Only `N` followed by digits and
+    /// a space starts a line number, so commands like `NOZZLE_WIPE` are left alone, and a `*` within a
+    /// quoted value, like `RESPOND MSG="a*b"`, doesn't start a checksum
+    pub fn parse(line: &'a str) -> PrintResult<FramedLine<'a>> {
+        let line = line.trim();
+        let (checked_text, checksum) = match rfind_unquoted(line, '*') {
+            Some(index) => match line[index + 1..].trim().parse::<u8>() {
+                Ok(checksum) => (&line[..index], Some(checksum)),
+                Err(_) => {
+                    return Err(Error::InvalidParameterInLine(
+                        line[index..].trim().to_string(),
+                        None,
+                    ));
+                }
+            },
+            None => (line, None),
+        };
+
+        let framing = checked_text.strip_prefix(['N', 'n']).and_then(|framed| {
+            let digits = framed
+                .find(|character: char| !character.is_ascii_digit())
+                .unwrap_or(framed.len());
+            let (number, command) = framed.split_at(digits);
+            (digits > 0 && (command.is_empty() || command.starts_with(char::is_whitespace)))
+                .then_some((number, command))
+        });
+        let (line_number, command) = match framing {
+            Some((number, command)) => match number.parse::<LineNumberType>() {
+                Ok(number) => (Some(number), command.trim()),
+                Err(_) => return Err(Error::InvalidParameterInLine(format!("N{number}"), None)),
+            },
+            None => (None, checked_text.trim()),
+        };
+
+        Ok(FramedLine {
+            line_number,
+            command,
+            checksum,
+            checked_text,
+        })
+    }
+
+    pub fn line_number(&self) -> Option<LineNumberType> {
+        self.line_number
+    }
+
+    /// Command of the line, without framing
+    pub fn command(&self) -> &'a str {
+        self.command
+    }
+
+    pub fn checksum(&self) -> Option<u8> {
+        self.checksum
+    }
+
+    /// True if the line has no checksum, or the checksum matches its text
+    pub fn has_valid_checksum(&self) -> bool {
+        self.checksum
+            .is_none_or(|checksum| checksum == line_checksum(self.checked_text))
+    }
+}
+
+/// Index of the first character outside double quoted values, like the `;` starting the comment of
+/// `RESPOND MSG="a;b" ; note`
+pub(crate) fn find_unquoted(text: &str, wanted: char) -> Option<usize> {
+    unquoted_indices(text, wanted).next()
+}
+
+/// Index of the last character outside double quoted values
+fn rfind_unquoted(text: &str, wanted: char) -> Option<usize> {
+    unquoted_indices(text, wanted).last()
+}
+
+fn unquoted_indices(text: &str, wanted: char) -> impl Iterator<Item = usize> + '_ {
+    let mut quoted = false;
+    text.char_indices().filter_map(move |(index, character)| {
+        if character == '"' {
+            quoted = !quoted;
+        }
+        (character == wanted && !quoted).then_some(index)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::{FramedLine, line_checksum};
+
+    #[test]
+    fn split_framed_lines() {
+        let text = "N12 G1 X10";
+        let line = format!("{text}*{}", line_checksum(text));
+        let framed = FramedLine::parse(&line).unwrap();
+
+        assert_eq!(framed.line_number(), Some(12));
+        assert_eq!(framed.command(), "G1 X10");
+        assert!(framed.has_valid_checksum());
+
+        let corrupted = FramedLine::parse("N12 G1 X11*0").unwrap();
+        assert!(!corrupted.has_valid_checksum());
+
+        let bare = FramedLine::parse("  M105 ").unwrap();
+        assert_eq!((bare.line_number(), bare.command()), (None, "M105"));
+        assert!(bare.has_valid_checksum());
+
+        //  Names starting with N are commands, not line numbers
+        let macro_line = FramedLine::parse("NOZZLE_WIPE").unwrap();
+        assert_eq!(
+            (macro_line.line_number(), macro_line.command()),
+            (None, "NOZZLE_WIPE")
+        );
+
+        let quoted = FramedLine::parse("N3 RESPOND MSG=\"a*b\"").unwrap();
+        assert_eq!(
+            (quoted.line_number(), quoted.command(), quoted.checksum()),
+            (Some(3), "RESPOND MSG=\"a*b\"", None)
+        );
+
+        assert!(FramedLine::parse("N99999999999999999999 G1").is_err());
+        assert!(FramedLine::parse("N1 G1*abc").is_err());
+    }
+}
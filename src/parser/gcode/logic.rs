@@ -1,19 +1,27 @@
 use std::{fs::File, io::BufRead};
 
 use crate::error::{Error, PrintResult};
+use crate::system::SystemConfig;
 
-use super::parse::parse_line;
+use super::dialect::Dialect;
+use super::parse::parse_line_with_dialect;
 
 /// Validates the full file gathering all present errors in the gcode file. Returns said set of errors if any
 /// Returns error if fails to handle any line of the file
 pub fn validate_file(file: &File) -> PrintResult<Vec<Error>> {
+    validate_file_with_dialect(file, Dialect::Generic)
+}
+
+/// Same as `validate_file()`, but commands the firmware doesn't have, or parameters it reads
+/// differently, are reported too
+pub fn validate_file_with_dialect(file: &File, dialect: Dialect) -> PrintResult<Vec<Error>> {
     let reader = std::io::BufReader::new(file);
     let mut error_list = vec![];
 
     for (line_number, line_result) in reader.lines().enumerate() {
         let line = line_result.map_err(Error::InputOutputError)?;
         //  Send line + 1 because the enumerate method starts at 0
-        if let Err(error) = parse_line(&line, line_number + 1) {
+        if let Err(error) = parse_line_with_dialect(&line, line_number + 1, dialect) {
             error_list.push(error);
         }
     }
@@ -21,11 +29,53 @@ pub fn validate_file(file: &File) -> PrintResult<Vec<Error>> {
     Ok(error_list)
 }
 
+/// Same as `validate_file()`, but every move is also replayed through the state tracker, checking the machine
+/// described by the config can actually reach it. Bed limits are checked only if the bed is configured.
+/// Commands are checked against the dialect of the config
+pub fn validate_file_with_config(file: &File, config: &SystemConfig) -> PrintResult<Vec<Error>> {
+    let reader = std::io::BufReader::new(file);
+    let mut state = config.clone();
+    let mut error_list = vec![];
+
+    for (line_number, line_result) in reader.lines().enumerate() {
+        let line = line_result.map_err(Error::InputOutputError)?;
+        let command = match parse_line_with_dialect(&line, line_number + 1, config.dialect()) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(error) => {
+                error_list.push(error);
+                continue;
+            }
+        };
+
+        match state.apply_command(&command) {
+            Ok(resolved_moves) => {
+                //  Arcs report the first point out of reach only
+                if let Some(resolved_move) = resolved_moves
+                    .iter()
+                    .find(|resolved_move| !state.is_reachable(&resolved_move.end()))
+                {
+                    error_list.push(Error::UnreachableLocation(
+                        resolved_move.end(),
+                        Some(line_number + 1),
+                    ));
+                }
+            }
+            Err(error) => error_list.push(error.in_line(line_number + 1)),
+        }
+    }
+
+    Ok(error_list)
+}
+
 #[cfg(test)]
 mod test {
     use crate::error::Error;
 
-    use super::validate_file;
+    use crate::motion::{LinearDeltaKinematics, MachineKinematics};
+    use crate::system::SystemConfig;
+
+    use super::{validate_file, validate_file_with_config};
 
     #[test]
     fn load_small_file_ok() {
@@ -54,4 +104,22 @@ mod test {
             Error::InvalidCommandInLine(_, Some(22))
         ))
     }
+
+    #[test]
+    fn validate_reachability_on_delta() {
+        let file = std::fs::File::open("small_example_error.gcode").unwrap();
+        let mut config = SystemConfig::default();
+        config.set_kinematics(MachineKinematics::LinearDelta(LinearDeltaKinematics::new(
+            250.0, 120.0, 90.0,
+        )));
+        let result = validate_file_with_config(&file, &config).unwrap();
+
+        //  The skirt sits far from the center of a delta bed, so every move around it is out of reach
+        assert!(matches!(result[0], Error::UnreachableLocation(_, Some(10))));
+        assert!(
+            result
+                .iter()
+                .any(|error| matches!(error, Error::InvalidCommandInLine(_, Some(22))))
+        );
+    }
 }
@@ -1,5 +1,25 @@
 mod commands;
+mod dialect;
+mod framing;
 mod logic;
+mod objects;
 mod parse;
+mod reader;
+mod writer;
 
-pub use logic::validate_file;
+pub use commands::{
+    AxisParameters, ExtendedCommand, G0Move, G1Move, G2ArcMove, G3ArcMove, G28Home, G29ProbeMesh,
+    G92SetPosition, GcodeCommand, M92StepsPerUnit, M201MaxAcceleration, M203MaxFeedrate,
+    M204Acceleration, M205AdvancedSettings, M301PidGains, M303PidAutotune, M304PidGains,
+    M420LevelingState, M421SetMeshPoint, M900PressureAdvance,
+};
+pub use dialect::Dialect;
+pub use framing::FramedLine;
+pub(crate) use framing::find_unquoted;
+pub use logic::{validate_file, validate_file_with_config, validate_file_with_dialect};
+pub use objects::{ObjectFilter, ObjectLabel};
+pub use reader::{GcodeLine, GcodeReader};
+pub(crate) use writer::format_number;
+#[cfg(test)]
+pub(crate) use writer::rewrite_source;
+pub use writer::{GcodeWriter, LineEnding, WriterOptions, line_checksum};
@@ -0,0 +1,493 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::error::PrintResult;
+use crate::system::SystemConfig;
+use crate::types::ExtrudeAmountType;
+
+use super::commands::{G1Move, G92SetPosition, GcodeCommand};
+use super::reader::GcodeLine;
+
+/// Marker of the objects a job is made of, as slicers and firmwares write them
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectLabel {
+    /// Object declared before it's printed. Ex: `EXCLUDE_OBJECT_DEFINE NAME=cube`
+    Define(String),
+    /// Name given to a numbered object. Ex: `M486 S0 A"cube"` names the object 0
+    Named { id: String, name: String },
+    /// Following moves belong to the object. Ex: `; printing object cube id:0 copy 0`
+    Start(String),
+    /// Following moves don't belong to any object. Ex: `M486 S-1`
+    End,
+    /// Object cancelled by the job itself. Ex: `EXCLUDE_OBJECT NAME=cube` or `M486 P0`
+    Cancel(String),
+    /// Cancels the object being printed. Ex: `M486 C`
+    CancelCurrent,
+    /// Takes back the cancellation of an object. Ex: `M486 U0`
+    Resume(String),
+}
+
+/// Drops the extrusions of excluded objects from a job, leaving the rest untouched. Moves of an
+/// excluded object that deposit filament are turned into travels, while retractions are kept so
+/// the filament is where the next object expects it. With absolute extrusion, the extruder is set
+/// back to the position the job expects with G92 before the next move using it.
+/// Objects can be excluded before reading the job, or at any point in the middle of it
+pub struct ObjectFilter<I> {
+    lines: I,
+    /// State of the original job
+    state: SystemConfig,
+    /// Names and ids of the excluded objects, in uppercase
+    excluded: HashSet<String>,
+    /// Object the moves being read belong to, if any
+    current: Option<String>,
+    /// Names of the numbered objects, by id
+    names: HashMap<String, String>,
+    /// Every object seen so far, in the order they showed up
+    objects: Vec<String>,
+    /// Millimeters of filament the original extruder is ahead of the filtered one
+    extrusion_offset: ExtrudeAmountType,
+    skipped_extrusion: ExtrudeAmountType,
+    /// Lines ready to be handed out, when a single line turns into more than one
+    pending: VecDeque<GcodeLine>,
+}
+
+impl ObjectLabel {
+    /// Reads the labels of a line, either from its command or from its comment
+    pub fn from_line(line: &GcodeLine) -> Vec<ObjectLabel> {
+        match line.command() {
+            Some(GcodeCommand::Extended(extended)) => {
+                let name = extended.parameter("NAME").map(str::to_string);
+                let label = match (extended.name(), name) {
+                    ("EXCLUDE_OBJECT_DEFINE", Some(name)) => ObjectLabel::Define(name),
+                    ("EXCLUDE_OBJECT_START", Some(name)) => ObjectLabel::Start(name),
+                    ("EXCLUDE_OBJECT_END", _) => ObjectLabel::End,
+                    ("EXCLUDE_OBJECT", Some(name)) if extended.parameter("RESET").is_some() => {
+                        ObjectLabel::Resume(name)
+                    }
+                    ("EXCLUDE_OBJECT", Some(name)) => ObjectLabel::Cancel(name),
+                    ("EXCLUDE_OBJECT", None) if extended.parameter("CURRENT").is_some() => {
+                        ObjectLabel::CancelCurrent
+                    }
+                    _ => return vec![],
+                };
+                vec![label]
+            }
+            Some(GcodeCommand::Passthrough(name, parameters)) if name == "M486" => {
+                m486_labels(&parameters.join(" "))
+            }
+            Some(_) => vec![],
+            None => line.comment().and_then(comment_label).into_iter().collect(),
+        }
+    }
+}
+
+/// Labels written as comments by PrusaSlicer, like `printing object cube id:0 copy 0`,
+/// and by Cura, like `MESH:cube.stl`. PrusaSlicer objects are named without their id and copy
+fn comment_label(comment: &str) -> Option<ObjectLabel> {
+    if let Some(name) = comment.strip_prefix("printing object ") {
+        Some(ObjectLabel::Start(without_copy(name.trim()).to_string()))
+    } else if comment.starts_with("stop printing object ") {
+        Some(ObjectLabel::End)
+    } else {
+        match comment.strip_prefix("MESH:")?.trim() {
+            "NONMESH" => Some(ObjectLabel::End),
+            name => Some(ObjectLabel::Start(name.to_string())),
+        }
+    }
+}
+
+/// Name of a PrusaSlicer object without its ` id:0 copy 0` suffix, if it has one
+fn without_copy(name: &str) -> &str {
+    let is_number =
+        |text: &str| !text.is_empty() && text.chars().all(|character| character.is_ascii_digit());
+    match name.rsplit_once(" id:") {
+        Some((object, suffix))
+            if suffix
+                .split_once(" copy ")
+                .is_some_and(|(id, copy)| is_number(id) && is_number(copy)) =>
+        {
+            object
+        }
+        _ => name,
+    }
+}
+
+/// Labels of a M486 command. S picks the object being printed, -1 for none, A names it,
+/// P cancels an object, U takes the cancellation back, and C cancels the current object
+fn m486_labels(parameters: &str) -> Vec<ObjectLabel> {
+    let mut labels = vec![];
+    let mut selected = None;
+    let mut rest = parameters.trim_start();
+
+    while let Some(letter) = rest.chars().next() {
+        let after_letter = &rest[1..];
+        //  Names take the rest of the line, unless they're quoted
+        if letter.eq_ignore_ascii_case(&'A') {
+            let name = match after_letter.strip_prefix('"') {
+                Some(quoted) => quoted.split('"').next().unwrap_or(quoted),
+                None => after_letter.trim(),
+            };
+            if let Some(id) = &selected {
+                labels.insert(
+                    0,
+                    ObjectLabel::Named {
+                        id: String::from(id),
+                        name: name.to_string(),
+                    },
+                );
+            }
+            break;
+        }
+
+        let value_end = after_letter
+            .find(char::is_whitespace)
+            .unwrap_or(after_letter.len());
+        let value = &after_letter[..value_end];
+        match letter.to_ascii_uppercase() {
+            'S' if value.starts_with('-') => labels.push(ObjectLabel::End),
+            'S' => {
+                selected = Some(value.to_string());
+                labels.push(ObjectLabel::Start(value.to_string()));
+            }
+            'P' => labels.push(ObjectLabel::Cancel(value.to_string())),
+            'U' => labels.push(ObjectLabel::Resume(value.to_string())),
+            'C' => labels.push(ObjectLabel::CancelCurrent),
+            _ => {}
+        }
+        rest = after_letter[value_end..].trim_start();
+    }
+
+    labels
+}
+
+impl<I> ObjectFilter<I>
+where
+    I: Iterator<Item = PrintResult<GcodeLine>>,
+{
+    pub fn new(lines: impl IntoIterator<IntoIter = I>) -> Self {
+        Self {
+            lines: lines.into_iter(),
+            state: SystemConfig::default(),
+            excluded: HashSet::new(),
+            current: None,
+            names: HashMap::new(),
+            objects: vec![],
+            extrusion_offset: 0.0,
+            skipped_extrusion: 0.0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Excludes an object by its name or id, ignoring the case. Takes effect on the next line read
+    pub fn exclude(&mut self, name: &str) {
+        self.excluded.insert(name.to_ascii_uppercase());
+    }
+
+    /// Prints an excluded object again from the next line read
+    pub fn include(&mut self, name: &str) {
+        self.excluded.remove(&name.to_ascii_uppercase());
+    }
+
+    /// True if the object, given by name or id, is excluded
+    pub fn is_excluded(&self, name: &str) -> bool {
+        let excluded = |name: &str| self.excluded.contains(&name.to_ascii_uppercase());
+        excluded(name) || self.names.get(name).is_some_and(|name| excluded(name))
+    }
+
+    /// Every object seen so far, in the order they showed up
+    pub fn objects(&self) -> &[String] {
+        &self.objects
+    }
+
+    /// Object the last line read belongs to, if any
+    pub fn current_object(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    /// Millimeters of filament not extruded because of the excluded objects
+    pub fn skipped_extrusion(&self) -> ExtrudeAmountType {
+        self.skipped_extrusion
+    }
+
+    fn apply_label(&mut self, label: ObjectLabel) {
+        match label {
+            ObjectLabel::Define(name) => self.add_object(name),
+            ObjectLabel::Named { id, name } => {
+                self.add_object(name.clone());
+                self.names.insert(id, name);
+            }
+            ObjectLabel::Start(name) => {
+                self.add_object(name.clone());
+                self.current = Some(name);
+            }
+            ObjectLabel::End => self.current = None,
+            ObjectLabel::Cancel(name) => self.exclude(&name),
+            ObjectLabel::CancelCurrent => {
+                if let Some(name) = self.current.clone() {
+                    self.exclude(&name);
+                }
+            }
+            ObjectLabel::Resume(name) => self.include(&name),
+        }
+    }
+
+    fn add_object(&mut self, name: String) {
+        if !self.objects.contains(&name) {
+            self.objects.push(name);
+        }
+    }
+
+    /// Moves of excluded objects depositing filament become travels. Any other line is kept,
+    /// after setting the extruder back where the job expects it if the line needs it
+    fn filter_line(&mut self, line: GcodeLine) -> PrintResult<()> {
+        for label in ObjectLabel::from_line(&line) {
+            self.apply_label(label);
+        }
+        let Some(command) = line.command() else {
+            self.pending.push_back(line);
+            return Ok(());
+        };
+
+        let start = self.state.current_location();
+        let start_extrusion = self.state.extruder_position();
+        let uses_extruder = match command {
+            GcodeCommand::G0(linear_move) | GcodeCommand::G1(linear_move) => {
+                linear_move.amount_to_extrude.is_some()
+            }
+            GcodeCommand::G2(arc_move) | GcodeCommand::G3(arc_move) => {
+                arc_move.amount_to_extrude.is_some()
+            }
+            _ => false,
+        };
+        //  Units of the values written, taken before the command could change them
+        let factor = self.state.to_millimeters(1.0);
+        let relative_extrusion = self.state.is_relative_extrusion();
+
+        if command.changes_position() {
+            self.state
+                .apply_command(command)
+                .map_err(|error| error.in_line(line.line_number()))?;
+        }
+        let end = self.state.current_location();
+        let extrusion = self.state.extruder_position() - start_extrusion;
+
+        if let GcodeCommand::G92(set_position) = command
+            && set_position.e.is_some()
+        {
+            self.extrusion_offset = 0.0;
+        }
+
+        let in_excluded_object = self
+            .current
+            .as_deref()
+            .is_some_and(|name| self.is_excluded(name));
+        let deposits = extrusion > 0.0 && (start.x, start.y) != (end.x, end.y);
+        if in_excluded_object && deposits {
+            self.extrusion_offset += extrusion;
+            self.skipped_extrusion += extrusion;
+            let travel = self.travel_of(command);
+            self.pending.push_back(GcodeLine::new(
+                line.line_number(),
+                Some(travel),
+                line.comment().map(str::to_string),
+            ));
+            return Ok(());
+        }
+
+        if uses_extruder && !relative_extrusion && self.extrusion_offset != 0.0 {
+            let set_position = G92SetPosition {
+                e: Some(start_extrusion / factor),
+                ..Default::default()
+            };
+            self.pending.push_back(GcodeLine::new(
+                line.line_number(),
+                Some(GcodeCommand::G92(set_position)),
+                None,
+            ));
+            self.extrusion_offset = 0.0;
+        }
+        self.pending.push_back(line);
+        Ok(())
+    }
+
+    /// Same move without extruding. Arcs become straight travels to the same end
+    fn travel_of(&self, command: &GcodeCommand) -> GcodeCommand {
+        match command {
+            GcodeCommand::G0(linear_move) | GcodeCommand::G1(linear_move) => {
+                let travel = G1Move {
+                    amount_to_extrude: None,
+                    ..linear_move.clone()
+                };
+                match command {
+                    GcodeCommand::G0(_) => GcodeCommand::G0(travel),
+                    _ => GcodeCommand::G1(travel),
+                }
+            }
+            GcodeCommand::G2(arc_move) | GcodeCommand::G3(arc_move) => GcodeCommand::G1(G1Move {
+                x_target: arc_move.x_target,
+                y_target: arc_move.y_target,
+                z_target: arc_move.z_target,
+                feedrate_per_minute: arc_move.feedrate_per_minute,
+                ..Default::default()
+            }),
+            command => command.clone(),
+        }
+    }
+}
+
+impl<I> Iterator for ObjectFilter<I>
+where
+    I: Iterator<Item = PrintResult<GcodeLine>>,
+{
+    type Item = PrintResult<GcodeLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(error) => return Some(Err(error)),
+            };
+            if let Err(error) = self.filter_line(line) {
+                return Some(Err(error));
+            }
+        }
+
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::{GcodeCommand, GcodeReader, ObjectFilter, ObjectLabel};
+
+    fn labels(source: &str) -> Vec<ObjectLabel> {
+        GcodeReader::new(source.as_bytes())
+            .flat_map(|line| ObjectLabel::from_line(&line.unwrap()))
+            .collect()
+    }
+
+    /// Filtered job, written back as text
+    fn filtered(source: &str, excluded: &[&str]) -> Vec<String> {
+        let mut filter = ObjectFilter::new(GcodeReader::new(source.as_bytes()));
+        for name in excluded {
+            filter.exclude(name);
+        }
+
+        filter
+            .filter_map(|line| Some(line.unwrap().command()?.to_gcode(5)))
+            .collect()
+    }
+
+    #[test]
+    fn labels_of_every_slicer() {
+        assert_eq!(
+            labels(
+                "; printing object cube id:0 copy 0\n; stop printing object cube id:0 copy 0\n\
+                 ;MESH:part.stl\n;MESH:NONMESH\n"
+            ),
+            vec![
+                ObjectLabel::Start("cube".to_string()),
+                ObjectLabel::End,
+                ObjectLabel::Start("part.stl".to_string()),
+                ObjectLabel::End
+            ]
+        );
+        assert_eq!(
+            labels(
+                "EXCLUDE_OBJECT_DEFINE NAME=cube CENTER=10,10\nEXCLUDE_OBJECT_START NAME=cube\n\
+                 EXCLUDE_OBJECT_END NAME=cube\nEXCLUDE_OBJECT NAME=cube\nEXCLUDE_OBJECT CURRENT=1\n"
+            ),
+            vec![
+                ObjectLabel::Define("cube".to_string()),
+                ObjectLabel::Start("cube".to_string()),
+                ObjectLabel::End,
+                ObjectLabel::Cancel("cube".to_string()),
+                ObjectLabel::CancelCurrent
+            ]
+        );
+        assert_eq!(
+            labels("M486 T2\nM486 S0 A\"left cube\"\nM486 S-1\nM486 P1\nM486 U1\nM486 C\n"),
+            vec![
+                ObjectLabel::Named {
+                    id: "0".to_string(),
+                    name: "left cube".to_string()
+                },
+                ObjectLabel::Start("0".to_string()),
+                ObjectLabel::End,
+                ObjectLabel::Cancel("1".to_string()),
+                ObjectLabel::Resume("1".to_string()),
+                ObjectLabel::CancelCurrent
+            ]
+        );
+    }
+
+    #[test]
+    fn excluded_object_keeps_extruder_consistent() {
+        let source = "M82\nG92 E0\n\
+                      ; printing object left\nG1 X10 Y0 E1\nG1 E0.2\nG1 X20 Y0 E2\n; stop printing object left\n\
+                      ; printing object right\nG1 X30 Y0 E3\n; stop printing object right\n";
+
+        assert_eq!(
+            filtered(source, &["left"]),
+            vec![
+                "M82",
+                "G92 E0",
+                "G1 X10 Y0",
+                "G92 E1",
+                "G1 E0.2",
+                "G1 X20 Y0",
+                "G92 E2",
+                "G1 X30 Y0 E3"
+            ]
+        );
+        assert_eq!(
+            filtered(source, &["right"]),
+            vec![
+                "M82",
+                "G92 E0",
+                "G1 X10 Y0 E1",
+                "G1 E0.2",
+                "G1 X20 Y0 E2",
+                "G1 X30 Y0"
+            ]
+        );
+    }
+
+    #[test]
+    fn prusaslicer_objects_excluded_by_name() {
+        let source = "M83\n; printing object cube id:0 copy 0\nG1 X10 E1\n\
+                      ; stop printing object cube id:0 copy 0\n\
+                      ; printing object cone id:1 copy 0\nG1 X20 E1\n\
+                      ; stop printing object cone id:1 copy 0\n";
+
+        assert_eq!(
+            filtered(source, &["cube"]),
+            vec!["M83", "G1 X10", "G1 X20 E1"]
+        );
+    }
+
+    #[test]
+    fn cancelled_mid_print() {
+        let source = "M83\nEXCLUDE_OBJECT_DEFINE NAME=a\nEXCLUDE_OBJECT_DEFINE NAME=b\n\
+                      EXCLUDE_OBJECT_START NAME=a\nG2 X10 Y0 I5 J0 E1\nEXCLUDE_OBJECT_END NAME=a\n\
+                      EXCLUDE_OBJECT_START NAME=b\nG1 X20 E1\nG1 E-0.8\nEXCLUDE_OBJECT_END NAME=b\n\
+                      EXCLUDE_OBJECT NAME=a\nEXCLUDE_OBJECT_START NAME=a\nG1 X30 E1\nEXCLUDE_OBJECT_END NAME=a\n";
+        let mut filter = ObjectFilter::new(GcodeReader::new(source.as_bytes()));
+        let moves: Vec<GcodeCommand> = filter
+            .by_ref()
+            .filter_map(|line| line.unwrap().into_command())
+            .filter(GcodeCommand::is_move)
+            .collect();
+
+        assert_eq!(filter.objects(), ["a", "b"]);
+        assert!(filter.is_excluded("A"));
+        assert_eq!(filter.skipped_extrusion(), 1.0);
+        assert!(matches!(&moves[0], GcodeCommand::G2(_)));
+        assert!(
+            matches!(&moves[3], GcodeCommand::G1(travel) if travel.amount_to_extrude().is_none())
+        );
+        //  Retractions are kept, since the next object expects the filament where they leave it
+        assert!(
+            matches!(&moves[2], GcodeCommand::G1(retract) if retract.amount_to_extrude() == Some(-0.8))
+        );
+    }
+}
@@ -1,21 +1,158 @@
 #[cfg(test)]
 mod tests;
 
-use super::commands::{G1Move, GcodeExecutable};
+use super::commands::{
+    AxisParameters, ExtendedCommand, G1Move, G2ArcMove, G28Home, G29ProbeMesh, G92SetPosition,
+    GcodeCommand, M204Acceleration, M205AdvancedSettings, M301PidGains, M303PidAutotune,
+    M420LevelingState, M421SetMeshPoint, M900PressureAdvance, tool_number,
+};
+use super::dialect::Dialect;
+use super::framing::{FramedLine, find_unquoted};
 use crate::error::Error;
 use crate::error::PrintResult;
-use crate::types::LineNumberType;
+use crate::heater::HeaterKind;
+use crate::types::{LineNumberType, PowerType};
 
-/// Reads the contents of a line and returns the command in generic format
+/// Reads the contents of a line and returns the command in generic format.
+/// Lines framed by a host, like `N12 G1 X10*85`, must hold a valid checksum
+#[cfg(test)]
 pub(super) fn parse_line(
     line: &str,
     line_number: LineNumberType,
-) -> PrintResult<Option<impl GcodeExecutable>> {
+) -> PrintResult<Option<GcodeCommand>> {
+    parse_line_with_dialect(line, line_number, Dialect::Generic)
+}
+
+/// Same as `parse_line()`, but only the commands of the dialect are accepted, and their parameters
+/// are read the way the dialect means them
+pub(super) fn parse_line_with_dialect(
+    line: &str,
+    line_number: LineNumberType,
+    dialect: Dialect,
+) -> PrintResult<Option<GcodeCommand>> {
+    //  Take the framing apart, if any, once the comment is gone
+    let without_comment = &line[..find_unquoted(line, ';').unwrap_or(line.len())];
+    let framed = FramedLine::parse(without_comment).map_err(|error| error.in_line(line_number))?;
+    if let Some(checksum) = framed.checksum().filter(|_| !framed.has_valid_checksum()) {
+        return Err(Error::InvalidParameterInLine(
+            format!("*{checksum}"),
+            Some(line_number),
+        ));
+    }
+
+    //  Extended commands are named with words, so they never clash with the G and M commands
+    if dialect.has_extended_commands()
+        && let Some(extended) = parse_extended_command(framed.command(), line_number, dialect)?
+    {
+        return Ok(Some(GcodeCommand::Extended(extended)));
+    }
+
     //  Extract the instructions from a line
-    let instructions = divide_into_instructions(line);
+    let instructions = divide_into_instructions(framed.command());
 
     //  Match the first instruction with the command, subsequent instructions are parameters to the first one
-    parse_command(instructions, line_number)
+    let command = parse_command(instructions, line_number, dialect)?;
+    match command {
+        Some(command) if !dialect.supports(command.name()) => Err(Error::UnsupportedByDialect(
+            command.name().to_string(),
+            dialect,
+            Some(line_number),
+        )),
+        command => Ok(command),
+    }
+}
+
+/// Returns the comment of a line without the leading semicolon, if the line has one
+pub(super) fn extract_comment(line: &str) -> Option<&str> {
+    find_unquoted(line, ';').map(|comment_index| line[comment_index + 1..].trim())
+}
+
+/// Reads a command named with a word, like `PRINT_START BED=60 EXTRUDER=210`. Values can be quoted
+/// to hold spaces, like `RESPOND MSG="Layer done"`. Returns None for commands of any other kind.
+/// Only Klipper reports broken parameters here, since generic gcode can't tell a broken extended
+/// command from a broken regular one, so the regular parser reports it instead
+fn parse_extended_command(
+    text: &str,
+    line_number: LineNumberType,
+    dialect: Dialect,
+) -> PrintResult<Option<ExtendedCommand>> {
+    let (name, parameters) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    if !is_extended_name(name) {
+        return Ok(None);
+    }
+
+    match split_extended_parameters(parameters) {
+        Ok(parameters) => Ok(Some(ExtendedCommand {
+            name: name.to_string(),
+            parameters,
+        })),
+        Err(parameter) if dialect == Dialect::Klipper => {
+            Err(invalid_parameter(parameter, line_number))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// True for names in uppercase made of words, like `EXCLUDE_OBJECT_DEFINE`.
+/// A letter followed by a number, like `G1` or `T0`, names a regular command instead
+fn is_extended_name(name: &str) -> bool {
+    let mut characters = name.chars();
+    let starts_with_letter = characters
+        .next()
+        .is_some_and(|character| character.is_ascii_uppercase() || character == '_');
+    let followed_by_word = characters
+        .next()
+        .is_some_and(|character| !character.is_ascii_digit());
+
+    starts_with_letter
+        && followed_by_word
+        && name.chars().all(|character| {
+            character.is_ascii_uppercase() || character.is_ascii_digit() || character == '_'
+        })
+}
+
+/// Splits `KEY=value` pairs apart, keys in uppercase and values without their quotes.
+/// Returns the first word that isn't a valid pair, if any
+fn split_extended_parameters(text: &str) -> Result<Vec<(String, String)>, &str> {
+    let mut parameters = vec![];
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let word = &rest[..rest.find(char::is_whitespace).unwrap_or(rest.len())];
+        let key = match word.split_once('=') {
+            Some((key, _))
+                if !key.is_empty()
+                    && key
+                        .chars()
+                        .all(|character| character.is_ascii_alphanumeric() || character == '_') =>
+            {
+                key
+            }
+            _ => return Err(word),
+        };
+
+        let after_key = &rest[key.len() + 1..];
+        let (value, remaining) = match after_key.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => return Err(rest.trim_end()),
+            },
+            None => after_key.split_at(
+                after_key
+                    .find(char::is_whitespace)
+                    .unwrap_or(after_key.len()),
+            ),
+        };
+        //  Closing quotes must end the value
+        if !remaining.is_empty() && !remaining.starts_with(char::is_whitespace) {
+            return Err(word);
+        }
+
+        parameters.push((key.to_ascii_uppercase(), value.to_string()));
+        rest = remaining.trim_start();
+    }
+
+    Ok(parameters)
 }
 
 /// Takes the contents of a single line and divides it into a set of instructions per line
@@ -43,7 +180,8 @@ fn divide_into_instructions(line: &str) -> Vec<&str> {
 fn parse_command(
     instructions: Vec<&str>,
     line_number: LineNumberType,
-) -> PrintResult<Option<impl GcodeExecutable>> {
+    dialect: Dialect,
+) -> PrintResult<Option<GcodeCommand>> {
     //  If instructions is empty, it means the line was either a comment or empty
     if instructions.is_empty() {
         return Ok(None);
@@ -57,47 +195,135 @@ fn parse_command(
     //  Match by the first element of the instructions set, it determines the command
     match base_command {
         //  G Commands
-        "G0" => Ok(Some(G1Move::default())),
-        "G1" => Ok(Some(G1Move::default())),
-        "G2" => Ok(Some(G1Move::default())),
-        "G3" => Ok(Some(G1Move::default())),
-        "G4" => Ok(Some(G1Move::default())),
-        "G10" => Ok(Some(G1Move::default())),
-        "G11" => Ok(Some(G1Move::default())),
-        "G21" => Ok(Some(G1Move::default())),
-        "G28" => Ok(Some(G1Move::default())),
-        "G29" => Ok(Some(G1Move::default())),
-        "G80" => Ok(Some(G1Move::default())),
-        "G90" => Ok(Some(G1Move::default())),
-        "G91" => Ok(Some(G1Move::default())),
-        "G92" => Ok(Some(G1Move::default())),
+        "G0" => Ok(Some(GcodeCommand::G0(parse_linear_move(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "G1" => Ok(Some(GcodeCommand::G1(parse_linear_move(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "G2" => Ok(Some(GcodeCommand::G2(parse_arc_move(
+            &instructions,
+            line_number,
+        )?))),
+        "G3" => Ok(Some(GcodeCommand::G3(parse_arc_move(
+            &instructions,
+            line_number,
+        )?))),
+        "G4" => Ok(Some(passthrough(&instructions))),
+        "G10" => Ok(Some(passthrough(&instructions))),
+        "G11" => Ok(Some(passthrough(&instructions))),
+        "G20" => Ok(Some(GcodeCommand::G20)),
+        "G21" => Ok(Some(GcodeCommand::G21)),
+        "G28" => Ok(Some(GcodeCommand::G28(parse_home(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "G29" => Ok(Some(GcodeCommand::G29(parse_probe_mesh(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "G80" => Ok(Some(passthrough(&instructions))),
+        "G90" => Ok(Some(GcodeCommand::G90)),
+        "G91" => Ok(Some(GcodeCommand::G91)),
+        "G92" => Ok(Some(GcodeCommand::G92(parse_set_position(
+            &instructions[1..],
+            line_number,
+        )?))),
 
         // M Commands
-        "M73" => Ok(Some(G1Move::default())),
-        "M82" => Ok(Some(G1Move::default())),
-        "M83" => Ok(Some(G1Move::default())),
-        "M84" => Ok(Some(G1Move::default())),
-        "M104" => Ok(Some(G1Move::default())),
-        "M106" => Ok(Some(G1Move::default())),
-        "M107" => Ok(Some(G1Move::default())),
-        "M109" => Ok(Some(G1Move::default())),
-        "M112" => Ok(Some(G1Move::default())),
-        "M115" => Ok(Some(G1Move::default())),
-        "M140" => Ok(Some(G1Move::default())),
-        "M190" => Ok(Some(G1Move::default())),
-        "M201" => Ok(Some(G1Move::default())),
-        "M203" => Ok(Some(G1Move::default())),
-        "M204" => Ok(Some(G1Move::default())),
-        "M205" => Ok(Some(G1Move::default())),
-        "M221" => Ok(Some(G1Move::default())),
-        "M500" => Ok(Some(G1Move::default())),
-        "M501" => Ok(Some(G1Move::default())),
-        "M502" => Ok(Some(G1Move::default())),
-        "M600" => Ok(Some(G1Move::default())),
-        "M701" => Ok(Some(G1Move::default())),
-        "M702" => Ok(Some(G1Move::default())),
-        "M862" => Ok(Some(G1Move::default())),
-        "M900" => Ok(Some(G1Move::default())),
+        "M73" => Ok(Some(passthrough(&instructions))),
+        "M98" => Ok(Some(passthrough(&instructions))),
+        "M82" => Ok(Some(GcodeCommand::M82)),
+        "M83" => Ok(Some(GcodeCommand::M83)),
+        "M84" => Ok(Some(passthrough(&instructions))),
+        "M92" => Ok(Some(GcodeCommand::M92(parse_axis_parameters(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "M104" => Ok(Some(passthrough(&instructions))),
+        "M106" => Ok(Some(passthrough(&instructions))),
+        "M107" => Ok(Some(passthrough(&instructions))),
+        "M109" => Ok(Some(passthrough(&instructions))),
+        "M112" => Ok(Some(GcodeCommand::M112)),
+        "M115" => Ok(Some(passthrough(&instructions))),
+        "M140" => Ok(Some(passthrough(&instructions))),
+        "M190" => Ok(Some(passthrough(&instructions))),
+        "M201" => Ok(Some(GcodeCommand::M201(parse_axis_parameters(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "M203" => Ok(Some(GcodeCommand::M203(parse_axis_parameters(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "M204" => Ok(Some(GcodeCommand::M204(parse_acceleration(
+            &instructions[1..],
+            line_number,
+            dialect,
+        )?))),
+        "M205" => Ok(Some(GcodeCommand::M205(parse_advanced_settings(
+            &instructions[1..],
+            line_number,
+            dialect,
+        )?))),
+        "M221" => {
+            //  Extruder is picked with T by Marlin, and with D by RepRapFirmware. Klipper has a single one
+            let letters = match dialect {
+                Dialect::Generic => "STD",
+                Dialect::Marlin | Dialect::Prusa => "ST",
+                Dialect::Klipper => "S",
+                Dialect::RepRapFirmware => "SD",
+            };
+            Ok(Some(checked_passthrough(
+                &instructions,
+                letters,
+                line_number,
+            )?))
+        }
+        "M301" => Ok(Some(GcodeCommand::M301(parse_pid_gains(
+            &instructions[1..],
+            "ECF",
+            line_number,
+        )?))),
+        "M303" => Ok(Some(GcodeCommand::M303(parse_pid_autotune(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "M304" => Ok(Some(GcodeCommand::M304(parse_pid_gains(
+            &instructions[1..],
+            "",
+            line_number,
+        )?))),
+        "M410" => Ok(Some(GcodeCommand::M410)),
+        "M413" => Ok(Some(passthrough(&instructions))),
+        "M420" => Ok(Some(GcodeCommand::M420(parse_leveling_state(
+            &instructions[1..],
+            line_number,
+        )?))),
+        "M421" => Ok(Some(GcodeCommand::M421(parse_set_mesh_point(
+            &instructions,
+            line_number,
+        )?))),
+        "M486" => Ok(Some(passthrough(&instructions))),
+        "M500" => Ok(Some(GcodeCommand::M500)),
+        "M501" => Ok(Some(GcodeCommand::M501)),
+        "M502" => Ok(Some(GcodeCommand::M502)),
+        "M503" => Ok(Some(GcodeCommand::M503)),
+        "M566" => Ok(Some(passthrough(&instructions))),
+        "M572" => Ok(Some(passthrough(&instructions))),
+        "M600" => Ok(Some(passthrough(&instructions))),
+        "M701" => Ok(Some(passthrough(&instructions))),
+        "M702" => Ok(Some(passthrough(&instructions))),
+        "M862" => Ok(Some(passthrough(&instructions))),
+        "M900" => Ok(Some(GcodeCommand::M900(parse_pressure_advance(
+            &instructions[1..],
+            line_number,
+        )?))),
+
+        //  Tool changes, named after the extruder they pick
+        tool if tool_number(tool).is_some() => Ok(Some(passthrough(&instructions))),
 
         //  Any other command might be either unsupported or wrong
         _ => {
@@ -112,6 +338,416 @@ fn parse_command(
     }
 }
 
+/// Wraps an accepted command that doesn't affect the machine yet, keeping its raw parameters
+fn passthrough(instructions: &[&str]) -> GcodeCommand {
+    GcodeCommand::Passthrough(
+        instructions[0].to_string(),
+        instructions[1..]
+            .iter()
+            .map(|parameter| parameter.to_string())
+            .collect(),
+    )
+}
+
+/// Same as `passthrough()`, but every parameter must be one of the letters given
+fn checked_passthrough(
+    instructions: &[&str],
+    letters: &str,
+    line_number: LineNumberType,
+) -> PrintResult<GcodeCommand> {
+    for parameter in &instructions[1..] {
+        let (letter, _) = parse_parameter(parameter, line_number)?;
+        if !letters.contains(letter) {
+            return Err(invalid_parameter(parameter, line_number));
+        }
+    }
+
+    Ok(passthrough(instructions))
+}
+
+/// Builds a linear move out of its parameters. Used for both G0 and G1
+fn parse_linear_move(parameters: &[&str], line_number: LineNumberType) -> PrintResult<G1Move> {
+    let mut linear_move = G1Move::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('X', value) => linear_move.x_target = Some(value),
+            ('Y', value) => linear_move.y_target = Some(value),
+            ('Z', value) => linear_move.z_target = Some(value),
+            ('E', value) => linear_move.amount_to_extrude = Some(value),
+            ('F', value) => linear_move.feedrate_per_minute = Some(value),
+            //  Float to integer casts saturate, so out of range powers are clamped
+            ('S', value) => linear_move.laser_power = Some(value as PowerType),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(linear_move)
+}
+
+/// Builds an arc out of the full instruction set. Used for both G2 and G3.
+/// The center needs either I and J or a radius, but not both
+fn parse_arc_move(instructions: &[&str], line_number: LineNumberType) -> PrintResult<G2ArcMove> {
+    let mut arc_move = G2ArcMove::default();
+
+    for parameter in &instructions[1..] {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('X', value) => arc_move.x_target = Some(value),
+            ('Y', value) => arc_move.y_target = Some(value),
+            ('Z', value) => arc_move.z_target = Some(value),
+            ('E', value) => arc_move.amount_to_extrude = Some(value),
+            ('F', value) => arc_move.feedrate_per_minute = Some(value),
+            ('I', value) => arc_move.i_offset = Some(value),
+            ('J', value) => arc_move.j_offset = Some(value),
+            ('R', value) if value != 0.0 => arc_move.radius = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    let has_offsets = arc_move.i_offset.is_some() || arc_move.j_offset.is_some();
+    if has_offsets == arc_move.radius.is_some() {
+        return Err(invalid_parameter(&instructions.join(" "), line_number));
+    }
+
+    Ok(arc_move)
+}
+
+/// Builds a G28 command out of its parameters. Axes can have a value, but it's ignored
+fn parse_home(parameters: &[&str], line_number: LineNumberType) -> PrintResult<G28Home> {
+    let mut home = G28Home::default();
+
+    for parameter in parameters {
+        match parse_parameter(parameter, line_number)? {
+            ('X', _) => home.x = true,
+            ('Y', _) => home.y = true,
+            ('Z', _) => home.z = true,
+            ('W', None) => home.skip_mesh_leveling = true,
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(home)
+}
+
+/// Builds a G29 command out of its parameters. Grids need at least two points along each axis
+fn parse_probe_mesh(parameters: &[&str], line_number: LineNumberType) -> PrintResult<G29ProbeMesh> {
+    let mut probe_mesh = G29ProbeMesh::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('P', _) => {
+                let points = parse_index(parameter, line_number, 2)?;
+                probe_mesh.columns = Some(points);
+                probe_mesh.rows = Some(points);
+            }
+            ('X', _) => probe_mesh.columns = Some(parse_index(parameter, line_number, 2)?),
+            ('Y', _) => probe_mesh.rows = Some(parse_index(parameter, line_number, 2)?),
+            ('L', value) => probe_mesh.left = Some(value),
+            ('R', value) => probe_mesh.right = Some(value),
+            ('F', value) => probe_mesh.front = Some(value),
+            ('B', value) => probe_mesh.back = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(probe_mesh)
+}
+
+/// Builds a M420 command out of its parameters. The fade height can't be negative
+fn parse_leveling_state(
+    parameters: &[&str],
+    line_number: LineNumberType,
+) -> PrintResult<M420LevelingState> {
+    let mut leveling_state = M420LevelingState::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('S', value) => leveling_state.enabled = Some(value != 0.0),
+            ('Z', value) if value >= 0.0 => leveling_state.fade_height = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(leveling_state)
+}
+
+/// Builds a M421 command out of the full instruction set. Both the column and the row of the point
+/// are needed, either as indexes or coordinates, along with exactly one of Z and Q
+fn parse_set_mesh_point(
+    instructions: &[&str],
+    line_number: LineNumberType,
+) -> PrintResult<M421SetMeshPoint> {
+    let mut mesh_point = M421SetMeshPoint::default();
+
+    for parameter in &instructions[1..] {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('I', _) => mesh_point.column = Some(parse_index(parameter, line_number, 0)?),
+            ('J', _) => mesh_point.row = Some(parse_index(parameter, line_number, 0)?),
+            ('X', value) => mesh_point.x = Some(value),
+            ('Y', value) => mesh_point.y = Some(value),
+            ('Z', value) => mesh_point.z = Some(value),
+            ('Q', value) => mesh_point.offset = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    let has_column = mesh_point.column.is_some() || mesh_point.x.is_some();
+    let has_row = mesh_point.row.is_some() || mesh_point.y.is_some();
+    if !has_column || !has_row || mesh_point.z.is_some() == mesh_point.offset.is_some() {
+        return Err(invalid_parameter(&instructions.join(" "), line_number));
+    }
+
+    Ok(mesh_point)
+}
+
+/// Builds a M900 command out of its parameters. The factor can't be negative. L and S belong to the
+/// older linear advance versions and are ignored
+fn parse_pressure_advance(
+    parameters: &[&str],
+    line_number: LineNumberType,
+) -> PrintResult<M900PressureAdvance> {
+    let mut pressure_advance = M900PressureAdvance::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('K', value) if value >= 0.0 => pressure_advance.factor = Some(value),
+            ('T', _) => {
+                let tool = parse_index(parameter, line_number, 0)?;
+                let tool =
+                    u8::try_from(tool).map_err(|_| invalid_parameter(parameter, line_number))?;
+                pressure_advance.tool = Some(tool);
+            }
+            ('L' | 'S', _) => {}
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(pressure_advance)
+}
+
+/// Builds a M301 or M304 command out of its parameters. Gains can't be negative. The letters in `ignored`
+/// belong to features not handled, like the extrusion scaling of M301, and are accepted without setting anything
+fn parse_pid_gains(
+    parameters: &[&str],
+    ignored: &str,
+    line_number: LineNumberType,
+) -> PrintResult<M301PidGains> {
+    let mut gains = M301PidGains::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('P', value) if value >= 0.0 => gains.proportional = Some(value),
+            ('I', value) if value >= 0.0 => gains.integral = Some(value),
+            ('D', value) if value >= 0.0 => gains.derivative = Some(value),
+            (letter, _) if ignored.contains(letter) => {}
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(gains)
+}
+
+/// Builds a M303 command out of its parameters. E-1 tunes the bed and any other tool the hotend.
+/// The target must be above zero, and the cycles fit a byte
+fn parse_pid_autotune(
+    parameters: &[&str],
+    line_number: LineNumberType,
+) -> PrintResult<M303PidAutotune> {
+    let mut autotune = M303PidAutotune::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('E', value) if value < 0.0 => autotune.heater = HeaterKind::Bed,
+            ('E', _) => autotune.heater = HeaterKind::Hotend,
+            ('S', value) if value > 0.0 => autotune.target = Some(value),
+            ('C', _) => {
+                let cycles = parse_index(parameter, line_number, 1)?;
+                let cycles =
+                    u8::try_from(cycles).map_err(|_| invalid_parameter(parameter, line_number))?;
+                autotune.cycles = Some(cycles);
+            }
+            ('U', value) => autotune.use_result = value != 0.0,
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(autotune)
+}
+
+/// Builds a G92 command out of its parameters
+fn parse_set_position(
+    parameters: &[&str],
+    line_number: LineNumberType,
+) -> PrintResult<G92SetPosition> {
+    let mut set_position = G92SetPosition::default();
+
+    for parameter in parameters {
+        match parse_valued_parameter(parameter, line_number)? {
+            ('X', value) => set_position.x = Some(value),
+            ('Y', value) => set_position.y = Some(value),
+            ('Z', value) => set_position.z = Some(value),
+            ('E', value) => set_position.e = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(set_position)
+}
+
+/// Builds the parameters of commands configuring each axis, like M92, M201 and M203.
+/// Values can't be zero or negative
+fn parse_axis_parameters(
+    parameters: &[&str],
+    line_number: LineNumberType,
+) -> PrintResult<AxisParameters> {
+    let mut axis_parameters = AxisParameters::default();
+
+    for parameter in parameters {
+        let (letter, value) = parse_positive_parameter(parameter, line_number)?;
+        match letter {
+            'X' => axis_parameters.x = Some(value),
+            'Y' => axis_parameters.y = Some(value),
+            'Z' => axis_parameters.z = Some(value),
+            'E' => axis_parameters.e = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(axis_parameters)
+}
+
+/// Builds a M204 command out of its parameters. Accelerations can't be zero or negative.
+/// Firmwares read them differently, so they're all turned into the Marlin meaning
+fn parse_acceleration(
+    parameters: &[&str],
+    line_number: LineNumberType,
+    dialect: Dialect,
+) -> PrintResult<M204Acceleration> {
+    let mut acceleration = M204Acceleration::default();
+
+    for parameter in parameters {
+        match (dialect, parse_positive_parameter(parameter, line_number)?) {
+            (Dialect::Klipper, ('R', _)) | (Dialect::RepRapFirmware, ('R' | 'S', _)) => {
+                return Err(invalid_parameter(parameter, line_number));
+            }
+            (_, ('P', value)) => acceleration.print = Some(value),
+            (_, ('R', value)) => acceleration.retract = Some(value),
+            (_, ('T', value)) => acceleration.travel = Some(value),
+            (_, ('S', value)) => acceleration.legacy = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    match dialect {
+        //  Without P, Prusa firmware follows Marlin 1.0, where T is the acceleration of retractions
+        Dialect::Prusa if acceleration.print.is_none() => {
+            if let Some(retract) = acceleration.travel.take() {
+                acceleration.retract = Some(retract);
+            }
+        }
+        //  Klipper has a single acceleration, set by S, or else by the lowest of P and T, which go together
+        Dialect::Klipper => match (acceleration.print.take(), acceleration.travel.take()) {
+            _ if acceleration.legacy.is_some() => {}
+            (Some(print), Some(travel)) => acceleration.legacy = Some(print.min(travel)),
+            (None, None) => {}
+            _ => return Err(invalid_parameter(&parameters.join(" "), line_number)),
+        },
+        _ => {}
+    }
+
+    Ok(acceleration)
+}
+
+/// Builds a M205 command out of its parameters. Every value can be zero, but not negative.
+/// RepRapFirmware only takes the jerk of each axis
+fn parse_advanced_settings(
+    parameters: &[&str],
+    line_number: LineNumberType,
+    dialect: Dialect,
+) -> PrintResult<M205AdvancedSettings> {
+    let mut settings = M205AdvancedSettings::default();
+
+    for parameter in parameters {
+        let (letter, value) = parse_valued_parameter(parameter, line_number)?;
+        if value < 0.0 {
+            return Err(invalid_parameter(parameter, line_number));
+        }
+
+        match letter {
+            'J' | 'S' | 'T' if dialect == Dialect::RepRapFirmware => {
+                return Err(invalid_parameter(parameter, line_number));
+            }
+            'X' => settings.jerk.x = Some(value),
+            'Y' => settings.jerk.y = Some(value),
+            'Z' => settings.jerk.z = Some(value),
+            'E' => settings.jerk.e = Some(value),
+            'J' => settings.junction_deviation = Some(value),
+            'S' => settings.min_feedrate = Some(value),
+            'T' => settings.min_travel_feedrate = Some(value),
+            _ => return Err(invalid_parameter(parameter, line_number)),
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Splits a parameter into its letter and value. Ex: `X116.259` is split into `('X', Some(116.259))`
+/// Parameters without a value, like the axes in `G28 X Y`, return None as value
+fn parse_parameter(
+    parameter: &str,
+    line_number: LineNumberType,
+) -> PrintResult<(char, Option<f32>)> {
+    let mut characters = parameter.chars();
+    let letter = match characters.next() {
+        Some(letter) if letter.is_ascii_alphabetic() => letter.to_ascii_uppercase(),
+        _ => return Err(invalid_parameter(parameter, line_number)),
+    };
+
+    let value = characters.as_str();
+    if value.is_empty() {
+        return Ok((letter, None));
+    }
+
+    match value.parse::<f32>() {
+        Ok(value) if value.is_finite() => Ok((letter, Some(value))),
+        _ => Err(invalid_parameter(parameter, line_number)),
+    }
+}
+
+/// Same as `parse_parameter()`, but the value is mandatory
+fn parse_valued_parameter(
+    parameter: &str,
+    line_number: LineNumberType,
+) -> PrintResult<(char, f32)> {
+    match parse_parameter(parameter, line_number)? {
+        (letter, Some(value)) => Ok((letter, value)),
+        (_, None) => Err(invalid_parameter(parameter, line_number)),
+    }
+}
+
+/// Same as `parse_valued_parameter()`, but the value must be greater than zero
+fn parse_positive_parameter(
+    parameter: &str,
+    line_number: LineNumberType,
+) -> PrintResult<(char, f32)> {
+    match parse_valued_parameter(parameter, line_number)? {
+        (letter, value) if value > 0.0 => Ok((letter, value)),
+        _ => Err(invalid_parameter(parameter, line_number)),
+    }
+}
+
+/// Reads the value of a parameter as a whole number, no lower than `min`. Used for indexes and amounts of points
+fn parse_index(parameter: &str, line_number: LineNumberType, min: usize) -> PrintResult<usize> {
+    match parse_valued_parameter(parameter, line_number)? {
+        (_, value) if value.fract() == 0.0 && value >= min as f32 => Ok(value as usize),
+        _ => Err(invalid_parameter(parameter, line_number)),
+    }
+}
+
+fn invalid_parameter(parameter: &str, line_number: LineNumberType) -> Error {
+    Error::InvalidParameterInLine(parameter.to_string(), Some(line_number))
+}
+
 /// Check a list of unsupported commands to this moment. These will be later implemented and added to the function `parse_command()`
 fn check_unsupported_commands(base_command: &str) -> Error {
     match base_command {
@@ -122,7 +758,6 @@ fn check_unsupported_commands(base_command: &str) -> Error {
         "G17" => Error::UnsupportedCommand(base_command.to_string()),
         "G18" => Error::UnsupportedCommand(base_command.to_string()),
         "G19" => Error::UnsupportedCommand(base_command.to_string()),
-        "G20" => Error::UnsupportedCommand(base_command.to_string()),
         "G26" => Error::UnsupportedCommand(base_command.to_string()),
         "G27" => Error::UnsupportedCommand(base_command.to_string()),
         "G30" => Error::UnsupportedCommand(base_command.to_string()),
@@ -180,7 +815,6 @@ fn check_unsupported_commands(base_command: &str) -> Error {
         "M77" => Error::UnsupportedCommand(base_command.to_string()),
         "M78" => Error::UnsupportedCommand(base_command.to_string()),
         "M85" => Error::UnsupportedCommand(base_command.to_string()),
-        "M92" => Error::UnsupportedCommand(base_command.to_string()),
         "M100" => Error::UnsupportedCommand(base_command.to_string()),
         "M110" => Error::UnsupportedCommand(base_command.to_string()),
         "M111" => Error::UnsupportedCommand(base_command.to_string()),
@@ -226,10 +860,7 @@ fn check_unsupported_commands(base_command: &str) -> Error {
         "M245" => Error::UnsupportedCommand(base_command.to_string()),
         "M246" => Error::UnsupportedCommand(base_command.to_string()),
         "M300" => Error::UnsupportedCommand(base_command.to_string()),
-        "M301" => Error::UnsupportedCommand(base_command.to_string()),
         "M302" => Error::UnsupportedCommand(base_command.to_string()),
-        "M303" => Error::UnsupportedCommand(base_command.to_string()),
-        "M304" => Error::UnsupportedCommand(base_command.to_string()),
         "M305" => Error::UnsupportedCommand(base_command.to_string()),
         "M306" => Error::UnsupportedCommand(base_command.to_string()),
         "M307" => Error::UnsupportedCommand(base_command.to_string()),
@@ -257,14 +888,10 @@ fn check_unsupported_commands(base_command: &str) -> Error {
         "M405" => Error::UnsupportedCommand(base_command.to_string()),
         "M406" => Error::UnsupportedCommand(base_command.to_string()),
         "M407" => Error::UnsupportedCommand(base_command.to_string()),
-        "M410" => Error::UnsupportedCommand(base_command.to_string()),
         "M412" => Error::UnsupportedCommand(base_command.to_string()),
-        "M420" => Error::UnsupportedCommand(base_command.to_string()),
-        "M421" => Error::UnsupportedCommand(base_command.to_string()),
         "M422" => Error::UnsupportedCommand(base_command.to_string()),
         "M425" => Error::UnsupportedCommand(base_command.to_string()),
         "M428" => Error::UnsupportedCommand(base_command.to_string()),
-        "M503" => Error::UnsupportedCommand(base_command.to_string()),
         "M540" => Error::UnsupportedCommand(base_command.to_string()),
         "M601" => Error::UnsupportedCommand(base_command.to_string()),
         "M602" => Error::UnsupportedCommand(base_command.to_string()),
@@ -1,6 +1,11 @@
 #[cfg(test)]
 mod test {
-    use crate::parser::gcode::parse::{divide_into_instructions, parse_line};
+    use crate::error::Error;
+    use crate::heater::HeaterKind;
+    use crate::parser::gcode::parse::{
+        divide_into_instructions, parse_line, parse_line_with_dialect,
+    };
+    use crate::parser::gcode::{Dialect, GcodeCommand};
 
     #[test]
     fn line_into_instructions_ok_with_comment() {
@@ -62,4 +67,232 @@ mod test {
 
         assert!(result.is_err())
     }
+
+    #[test]
+    fn parse_full_line_invalid_parameter() {
+        let line = "G1 X10.5.2 Y119.062 ; perimeter";
+        let result = parse_line(line, 7);
+
+        assert!(matches!(
+            result,
+            Err(Error::InvalidParameterInLine(parameter, Some(7))) if parameter == "X10.5.2"
+        ))
+    }
+
+    #[test]
+    fn parse_mesh_commands() {
+        assert!(matches!(
+            parse_line("G29 P4 L10 R200", 3),
+            Ok(Some(GcodeCommand::G29(probe_mesh)))
+                if probe_mesh.columns == Some(4) && probe_mesh.rows == Some(4)
+        ));
+        assert!(matches!(
+            parse_line("G29 X1", 4),
+            Err(Error::InvalidParameterInLine(parameter, Some(4))) if parameter == "X1"
+        ));
+        assert!(parse_line("M421 I1 J2 Z-0.05", 5).is_ok());
+        assert!(matches!(
+            parse_line("M421 I1 Z-0.05 Q0.1", 6),
+            Err(Error::InvalidParameterInLine(_, Some(6)))
+        ));
+    }
+
+    #[test]
+    fn parse_pressure_advance() {
+        assert!(matches!(
+            parse_line("M900 K0.05 T1", 1),
+            Ok(Some(GcodeCommand::M900(pressure_advance)))
+                if pressure_advance.factor == Some(0.05) && pressure_advance.tool == Some(1)
+        ));
+        //  Linear advance 1.0 parameters are accepted, but they don't set anything
+        assert!(matches!(
+            parse_line("M900 L0.8", 2),
+            Ok(Some(GcodeCommand::M900(pressure_advance))) if pressure_advance.factor.is_none()
+        ));
+        assert!(matches!(
+            parse_line("M900 K-0.1", 3),
+            Err(Error::InvalidParameterInLine(parameter, Some(3))) if parameter == "K-0.1"
+        ));
+    }
+
+    #[test]
+    fn parse_pid_commands() {
+        assert!(matches!(
+            parse_line("M301 P22.2 I1.08 D114 E0", 1),
+            Ok(Some(GcodeCommand::M301(gains)))
+                if gains.proportional == Some(22.2) && gains.derivative == Some(114.0)
+        ));
+        assert!(matches!(
+            parse_line("M304 I0.02", 2),
+            Ok(Some(GcodeCommand::M304(gains)))
+                if gains.integral == Some(0.02) && gains.proportional.is_none()
+        ));
+        assert!(matches!(
+            parse_line("M304 P-1", 3),
+            Err(Error::InvalidParameterInLine(parameter, Some(3))) if parameter == "P-1"
+        ));
+        //  E-1 picks the bed
+        assert!(matches!(
+            parse_line("M303 E-1 S60 C8 U1", 4),
+            Ok(Some(GcodeCommand::M303(autotune)))
+                if autotune.heater == HeaterKind::Bed
+                    && autotune.target == Some(60.0)
+                    && autotune.cycles == Some(8)
+                    && autotune.use_result
+        ));
+        assert!(matches!(
+            parse_line("M303 C0", 5),
+            Err(Error::InvalidParameterInLine(_, Some(5)))
+        ));
+    }
+
+    #[test]
+    fn parse_arc_moves() {
+        assert!(matches!(
+            parse_line("G2 X10 Y0 I5 J0 E0.4", 7),
+            Ok(Some(GcodeCommand::G2(arc_move)))
+                if arc_move.i_offset == Some(5.0) && arc_move.radius.is_none()
+        ));
+        assert!(matches!(
+            parse_line("G3 X10 R-5", 8),
+            Ok(Some(GcodeCommand::G3(arc_move))) if arc_move.radius == Some(-5.0)
+        ));
+        //  The center needs either offsets or a radius, but not both
+        assert!(matches!(
+            parse_line("G2 X10 Y0", 9),
+            Err(Error::InvalidParameterInLine(_, Some(9)))
+        ));
+        assert!(matches!(
+            parse_line("G2 X10 I5 R5", 10),
+            Err(Error::InvalidParameterInLine(_, Some(10)))
+        ));
+    }
+
+    #[test]
+    fn parse_by_dialect() {
+        //  Prusa specific checks are fine anywhere but on other firmwares
+        assert!(parse_line_with_dialect("M862.3 P \"MK3S\"", 1, Dialect::Prusa).is_ok());
+        assert!(matches!(
+            parse_line_with_dialect("M862.3 P \"MK3S\"", 2, Dialect::Marlin),
+            Err(Error::UnsupportedByDialect(command, Dialect::Marlin, Some(2)))
+                if command == "M862.3"
+        ));
+        assert!(matches!(
+            parse_line_with_dialect("M900 K0.05", 3, Dialect::RepRapFirmware),
+            Err(Error::UnsupportedByDialect(
+                _,
+                Dialect::RepRapFirmware,
+                Some(3)
+            ))
+        ));
+        assert!(parse_line_with_dialect("M572 D0 S0.05", 4, Dialect::RepRapFirmware).is_ok());
+        //  Tool changes are known to every firmware
+        assert!(matches!(
+            parse_line_with_dialect("T1", 4, Dialect::Klipper),
+            Ok(Some(command)) if command.tool_change() == Some(1)
+        ));
+
+        //  Same M204 parameters, different accelerations
+        assert!(matches!(
+            parse_line_with_dialect("M204 S1000 T3000", 5, Dialect::Marlin),
+            Ok(Some(GcodeCommand::M204(acceleration)))
+                if acceleration.travel == Some(3000.0) && acceleration.retract.is_none()
+        ));
+        assert!(matches!(
+            parse_line_with_dialect("M204 S1000 T3000", 6, Dialect::Prusa),
+            Ok(Some(GcodeCommand::M204(acceleration)))
+                if acceleration.retract == Some(3000.0) && acceleration.travel.is_none()
+        ));
+        assert!(matches!(
+            parse_line_with_dialect("M204 P1000 T3000", 7, Dialect::Klipper),
+            Ok(Some(GcodeCommand::M204(acceleration)))
+                if acceleration.legacy == Some(1000.0) && acceleration.print.is_none()
+        ));
+        assert!(parse_line_with_dialect("M204 P1000", 8, Dialect::Klipper).is_err());
+        assert!(matches!(
+            parse_line_with_dialect("M204 S1500 T3000", 8, Dialect::Klipper),
+            Ok(Some(GcodeCommand::M204(acceleration)))
+                if acceleration.legacy == Some(1500.0) && acceleration.travel.is_none()
+        ));
+        assert!(parse_line_with_dialect("M204 R1000", 9, Dialect::RepRapFirmware).is_err());
+        assert!(parse_line_with_dialect("M221 S95 D0", 10, Dialect::RepRapFirmware).is_ok());
+        assert!(parse_line_with_dialect("M221 S95 D0", 11, Dialect::Marlin).is_err());
+
+        //  Extended commands exist on Klipper, and generic gcode takes them too
+        assert!(matches!(
+            parse_line_with_dialect("SET_PRESSURE_ADVANCE ADVANCE=0.05", 12, Dialect::Klipper),
+            Ok(Some(GcodeCommand::Extended(extended)))
+                if extended.name() == "SET_PRESSURE_ADVANCE" && extended.number("advance").unwrap() == Some(0.05)
+        ));
+        assert!(parse_line_with_dialect("PRINT_START BED=60", 13, Dialect::Generic).is_ok());
+        assert!(matches!(
+            parse_line_with_dialect("PRINT_START BED=60", 14, Dialect::Marlin),
+            Err(Error::InvalidCommandInLine(_, Some(14)))
+        ));
+        assert!(matches!(
+            parse_line_with_dialect("M92 X80", 15, Dialect::Klipper),
+            Err(Error::UnsupportedByDialect(..))
+        ));
+    }
+
+    #[test]
+    fn parse_extended_commands() {
+        let line = "EXCLUDE_OBJECT_DEFINE NAME=part_1 CENTER=10,20 POLYGON=[[5,15],[15,25]]";
+        let Ok(Some(GcodeCommand::Extended(extended))) = parse_line(line, 1) else {
+            panic!("expected an extended command");
+        };
+        assert_eq!(extended.name(), "EXCLUDE_OBJECT_DEFINE");
+        assert_eq!(extended.parameters().len(), 3);
+        assert_eq!(extended.parameter("center"), Some("10,20"));
+        assert!(extended.number("NAME").is_err());
+
+        //  Quotes keep spaces, and keys are always uppercase
+        assert!(matches!(
+            parse_line("RESPOND type=echo MSG=\"Layer 2 done\" ; note", 2),
+            Ok(Some(GcodeCommand::Extended(extended)))
+                if extended.parameters() == [
+                    ("TYPE".to_string(), "echo".to_string()),
+                    ("MSG".to_string(), "Layer 2 done".to_string())
+                ]
+        ));
+        assert!(parse_line("PRINT_END", 3).is_ok());
+
+        //  Broken pairs are reported by Klipper, while generic gcode takes them for regular commands
+        assert!(matches!(
+            parse_line_with_dialect("RESPOND MSG=\"unclosed", 4, Dialect::Klipper),
+            Err(Error::InvalidParameterInLine(parameter, Some(4))) if parameter == "MSG=\"unclosed"
+        ));
+        assert!(matches!(
+            parse_line_with_dialect("SET_FAN_SPEED FAN", 5, Dialect::Klipper),
+            Err(Error::InvalidParameterInLine(parameter, Some(5))) if parameter == "FAN"
+        ));
+        assert!(matches!(
+            parse_line("GE1 X105.476 E0.039", 6),
+            Err(Error::InvalidCommandInLine(_, Some(6)))
+        ));
+        assert!(matches!(
+            parse_line("print_start BED=60", 7),
+            Err(Error::InvalidCommandInLine(_, Some(7)))
+        ));
+    }
+
+    #[test]
+    fn parse_extended_commands_like_framing() {
+        //  Macro names starting with N aren't line numbers
+        assert!(matches!(
+            parse_line_with_dialect("NOZZLE_WIPE", 1, Dialect::Klipper),
+            Ok(Some(GcodeCommand::Extended(extended)))
+                if extended.name() == "NOZZLE_WIPE" && extended.parameters().is_empty()
+        ));
+
+        //  Neither comments nor checksums start within quotes
+        assert!(matches!(
+            parse_line_with_dialect("RESPOND MSG=\"a;b\" ; note", 2, Dialect::Klipper),
+            Ok(Some(GcodeCommand::Extended(extended))) if extended.parameter("MSG") == Some("a;b")
+        ));
+        assert!(matches!(
+            parse_line_with_dialect("RESPOND MSG=\"a*b\"", 3, Dialect::Klipper),
+            Ok(Some(GcodeCommand::Extended(extended))) if extended.parameter("MSG") == Some("a*b")
+        ));
+    }
 }
@@ -0,0 +1,181 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{
+    error::{Error, PrintResult},
+    types::LineNumberType,
+};
+
+use super::{
+    commands::GcodeCommand,
+    dialect::Dialect,
+    parse::{extract_comment, parse_line_with_dialect},
+};
+
+/// Reads a gcode source line by line, handing out every line already parsed
+pub struct GcodeReader<R: BufRead> {
+    reader: R,
+    /// Number of the last line read, starting at 1
+    line_number: LineNumberType,
+    /// Bytes read from the start of the source
+    offset: u64,
+    dialect: Dialect,
+}
+
+/// Single line of a gcode source. Lines can hold a command, a comment, both or none at all
+#[derive(Debug, Clone)]
+pub struct GcodeLine {
+    line_number: LineNumberType,
+    /// Bytes from the start of the source to the line. None if the line wasn't read from a source
+    offset: Option<u64>,
+    command: Option<GcodeCommand>,
+    comment: Option<String>,
+}
+
+impl GcodeReader<BufReader<File>> {
+    pub fn from_file(file: File) -> Self {
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: BufRead> GcodeReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line_number: 0,
+            offset: 0,
+            dialect: Dialect::Generic,
+        }
+    }
+
+    /// Reads a source already positioned at the start of a line of a bigger one, like a file seeked
+    /// to the offset of a line, numbering the lines and offsets as in the whole source
+    pub fn starting_at(mut self, line_number: LineNumberType, offset: u64) -> Self {
+        self.line_number = line_number.saturating_sub(1);
+        self.offset = offset;
+        self
+    }
+
+    /// Reads the lines as the firmware of the dialect would. Every dialect is accepted by default
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Number of the last line read. Zero if nothing was read yet
+    pub fn line_number(&self) -> LineNumberType {
+        self.line_number
+    }
+}
+
+impl<R: BufRead> Iterator for GcodeReader<R> {
+    type Item = PrintResult<GcodeLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        let length = match self.reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(length) => length,
+            Err(error) => return Some(Err(Error::InputOutputError(error))),
+        };
+        let offset = self.offset;
+        self.offset += length as u64;
+        self.line_number += 1;
+
+        //  Line endings are dropped, both LF and CR LF
+        let line = match line.strip_suffix('\n') {
+            Some(line) => line.strip_suffix('\r').unwrap_or(line),
+            None => &line,
+        };
+        Some(
+            GcodeLine::parse_with_dialect(line, self.line_number, self.dialect).map(|mut line| {
+                line.offset = Some(offset);
+                line
+            }),
+        )
+    }
+}
+
+impl GcodeLine {
+    /// Parses a single line of text, like the reader does with every line of a source
+    pub fn parse(line: &str, line_number: LineNumberType) -> PrintResult<GcodeLine> {
+        Self::parse_with_dialect(line, line_number, Dialect::Generic)
+    }
+
+    /// Same as `parse()`, but only the commands of the dialect are accepted
+    pub fn parse_with_dialect(
+        line: &str,
+        line_number: LineNumberType,
+        dialect: Dialect,
+    ) -> PrintResult<GcodeLine> {
+        Ok(GcodeLine {
+            line_number,
+            offset: None,
+            command: parse_line_with_dialect(line, line_number, dialect)?,
+            comment: extract_comment(line).map(str::to_string),
+        })
+    }
+
+    /// Line made by a transformation of the source instead of read from it
+    pub(crate) fn new(
+        line_number: LineNumberType,
+        command: Option<GcodeCommand>,
+        comment: Option<String>,
+    ) -> GcodeLine {
+        GcodeLine {
+            line_number,
+            offset: None,
+            command,
+            comment,
+        }
+    }
+
+    pub fn line_number(&self) -> LineNumberType {
+        self.line_number
+    }
+
+    /// Bytes from the start of the source to the line. None if the line wasn't read from a source
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn command(&self) -> Option<&GcodeCommand> {
+        self.command.as_ref()
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Consumes the line and keeps only its command
+    pub fn into_command(self) -> Option<GcodeCommand> {
+        self.command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::GcodeCommand;
+
+    use super::GcodeReader;
+
+    #[test]
+    fn read_lines_with_comments() {
+        let source = "; header\n\nG1 X1 Y2 ; move\nM112\n";
+        let lines = GcodeReader::new(source.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].command().is_none());
+        assert_eq!(lines[0].comment(), Some("header"));
+        assert!(lines[1].command().is_none() && lines[1].comment().is_none());
+        assert!(matches!(lines[2].command(), Some(GcodeCommand::G1(_))));
+        assert_eq!(lines[2].comment(), Some("move"));
+        assert!(matches!(lines[3].command(), Some(GcodeCommand::M112)));
+        assert_eq!(lines[3].line_number(), 4);
+        assert_eq!(lines[3].offset(), Some(26));
+    }
+}
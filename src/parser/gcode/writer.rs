@@ -0,0 +1,415 @@
+use std::io::Write;
+
+use crate::error::{Error, PrintResult};
+use crate::heater::HeaterKind;
+use crate::types::LineNumberType;
+
+use super::commands::{AxisParameters, GcodeCommand};
+use super::reader::GcodeLine;
+#[cfg(test)]
+use super::reader::GcodeReader;
+
+/// Decimals written when the options don't say otherwise
+const DEFAULT_PRECISION: usize = 5;
+
+/// Characters ending each written line
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+/// How the commands are turned into text
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriterOptions {
+    /// Max amount of decimals of every value. Trailing zeros are never written
+    precision: usize,
+    /// Write the comments of the lines, and the lines holding only a comment
+    comments: bool,
+    /// Frame every command with a line number and a checksum, like hosts do when streaming to a printer.
+    /// Comments and empty lines are never written when framing
+    checksums: bool,
+    line_ending: LineEnding,
+}
+
+/// Writes commands as canonical gcode text, one per line
+pub struct GcodeWriter<W: Write> {
+    output: W,
+    options: WriterOptions,
+    /// Number given to the next framed line. Starts at 1
+    next_line_number: LineNumberType,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            precision: DEFAULT_PRECISION,
+            comments: true,
+            checksums: false,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
+
+impl WriterOptions {
+    pub fn new(precision: usize, comments: bool, checksums: bool, line_ending: LineEnding) -> Self {
+        Self {
+            precision,
+            comments,
+            checksums,
+            line_ending,
+        }
+    }
+
+    pub fn precision(&self) -> usize {
+        self.precision
+    }
+}
+
+impl LineEnding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+impl<W: Write> GcodeWriter<W> {
+    pub fn new(output: W, options: WriterOptions) -> Self {
+        Self {
+            output,
+            options,
+            next_line_number: 1,
+        }
+    }
+
+    /// Writes a command, followed by its comment if comments are enabled
+    pub fn write_command(
+        &mut self,
+        command: &GcodeCommand,
+        comment: Option<&str>,
+    ) -> PrintResult<()> {
+        let text = command.to_gcode(self.options.precision);
+
+        if self.options.checksums {
+            let framed = format!("N{} {}", self.next_line_number, text);
+            self.next_line_number += 1;
+            return self.write_text(&format!("{}*{}", framed, line_checksum(&framed)));
+        }
+
+        match comment {
+            Some(comment) if self.options.comments => {
+                self.write_text(&format!("{text} ; {comment}"))
+            }
+            _ => self.write_text(&text),
+        }
+    }
+
+    /// Writes a line holding only a comment. Skipped if comments are disabled
+    pub fn write_comment(&mut self, comment: &str) -> PrintResult<()> {
+        if !self.options.comments || self.options.checksums {
+            return Ok(());
+        }
+
+        self.write_text(&format!("; {comment}"))
+    }
+
+    /// Writes a line read by the reader. Lines without a command keep their comment, or are written empty
+    pub fn write_line(&mut self, line: &GcodeLine) -> PrintResult<()> {
+        match (line.command(), line.comment()) {
+            (Some(command), comment) => self.write_command(command, comment),
+            (None, Some(comment)) => self.write_comment(comment),
+            (None, None) if self.options.comments && !self.options.checksums => self.write_text(""),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Flushes the output and hands it back
+    pub fn into_inner(mut self) -> PrintResult<W> {
+        self.output.flush().map_err(Error::InputOutputError)?;
+        Ok(self.output)
+    }
+
+    fn write_text(&mut self, text: &str) -> PrintResult<()> {
+        self.output
+            .write_all(text.as_bytes())
+            .and_then(|_| {
+                self.output
+                    .write_all(self.options.line_ending.as_str().as_bytes())
+            })
+            .map_err(Error::InputOutputError)
+    }
+}
+
+impl GcodeCommand {
+    /// Canonical text of the command, without comment nor line ending. Parameters go in a fixed order,
+    /// and values are written with at most `precision` decimals
+    pub fn to_gcode(&self, precision: usize) -> String {
+        let mut text = String::new();
+        let mut value = |letter: char, value: Option<f32>| {
+            if let Some(value) = value {
+                text.push_str(&format!(" {letter}{}", format_number(value, precision)));
+            }
+        };
+
+        let name = match self {
+            GcodeCommand::G0(linear_move) | GcodeCommand::G1(linear_move) => {
+                value('X', linear_move.x_target);
+                value('Y', linear_move.y_target);
+                value('Z', linear_move.z_target);
+                value('E', linear_move.amount_to_extrude);
+                value('F', linear_move.feedrate_per_minute);
+                value('S', linear_move.laser_power.map(f32::from));
+                match self {
+                    GcodeCommand::G0(_) => "G0",
+                    _ => "G1",
+                }
+            }
+            GcodeCommand::G2(arc_move) | GcodeCommand::G3(arc_move) => {
+                value('X', arc_move.x_target);
+                value('Y', arc_move.y_target);
+                value('Z', arc_move.z_target);
+                value('I', arc_move.i_offset);
+                value('J', arc_move.j_offset);
+                value('R', arc_move.radius);
+                value('E', arc_move.amount_to_extrude);
+                value('F', arc_move.feedrate_per_minute);
+                match self {
+                    GcodeCommand::G2(_) => "G2",
+                    _ => "G3",
+                }
+            }
+            GcodeCommand::G20 => "G20",
+            GcodeCommand::G28(home) => {
+                for (letter, requested) in [
+                    ('X', home.x),
+                    ('Y', home.y),
+                    ('Z', home.z),
+                    ('W', home.skip_mesh_leveling),
+                ] {
+                    if requested {
+                        text.push(' ');
+                        text.push(letter);
+                    }
+                }
+                "G28"
+            }
+            GcodeCommand::G29(probe_mesh) => {
+                value('X', probe_mesh.columns.map(|columns| columns as f32));
+                value('Y', probe_mesh.rows.map(|rows| rows as f32));
+                value('L', probe_mesh.left);
+                value('R', probe_mesh.right);
+                value('F', probe_mesh.front);
+                value('B', probe_mesh.back);
+                "G29"
+            }
+            GcodeCommand::G21 => "G21",
+            GcodeCommand::G90 => "G90",
+            GcodeCommand::G91 => "G91",
+            GcodeCommand::G92(set_position) => {
+                value('X', set_position.x);
+                value('Y', set_position.y);
+                value('Z', set_position.z);
+                value('E', set_position.e);
+                "G92"
+            }
+            GcodeCommand::M82 => "M82",
+            GcodeCommand::M83 => "M83",
+            GcodeCommand::M92(parameters) => {
+                axis_values(&mut value, parameters);
+                "M92"
+            }
+            GcodeCommand::M112 => "M112",
+            GcodeCommand::M201(parameters) => {
+                axis_values(&mut value, parameters);
+                "M201"
+            }
+            GcodeCommand::M203(parameters) => {
+                axis_values(&mut value, parameters);
+                "M203"
+            }
+            GcodeCommand::M204(acceleration) => {
+                value('P', acceleration.print);
+                value('R', acceleration.retract);
+                value('T', acceleration.travel);
+                value('S', acceleration.legacy);
+                "M204"
+            }
+            GcodeCommand::M205(settings) => {
+                axis_values(&mut value, &settings.jerk);
+                value('J', settings.junction_deviation);
+                value('S', settings.min_feedrate);
+                value('T', settings.min_travel_feedrate);
+                "M205"
+            }
+            GcodeCommand::M410 => "M410",
+            GcodeCommand::M420(leveling_state) => {
+                value(
+                    'S',
+                    leveling_state.enabled.map(|enabled| enabled as u8 as f32),
+                );
+                value('Z', leveling_state.fade_height);
+                "M420"
+            }
+            GcodeCommand::M421(mesh_point) => {
+                value('I', mesh_point.column.map(|column| column as f32));
+                value('J', mesh_point.row.map(|row| row as f32));
+                value('X', mesh_point.x);
+                value('Y', mesh_point.y);
+                value('Z', mesh_point.z);
+                value('Q', mesh_point.offset);
+                "M421"
+            }
+            GcodeCommand::M500 => "M500",
+            GcodeCommand::M501 => "M501",
+            GcodeCommand::M502 => "M502",
+            GcodeCommand::M503 => "M503",
+            GcodeCommand::M301(gains) | GcodeCommand::M304(gains) => {
+                value('P', gains.proportional);
+                value('I', gains.integral);
+                value('D', gains.derivative);
+                match self {
+                    GcodeCommand::M301(_) => "M301",
+                    _ => "M304",
+                }
+            }
+            GcodeCommand::M303(autotune) => {
+                if autotune.heater == HeaterKind::Bed {
+                    value('E', Some(-1.0));
+                }
+                value('S', autotune.target);
+                value('C', autotune.cycles.map(f32::from));
+                if autotune.use_result {
+                    value('U', Some(1.0));
+                }
+                "M303"
+            }
+            GcodeCommand::M900(pressure_advance) => {
+                value('K', pressure_advance.factor);
+                value('T', pressure_advance.tool.map(f32::from));
+                "M900"
+            }
+            GcodeCommand::Passthrough(name, parameters) => {
+                for parameter in parameters {
+                    text.push(' ');
+                    text.push_str(parameter);
+                }
+                name
+            }
+            //  Values holding spaces need quotes to be read back as a single value
+            GcodeCommand::Extended(extended) => {
+                for (key, parameter) in extended.parameters() {
+                    match parameter.contains(char::is_whitespace) {
+                        true => text.push_str(&format!(" {key}=\"{parameter}\"")),
+                        false => text.push_str(&format!(" {key}={parameter}")),
+                    }
+                }
+                extended.name()
+            }
+        };
+
+        format!("{name}{text}")
+    }
+}
+
+fn axis_values(value: &mut impl FnMut(char, Option<f32>), parameters: &AxisParameters) {
+    value('X', parameters.x);
+    value('Y', parameters.y);
+    value('Z', parameters.z);
+    value('E', parameters.e);
+}
+
+/// Writes a value with at most `precision` decimals, dropping the trailing zeros. Ex: `0.80000` is written `0.8`
+pub(crate) fn format_number(value: f32, precision: usize) -> String {
+    let text = format!("{value:.precision$}");
+    let text = match text.contains('.') {
+        true => text.trim_end_matches('0').trim_end_matches('.'),
+        false => &text,
+    };
+
+    match text {
+        "-0" => "0".to_string(),
+        text => text.to_string(),
+    }
+}
+
+/// Checksum of a framed line, as hosts compute it: the XOR of every byte before the `*`
+pub fn line_checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+/// Runs a pass rewriting a job on a source, returning what it wrote along with what it returned
+#[cfg(test)]
+pub(crate) fn rewrite_source<T>(
+    source: &str,
+    rewrite: impl FnOnce(GcodeReader<&[u8]>, &mut GcodeWriter<Vec<u8>>) -> PrintResult<T>,
+) -> PrintResult<(String, T)> {
+    let mut writer = GcodeWriter::new(vec![], WriterOptions::default());
+    let result = rewrite(GcodeReader::new(source.as_bytes()), &mut writer)?;
+    Ok((String::from_utf8(writer.into_inner()?).unwrap(), result))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::{
+        GcodeCommand, GcodeReader, GcodeWriter, LineEnding, WriterOptions, line_checksum,
+    };
+
+    fn write_source(source: &str, options: WriterOptions) -> String {
+        let mut writer = GcodeWriter::new(vec![], options);
+        for line in GcodeReader::new(source.as_bytes()) {
+            writer.write_line(&line.unwrap()).unwrap();
+        }
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn small_example_round_trip() {
+        let source = std::fs::read_to_string("small_example.gcode").unwrap();
+        assert_eq!(write_source(&source, WriterOptions::default()), source);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let source = "G1 X117.536 Y130.259 E0.8 F2100 ; skirt\nG0 Z0.6\nG2 X20 Y10 I5 J-5 E1.2\nG3 X0 Y0 R-10\nG28 X Y W\nG29 X4 Y3 L10 R190\n\
+                      G92 E0\nM82\nM92 X80 E93.5\nM204 P1000 T2000\nM205 X8 J0.02\nM420 S1 Z10\n\
+                      M421 I1 J2 Z-0.05\nM104 S215\nM862.3 P \"MK3S\"\nM500\nM900 K0.05 T1\n\
+                      M301 P22.2 I1.08 D114\nM303 E-1 S60 C8 U1\nM304 P10\n\
+                      PRINT_START BED=60 EXTRUDER=210\nRESPOND MSG=\"Layer 2 done\"\n";
+        let written = write_source(source, WriterOptions::default());
+        assert_eq!(written, source);
+
+        let commands = |source: &str| {
+            GcodeReader::new(source.as_bytes())
+                .map(|line| line.unwrap().into_command())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(commands(&written), commands(source));
+    }
+
+    #[test]
+    fn precision_and_framing() {
+        let options = WriterOptions::new(2, false, true, LineEnding::CrLf);
+        let written = write_source("; start\nG1 X10.126 Y-0.001 E0.5 ; move\n\nM83\n", options);
+        let lines: Vec<&str> = written.split_terminator("\r\n").collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            format!(
+                "N1 G1 X10.13 Y0 E0.5*{}",
+                line_checksum("N1 G1 X10.13 Y0 E0.5")
+            )
+        );
+        assert!(lines[1].starts_with("N2 M83*"));
+
+        //  Framed lines read back into the same commands
+        let commands: Vec<_> = GcodeReader::new(written.as_bytes())
+            .map(|line| line.unwrap().into_command())
+            .collect();
+        assert_eq!(commands.len(), 2);
+        assert!(matches!(commands[1], Some(GcodeCommand::M83)));
+    }
+}
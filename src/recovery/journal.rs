@@ -0,0 +1,440 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, Seek, SeekFrom};
+
+use crate::error::{Error, PrintResult};
+use crate::gcode::GcodeReader;
+use crate::storage::Storage;
+use crate::system::{Location, PayloadReader, checksum, push_f32};
+use crate::types::{LineNumberType, LocationType};
+
+use super::{ModalState, ResumedJob};
+
+/// Marks the start of the journal in the storage
+const JOURNAL_MAGIC: &[u8; 4] = b"PRJL";
+/// Bumped on every change of the layout, so journals written by another version are never misread
+const JOURNAL_VERSION: u16 = 1;
+/// Magic, version and payload length
+const HEADER_SIZE: usize = 10;
+const CHECKSUM_SIZE: usize = 2;
+/// Commands executed between two writes of the journal when no interval is given
+const DEFAULT_JOURNAL_INTERVAL: usize = 20;
+
+const INCHES_FLAG: u8 = 1;
+const RELATIVE_POSITIONING_FLAG: u8 = 1 << 1;
+const RELATIVE_EXTRUSION_FLAG: u8 = 1 << 2;
+
+/// Record of the progress of a job, kept up to date by the executor while it runs, like the
+/// power-loss recovery of Marlin enabled with M413. A job still in the journal on boot was interrupted.
+/// Writes are atomic as long as the storage is, like `FileStorage`
+pub struct RecoveryJournal {
+    storage: Box<dyn Storage>,
+    /// Name the job is found by on boot, usually the path of its file
+    job: String,
+    /// Commands executed between two writes
+    interval: usize,
+    /// Turned on and off by M413
+    enabled: bool,
+    commands_since_write: usize,
+    /// Height of the last point written. Going up starts a layer, which is always written
+    last_z: Option<LocationType>,
+    /// Points of the commands executed while moves of the commands before them were still on their
+    /// way to the driver. Resuming from them would skip those moves, so they wait until they're sent
+    pending: VecDeque<RecoveryPoint>,
+}
+
+/// Point of an interrupted job the journal can resume it from
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryPoint {
+    job: String,
+    /// First line to run again
+    line_number: LineNumberType,
+    /// Bytes from the start of the job to the line
+    offset: u64,
+    /// State of the machine right before the line
+    state: ModalState,
+}
+
+impl RecoveryJournal {
+    pub fn new(storage: Box<dyn Storage>, job: &str) -> Self {
+        Self {
+            storage,
+            job: job.to_string(),
+            interval: DEFAULT_JOURNAL_INTERVAL,
+            enabled: true,
+            commands_since_write: 0,
+            last_z: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Sets how many commands are executed between two writes. Fewer writes wear the storage less,
+    /// but more of the job is printed again when resuming
+    pub fn with_interval(mut self, commands: usize) -> Self {
+        self.interval = commands.max(1);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// M413. Turning the journal off clears it, since it won't follow the job anymore
+    pub(crate) fn set_enabled(&mut self, enabled: bool) -> PrintResult<()> {
+        self.enabled = enabled;
+        match enabled {
+            true => Ok(()),
+            false => self.clear(),
+        }
+    }
+
+    /// Reads the journal left in a storage on boot. Returns the point to resume the interrupted job
+    /// from, or None if the last job finished or no job was ever journaled
+    pub fn interrupted_job(storage: &mut dyn Storage) -> PrintResult<Option<RecoveryPoint>> {
+        let data = storage.read()?;
+        //  Storage never written, like an erased EEPROM
+        if data.len() < HEADER_SIZE || &data[..4] != JOURNAL_MAGIC {
+            return Ok(None);
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        let payload_length = u32::from_le_bytes([data[6], data[7], data[8], data[9]]) as usize;
+        let checksum_start = HEADER_SIZE + payload_length;
+        if version != JOURNAL_VERSION || data.len() < checksum_start + CHECKSUM_SIZE {
+            return Err(Error::CorruptJournal);
+        }
+        let stored_checksum = u16::from_le_bytes([data[checksum_start], data[checksum_start + 1]]);
+        if stored_checksum != checksum(&data[..checksum_start]) {
+            return Err(Error::CorruptJournal);
+        }
+
+        match payload_length {
+            0 => Ok(None),
+            _ => decode_point(&data[HEADER_SIZE..checksum_start])
+                .map(Some)
+                .map_err(|_| Error::CorruptJournal),
+        }
+    }
+
+    /// Counts an executed command along with the state before it. The point is held until `reached()`
+    /// tells every move of the commands before it was sent to the driver
+    pub(crate) fn update(
+        &mut self,
+        line_number: LineNumberType,
+        offset: u64,
+        state: &ModalState,
+    ) -> PrintResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        self.pending.push_back(RecoveryPoint {
+            job: self.job.clone(),
+            line_number,
+            offset,
+            state: state.clone(),
+        });
+        Ok(())
+    }
+
+    /// Takes the moves up to the line at an offset as sent to the driver, writing the points held up
+    /// to that line if the journal is due. None when every move was sent, like once the machine stops
+    pub(crate) fn reached(&mut self, offset: Option<u64>) -> PrintResult<()> {
+        while let Some(point) = self.pending.front()
+            && offset.is_none_or(|offset| point.offset <= offset)
+        {
+            if let Some(point) = self.pending.pop_front() {
+                self.write_if_due(point)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the points held, like when the moves before them were dropped by an emergency stop
+    pub(crate) fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Marks the job as finished, so it isn't offered for resuming on boot
+    pub fn clear(&mut self) -> PrintResult<()> {
+        self.commands_since_write = 0;
+        self.last_z = None;
+        self.pending.clear();
+        self.storage.write(&frame(&[]))
+    }
+
+    fn write_if_due(&mut self, point: RecoveryPoint) -> PrintResult<()> {
+        let z = point.state.location.z;
+        let starts_layer = self.last_z.is_none_or(|last_z| z > last_z);
+        if !starts_layer && self.commands_since_write < self.interval {
+            self.commands_since_write += 1;
+            return Ok(());
+        }
+
+        self.commands_since_write = 1;
+        self.last_z = Some(z);
+        self.storage.write(&frame(&encode_point(&point)))
+    }
+}
+
+impl RecoveryPoint {
+    /// Name of the job, as given to the journal
+    pub fn job(&self) -> &str {
+        &self.job
+    }
+
+    pub fn line_number(&self) -> LineNumberType {
+        self.line_number
+    }
+
+    /// Bytes from the start of the job to the line
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn state(&self) -> &ModalState {
+        &self.state
+    }
+
+    /// Plan to resume the job: the preamble taking the machine back to the journaled state, followed
+    /// by the lines of the job from the journaled one. The source is seeked right to the line
+    pub fn resume<R: BufRead + Seek>(
+        &self,
+        mut source: R,
+    ) -> PrintResult<ResumedJob<GcodeReader<R>>> {
+        source
+            .seek(SeekFrom::Start(self.offset))
+            .map_err(Error::InputOutputError)?;
+        let lines = GcodeReader::new(source).starting_at(self.line_number, self.offset);
+
+        Ok(ResumedJob::with_preamble(
+            lines,
+            self.state.clone(),
+            self.line_number,
+        ))
+    }
+}
+
+/// Header with the magic, version and payload length, the payload, and a CRC-16 of everything before it.
+/// Same layout as the saved settings
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len() + CHECKSUM_SIZE);
+    data.extend_from_slice(JOURNAL_MAGIC);
+    data.extend_from_slice(&JOURNAL_VERSION.to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(payload);
+    data.extend_from_slice(&checksum(&data).to_le_bytes());
+    data
+}
+
+fn encode_point(point: &RecoveryPoint) -> Vec<u8> {
+    let state = &point.state;
+    let mut payload = vec![];
+
+    payload.extend_from_slice(&(point.job.len() as u16).to_le_bytes());
+    payload.extend_from_slice(point.job.as_bytes());
+    payload.extend_from_slice(&(point.line_number as u64).to_le_bytes());
+    payload.extend_from_slice(&point.offset.to_le_bytes());
+
+    for value in [
+        state.location.x,
+        state.location.y,
+        state.location.z,
+        state.extruder_position,
+        state.feedrate,
+    ] {
+        push_f32(&mut payload, value);
+    }
+    let mut flags = 0;
+    for (enabled, flag) in [
+        (state.inches, INCHES_FLAG),
+        (state.relative_positioning, RELATIVE_POSITIONING_FLAG),
+        (state.relative_extrusion, RELATIVE_EXTRUSION_FLAG),
+    ] {
+        if enabled {
+            flags |= flag;
+        }
+    }
+    payload.push(flags);
+    payload.push(state.hotend_temperatures.len() as u8);
+    for temperature in &state.hotend_temperatures {
+        payload.extend_from_slice(&temperature.to_le_bytes());
+    }
+    payload.extend_from_slice(&state.bed_temperature.to_le_bytes());
+    payload.push(state.fan_speed);
+    payload.push(state.tool);
+
+    payload
+}
+
+fn decode_point(payload: &[u8]) -> PrintResult<RecoveryPoint> {
+    let mut reader = PayloadReader::new(payload);
+
+    let job_length = reader.read_u16()? as usize;
+    let job = String::from_utf8(reader.read_slice(job_length)?.to_vec())
+        .map_err(|_| Error::CorruptJournal)?;
+    let line_number = reader.read_u64()? as LineNumberType;
+    let offset = reader.read_u64()?;
+
+    let location = Location::new(reader.read_f32()?, reader.read_f32()?, reader.read_f32()?);
+    let extruder_position = reader.read_f32()?;
+    let feedrate = reader.read_f32()?;
+    let flags = reader.read_u8()?;
+    let hotend_temperatures = (0..reader.read_u8()?)
+        .map(|_| reader.read_u16())
+        .collect::<PrintResult<Vec<_>>>()?;
+    let bed_temperature = reader.read_u16()?;
+    let fan_speed = reader.read_u8()?;
+    let tool = reader.read_u8()?;
+
+    if !reader.is_finished() {
+        return Err(Error::CorruptJournal);
+    }
+
+    Ok(RecoveryPoint {
+        job,
+        line_number,
+        offset,
+        state: ModalState {
+            location,
+            extruder_position,
+            feedrate,
+            inches: flags & INCHES_FLAG != 0,
+            relative_positioning: flags & RELATIVE_POSITIONING_FLAG != 0,
+            relative_extrusion: flags & RELATIVE_EXTRUSION_FLAG != 0,
+            hotend_temperatures,
+            bed_temperature,
+            fan_speed,
+            tool,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::error::Error;
+    use crate::executor::Executor;
+    use crate::gcode::GcodeReader;
+    use crate::motion::mock::MockDriver;
+    use crate::recovery::RecoveryJournal;
+    use crate::storage::{MemoryEeprom, Storage};
+    use crate::system::{Location, SystemConfig};
+
+    const JOB: &str = "M104 S210\nM140 S60\nM83\nG1 Z0.2 F1200\nG1 X10 Y10 E1\nG1 X20 Y10 E1\n\
+                       G1 Z0.4\nG1 X20 Y20 E1\nM106 S255\nG1 X10 Y20 E1\nG1 X10 Y10 E1\n";
+
+    fn executor() -> Executor {
+        let mut config = SystemConfig::default();
+        config.set_bed(
+            Location::new(0.0, 0.0, 0.0),
+            Location::new(200.0, 200.0, 200.0),
+        );
+        Executor::new(config)
+    }
+
+    #[test]
+    fn interrupted_job_resumes_from_journal() {
+        let eeprom = MemoryEeprom::default();
+        assert!(
+            RecoveryJournal::interrupted_job(&mut eeprom.clone())
+                .unwrap()
+                .is_none()
+        );
+
+        //  Power goes off in the middle of the second layer, once the moves so far reached the driver
+        let mut executor = executor();
+        executor.attach_journal(RecoveryJournal::new(Box::new(eeprom.clone()), "cube.gcode"));
+        let mut driver = MockDriver::default();
+        for line in GcodeReader::new(JOB.as_bytes()).take(9) {
+            executor.submit_line(line.unwrap()).unwrap();
+            executor.step(&mut driver).unwrap();
+        }
+        executor.finish(&mut driver).unwrap();
+
+        let point = RecoveryJournal::interrupted_job(&mut eeprom.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(point.job(), "cube.gcode");
+        //  Last write was the first command of the second layer
+        assert_eq!(point.line_number(), 8);
+        assert_eq!(point.offset(), 73);
+        assert_eq!(point.state().location(), Location::new(20.0, 10.0, 0.4));
+        assert_eq!(point.state().fan_speed(), 0);
+        assert_eq!(point.state().hotend_temperatures(), [210]);
+        assert!(point.state().is_relative_extrusion());
+
+        let resumed: Vec<String> = point
+            .resume(Cursor::new(JOB))
+            .unwrap()
+            .filter_map(|line| Some(line.unwrap().command()?.to_gcode(5)))
+            .collect();
+        assert_eq!(resumed[..2], ["M140 S60", "M104 S210"]);
+        assert_eq!(
+            resumed[resumed.len() - 6..],
+            [
+                "G1 F1200",
+                "M83",
+                "G1 X20 Y20 E1",
+                "M106 S255",
+                "G1 X10 Y20 E1",
+                "G1 X10 Y10 E1"
+            ]
+        );
+    }
+
+    #[test]
+    fn journal_follows_moves_sent_to_driver() {
+        let moves: String = (1..=30).map(|x| format!("G1 X{x} E0.1\n")).collect();
+        let job = format!("M83\nG1 Z0.2 F1200\n{moves}");
+        let eeprom = MemoryEeprom::default();
+        let mut executor = executor();
+        executor.attach_journal(
+            RecoveryJournal::new(Box::new(eeprom.clone()), "line.gcode").with_interval(1),
+        );
+        let mut driver = MockDriver::default();
+
+        //  Power goes off with the last 16 moves still in the planner
+        for line in GcodeReader::new(job.as_bytes()) {
+            executor.submit_line(line.unwrap()).unwrap();
+            executor.step(&mut driver).unwrap();
+        }
+
+        //  Last move sent was the one to X14, so it's run again from the state before it
+        let point = RecoveryJournal::interrupted_job(&mut eeprom.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(point.line_number(), 16);
+        assert_eq!(point.state().location(), Location::new(13.0, 0.0, 0.2));
+    }
+
+    #[test]
+    fn finished_job_clears_journal() {
+        let eeprom = MemoryEeprom::default();
+        let mut executor = executor();
+        executor.attach_journal(
+            RecoveryJournal::new(Box::new(eeprom.clone()), "cube.gcode").with_interval(1),
+        );
+        let mut driver = MockDriver::default();
+
+        executor
+            .run(GcodeReader::new(JOB.as_bytes()), &mut driver)
+            .unwrap();
+        assert!(
+            RecoveryJournal::interrupted_job(&mut eeprom.clone())
+                .unwrap()
+                .is_none()
+        );
+
+        //  Half written journals are rejected instead of misread
+        let mut storage = eeprom.clone();
+        let mut data = storage.read().unwrap();
+        data[6] = 4;
+        storage.write(&data).unwrap();
+        assert!(matches!(
+            RecoveryJournal::interrupted_job(&mut storage),
+            Err(Error::CorruptJournal)
+        ));
+    }
+}
@@ -0,0 +1,497 @@
+mod journal;
+
+use std::collections::VecDeque;
+
+use crate::error::{Error, PrintResult};
+use crate::export::LayerTracker;
+use crate::gcode::{G1Move, G28Home, G92SetPosition, GcodeCommand, GcodeLine};
+use crate::system::{Location, SystemConfig};
+use crate::types::{
+    ExtrudeAmountType, FeedrateAmountType, LineNumberType, LocationType, TemperatureType,
+};
+
+pub use journal::{RecoveryJournal, RecoveryPoint};
+
+/// Height the nozzle is raised above the print before homing, in millimeters
+const RESUME_Z_LIFT: LocationType = 2.0;
+/// Feedrate of the moves taking the nozzle back to the print, in millimeters per minute
+const RESUME_TRAVEL_FEEDRATE: FeedrateAmountType = 3000.0;
+/// Feedrate of the moves along Z taking the nozzle back to the print, in millimeters per minute
+const RESUME_Z_FEEDRATE: FeedrateAmountType = 600.0;
+
+/// Point of a job to restart a print from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePoint {
+    /// First line run again. Lines before it are skipped
+    Line(LineNumberType),
+    /// Layers are counted from 0, like in the job stats. Resumes from the first move printing it
+    Layer(usize),
+}
+
+/// Modal state of the machine at some point of a job, holding everything a print needs
+/// to go on from there on a machine that has just been turned on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModalState {
+    location: Location,
+    /// Logical position of the extruder axis, in millimeters of filament
+    extruder_position: ExtrudeAmountType,
+    /// Millimeters per minute. Zero if no move set it yet
+    feedrate: FeedrateAmountType,
+    inches: bool,
+    relative_positioning: bool,
+    relative_extrusion: bool,
+    /// Target of every hotend, in °C. Zero if off
+    hotend_temperatures: Vec<TemperatureType>,
+    /// Target of the bed, in °C. Zero if off
+    bed_temperature: TemperatureType,
+    /// Speed of the part cooling fan, from 0 to 255
+    fan_speed: u8,
+    /// Extruder picked by the last tool change
+    tool: u8,
+}
+
+/// Job restarted from some point. Hands out a preamble taking the machine back to the state of that
+/// point first, followed by the lines of the job from there on
+pub struct ResumedJob<I> {
+    lines: I,
+    state: ModalState,
+    /// Line the job resumes from
+    line_number: LineNumberType,
+    /// Preamble and the first line resumed, ready to be handed out
+    pending: VecDeque<GcodeLine>,
+}
+
+impl std::fmt::Display for ResumePoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResumePoint::Line(line_number) => write!(f, "line {line_number}"),
+            ResumePoint::Layer(layer) => write!(f, "layer {layer}"),
+        }
+    }
+}
+
+impl ModalState {
+    /// State of a machine just configured, before running any command
+    pub fn new(config: &SystemConfig) -> Self {
+        let mut state = Self {
+            location: Location::default(),
+            extruder_position: 0.0,
+            feedrate: 0.0,
+            inches: false,
+            relative_positioning: false,
+            relative_extrusion: false,
+            hotend_temperatures: vec![0; config.extruder_count().max(1) as usize],
+            bed_temperature: 0,
+            fan_speed: 0,
+            tool: 0,
+        };
+        state.follow(config);
+        state
+    }
+
+    /// Takes the position and the modes from the state tracker
+    pub(crate) fn follow(&mut self, config: &SystemConfig) {
+        self.location = config.current_location();
+        self.extruder_position = config.extruder_position();
+        self.feedrate = config.feedrate();
+        self.inches = config.is_inches();
+        self.relative_positioning = config.is_relative_positioning();
+        self.relative_extrusion = config.is_relative_extrusion();
+    }
+
+    /// Takes the temperatures, the fan and the tool from the commands the state tracker lets through
+    pub(crate) fn apply_command(&mut self, command: &GcodeCommand) {
+        if let Some(tool) = command.tool_change() {
+            self.tool = tool;
+        }
+
+        let temperature = || {
+            command
+                .parameter_value('S')
+                .or(command.parameter_value('R'))
+                .map(|value| value.round().max(0.0) as TemperatureType)
+        };
+        match command.name() {
+            "M104" | "M109" => {
+                let tool = match command.parameter_value('T') {
+                    Some(tool) => tool as usize,
+                    None => self.tool as usize,
+                };
+                if let Some(temperature) = temperature() {
+                    if self.hotend_temperatures.len() <= tool {
+                        self.hotend_temperatures.resize(tool + 1, 0);
+                    }
+                    self.hotend_temperatures[tool] = temperature;
+                }
+            }
+            "M140" | "M190" => {
+                if let Some(temperature) = temperature() {
+                    self.bed_temperature = temperature;
+                }
+            }
+            //  Only the part cooling fan is followed, the first one
+            "M106" if command.parameter_value('P').unwrap_or_default() == 0.0 => {
+                self.fan_speed = command
+                    .parameter_value('S')
+                    .map_or(255.0, |speed| speed.clamp(0.0, 255.0))
+                    as u8;
+            }
+            "M107" if command.parameter_value('P').unwrap_or_default() == 0.0 => self.fan_speed = 0,
+            _ => {}
+        }
+    }
+
+    /// Location of the toolhead, in millimeters
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    /// Logical position of the extruder axis, in millimeters of filament
+    pub fn extruder_position(&self) -> ExtrudeAmountType {
+        self.extruder_position
+    }
+
+    /// Millimeters per minute. Zero if no move set it yet
+    pub fn feedrate(&self) -> FeedrateAmountType {
+        self.feedrate
+    }
+
+    pub fn is_inches(&self) -> bool {
+        self.inches
+    }
+
+    pub fn is_relative_positioning(&self) -> bool {
+        self.relative_positioning
+    }
+
+    pub fn is_relative_extrusion(&self) -> bool {
+        self.relative_extrusion
+    }
+
+    /// Target of every hotend, in °C. Zero if off
+    pub fn hotend_temperatures(&self) -> &[TemperatureType] {
+        &self.hotend_temperatures
+    }
+
+    /// Target of the bed, in °C. Zero if off
+    pub fn bed_temperature(&self) -> TemperatureType {
+        self.bed_temperature
+    }
+
+    /// Speed of the part cooling fan, from 0 to 255
+    pub fn fan_speed(&self) -> u8 {
+        self.fan_speed
+    }
+
+    pub fn tool(&self) -> u8 {
+        self.tool
+    }
+
+    /// Commands taking a machine just turned on to this state without touching the print.
+    /// Heaters are waited for, X and Y are homed after raising the nozzle, and the nozzle goes
+    /// back to the location and the extruder position before the modes of the job are restored.
+    /// Z can't be homed with the print on the bed, so it's taken to be where the job left it
+    pub fn preamble(&self) -> Vec<GcodeCommand> {
+        let passthrough = |name: &str, parameters: Vec<String>| {
+            GcodeCommand::Passthrough(name.to_string(), parameters)
+        };
+        let multiple_hotends = self.hotend_temperatures.len() > 1;
+        let hotend_parameters = |tool: usize, temperature: TemperatureType| match multiple_hotends {
+            true => vec![format!("S{temperature}"), format!("T{tool}")],
+            false => vec![format!("S{temperature}")],
+        };
+        let heated_hotends = || {
+            self.hotend_temperatures
+                .iter()
+                .enumerate()
+                .filter(|(_, temperature)| **temperature > 0)
+        };
+
+        //  Every heater starts heating before waiting for any of them
+        let mut commands = vec![];
+        if self.bed_temperature > 0 {
+            commands.push(passthrough(
+                "M140",
+                vec![format!("S{}", self.bed_temperature)],
+            ));
+        }
+        for (tool, temperature) in heated_hotends() {
+            commands.push(passthrough("M104", hotend_parameters(tool, *temperature)));
+        }
+        if self.bed_temperature > 0 {
+            commands.push(passthrough(
+                "M190",
+                vec![format!("S{}", self.bed_temperature)],
+            ));
+        }
+        for (tool, temperature) in heated_hotends() {
+            commands.push(passthrough("M109", hotend_parameters(tool, *temperature)));
+        }
+        if multiple_hotends {
+            commands.push(passthrough(&format!("T{}", self.tool), vec![]));
+        }
+
+        let location = self.location;
+        commands.extend([
+            GcodeCommand::G21,
+            GcodeCommand::G90,
+            GcodeCommand::M82,
+            GcodeCommand::G92(G92SetPosition {
+                z: Some(location.z),
+                ..Default::default()
+            }),
+            GcodeCommand::G1(G1Move {
+                z_target: Some(location.z + RESUME_Z_LIFT),
+                feedrate_per_minute: Some(RESUME_Z_FEEDRATE),
+                ..Default::default()
+            }),
+            GcodeCommand::G28(G28Home {
+                x: true,
+                y: true,
+                ..Default::default()
+            }),
+            GcodeCommand::G1(G1Move {
+                x_target: Some(location.x),
+                y_target: Some(location.y),
+                feedrate_per_minute: Some(RESUME_TRAVEL_FEEDRATE),
+                ..Default::default()
+            }),
+            GcodeCommand::G1(G1Move {
+                z_target: Some(location.z),
+                feedrate_per_minute: Some(RESUME_Z_FEEDRATE),
+                ..Default::default()
+            }),
+            GcodeCommand::G92(G92SetPosition {
+                e: Some(self.extruder_position),
+                ..Default::default()
+            }),
+        ]);
+
+        commands.push(match self.fan_speed {
+            0 => passthrough("M107", vec![]),
+            speed => passthrough("M106", vec![format!("S{speed}")]),
+        });
+        if self.feedrate > 0.0 {
+            commands.push(GcodeCommand::G1(G1Move {
+                feedrate_per_minute: Some(self.feedrate),
+                ..Default::default()
+            }));
+        }
+        //  G91 makes the extruder relative too, so M82 and M83 come after it
+        if self.relative_positioning {
+            commands.push(GcodeCommand::G91);
+        }
+        commands.push(match self.relative_extrusion {
+            true => GcodeCommand::M83,
+            false => GcodeCommand::M82,
+        });
+        if self.inches {
+            commands.push(GcodeCommand::G20);
+        }
+
+        commands
+    }
+}
+
+impl<I> ResumedJob<I>
+where
+    I: Iterator<Item = PrintResult<GcodeLine>>,
+{
+    /// Replays the job on a copy of the configuration until the resume point, rebuilding the
+    /// modal state found there. Fails if the job ends before reaching the point
+    pub fn new(
+        lines: impl IntoIterator<IntoIter = I>,
+        config: &SystemConfig,
+        point: ResumePoint,
+    ) -> PrintResult<Self> {
+        let mut lines = lines.into_iter();
+        let mut config = config.clone();
+        let mut state = ModalState::new(&config);
+        let mut layers = LayerTracker::default();
+
+        loop {
+            let Some(line) = lines.next().transpose()? else {
+                return Err(Error::ResumePointNotReached(point));
+            };
+            state.follow(&config);
+            if let ResumePoint::Line(line_number) = point
+                && line.line_number() >= line_number
+            {
+                return Ok(Self::resume_from(lines, state, line));
+            }
+            let Some(command) = line.command() else {
+                continue;
+            };
+
+            state.apply_command(command);
+            let starts_layer = config
+                .apply_command(command)
+                .map_err(|error| error.in_line(line.line_number()))?
+                .iter()
+                .map(|resolved_move| layers.layer_of(resolved_move).index)
+                .any(|layer| matches!(point, ResumePoint::Layer(target) if layer >= target));
+            if starts_layer {
+                return Ok(Self::resume_from(lines, state, line));
+            }
+        }
+    }
+
+    fn resume_from(lines: I, state: ModalState, line: GcodeLine) -> Self {
+        let mut job = Self::with_preamble(lines, state, line.line_number());
+        job.pending.push_back(line);
+        job
+    }
+
+    /// Job going on from the state given, with the lines left starting at the line number
+    pub(crate) fn with_preamble(lines: I, state: ModalState, line_number: LineNumberType) -> Self {
+        let pending = state
+            .preamble()
+            .into_iter()
+            .map(|command| GcodeLine::new(line_number, Some(command), None))
+            .collect();
+
+        Self {
+            lines,
+            state,
+            line_number,
+            pending,
+        }
+    }
+
+    /// Modal state of the machine right before the line the job resumes from
+    pub fn state(&self) -> &ModalState {
+        &self.state
+    }
+
+    /// Line the job resumes from. Preamble lines share its number
+    pub fn line_number(&self) -> LineNumberType {
+        self.line_number
+    }
+}
+
+impl<I> Iterator for ResumedJob<I>
+where
+    I: Iterator<Item = PrintResult<GcodeLine>>,
+{
+    type Item = PrintResult<GcodeLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.pending.pop_front() {
+            Some(line) => Some(Ok(line)),
+            None => self.lines.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::gcode::GcodeReader;
+    use crate::recovery::{ResumePoint, ResumedJob};
+    use crate::system::{Location, SystemConfig};
+
+    const JOB: &str = "M140 S60\nM104 S215\nM190 S60\nM109 S215\nG28\nG92 E0\nM107\n\
+                       G1 Z0.2 F600\nG1 X10 Y10 F3000\nG1 X20 Y10 E1 F1200\n\
+                       M106 S128\nG1 E0.2\nG1 Z0.4\nG1 X20 Y20\nG1 X10 Y20 E1.8\nG1 X10 Y10 E2.6\n";
+
+    fn resumed(point: ResumePoint) -> Vec<String> {
+        ResumedJob::new(
+            GcodeReader::new(JOB.as_bytes()),
+            &SystemConfig::default(),
+            point,
+        )
+        .unwrap()
+        .filter_map(|line| Some(line.unwrap().command()?.to_gcode(5)))
+        .collect()
+    }
+
+    #[test]
+    fn resume_from_layer() {
+        let job = ResumedJob::new(
+            GcodeReader::new(JOB.as_bytes()),
+            &SystemConfig::default(),
+            ResumePoint::Layer(1),
+        )
+        .unwrap();
+        let state = job.state();
+
+        assert_eq!(job.line_number(), 15);
+        assert_eq!(state.location(), Location::new(20.0, 20.0, 0.4));
+        //  Retraction before the Z change leaves the extruder behind
+        assert!((state.extruder_position() - 0.2).abs() < 1e-6);
+        assert_eq!(state.hotend_temperatures(), [215]);
+        assert_eq!(state.bed_temperature(), 60);
+        assert_eq!(state.fan_speed(), 128);
+
+        assert_eq!(
+            resumed(ResumePoint::Layer(1)),
+            vec![
+                "M140 S60",
+                "M104 S215",
+                "M190 S60",
+                "M109 S215",
+                "G21",
+                "G90",
+                "M82",
+                "G92 Z0.4",
+                "G1 Z2.4 F600",
+                "G28 X Y",
+                "G1 X20 Y20 F3000",
+                "G1 Z0.4 F600",
+                "G92 E0.2",
+                "M106 S128",
+                "G1 F1200",
+                "M82",
+                "G1 X10 Y20 E1.8",
+                "G1 X10 Y10 E2.6"
+            ]
+        );
+    }
+
+    #[test]
+    fn resume_from_line_keeps_modes() {
+        let job = "M83\nG91\nT1\nM104 S200 T0\nM104 S210\nG1 X5 F1200\nG20\nG1 X1 E0.1\n";
+        let resumed: Vec<String> = ResumedJob::new(
+            GcodeReader::new(job.as_bytes()),
+            &SystemConfig::default(),
+            ResumePoint::Line(8),
+        )
+        .unwrap()
+        .filter_map(|line| Some(line.unwrap().command()?.to_gcode(5)))
+        .collect();
+
+        assert_eq!(
+            resumed,
+            vec![
+                "M104 S200 T0",
+                "M104 S210 T1",
+                "M109 S200 T0",
+                "M109 S210 T1",
+                "T1",
+                "G21",
+                "G90",
+                "M82",
+                "G92 Z0",
+                "G1 Z2 F600",
+                "G28 X Y",
+                "G1 X5 Y0 F3000",
+                "G1 Z0 F600",
+                "G92 E0",
+                "M107",
+                "G1 F1200",
+                "G91",
+                "M83",
+                "G20",
+                "G1 X1 E0.1"
+            ]
+        );
+        assert!(matches!(
+            ResumedJob::new(
+                GcodeReader::new(job.as_bytes()),
+                &SystemConfig::default(),
+                ResumePoint::Layer(3)
+            ),
+            Err(Error::ResumePointNotReached(ResumePoint::Layer(3)))
+        ));
+    }
+}
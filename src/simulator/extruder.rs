@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+
+use crate::error::PrintResult;
+use crate::export::replay_job;
+use crate::gcode::GcodeLine;
+use crate::motion::{ExtruderSample, Planner, sample_extrusion};
+use crate::system::SystemConfig;
+
+/// Replays a job through the planner and samples the extruder motion every `interval` seconds, with
+/// the pressure advance of the active extruder applied. Simulating the same job with different factors
+/// shows how each one shapes the extrusion. Factors set by the job with M900 win over the ones of the config
+pub fn simulate_extruder(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    interval: f32,
+) -> PrintResult<Vec<ExtruderSample>> {
+    let mut planner = Planner::new();
+    let mut segments = vec![];
+    //  Planner releases the segments in the same order the moves were pushed, so the settings follow them
+    let mut pending_settings = VecDeque::new();
+
+    replay_job(lines, config, |_, resolved_move, _, state| {
+        pending_settings.push_back(state.pressure_advance(state.active_extruder()));
+        for segment in planner.push(resolved_move.clone(), state.motion_limits()) {
+            segments.push((segment, pending_settings.pop_front().unwrap_or_default()));
+        }
+        Ok(())
+    })?;
+
+    for segment in planner.flush() {
+        segments.push((segment, pending_settings.pop_front().unwrap_or_default()));
+    }
+
+    Ok(sample_extrusion(segments, interval))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::GcodeReader;
+    use crate::system::SystemConfig;
+
+    use super::simulate_extruder;
+
+    #[test]
+    fn compare_advance_factors() {
+        let source = "M83\nG1 X10 E0.5 F1200\nG1 X10 Y10 E0.5\nG1 X30 Y10 E1\n";
+        let simulate = |factor: f32| {
+            let profile = format!("[extruder]\npressure_advance = {factor}\n");
+            let config = SystemConfig::from_profile(&profile).unwrap();
+            simulate_extruder(GcodeReader::new(source.as_bytes()), &config, 0.001).unwrap()
+        };
+        let peak_advance = |samples: &[crate::motion::ExtruderSample]| {
+            samples
+                .iter()
+                .map(|sample| sample.advance())
+                .fold(0.0, f32::max)
+        };
+
+        let off = simulate(0.0);
+        let low = simulate(0.02);
+        let high = simulate(0.06);
+
+        //  Toolhead motion is the same, only the extruder changes
+        assert_eq!(off.len(), low.len());
+        assert!(
+            off.iter()
+                .all(|sample| sample.speed() == sample.nominal_speed())
+        );
+        assert!((peak_advance(&high) - 3.0 * peak_advance(&low)).abs() < 1e-4);
+
+        //  Every job ends with the filament where it asked for
+        let last = high.last().unwrap();
+        assert!((last.position() - 2.0).abs() < 1e-4);
+    }
+}
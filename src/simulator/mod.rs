@@ -0,0 +1,473 @@
+mod extruder;
+#[cfg(target_os = "linux")]
+mod pty;
+mod shaping;
+
+use crate::error::Error;
+use crate::gcode::{FramedLine, GcodeCommand, GcodeLine, find_unquoted};
+use crate::motion::{Axis, Planner};
+use crate::system::SystemConfig;
+use crate::types::LineNumberType;
+
+pub use extruder::simulate_extruder;
+#[cfg(target_os = "linux")]
+pub use pty::Pseudoterminal;
+pub use shaping::simulate_shaping;
+
+/// Seconds between busy messages while a command keeps the printer busy, like Marlin does
+const BUSY_INTERVAL: f32 = 2.0;
+/// Seconds between temperature reports while waiting for a heater
+const TEMPERATURE_REPORT_INTERVAL: f32 = 1.0;
+/// Heaters waited for are done once this close to their target, in °C
+const TEMPERATURE_WINDOW: f32 = 1.0;
+/// Longest wait for a heater, in seconds. Heaters that can't reach their target end the wait here
+const MAX_HEATING_WAIT: f32 = 900.0;
+/// Temperature of the room the printer is in, in °C
+const AMBIENT_TEMPERATURE: f32 = 25.0;
+/// Seconds it takes each heater to cover about 63% of the way to its target
+const HOTEND_TIME_CONSTANT: f32 = 20.0;
+const BED_TIME_CONSTANT: f32 = 60.0;
+/// Speed the axes go back to the origin while homing, in millimeters per second
+const HOMING_SPEED: f32 = 50.0;
+
+/// Message the printer sends back to the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatorMessage {
+    /// Simulated seconds since the line was received, when the message is sent
+    delay: f32,
+    text: String,
+}
+
+/// Heater following a first order model, getting closer to its target the longer it runs
+#[derive(Debug, Clone, PartialEq)]
+struct SimulatedHeater {
+    temperature: f32,
+    /// Zero if the heater is off
+    target: f32,
+    time_constant: f32,
+}
+
+/// Printer without hardware answering a host with the Marlin line protocol. Commands are executed
+/// by the parser and the state model, and take the time the planner says they take, in simulated time
+pub struct VirtualPrinter {
+    config: SystemConfig,
+    /// Simulated seconds since the printer started
+    clock: f32,
+    /// Number of the last framed line accepted
+    last_line_number: LineNumberType,
+    hotend: SimulatedHeater,
+    bed: SimulatedHeater,
+    /// An emergency stop kills the printer until it's restarted
+    halted: bool,
+}
+
+/// Messages sent back for a single line, and the simulated time they take
+#[derive(Default)]
+struct Reply {
+    messages: Vec<SimulatorMessage>,
+    elapsed: f32,
+}
+
+impl SimulatorMessage {
+    pub fn delay(&self) -> f32 {
+        self.delay
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl SimulatedHeater {
+    fn new(time_constant: f32) -> Self {
+        Self {
+            temperature: AMBIENT_TEMPERATURE,
+            target: 0.0,
+            time_constant,
+        }
+    }
+
+    /// Heaters that are off cool down to the room temperature
+    fn advance(&mut self, seconds: f32) {
+        let goal = match self.target {
+            target if target > 0.0 => target,
+            _ => AMBIENT_TEMPERATURE,
+        };
+        self.temperature +=
+            (goal - self.temperature) * (1.0 - (-seconds / self.time_constant).exp());
+    }
+
+    fn is_settled(&self) -> bool {
+        self.target <= 0.0 || (self.temperature - self.target).abs() <= TEMPERATURE_WINDOW
+    }
+}
+
+impl Reply {
+    fn send(&mut self, text: impl Into<String>) {
+        self.messages.push(SimulatorMessage {
+            delay: self.elapsed,
+            text: text.into(),
+        });
+    }
+}
+
+impl VirtualPrinter {
+    pub fn new(config: SystemConfig) -> Self {
+        Self {
+            config,
+            clock: 0.0,
+            last_line_number: 0,
+            hotend: SimulatedHeater::new(HOTEND_TIME_CONSTANT),
+            bed: SimulatedHeater::new(BED_TIME_CONSTANT),
+            halted: false,
+        }
+    }
+
+    /// Message sent once the printer starts, before any line is received
+    pub fn greeting(&self) -> &'static str {
+        "start"
+    }
+
+    /// Simulated seconds since the printer started
+    pub fn clock(&self) -> f32 {
+        self.clock
+    }
+
+    pub fn config(&self) -> &SystemConfig {
+        &self.config
+    }
+
+    /// Current temperature of the hotend, in °C
+    pub fn hotend_temperature(&self) -> f32 {
+        self.hotend.temperature
+    }
+
+    /// Current temperature of the bed, in °C
+    pub fn bed_temperature(&self) -> f32 {
+        self.bed.temperature
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Executes a line sent by the host, and returns every message sent back for it. Framed lines
+    /// must follow the last accepted one and match their checksum, or the host is asked to resend them.
+    /// Every accepted line is acknowledged with `ok`, once the time it takes has gone by
+    pub fn handle_line(&mut self, line: &str) -> Vec<SimulatorMessage> {
+        let mut reply = Reply::default();
+        if self.halted {
+            reply.send("Error:Printer halted. kill() called!");
+            return reply.messages;
+        }
+
+        let without_comment = &line[..find_unquoted(line, ';').unwrap_or(line.len())];
+        let framed = match FramedLine::parse(without_comment) {
+            Ok(framed) => framed,
+            Err(error) => {
+                reply.send(format!("Error:{error}"));
+                self.request_resend(&mut reply);
+                return reply.messages;
+            }
+        };
+        if framed.command().is_empty() && framed.line_number().is_none() {
+            return reply.messages;
+        }
+
+        if let Some(line_number) = framed.line_number()
+            && !self.accept_line_number(&framed, line_number, &mut reply)
+        {
+            return reply.messages;
+        }
+
+        self.execute(framed.command(), &mut reply);
+        self.clock += reply.elapsed;
+        reply.messages
+    }
+
+    /// Checks the framing of a numbered line. Rejected lines are asked to be sent again
+    fn accept_line_number(
+        &mut self,
+        framed: &FramedLine,
+        line_number: LineNumberType,
+        reply: &mut Reply,
+    ) -> bool {
+        let last = self.last_line_number;
+        let problem = if framed.checksum().is_none() {
+            Some(format!(
+                "Error:No Checksum with line number, Last Line: {last}"
+            ))
+        } else if !framed.has_valid_checksum() {
+            Some(format!("Error:checksum mismatch, Last Line: {last}"))
+        } else if line_number != last + 1 && !is_named(framed.command(), "M110") {
+            Some(format!(
+                "Error:Line Number is not Last Line Number+1, Last Line: {last}"
+            ))
+        } else {
+            None
+        };
+
+        match problem {
+            Some(problem) => {
+                reply.send(problem);
+                self.request_resend(reply);
+                false
+            }
+            None => {
+                self.last_line_number = line_number;
+                true
+            }
+        }
+    }
+
+    fn request_resend(&self, reply: &mut Reply) {
+        reply.send(format!("Resend: {}", self.last_line_number + 1));
+        reply.send("ok");
+    }
+
+    fn execute(&mut self, command: &str, reply: &mut Reply) {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or_default().to_ascii_uppercase();
+        let value_of = |letter: char| {
+            command.split_whitespace().skip(1).find_map(|word| {
+                word.strip_prefix([letter, letter.to_ascii_lowercase()])
+                    .and_then(|value| value.parse::<f32>().ok())
+            })
+        };
+
+        //  Host protocol commands are answered here, since they don't touch the machine state
+        match name.as_str() {
+            "M105" => return reply.send(format!("ok {}", self.temperature_report())),
+            "M110" => {
+                if let Some(line_number) = value_of('N') {
+                    self.last_line_number = line_number as LineNumberType;
+                }
+                return reply.send("ok");
+            }
+            "M114" => {
+                reply.send(self.position_report());
+                return reply.send("ok");
+            }
+            "M115" => {
+                reply.send(format!(
+                    "FIRMWARE_NAME:printy {} PROTOCOL_VERSION:1.0 MACHINE_TYPE:Virtual EXTRUDER_COUNT:{}",
+                    env!("CARGO_PKG_VERSION"),
+                    self.config.extruder_count()
+                ));
+                return reply.send("ok");
+            }
+            "M400" => return reply.send("ok"),
+            "M104" | "M109" => {
+                if let Some(target) = value_of('S').or(value_of('R')) {
+                    self.hotend.target = target;
+                }
+            }
+            "M140" | "M190" => {
+                if let Some(target) = value_of('S').or(value_of('R')) {
+                    self.bed.target = target;
+                }
+            }
+            _ => {}
+        }
+
+        let parsed =
+            GcodeLine::parse_with_dialect(command, self.last_line_number, self.config.dialect());
+        match parsed.map(GcodeLine::into_command) {
+            Ok(Some(GcodeCommand::M112)) => {
+                self.halted = true;
+                return reply.send("Error:Printer halted. kill() called!");
+            }
+            Ok(Some(command)) => self.run(&command, reply),
+            Ok(None) => {}
+            Err(
+                Error::InvalidCommandInLine(..)
+                | Error::UnsupportedCommand(_)
+                | Error::UnsupportedByDialect(..),
+            ) => reply.send(format!("echo:Unknown command: \"{command}\"")),
+            Err(error) => reply.send(format!("Error:{error}")),
+        }
+
+        match name.as_str() {
+            "M109" => self.wait_for_heater(reply, true),
+            "M190" => self.wait_for_heater(reply, false),
+            _ => {}
+        }
+        reply.send("ok");
+    }
+
+    /// Applies a command to the state, letting the simulated time go by while it moves
+    fn run(&mut self, command: &GcodeCommand, reply: &mut Reply) {
+        let start = self.config.current_location();
+        let resolved_moves = match self.config.apply_command(command) {
+            Ok(resolved_moves) => resolved_moves,
+            Err(error) => return reply.send(format!("Error:{error}")),
+        };
+
+        let mut duration = match command {
+            GcodeCommand::G28(_) => {
+                start.distance_to(&self.config.current_location()) / HOMING_SPEED
+            }
+            _ => 0.0,
+        };
+        //  Commands are acknowledged once they're done, so every command ends at a full stop
+        let mut planner = Planner::new();
+        for resolved_move in resolved_moves {
+            for segment in planner.push(resolved_move, self.config.motion_limits()) {
+                duration += segment.duration();
+            }
+        }
+        duration += planner
+            .flush()
+            .iter()
+            .map(|segment| segment.duration())
+            .sum::<f32>();
+
+        self.busy_for(duration, reply);
+    }
+
+    /// Lets the time go by, sending a busy message every `BUSY_INTERVAL` seconds
+    fn busy_for(&mut self, duration: f32, reply: &mut Reply) {
+        let end = reply.elapsed + duration;
+        while reply.elapsed + BUSY_INTERVAL < end {
+            self.advance(BUSY_INTERVAL, reply);
+            reply.send("echo:busy: processing");
+        }
+        self.advance(end - reply.elapsed, reply);
+    }
+
+    /// Waits until the heater reaches its target, reporting the temperatures every second
+    fn wait_for_heater(&mut self, reply: &mut Reply, hotend: bool) {
+        let start = reply.elapsed;
+        loop {
+            let heater = match hotend {
+                true => &self.hotend,
+                false => &self.bed,
+            };
+            if heater.is_settled() || reply.elapsed - start >= MAX_HEATING_WAIT {
+                break;
+            }
+
+            self.advance(TEMPERATURE_REPORT_INTERVAL, reply);
+            reply.send(self.temperature_report());
+        }
+    }
+
+    fn advance(&mut self, seconds: f32, reply: &mut Reply) {
+        self.hotend.advance(seconds);
+        self.bed.advance(seconds);
+        reply.elapsed += seconds;
+    }
+
+    /// Ex: `T:200.00 /200.00 B:60.00 /60.00 @:0 B@:0`
+    fn temperature_report(&self) -> String {
+        format!(
+            "T:{:.2} /{:.2} B:{:.2} /{:.2} @:0 B@:0",
+            self.hotend.temperature, self.hotend.target, self.bed.temperature, self.bed.target
+        )
+    }
+
+    /// Ex: `X:10.00 Y:20.00 Z:0.20 E:1.50 Count X:800 Y:1600 Z:80`
+    fn position_report(&self) -> String {
+        let location = self.config.current_location();
+        let steps = |axis: Axis, value: f32| {
+            (value * self.config.steps_per_unit().get(axis)).round() as i64
+        };
+        format!(
+            "X:{:.2} Y:{:.2} Z:{:.2} E:{:.2} Count X:{} Y:{} Z:{}",
+            location.x(),
+            location.y(),
+            location.z(),
+            self.config.extruder_position(),
+            steps(Axis::X, location.x()),
+            steps(Axis::Y, location.y()),
+            steps(Axis::Z, location.z())
+        )
+    }
+}
+
+/// True if the command has the given name. Ex: `M110 N0` is named `M110`
+fn is_named(command: &str, name: &str) -> bool {
+    command
+        .split_whitespace()
+        .next()
+        .is_some_and(|first| first.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::line_checksum;
+    use crate::simulator::VirtualPrinter;
+    use crate::system::SystemConfig;
+
+    fn framed(line_number: usize, command: &str) -> String {
+        let text = format!("N{line_number} {command}");
+        format!("{text}*{}", line_checksum(&text))
+    }
+
+    fn texts(printer: &mut VirtualPrinter, line: &str) -> Vec<String> {
+        printer
+            .handle_line(line)
+            .iter()
+            .map(|message| message.text().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn line_protocol() {
+        let mut printer = VirtualPrinter::new(SystemConfig::default());
+
+        assert_eq!(texts(&mut printer, &framed(0, "M110 N0")), vec!["ok"]);
+        assert_eq!(texts(&mut printer, &framed(1, "G1 X10 F6000")), vec!["ok"]);
+        //  Corrupted line, the host must send it again
+        assert_eq!(
+            texts(&mut printer, "N2 G1 X20*1"),
+            vec!["Error:checksum mismatch, Last Line: 1", "Resend: 2", "ok"]
+        );
+        //  Skipped line
+        assert_eq!(texts(&mut printer, &framed(3, "G1 X30"))[1], "Resend: 2");
+        assert_eq!(texts(&mut printer, &framed(2, "G1 X20")), vec!["ok"]);
+        assert_eq!(
+            texts(&mut printer, "M114"),
+            vec!["X:20.00 Y:0.00 Z:0.00 E:0.00 Count X:1600 Y:0 Z:0", "ok"]
+        );
+        assert_eq!(
+            texts(&mut printer, "M105"),
+            vec!["ok T:25.00 /0.00 B:25.00 /0.00 @:0 B@:0"]
+        );
+        assert_eq!(
+            texts(&mut printer, "M999 S1"),
+            vec!["echo:Unknown command: \"M999 S1\"", "ok"]
+        );
+        assert!(printer.clock() > 0.0);
+    }
+
+    #[test]
+    fn busy_while_waiting() {
+        let mut printer = VirtualPrinter::new(SystemConfig::default());
+
+        let messages = printer.handle_line("M109 S200");
+        let reports = messages
+            .iter()
+            .filter(|message| message.text().starts_with("T:"))
+            .count();
+        let ok = messages.last().unwrap();
+        assert_eq!(ok.text(), "ok");
+        assert_eq!(reports as f32, ok.delay());
+        assert!((printer.hotend_temperature() - 200.0).abs() <= 1.0);
+
+        //  Slow move of 5 seconds
+        let messages = printer.handle_line("G1 X50 F600");
+        let busy = messages
+            .iter()
+            .filter(|message| message.text() == "echo:busy: processing")
+            .count();
+        assert_eq!(busy, 2);
+
+        assert_eq!(
+            texts(&mut printer, "M112"),
+            vec!["Error:Printer halted. kill() called!"]
+        );
+        assert!(printer.is_halted());
+        assert_eq!(texts(&mut printer, "M105").len(), 1);
+    }
+}
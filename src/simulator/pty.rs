@@ -0,0 +1,148 @@
+use std::ffi::{CStr, c_char, c_int};
+use std::fs::{File, OpenOptions};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, PrintResult};
+
+const O_RDWR: c_int = 0o2;
+const O_NOCTTY: c_int = 0o400;
+const TCSANOW: c_int = 0;
+/// Longest path of a slave device, `/dev/pts/N`, null included
+const SLAVE_PATH_LENGTH: usize = 64;
+
+/// Terminal settings, laid out like the glibc `struct termios`
+#[repr(C)]
+struct Termios {
+    c_iflag: u32,
+    c_oflag: u32,
+    c_cflag: u32,
+    c_lflag: u32,
+    c_line: u8,
+    c_cc: [u8; 32],
+    c_ispeed: u32,
+    c_ospeed: u32,
+}
+
+unsafe extern "C" {
+    fn posix_openpt(flags: c_int) -> c_int;
+    fn grantpt(fd: c_int) -> c_int;
+    fn unlockpt(fd: c_int) -> c_int;
+    fn ptsname_r(fd: c_int, buf: *mut c_char, buflen: usize) -> c_int;
+    fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+    fn tcsetattr(fd: c_int, optional_actions: c_int, termios: *const Termios) -> c_int;
+    fn cfmakeraw(termios: *mut Termios);
+}
+
+/// Pair of connected terminal devices. Hosts open the slave like the serial port of a printer,
+/// while the simulator reads and writes the master
+pub struct Pseudoterminal {
+    master: File,
+    /// Kept open so reading the master blocks instead of failing while no host is connected
+    _slave: File,
+    slave_path: PathBuf,
+}
+
+impl Pseudoterminal {
+    /// Opens a new pseudoterminal in raw mode, so bytes go through without echo or line editing
+    pub fn open() -> PrintResult<Pseudoterminal> {
+        // SAFETY: posix_openpt takes no pointers, and the descriptor it returns is owned by the file
+        let master = unsafe {
+            let fd = check(posix_openpt(O_RDWR | O_NOCTTY))?;
+            File::from_raw_fd(fd)
+        };
+
+        let mut path = [0 as c_char; SLAVE_PATH_LENGTH];
+        // SAFETY: the descriptor is open, and ptsname_r writes a null terminated path within the buffer length
+        let slave_path = unsafe {
+            check(grantpt(master.as_raw_fd()))?;
+            check(unlockpt(master.as_raw_fd()))?;
+            check_result(ptsname_r(
+                master.as_raw_fd(),
+                path.as_mut_ptr(),
+                SLAVE_PATH_LENGTH,
+            ))?;
+            PathBuf::from(CStr::from_ptr(path.as_ptr()).to_string_lossy().into_owned())
+        };
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NOCTTY)
+            .open(&slave_path)
+            .map_err(Error::InputOutputError)?;
+
+        // SAFETY: every field is plain data, so zeroes are a valid value that tcgetattr overwrites
+        unsafe {
+            let mut termios: Termios = std::mem::zeroed();
+            check(tcgetattr(slave.as_raw_fd(), &mut termios))?;
+            cfmakeraw(&mut termios);
+            check(tcsetattr(slave.as_raw_fd(), TCSANOW, &termios))?;
+        }
+
+        Ok(Pseudoterminal {
+            master,
+            _slave: slave,
+            slave_path,
+        })
+    }
+
+    /// Device hosts connect to. Ex: `/dev/pts/3`
+    pub fn slave_path(&self) -> &Path {
+        &self.slave_path
+    }
+
+    /// Side of the simulator. Reading it returns whatever the host sent
+    pub fn master(&self) -> &File {
+        &self.master
+    }
+}
+
+/// Calls returning -1 on failure leave the reason in `errno`
+fn check(result: c_int) -> PrintResult<c_int> {
+    match result {
+        -1 => Err(Error::InputOutputError(std::io::Error::last_os_error())),
+        result => Ok(result),
+    }
+}
+
+/// Calls returning the error number itself, or zero on success
+fn check_result(result: c_int) -> PrintResult<()> {
+    match result {
+        0 => Ok(()),
+        error => Err(Error::InputOutputError(std::io::Error::from_raw_os_error(
+            error,
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::{BufRead, BufReader, Write};
+
+    use crate::simulator::Pseudoterminal;
+
+    #[test]
+    fn bytes_go_through() {
+        let terminal = Pseudoterminal::open().unwrap();
+        let mut host = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(terminal.slave_path())
+            .unwrap();
+
+        host.write_all(b"M105\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(terminal.master())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "M105\n");
+
+        terminal.master().write_all(b"ok\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(&host).read_line(&mut line).unwrap();
+        assert_eq!(line, "ok\n");
+    }
+}
@@ -0,0 +1,68 @@
+use crate::error::PrintResult;
+use crate::export::replay_job;
+use crate::gcode::GcodeLine;
+use crate::motion::{Planner, ShapingSample, sample_shaping};
+use crate::system::SystemConfig;
+
+/// Replays a job through the planner and samples the acceleration of X and Y every `interval` seconds,
+/// before and after the input shapers of the config. Axes without a shaper keep their planned acceleration
+pub fn simulate_shaping(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    config: &SystemConfig,
+    interval: f32,
+) -> PrintResult<Vec<ShapingSample>> {
+    let mut planner = Planner::new();
+    let mut segments = vec![];
+
+    replay_job(lines, config, |_, resolved_move, _, state| {
+        segments.extend(planner.push(resolved_move.clone(), state.motion_limits()));
+        Ok(())
+    })?;
+    segments.extend(planner.flush());
+
+    Ok(sample_shaping(segments, config.input_shapers(), interval))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::GcodeReader;
+    use crate::motion::{Axis, ShapingSample};
+    use crate::system::SystemConfig;
+
+    use super::simulate_shaping;
+
+    #[test]
+    fn compare_shaped_acceleration() {
+        let source = "G1 X20 F6000\nG1 X20 Y20\n";
+        let simulate = |profile: &str| {
+            let config = SystemConfig::from_profile(profile).unwrap();
+            simulate_shaping(GcodeReader::new(source.as_bytes()), &config, 0.0005).unwrap()
+        };
+        let peak = |samples: &[ShapingSample], axis: Axis| {
+            samples
+                .iter()
+                .map(|sample| sample.shaped_acceleration(axis).abs())
+                .fold(0.0, f32::max)
+        };
+
+        let unshaped = simulate("[input_shaper]\nfrequency = 0, 0\n");
+        let shaped = simulate("[input_shaper]\ntype = ei, zv\nfrequency = 40, 0\n");
+
+        assert!(
+            unshaped
+                .iter()
+                .all(|sample| sample.shaped_acceleration(Axis::X) == sample.acceleration(Axis::X))
+        );
+        //  Shaping spreads the changes of acceleration of X over time, without growing its peaks
+        assert!(peak(&shaped, Axis::X) <= peak(&unshaped, Axis::X) + 1e-3);
+        assert!(
+            shaped.iter().any(|sample| {
+                sample.shaped_acceleration(Axis::X) != sample.acceleration(Axis::X)
+            })
+        );
+        assert_eq!(peak(&shaped, Axis::Y), peak(&unshaped, Axis::Y));
+        //  The shaped profile starts before the first move and ends after the last one
+        assert!(shaped[0].time() < 0.0);
+        assert!(shaped.last().unwrap().time() > unshaped.last().unwrap().time());
+    }
+}
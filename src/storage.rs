@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::error::{Error, PrintResult};
+
+/// Bytes of the in-memory EEPROM when no capacity is given
+const DEFAULT_EEPROM_CAPACITY: usize = 4096;
+/// Value of the bytes of an EEPROM never written
+const ERASED_BYTE: u8 = 0xFF;
+
+/// Non-volatile memory holding a single block of bytes, like the EEPROM of a board or a file on an SD card.
+/// Whoever writes the block is responsible for telling valid contents from garbage when reading it back
+pub trait Storage {
+    /// Every byte held by the storage. Empty if nothing was written yet
+    fn read(&mut self) -> PrintResult<Vec<u8>>;
+    /// Replaces the contents of the storage
+    fn write(&mut self, data: &[u8]) -> PrintResult<()>;
+}
+
+/// Storage backed by a file. Writes go to a temporary file first, which then replaces the original one,
+/// so a power loss in the middle of a write never leaves half of the contents behind
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    path: PathBuf,
+}
+
+/// EEPROM without hardware, with a fixed capacity. Clones share the same memory, so the contents can be
+/// inspected after handing a clone over to the executor
+#[derive(Debug, Clone)]
+pub struct MemoryEeprom {
+    memory: Rc<RefCell<Vec<u8>>>,
+}
+
+impl FileStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Storage for FileStorage {
+    fn read(&mut self) -> PrintResult<Vec<u8>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => Ok(data),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(error) => Err(Error::InputOutputError(error)),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> PrintResult<()> {
+        let mut temporary_path = self.path.clone().into_os_string();
+        temporary_path.push(".tmp");
+
+        std::fs::write(&temporary_path, data).map_err(Error::InputOutputError)?;
+        std::fs::rename(&temporary_path, &self.path).map_err(Error::InputOutputError)
+    }
+}
+
+impl Default for MemoryEeprom {
+    fn default() -> Self {
+        Self::new(DEFAULT_EEPROM_CAPACITY)
+    }
+}
+
+impl MemoryEeprom {
+    /// Erased EEPROM of the given amount of bytes
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            memory: Rc::new(RefCell::new(vec![ERASED_BYTE; capacity])),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.memory.borrow().len()
+    }
+}
+
+impl Storage for MemoryEeprom {
+    /// Always returns the full memory, erased bytes included
+    fn read(&mut self) -> PrintResult<Vec<u8>> {
+        Ok(self.memory.borrow().clone())
+    }
+
+    /// Bytes past the written data keep their old values, like in a real EEPROM
+    fn write(&mut self, data: &[u8]) -> PrintResult<()> {
+        let mut memory = self.memory.borrow_mut();
+        if data.len() > memory.len() {
+            return Err(Error::InputOutputError(std::io::Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "{} bytes don't fit in {} bytes of EEPROM",
+                    data.len(),
+                    memory.len()
+                ),
+            )));
+        }
+
+        memory[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::storage::{FileStorage, MemoryEeprom, Storage};
+
+    #[test]
+    fn file_storage_round_trip() {
+        let path = std::env::temp_dir().join(format!("printy_storage_{}.bin", std::process::id()));
+        let mut storage = FileStorage::new(&path);
+
+        assert!(storage.read().unwrap().is_empty());
+        storage.write(&[1, 2, 3]).unwrap();
+        storage.write(&[4, 5]).unwrap();
+        assert_eq!(storage.read().unwrap(), vec![4, 5]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn eeprom_keeps_its_capacity() {
+        let mut eeprom = MemoryEeprom::new(4);
+
+        eeprom.write(&[1, 2]).unwrap();
+        assert_eq!(eeprom.clone().read().unwrap(), vec![1, 2, 0xFF, 0xFF]);
+        assert!(matches!(
+            eeprom.write(&[0; 5]),
+            Err(Error::InputOutputError(_))
+        ));
+    }
+}
@@ -0,0 +1,390 @@
+use crate::error::{Error, PrintResult};
+use crate::gcode::{M420LevelingState, M421SetMeshPoint};
+use crate::types::LocationType;
+
+use super::{Location, ResolvedMove, SystemConfig};
+
+/// Grid of Z offsets measured over the bed. Offsets between the points are interpolated,
+/// and outside the grid the closest border is used
+#[derive(Debug, Clone, PartialEq)]
+pub struct BedMesh {
+    min_x: LocationType,
+    min_y: LocationType,
+    max_x: LocationType,
+    max_y: LocationType,
+    /// Amount of points along X
+    columns: usize,
+    /// Amount of points along Y
+    rows: usize,
+    /// Offsets row by row, starting from the front. Each row goes from left to right
+    z_offsets: Vec<LocationType>,
+}
+
+impl BedMesh {
+    /// Flat mesh with evenly spaced points covering the area between both corners
+    pub fn new(
+        min_x: LocationType,
+        min_y: LocationType,
+        max_x: LocationType,
+        max_y: LocationType,
+        columns: usize,
+        rows: usize,
+    ) -> PrintResult<Self> {
+        if columns < 2 || rows < 2 || min_x == max_x || min_y == max_y {
+            return Err(Error::InvalidMeshSize(columns, rows));
+        }
+
+        Ok(Self {
+            min_x: min_x.min(max_x),
+            min_y: min_y.min(max_y),
+            max_x: min_x.max(max_x),
+            max_y: min_y.max(max_y),
+            columns,
+            rows,
+            z_offsets: vec![0.0; columns * rows],
+        })
+    }
+
+    pub fn min_x(&self) -> LocationType {
+        self.min_x
+    }
+
+    pub fn min_y(&self) -> LocationType {
+        self.min_y
+    }
+
+    pub fn max_x(&self) -> LocationType {
+        self.max_x
+    }
+
+    pub fn max_y(&self) -> LocationType {
+        self.max_y
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// X coordinate of a column of points
+    pub fn column_x(&self, column: usize) -> LocationType {
+        self.min_x
+            + (self.max_x - self.min_x) * column as LocationType
+                / (self.columns - 1) as LocationType
+    }
+
+    /// Y coordinate of a row of points
+    pub fn row_y(&self, row: usize) -> LocationType {
+        self.min_y
+            + (self.max_y - self.min_y) * row as LocationType / (self.rows - 1) as LocationType
+    }
+
+    /// Offset of a single point, or None if it's outside the grid
+    pub fn point(&self, column: usize, row: usize) -> Option<LocationType> {
+        if column >= self.columns || row >= self.rows {
+            return None;
+        }
+
+        Some(self.z_offsets[row * self.columns + column])
+    }
+
+    pub fn set_point(
+        &mut self,
+        column: usize,
+        row: usize,
+        z_offset: LocationType,
+    ) -> PrintResult<()> {
+        if column >= self.columns || row >= self.rows {
+            return Err(Error::InvalidMeshPoint(column, row));
+        }
+
+        self.z_offsets[row * self.columns + column] = z_offset;
+        Ok(())
+    }
+
+    /// Column and row of the point closest to the coordinates
+    pub fn nearest_point(&self, x: LocationType, y: LocationType) -> (usize, usize) {
+        let nearest = |value: LocationType, min: LocationType, max: LocationType, points: usize| {
+            let position = (value - min) / (max - min) * (points - 1) as LocationType;
+            (position.round().max(0.0) as usize).min(points - 1)
+        };
+
+        (
+            nearest(x, self.min_x, self.max_x, self.columns),
+            nearest(y, self.min_y, self.max_y, self.rows),
+        )
+    }
+
+    /// Bilinear interpolation of the four points around the coordinates
+    pub fn z_offset_at(&self, x: LocationType, y: LocationType) -> LocationType {
+        //  Cell holding the coordinates, and how far into it they are, from 0 to 1
+        let cell = |value: LocationType, min: LocationType, max: LocationType, points: usize| {
+            let position =
+                (value.clamp(min, max) - min) / (max - min) * (points - 1) as LocationType;
+            let index = (position.floor() as usize).min(points - 2);
+            (index, position - index as LocationType)
+        };
+        let (column, x_fraction) = cell(x, self.min_x, self.max_x, self.columns);
+        let (row, y_fraction) = cell(y, self.min_y, self.max_y, self.rows);
+
+        let offset = |column: usize, row: usize| self.z_offsets[row * self.columns + column];
+        let front = offset(column, row) * (1.0 - x_fraction) + offset(column + 1, row) * x_fraction;
+        let back =
+            offset(column, row + 1) * (1.0 - x_fraction) + offset(column + 1, row + 1) * x_fraction;
+
+        front * (1.0 - y_fraction) + back * y_fraction
+    }
+
+    /// Fractions of a move, from 0 to 1, where it crosses a column or a row of the grid. Sorted and without the ends
+    fn crossings(&self, start: &Location, end: &Location) -> Vec<f32> {
+        let mut crossings = vec![];
+        let mut add_crossings = |from: LocationType, to: LocationType, lines: Vec<LocationType>| {
+            if from == to {
+                return;
+            }
+            crossings.extend(
+                lines
+                    .into_iter()
+                    .map(|line| (line - from) / (to - from))
+                    .filter(|fraction| *fraction > 0.0 && *fraction < 1.0),
+            );
+        };
+
+        add_crossings(
+            start.x,
+            end.x,
+            (0..self.columns)
+                .map(|column| self.column_x(column))
+                .collect(),
+        );
+        add_crossings(
+            start.y,
+            end.y,
+            (0..self.rows).map(|row| self.row_y(row)).collect(),
+        );
+
+        crossings.sort_by(|a, b| a.total_cmp(b));
+        crossings.dedup_by(|a, b| (*a - *b).abs() < f32::EPSILON);
+        crossings
+    }
+}
+
+impl SystemConfig {
+    pub fn bed_mesh(&self) -> Option<&BedMesh> {
+        self.leveling_config.mesh.as_ref()
+    }
+
+    /// Stores a new mesh and turns the leveling on, same as a successful G29
+    pub fn set_bed_mesh(&mut self, mesh: BedMesh) {
+        self.leveling_config.mesh = Some(mesh);
+        self.leveling_config.enabled = true;
+    }
+
+    /// True if moves are being compensated with the bed mesh
+    pub fn is_leveling_active(&self) -> bool {
+        self.leveling_config.enabled && self.leveling_config.mesh.is_some()
+    }
+
+    /// Height at which the compensation is faded out completely, if any
+    pub fn fade_height(&self) -> Option<LocationType> {
+        self.leveling_config.fade_height
+    }
+
+    /// Location the toolhead is actually sent to for a location in the gcode, after compensating the bed mesh
+    pub fn leveled_location(&self, location: &Location) -> Location {
+        let Some(mesh) = self.leveling_config.mesh.as_ref() else {
+            return *location;
+        };
+        if !self.leveling_config.enabled {
+            return *location;
+        }
+
+        let fade_factor = match self.leveling_config.fade_height {
+            Some(fade_height) => (1.0 - location.z / fade_height).clamp(0.0, 1.0),
+            None => 1.0,
+        };
+
+        Location {
+            z: location.z + mesh.z_offset_at(location.x, location.y) * fade_factor,
+            ..*location
+        }
+    }
+
+    /// Compensates a move with the bed mesh. The move is split wherever it crosses a column or a row of the grid,
+    /// so each piece follows the surface of the bed. Moves are returned as they are while the leveling is off
+    pub fn level_move(&self, resolved_move: &ResolvedMove) -> Vec<ResolvedMove> {
+        let Some(mesh) = self.leveling_config.mesh.as_ref() else {
+            return vec![resolved_move.clone()];
+        };
+        if !self.leveling_config.enabled {
+            return vec![resolved_move.clone()];
+        }
+
+        let mut fractions = vec![0.0];
+        fractions.extend(mesh.crossings(&resolved_move.start(), &resolved_move.end()));
+        fractions.push(1.0);
+
+        fractions
+            .windows(2)
+            .map(|window| {
+                let piece = resolved_move.portion(window[0], window[1]);
+                piece.with_ends(
+                    self.leveled_location(&piece.start()),
+                    self.leveled_location(&piece.end()),
+                )
+            })
+            .collect()
+    }
+
+    /// M420. Turning the leveling on without a mesh leaves it off
+    pub(super) fn set_leveling_state(&mut self, leveling_state: &M420LevelingState) {
+        if let Some(enabled) = leveling_state.enabled {
+            self.leveling_config.enabled = enabled && self.leveling_config.mesh.is_some();
+        }
+        if let Some(fade_height) = leveling_state.fade_height {
+            self.leveling_config.fade_height =
+                (fade_height > 0.0).then(|| self.to_millimeters(fade_height));
+        }
+    }
+
+    /// M421. Coordinates pick the closest point, but indexes take priority over them
+    pub(super) fn set_mesh_point(&mut self, mesh_point: &M421SetMeshPoint) -> PrintResult<()> {
+        let x = mesh_point
+            .x
+            .map(|x| self.to_millimeters(x))
+            .unwrap_or_default();
+        let y = mesh_point
+            .y
+            .map(|y| self.to_millimeters(y))
+            .unwrap_or_default();
+        let z = mesh_point.z.map(|z| self.to_millimeters(z));
+        let offset = mesh_point.offset.map(|offset| self.to_millimeters(offset));
+        let Some(mesh) = self.leveling_config.mesh.as_mut() else {
+            return Err(Error::MeshNotConfigured);
+        };
+
+        let (nearest_column, nearest_row) = mesh.nearest_point(x, y);
+        let column = mesh_point.column.unwrap_or(nearest_column);
+        let row = mesh_point.row.unwrap_or(nearest_row);
+        let Some(current) = mesh.point(column, row) else {
+            return Err(Error::InvalidMeshPoint(column, row));
+        };
+
+        mesh.set_point(
+            column,
+            row,
+            z.unwrap_or(current + offset.unwrap_or_default()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::gcode::GcodeReader;
+    use crate::system::{BedMesh, Location, SystemConfig};
+
+    /// 3x3 mesh over a 200 x 200 bed, rising towards the back right corner
+    fn tilted_mesh() -> BedMesh {
+        let mut mesh = BedMesh::new(0.0, 0.0, 200.0, 200.0, 3, 3).unwrap();
+        for row in 0..3 {
+            for column in 0..3 {
+                mesh.set_point(column, row, 0.1 * (column + row) as f32)
+                    .unwrap();
+            }
+        }
+        mesh
+    }
+
+    fn apply_source(config: &mut SystemConfig, source: &str) -> Result<(), Error> {
+        for line in GcodeReader::new(source.as_bytes()) {
+            if let Some(command) = line.unwrap().into_command() {
+                config.apply_command(&command)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bilinear_interpolation() {
+        let mesh = tilted_mesh();
+
+        assert_eq!(mesh.z_offset_at(0.0, 0.0), 0.0);
+        assert!((mesh.z_offset_at(50.0, 50.0) - 0.1).abs() < 1e-6);
+        assert!((mesh.z_offset_at(150.0, 100.0) - 0.25).abs() < 1e-6);
+        //  Outside the grid the closest border is used
+        assert!((mesh.z_offset_at(250.0, 300.0) - 0.4).abs() < 1e-6);
+        assert!(matches!(
+            BedMesh::new(0.0, 0.0, 200.0, 200.0, 1, 3),
+            Err(Error::InvalidMeshSize(1, 3))
+        ));
+    }
+
+    #[test]
+    fn moves_split_at_grid_lines() {
+        let mut config = SystemConfig::default();
+        config.set_bed_mesh(tilted_mesh());
+        let moves = {
+            let mut moves = vec![];
+            for line in GcodeReader::new("G1 X10 Y50 Z0.2\nG1 X190 E4\n".as_bytes()) {
+                let command = line.unwrap().into_command().unwrap();
+                moves.extend(config.apply_command(&command).unwrap());
+            }
+            moves
+        };
+
+        let pieces = config.level_move(&moves[1]);
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].end().x(), 100.0);
+        assert!((pieces[0].end().z() - 0.35).abs() < 1e-6);
+        assert!((pieces[0].extrusion() + pieces[1].extrusion() - 4.0).abs() < 1e-6);
+        assert_eq!(pieces[1].start(), pieces[0].end());
+    }
+
+    #[test]
+    fn leveling_commands() {
+        let mut config = SystemConfig::default();
+        assert!(matches!(
+            apply_source(&mut config, "M421 I0 J0 Z0.1"),
+            Err(Error::MeshNotConfigured)
+        ));
+        apply_source(&mut config, "M420 S1").unwrap();
+        assert!(!config.is_leveling_active());
+
+        config.set_bed_mesh(tilted_mesh());
+        apply_source(
+            &mut config,
+            "M421 X190 Y10 Q0.05\nM421 I0 J2 Z-0.1\nM420 Z10",
+        )
+        .unwrap();
+        let mesh = config.bed_mesh().unwrap();
+        assert!((mesh.point(2, 0).unwrap() - 0.25).abs() < 1e-6);
+        assert_eq!(mesh.point(0, 2), Some(-0.1));
+        assert!(matches!(
+            apply_source(&mut config, "M421 I3 J0 Z0"),
+            Err(Error::InvalidMeshPoint(3, 0))
+        ));
+
+        //  Halfway through the fade height, only half of the offset is applied
+        let leveled = config.leveled_location(&Location::new(200.0, 200.0, 5.0));
+        assert!((leveled.z() - 5.2).abs() < 1e-6);
+        assert_eq!(
+            config
+                .leveled_location(&Location::new(200.0, 200.0, 12.0))
+                .z(),
+            12.0
+        );
+
+        apply_source(&mut config, "M420 S0").unwrap();
+        assert!(!config.is_leveling_active());
+        assert_eq!(
+            config
+                .leveled_location(&Location::new(200.0, 200.0, 0.0))
+                .z(),
+            0.0
+        );
+    }
+}
@@ -1,27 +1,54 @@
-use crate::types::{LocationType, TemperatureType};
+mod leveling;
+mod profile;
+mod settings;
+mod state;
 
-#[derive(Default)]
+use crate::gcode::Dialect;
+use crate::heater::{HeaterKind, PidGains};
+use crate::motion::{
+    Axis, InputShaper, InputShapers, Kinematics, MachineKinematics, MotionLimits, PressureAdvance,
+    StepsPerUnit,
+};
+use crate::types::{ExtrudeAmountType, FeedrateAmountType, LocationType, TemperatureType};
+
+/// Hottest the bed can be set to when the profile doesn't say otherwise, in °C
+const DEFAULT_BED_MAX_TEMP: TemperatureType = 120;
+/// Hottest the hotend can be set to when the profile doesn't say otherwise, in °C
+const DEFAULT_HOTEND_MAX_TEMP: TemperatureType = 275;
+
+pub use leveling::BedMesh;
+pub use settings::SETTINGS_VERSION;
+pub(crate) use settings::{PayloadReader, checksum, push_f32};
+pub use state::ResolvedMove;
+
+#[derive(Default, Clone)]
 pub struct SystemConfig {
     bed_config: BedConfig,
     extruder_config: ExtruderConfig,
     global: GlobalConfig,
+    leveling_config: LevelingConfig,
+    motion_config: MotionConfig,
 }
 
 //------------------------------------------------------------------------------------------------
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct GlobalConfig {
     units_config: UnitsConfig,
     coordinates_config: CoordinatesConfig,
+    /// Extruder can be set to relative on its own with M83, while the rest of the axes stay absolute
+    extruder_coordinates_config: CoordinatesConfig,
+    /// Firmware of the machine, which decides the commands jobs can use
+    dialect: Dialect,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 enum UnitsConfig {
     #[default]
     Millimeters,
     Inches,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 enum CoordinatesConfig {
     #[default]
     Absolute,
@@ -29,33 +56,322 @@ enum CoordinatesConfig {
 }
 
 //------------------------------------------------------------------------------------------------
-#[derive(Default)]
+#[derive(Clone)]
 struct BedConfig {
     /// Some if origin is configured, None if it's pending to be configured
     origin: Option<Location>,
     /// Some if printing limit is configured, None if it's pending to be configured
     limit: Option<Location>,
+    /// Hottest the bed can be set to, in °C
+    max_temp: TemperatureType,
+    /// Last temperature read from the bed, in °C. Zero until a heater is regulated
+    current_temp: f32,
+    /// Set through M304
+    pid_gains: PidGains,
 }
 
 //------------------------------------------------------------------------------------------------
-#[derive(Default)]
+#[derive(Clone)]
 struct ExtruderConfig {
-    /// Off by default
+    /// Amount of extruders on the machine
+    count: u8,
+    /// Hottest the hotend can be set to, in °C
+    max_temp: TemperatureType,
+    /// Extruder picked by the last tool change
+    active: u8,
+    /// Set through M900, one per extruder
+    pressure_advance: Vec<PressureAdvance>,
+    /// Off by default. Set through M106 and M107
     fan_enabled: bool,
-    current_temp: TemperatureType,
+    /// Last temperature read from the hotend, in °C. Zero until a heater is regulated
+    current_temp: f32,
+    /// Set through M301
+    pid_gains: PidGains,
     /// Always needs to have a value, and its value will be relative to the origin
     /// When printer boots, it'll be 0, 0, 0
     current_location: Location,
+    /// Logical position of the extruder axis, in millimeters of filament
+    extruder_position: ExtrudeAmountType,
+    /// Last feedrate set, in millimeters per minute. Moves without F use it
+    feedrate: FeedrateAmountType,
+}
+
+//------------------------------------------------------------------------------------------------
+#[derive(Default, Clone)]
+struct LevelingConfig {
+    /// Probed or loaded Z offsets of the bed. None until G29 runs or the mesh is set
+    mesh: Option<BedMesh>,
+    /// Compensation is applied only while enabled, and it's never enabled without a mesh
+    enabled: bool,
+    /// Height at which the compensation is faded out completely. None applies it at every height
+    fade_height: Option<LocationType>,
+}
+
+//------------------------------------------------------------------------------------------------
+#[derive(Default, Clone)]
+struct MotionConfig {
+    steps_per_unit: StepsPerUnit,
+    limits: MotionLimits,
+    kinematics: MachineKinematics,
+    /// Input shapers of the X and Y axes. None moves the axis as planned
+    input_shapers: InputShapers,
 }
 
 //------------------------------------------------------------------------------------------------
 /// Used to identify locations in a three dimentional space.
 /// Coordinates can be negative for out-of-bounds locations
-#[derive(Default)]
-struct Location {
-    x: LocationType,
-    y: LocationType,
-    z: LocationType,
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub(crate) x: LocationType,
+    pub(crate) y: LocationType,
+    pub(crate) z: LocationType,
+}
+
+impl Default for BedConfig {
+    fn default() -> Self {
+        Self {
+            origin: None,
+            limit: None,
+            max_temp: DEFAULT_BED_MAX_TEMP,
+            current_temp: 0.0,
+            pid_gains: HeaterKind::Bed.default_gains(),
+        }
+    }
+}
+
+impl Default for ExtruderConfig {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            max_temp: DEFAULT_HOTEND_MAX_TEMP,
+            active: 0,
+            pressure_advance: vec![PressureAdvance::default()],
+            fan_enabled: false,
+            current_temp: 0.0,
+            pid_gains: HeaterKind::Hotend.default_gains(),
+            current_location: Location::default(),
+            extruder_position: 0.0,
+            feedrate: 0.0,
+        }
+    }
+}
+
+impl SystemConfig {
+    /// Sets the printable area of the bed. Usually written by the bed calibration
+    pub fn set_bed(&mut self, origin: Location, limit: Location) {
+        self.bed_config.origin = Some(origin);
+        self.bed_config.limit = Some(limit);
+    }
+
+    /// Origin of the bed, if configured
+    pub fn bed_origin(&self) -> Option<Location> {
+        self.bed_config.origin
+    }
+
+    /// Far end of the printable area of the bed, if configured
+    pub fn bed_limit(&self) -> Option<Location> {
+        self.bed_config.limit
+    }
+
+    /// Hottest the bed can be set to, in °C
+    pub fn bed_max_temp(&self) -> TemperatureType {
+        self.bed_config.max_temp
+    }
+
+    /// Hottest the hotend can be set to, in °C
+    pub fn hotend_max_temp(&self) -> TemperatureType {
+        self.extruder_config.max_temp
+    }
+
+    /// Last temperature read from a heater, in °C. Zero until the heater is regulated
+    pub fn temperature(&self, heater: HeaterKind) -> f32 {
+        match heater {
+            HeaterKind::Hotend => self.extruder_config.current_temp,
+            HeaterKind::Bed => self.bed_config.current_temp,
+        }
+    }
+
+    pub(crate) fn set_temperature(&mut self, heater: HeaterKind, temperature: f32) {
+        match heater {
+            HeaterKind::Hotend => self.extruder_config.current_temp = temperature,
+            HeaterKind::Bed => self.bed_config.current_temp = temperature,
+        }
+    }
+
+    /// Gains of the PID loop regulating a heater
+    pub fn pid_gains(&self, heater: HeaterKind) -> PidGains {
+        match heater {
+            HeaterKind::Hotend => self.extruder_config.pid_gains,
+            HeaterKind::Bed => self.bed_config.pid_gains,
+        }
+    }
+
+    pub fn set_pid_gains(&mut self, heater: HeaterKind, gains: PidGains) {
+        match heater {
+            HeaterKind::Hotend => self.extruder_config.pid_gains = gains,
+            HeaterKind::Bed => self.bed_config.pid_gains = gains,
+        }
+    }
+
+    /// Part cooling fan, turned on by M106 and off by M107
+    pub fn is_fan_enabled(&self) -> bool {
+        self.extruder_config.fan_enabled
+    }
+
+    pub fn extruder_count(&self) -> u8 {
+        self.extruder_config.count
+    }
+
+    /// Extruder picked by the last tool change. The first one until a job changes it
+    pub fn active_extruder(&self) -> u8 {
+        self.extruder_config.active
+    }
+
+    /// Pressure advance of an extruder. Extruders the machine doesn't have never advance
+    pub fn pressure_advance(&self, extruder: u8) -> PressureAdvance {
+        match self.extruder_config.pressure_advance.get(extruder as usize) {
+            Some(pressure_advance) => *pressure_advance,
+            None => PressureAdvance::new(0.0, 0.0),
+        }
+    }
+
+    /// Sets the pressure advance of an extruder. Extruders the machine doesn't have are left alone
+    pub fn set_pressure_advance(&mut self, extruder: u8, pressure_advance: PressureAdvance) {
+        if let Some(current) = self
+            .extruder_config
+            .pressure_advance
+            .get_mut(extruder as usize)
+        {
+            *current = pressure_advance;
+        }
+    }
+
+    /// Sets the amount of extruders. New ones take the pressure advance of the first one
+    pub(crate) fn set_extruder_count(&mut self, count: u8) {
+        let extruder_config = &mut self.extruder_config;
+        let first = extruder_config.pressure_advance[0];
+        extruder_config.count = count;
+        extruder_config
+            .pressure_advance
+            .resize(count as usize, first);
+        extruder_config.active = extruder_config.active.min(count - 1);
+    }
+
+    /// Printing isn't allowed until both origin and limit of the bed are configured
+    pub fn is_bed_configured(&self) -> bool {
+        self.bed_config.origin.is_some() && self.bed_config.limit.is_some()
+    }
+
+    /// True if the kinematics can reach the location, and it's inside the bed when the bed is configured
+    pub fn is_reachable(&self, location: &Location) -> bool {
+        let within_bed = match (self.bed_config.origin, self.bed_config.limit) {
+            (Some(origin), Some(limit)) => location.is_between(&origin, &limit),
+            _ => true,
+        };
+
+        within_bed && self.motion_config.kinematics.is_reachable(location)
+    }
+
+    pub fn steps_per_unit(&self) -> &StepsPerUnit {
+        &self.motion_config.steps_per_unit
+    }
+
+    pub fn motion_limits(&self) -> &MotionLimits {
+        &self.motion_config.limits
+    }
+
+    pub fn kinematics(&self) -> &MachineKinematics {
+        &self.motion_config.kinematics
+    }
+
+    pub fn set_kinematics(&mut self, kinematics: MachineKinematics) {
+        self.motion_config.kinematics = kinematics;
+    }
+
+    /// Input shaper of an axis. Only X and Y can be shaped
+    pub fn input_shaper(&self, axis: Axis) -> Option<InputShaper> {
+        match axis {
+            Axis::X => self.motion_config.input_shapers.x,
+            Axis::Y => self.motion_config.input_shapers.y,
+            _ => None,
+        }
+    }
+
+    /// Sets the input shaper of an axis, or removes it with None. Axes other than X and Y are left alone
+    pub fn set_input_shaper(&mut self, axis: Axis, shaper: Option<InputShaper>) {
+        match axis {
+            Axis::X => self.motion_config.input_shapers.x = shaper,
+            Axis::Y => self.motion_config.input_shapers.y = shaper,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn input_shapers(&self) -> InputShapers {
+        self.motion_config.input_shapers
+    }
+
+    /// Firmware of the machine. Jobs are validated against its commands
+    pub fn dialect(&self) -> Dialect {
+        self.global.dialect
+    }
+
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.global.dialect = dialect;
+    }
+
+    /// Current location of the toolhead, in millimeters
+    pub fn current_location(&self) -> Location {
+        self.extruder_config.current_location
+    }
+
+    pub(crate) fn set_current_location(&mut self, location: Location) {
+        self.extruder_config.current_location = location;
+    }
+
+    /// Current logical position of the extruder axis, in millimeters of filament
+    pub fn extruder_position(&self) -> ExtrudeAmountType {
+        self.extruder_config.extruder_position
+    }
+
+    /// Last feedrate set, in millimeters per minute. Zero until a move sets it
+    pub fn feedrate(&self) -> FeedrateAmountType {
+        self.extruder_config.feedrate
+    }
+}
+
+impl Location {
+    pub fn new(x: LocationType, y: LocationType, z: LocationType) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn x(&self) -> LocationType {
+        self.x
+    }
+
+    pub fn y(&self) -> LocationType {
+        self.y
+    }
+
+    pub fn z(&self) -> LocationType {
+        self.z
+    }
+
+    /// True if every coordinate lies between the ones of both corners, borders included
+    pub fn is_between(&self, corner: &Location, opposite_corner: &Location) -> bool {
+        let between = |value: LocationType, a: LocationType, b: LocationType| {
+            value >= a.min(b) && value <= a.max(b)
+        };
+
+        between(self.x, corner.x, opposite_corner.x)
+            && between(self.y, corner.y, opposite_corner.y)
+            && between(self.z, corner.z, opposite_corner.z)
+    }
+
+    /// Straight line distance to another location
+    pub fn distance_to(&self, other: &Location) -> LocationType {
+        ((other.x - self.x).powi(2) + (other.y - self.y).powi(2) + (other.z - self.z).powi(2))
+            .sqrt()
+    }
 }
 
 //------------------------------------------------------------------------------------------------
@@ -0,0 +1,732 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::error::{Error, PrintResult};
+use crate::gcode::{AxisParameters, Dialect, M204Acceleration, M205AdvancedSettings};
+use crate::heater::PidGains;
+use crate::motion::{
+    Axis, CartesianKinematics, CoreXYKinematics, CoreXZKinematics, DEFAULT_DAMPING, InputShaper,
+    LinearDeltaKinematics, MachineKinematics, ShaperType,
+};
+use crate::types::{LineNumberType, TemperatureType};
+
+use super::{Location, SystemConfig};
+
+/// Sections of the profile, each one written between brackets. Ex: `[bed]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Section {
+    Bed,
+    Axes,
+    Motion,
+    Extruder,
+    Kinematics,
+    InputShaper,
+    Firmware,
+}
+
+/// Values that are only applied once the full profile is read, since they depend on each other
+#[derive(Default)]
+struct PendingValues {
+    /// Line of the `[bed]` header, blamed for an incomplete bed
+    bed_line: Option<LineNumberType>,
+    origin: Option<Location>,
+    size: Option<Location>,
+    /// Line of the `[kinematics]` header, blamed for incomplete kinematics
+    kinematics_line: Option<LineNumberType>,
+    kinematics_type: Option<(String, LineNumberType)>,
+    diagonal_rod: Option<f32>,
+    radius: Option<f32>,
+    print_radius: Option<f32>,
+    /// Shaper types of X and Y
+    shaper_types: Option<[ShaperType; 2]>,
+    /// Resonance frequencies of X and Y. Zero leaves the axis unshaped
+    shaper_frequencies: Option<[f32; 2]>,
+    shaper_dampings: Option<[f32; 2]>,
+}
+
+impl SystemConfig {
+    /// Builds a config out of a printer profile. Lines hold either a `[section]` header or a `key = value` pair,
+    /// and anything after a `#` is a comment. Missing values keep their defaults. The bed is optional, since it's
+    /// usually calibrated at boot, but its origin and size must be given together
+    pub fn from_profile(profile: &str) -> PrintResult<SystemConfig> {
+        let mut config = SystemConfig::default();
+        let mut pending = PendingValues::default();
+        let mut section = None;
+        let mut seen_keys = HashSet::new();
+
+        for (index, line) in profile.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                let new_section = Section::from_name(name.trim(), line_number)?;
+                match new_section {
+                    Section::Bed => pending.bed_line = Some(line_number),
+                    Section::Kinematics => pending.kinematics_line = Some(line_number),
+                    _ => {}
+                }
+                section = Some(new_section);
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(profile_error(
+                    format!("expected `key = value` or `[section]`, found `{line}`"),
+                    line_number,
+                ));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let Some(section) = section else {
+                return Err(profile_error(
+                    format!("`{key}` must be inside a section"),
+                    line_number,
+                ));
+            };
+            if !seen_keys.insert((section, key.to_string())) {
+                return Err(profile_error(
+                    format!("`{key}` is set more than once"),
+                    line_number,
+                ));
+            }
+
+            config.apply_profile_value(section, key, value, line_number, &mut pending)?;
+        }
+
+        config.apply_pending_values(pending)?;
+        Ok(config)
+    }
+
+    /// Reads a printer profile from a file. See `from_profile()`
+    pub fn load_profile(path: impl AsRef<Path>) -> PrintResult<SystemConfig> {
+        let profile = std::fs::read_to_string(path).map_err(Error::InputOutputError)?;
+        Self::from_profile(&profile)
+    }
+
+    /// Writes the config as a printer profile, which `from_profile()` reads back into the same config
+    pub fn to_profile(&self) -> String {
+        let limits = self.motion_limits();
+        let steps_per_unit = self.steps_per_unit();
+        let mut profile = String::from("# Printer profile\n");
+
+        profile.push_str("\n[bed]\n");
+        match (self.bed_config.origin, self.bed_config.limit) {
+            (Some(origin), Some(limit)) => {
+                let size =
+                    Location::new(limit.x - origin.x, limit.y - origin.y, limit.z - origin.z);
+                profile.push_str(&format!("origin = {}\n", location_values(&origin)));
+                profile.push_str(&format!("size = {}\n", location_values(&size)));
+            }
+            _ => profile.push_str("# Origin and size are calibrated at boot\n"),
+        }
+        profile.push_str(&format!("max_temp = {}\n", self.bed_config.max_temp));
+        profile.push_str(&format!(
+            "pid = {}\n",
+            pid_values(&self.bed_config.pid_gains)
+        ));
+
+        profile.push_str("\n[axes]\n# X, Y, Z, E\n");
+        profile.push_str(&format!(
+            "steps_per_unit = {}\n",
+            axis_values(|axis| steps_per_unit.get(axis))
+        ));
+        profile.push_str(&format!(
+            "max_feedrate = {}\n",
+            axis_values(|axis| limits.max_feedrate(axis))
+        ));
+        profile.push_str(&format!(
+            "max_acceleration = {}\n",
+            axis_values(|axis| limits.max_acceleration(axis))
+        ));
+        profile.push_str(&format!(
+            "jerk = {}\n",
+            axis_values(|axis| limits.jerk(axis))
+        ));
+
+        profile.push_str("\n[motion]\n");
+        profile.push_str(&format!(
+            "print_acceleration = {}\n",
+            limits.print_acceleration()
+        ));
+        profile.push_str(&format!(
+            "retract_acceleration = {}\n",
+            limits.retract_acceleration()
+        ));
+        profile.push_str(&format!(
+            "travel_acceleration = {}\n",
+            limits.travel_acceleration()
+        ));
+        profile.push_str(&format!(
+            "junction_deviation = {}\n",
+            limits.junction_deviation()
+        ));
+        profile.push_str(&format!("min_feedrate = {}\n", limits.min_feedrate()));
+        profile.push_str(&format!(
+            "min_travel_feedrate = {}\n",
+            limits.min_travel_feedrate()
+        ));
+
+        profile.push_str("\n[extruder]\n");
+        profile.push_str(&format!("count = {}\n", self.extruder_config.count));
+        profile.push_str(&format!("max_temp = {}\n", self.extruder_config.max_temp));
+        profile.push_str(&format!(
+            "pid = {}\n",
+            pid_values(&self.extruder_config.pid_gains)
+        ));
+        let pressure_advance = self.pressure_advance(0);
+        profile.push_str(&format!(
+            "pressure_advance = {}\n",
+            pressure_advance.factor()
+        ));
+        profile.push_str(&format!(
+            "smooth_time = {}\n",
+            pressure_advance.smooth_time()
+        ));
+
+        profile.push_str("\n[kinematics]\n");
+        match self.kinematics() {
+            MachineKinematics::Cartesian(_) => profile.push_str("type = cartesian\n"),
+            MachineKinematics::CoreXY(_) => profile.push_str("type = corexy\n"),
+            MachineKinematics::CoreXZ(_) => profile.push_str("type = corexz\n"),
+            MachineKinematics::LinearDelta(delta) => {
+                profile.push_str("type = delta\n");
+                profile.push_str(&format!("diagonal_rod = {}\n", delta.diagonal_rod()));
+                profile.push_str(&format!("radius = {}\n", delta.radius()));
+                profile.push_str(&format!("print_radius = {}\n", delta.print_radius()));
+            }
+        }
+
+        profile
+            .push_str("\n[input_shaper]\n# X, Y. A frequency of zero leaves the axis unshaped\n");
+        let shapers = [Axis::X, Axis::Y].map(|axis| self.input_shaper(axis));
+        let shaper_values = |value: &dyn Fn(&InputShaper) -> String, default: String| {
+            shapers
+                .iter()
+                .map(|shaper| shaper.as_ref().map_or(default.clone(), value))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        profile.push_str(&format!(
+            "type = {}\n",
+            shaper_values(
+                &|shaper| shaper.shaper_type().name().to_string(),
+                ShaperType::default().name().to_string()
+            )
+        ));
+        profile.push_str(&format!(
+            "frequency = {}\n",
+            shaper_values(&|shaper| shaper.frequency().to_string(), "0".to_string())
+        ));
+        profile.push_str(&format!(
+            "damping = {}\n",
+            shaper_values(
+                &|shaper| shaper.damping().to_string(),
+                DEFAULT_DAMPING.to_string()
+            )
+        ));
+
+        profile.push_str("\n[firmware]\n");
+        profile.push_str(&format!("dialect = {}\n", self.dialect().name()));
+
+        profile
+    }
+
+    /// Writes the config as a printer profile to a file. See `to_profile()`
+    pub fn save_profile(&self, path: impl AsRef<Path>) -> PrintResult<()> {
+        std::fs::write(path, self.to_profile()).map_err(Error::InputOutputError)
+    }
+
+    fn apply_profile_value(
+        &mut self,
+        section: Section,
+        key: &str,
+        value: &str,
+        line_number: LineNumberType,
+        pending: &mut PendingValues,
+    ) -> PrintResult<()> {
+        let limits = &mut self.motion_config.limits;
+
+        match (section, key) {
+            (Section::Bed, "origin") => {
+                let [x, y, z] = parse_values(value, line_number, f32::is_finite)?;
+                pending.origin = Some(Location::new(x, y, z));
+            }
+            (Section::Bed, "size") => {
+                let [x, y, z] = parse_values(value, line_number, |value| value > 0.0)?;
+                pending.size = Some(Location::new(x, y, z));
+            }
+            (Section::Bed, "max_temp") => {
+                self.bed_config.max_temp = parse_temperature(value, line_number)?
+            }
+            (Section::Bed, "pid") => self.bed_config.pid_gains = parse_pid(value, line_number)?,
+            (Section::Axes, "steps_per_unit") => {
+                let values: [f32; 4] = parse_values(value, line_number, |value| value > 0.0)?;
+                for (axis, value) in Axis::ALL.into_iter().zip(values) {
+                    self.motion_config.steps_per_unit.set(axis, value);
+                }
+            }
+            (Section::Axes, "max_feedrate") => limits.set_max_feedrate(&axis_parameters(
+                parse_values(value, line_number, |value| value > 0.0)?,
+            )),
+            (Section::Axes, "max_acceleration") => limits.set_max_acceleration(&axis_parameters(
+                parse_values(value, line_number, |value| value > 0.0)?,
+            )),
+            (Section::Axes, "jerk") => limits.set_advanced_settings(&M205AdvancedSettings {
+                jerk: axis_parameters(parse_values(value, line_number, |value| value >= 0.0)?),
+                ..Default::default()
+            }),
+            (Section::Motion, "print_acceleration") => limits.set_acceleration(&M204Acceleration {
+                print: Some(parse_positive(value, line_number)?),
+                ..Default::default()
+            }),
+            (Section::Motion, "retract_acceleration") => {
+                limits.set_acceleration(&M204Acceleration {
+                    retract: Some(parse_positive(value, line_number)?),
+                    ..Default::default()
+                })
+            }
+            (Section::Motion, "travel_acceleration") => {
+                limits.set_acceleration(&M204Acceleration {
+                    travel: Some(parse_positive(value, line_number)?),
+                    ..Default::default()
+                })
+            }
+            (Section::Motion, "junction_deviation") => {
+                limits.set_advanced_settings(&M205AdvancedSettings {
+                    junction_deviation: Some(parse_not_negative(value, line_number)?),
+                    ..Default::default()
+                })
+            }
+            (Section::Motion, "min_feedrate") => {
+                limits.set_advanced_settings(&M205AdvancedSettings {
+                    min_feedrate: Some(parse_not_negative(value, line_number)?),
+                    ..Default::default()
+                })
+            }
+            (Section::Motion, "min_travel_feedrate") => {
+                limits.set_advanced_settings(&M205AdvancedSettings {
+                    min_travel_feedrate: Some(parse_not_negative(value, line_number)?),
+                    ..Default::default()
+                })
+            }
+            (Section::Extruder, "count") => match value.parse::<u8>() {
+                Ok(count) if count > 0 => self.set_extruder_count(count),
+                _ => {
+                    return Err(profile_error(
+                        format!("`count` must be a whole number from 1 to 255, found `{value}`"),
+                        line_number,
+                    ));
+                }
+            },
+            (Section::Extruder, "max_temp") => {
+                self.extruder_config.max_temp = parse_temperature(value, line_number)?
+            }
+            (Section::Extruder, "pid") => {
+                self.extruder_config.pid_gains = parse_pid(value, line_number)?
+            }
+            //  Every extruder shares the same pressure advance until M900 sets them apart
+            (Section::Extruder, "pressure_advance") => {
+                let factor = parse_not_negative(value, line_number)?;
+                for pressure_advance in &mut self.extruder_config.pressure_advance {
+                    pressure_advance.set_factor(factor);
+                }
+            }
+            (Section::Extruder, "smooth_time") => {
+                let smooth_time = parse_not_negative(value, line_number)?;
+                for pressure_advance in &mut self.extruder_config.pressure_advance {
+                    pressure_advance.set_smooth_time(smooth_time);
+                }
+            }
+            (Section::Kinematics, "type") => {
+                pending.kinematics_type = Some((value.to_lowercase(), line_number))
+            }
+            (Section::Kinematics, "diagonal_rod") => {
+                pending.diagonal_rod = Some(parse_positive(value, line_number)?)
+            }
+            (Section::Kinematics, "radius") => {
+                pending.radius = Some(parse_positive(value, line_number)?)
+            }
+            (Section::Kinematics, "print_radius") => {
+                pending.print_radius = Some(parse_positive(value, line_number)?)
+            }
+            (Section::InputShaper, "type") => {
+                let mut shaper_types = [ShaperType::default(); 2];
+                let parts: Vec<&str> = value.split(',').collect();
+                if parts.len() != shaper_types.len() {
+                    return Err(profile_error(
+                        format!(
+                            "expected 2 values separated by commas, found {}",
+                            parts.len()
+                        ),
+                        line_number,
+                    ));
+                }
+                for (shaper_type, name) in shaper_types.iter_mut().zip(parts) {
+                    *shaper_type = ShaperType::from_name(name).ok_or_else(|| {
+                        profile_error(
+                            format!(
+                                "unknown shaper `{}`, expected zv, mzv, ei or 2hump_ei",
+                                name.trim()
+                            ),
+                            line_number,
+                        )
+                    })?;
+                }
+                pending.shaper_types = Some(shaper_types);
+            }
+            (Section::InputShaper, "frequency") => {
+                pending.shaper_frequencies =
+                    Some(parse_values(value, line_number, |value| value >= 0.0)?)
+            }
+            (Section::InputShaper, "damping") => {
+                pending.shaper_dampings = Some(parse_values(value, line_number, |value| {
+                    (0.0..1.0).contains(&value)
+                })?)
+            }
+            (Section::Firmware, "dialect") => match Dialect::from_name(value) {
+                Some(dialect) => self.set_dialect(dialect),
+                None => {
+                    return Err(profile_error(
+                        format!(
+                            "unknown dialect `{value}`, expected generic, marlin, prusa, klipper or reprapfirmware"
+                        ),
+                        line_number,
+                    ));
+                }
+            },
+            (section, key) => {
+                return Err(profile_error(
+                    format!("unknown key `{key}` in section `[{}]`", section.name()),
+                    line_number,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_pending_values(&mut self, pending: PendingValues) -> PrintResult<()> {
+        match (pending.origin, pending.size) {
+            (Some(origin), Some(size)) => self.set_bed(
+                origin,
+                Location::new(origin.x + size.x, origin.y + size.y, origin.z + size.z),
+            ),
+            (None, None) => {}
+            _ => {
+                return Err(profile_error(
+                    "`origin` and `size` of the bed must be given together".to_string(),
+                    pending.bed_line.unwrap_or_default(),
+                ));
+            }
+        }
+
+        if let Some(frequencies) = pending.shaper_frequencies {
+            let shaper_types = pending.shaper_types.unwrap_or_default();
+            let dampings = pending.shaper_dampings.unwrap_or([DEFAULT_DAMPING; 2]);
+            for (index, axis) in [Axis::X, Axis::Y].into_iter().enumerate() {
+                let shaper = (frequencies[index] > 0.0).then(|| {
+                    InputShaper::new(shaper_types[index], frequencies[index], dampings[index])
+                });
+                self.set_input_shaper(axis, shaper);
+            }
+        }
+
+        let kinematics_line = pending.kinematics_line.unwrap_or_default();
+        let Some((kinematics_type, type_line)) = pending.kinematics_type else {
+            if pending.kinematics_line.is_some() {
+                return Err(profile_error(
+                    "`type` of the kinematics is missing".to_string(),
+                    kinematics_line,
+                ));
+            }
+            return Ok(());
+        };
+
+        let is_delta = kinematics_type == "delta";
+        let has_delta_values = pending.diagonal_rod.is_some()
+            || pending.radius.is_some()
+            || pending.print_radius.is_some();
+        if !is_delta && has_delta_values {
+            return Err(profile_error(
+                format!("`{kinematics_type}` kinematics don't take delta dimensions"),
+                kinematics_line,
+            ));
+        }
+
+        self.motion_config.kinematics = match kinematics_type.as_str() {
+            "cartesian" => MachineKinematics::Cartesian(CartesianKinematics),
+            "corexy" => MachineKinematics::CoreXY(CoreXYKinematics),
+            "corexz" => MachineKinematics::CoreXZ(CoreXZKinematics),
+            "delta" => match (pending.diagonal_rod, pending.radius, pending.print_radius) {
+                (Some(diagonal_rod), Some(radius), Some(print_radius)) => {
+                    MachineKinematics::LinearDelta(LinearDeltaKinematics::new(
+                        diagonal_rod,
+                        radius,
+                        print_radius,
+                    ))
+                }
+                _ => {
+                    return Err(profile_error(
+                        "delta kinematics need `diagonal_rod`, `radius` and `print_radius`"
+                            .to_string(),
+                        kinematics_line,
+                    ));
+                }
+            },
+            other => {
+                return Err(profile_error(
+                    format!(
+                        "unknown kinematics `{other}`, expected cartesian, corexy, corexz or delta"
+                    ),
+                    type_line,
+                ));
+            }
+        };
+
+        Ok(())
+    }
+}
+
+impl Section {
+    fn from_name(name: &str, line_number: LineNumberType) -> PrintResult<Section> {
+        match name {
+            "bed" => Ok(Section::Bed),
+            "axes" => Ok(Section::Axes),
+            "motion" => Ok(Section::Motion),
+            "extruder" => Ok(Section::Extruder),
+            "kinematics" => Ok(Section::Kinematics),
+            "input_shaper" => Ok(Section::InputShaper),
+            "firmware" => Ok(Section::Firmware),
+            _ => Err(profile_error(
+                format!("unknown section `[{name}]`"),
+                line_number,
+            )),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Section::Bed => "bed",
+            Section::Axes => "axes",
+            Section::Motion => "motion",
+            Section::Extruder => "extruder",
+            Section::Kinematics => "kinematics",
+            Section::InputShaper => "input_shaper",
+            Section::Firmware => "firmware",
+        }
+    }
+}
+
+/// Parses a list of exactly N numbers separated by commas, each of them passing the check
+fn parse_values<const N: usize>(
+    value: &str,
+    line_number: LineNumberType,
+    is_valid: impl Fn(f32) -> bool,
+) -> PrintResult<[f32; N]> {
+    let parts: Vec<&str> = value.split(',').map(str::trim).collect();
+    if parts.len() != N {
+        return Err(profile_error(
+            format!(
+                "expected {N} values separated by commas, found {}",
+                parts.len()
+            ),
+            line_number,
+        ));
+    }
+
+    let mut values = [0.0; N];
+    for (index, part) in parts.iter().enumerate() {
+        values[index] = match part.parse::<f32>() {
+            Ok(number) if number.is_finite() && is_valid(number) => number,
+            Ok(_) => {
+                return Err(profile_error(
+                    format!("`{part}` is out of the allowed range"),
+                    line_number,
+                ));
+            }
+            Err(_) => {
+                return Err(profile_error(
+                    format!("`{part}` is not a number"),
+                    line_number,
+                ));
+            }
+        };
+    }
+
+    Ok(values)
+}
+
+fn parse_positive(value: &str, line_number: LineNumberType) -> PrintResult<f32> {
+    let [value] = parse_values(value, line_number, |value| value > 0.0)?;
+    Ok(value)
+}
+
+fn parse_not_negative(value: &str, line_number: LineNumberType) -> PrintResult<f32> {
+    let [value] = parse_values(value, line_number, |value| value >= 0.0)?;
+    Ok(value)
+}
+
+/// Proportional, integral and derivative gains, none of them negative
+fn parse_pid(value: &str, line_number: LineNumberType) -> PrintResult<PidGains> {
+    let [proportional, integral, derivative] =
+        parse_values(value, line_number, |value| value >= 0.0)?;
+    Ok(PidGains::new(proportional, integral, derivative))
+}
+
+fn parse_temperature(value: &str, line_number: LineNumberType) -> PrintResult<TemperatureType> {
+    match value.parse::<TemperatureType>() {
+        Ok(temperature) if temperature > 0 => Ok(temperature),
+        _ => Err(profile_error(
+            format!("`{value}` is not a valid temperature, expected whole degrees Celsius"),
+            line_number,
+        )),
+    }
+}
+
+fn axis_parameters([x, y, z, e]: [f32; 4]) -> AxisParameters {
+    AxisParameters {
+        x: Some(x),
+        y: Some(y),
+        z: Some(z),
+        e: Some(e),
+    }
+}
+
+/// Values of each axis separated by commas. Ex: `80, 80, 400, 93`
+fn axis_values(value: impl Fn(Axis) -> f32) -> String {
+    Axis::ALL
+        .iter()
+        .map(|axis| value(*axis).to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn location_values(location: &Location) -> String {
+    format!("{}, {}, {}", location.x, location.y, location.z)
+}
+
+fn pid_values(gains: &PidGains) -> String {
+    format!(
+        "{}, {}, {}",
+        gains.proportional(),
+        gains.integral(),
+        gains.derivative()
+    )
+}
+
+fn profile_error(description: String, line_number: LineNumberType) -> Error {
+    Error::InvalidProfileLine(description, line_number)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::gcode::Dialect;
+    use crate::heater::{HeaterKind, PidGains};
+    use crate::motion::{Axis, InputShaper, MachineKinematics, PressureAdvance, ShaperType};
+    use crate::system::{Location, SystemConfig};
+
+    fn profile_error(profile: &str) -> (String, usize) {
+        match SystemConfig::from_profile(profile) {
+            Err(Error::InvalidProfileLine(description, line_number)) => (description, line_number),
+            other => panic!("expected a profile error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn load_example_profile() {
+        let config = SystemConfig::load_profile("example.profile").unwrap();
+
+        assert_eq!(config.bed_origin(), Some(Location::new(0.0, 0.0, 0.0)));
+        assert_eq!(config.bed_limit(), Some(Location::new(250.0, 210.0, 210.0)));
+        assert_eq!(config.bed_max_temp(), 120);
+        assert_eq!(config.hotend_max_temp(), 300);
+        assert_eq!(config.extruder_count(), 1);
+        assert_eq!(config.steps_per_unit().get(Axis::E), 280.0);
+        assert_eq!(config.motion_limits().max_feedrate(Axis::Z), 12.0);
+        assert_eq!(config.motion_limits().junction_deviation(), 0.02);
+        assert!(matches!(config.kinematics(), MachineKinematics::CoreXY(_)));
+        assert_eq!(config.dialect(), Dialect::Marlin);
+    }
+
+    #[test]
+    fn profile_round_trip() {
+        let profile = "[bed]\norigin = -2, -3, 0\nsize = 180, 180, 300\n\
+                       [axes]\njerk = 8, 8, 0.4, 2.5\n\
+                       [extruder]\npressure_advance = 0.045\ncount = 2\npid = 30, 2.5, 95.25\n\
+                       [kinematics]\ntype = delta\ndiagonal_rod = 215\nradius = 105.2\nprint_radius = 90\n\
+                       [input_shaper]\ntype = 2HUMP_EI, zv\nfrequency = 48.5, 0\n\
+                       [firmware]\ndialect = RRF\n";
+        let config = SystemConfig::from_profile(profile).unwrap();
+        let written = config.to_profile();
+        let reloaded = SystemConfig::from_profile(&written).unwrap();
+
+        assert_eq!(reloaded.to_profile(), written);
+        assert_eq!(
+            reloaded.bed_limit(),
+            Some(Location::new(178.0, 177.0, 300.0))
+        );
+        assert_eq!(reloaded.extruder_count(), 2);
+        assert_eq!(
+            reloaded.pressure_advance(1),
+            PressureAdvance::new(0.045, 0.04)
+        );
+        assert_eq!(
+            reloaded.input_shaper(Axis::X),
+            Some(InputShaper::new(ShaperType::TwoHumpEi, 48.5, 0.1))
+        );
+        assert_eq!(reloaded.input_shaper(Axis::Y), None);
+        assert_eq!(
+            reloaded.pid_gains(HeaterKind::Hotend),
+            PidGains::new(30.0, 2.5, 95.25)
+        );
+        assert_eq!(
+            reloaded.pid_gains(HeaterKind::Bed),
+            HeaterKind::Bed.default_gains()
+        );
+        assert_eq!(reloaded.motion_limits(), config.motion_limits());
+        assert_eq!(reloaded.kinematics(), config.kinematics());
+        assert_eq!(reloaded.dialect(), Dialect::RepRapFirmware);
+    }
+
+    #[test]
+    fn profile_errors_point_at_line() {
+        assert_eq!(
+            profile_error("[axes]\n\nsteps_per_unit = 80, 80, 400\n"),
+            (
+                "expected 4 values separated by commas, found 3".to_string(),
+                3
+            )
+        );
+        assert_eq!(
+            profile_error("# comment\n[motion]\nprint_acceleration = fast\n"),
+            ("`fast` is not a number".to_string(), 3)
+        );
+        assert_eq!(profile_error("[printer]\n").1, 1);
+        assert_eq!(profile_error("count = 1\n").1, 1);
+        assert_eq!(profile_error("[extruder]\ncount = 1\ncount = 2\n").1, 3);
+        assert_eq!(
+            profile_error("[bed]\nmax_temp = 100\norigin = 0, 0, 0\n").1,
+            1
+        );
+        assert_eq!(
+            profile_error("[kinematics]\ntype = delta\nradius = 100\n").1,
+            1
+        );
+        assert_eq!(profile_error("[kinematics]\ntype = scara\n").1, 2);
+        assert_eq!(
+            profile_error("[input_shaper]\ntype = mzv, zvd\n"),
+            (
+                "unknown shaper `zvd`, expected zv, mzv, ei or 2hump_ei".to_string(),
+                2
+            )
+        );
+        assert_eq!(profile_error("[input_shaper]\ndamping = 0.1, 1\n").1, 2);
+        assert_eq!(profile_error("[bed]\npid = 10, -1, 300\n").1, 2);
+    }
+}
@@ -0,0 +1,481 @@
+use crate::error::{Error, PrintResult};
+use crate::gcode::{AxisParameters, M204Acceleration, M205AdvancedSettings};
+use crate::heater::{HeaterKind, PidGains};
+use crate::motion::{Axis, MotionLimits, StepsPerUnit};
+use crate::storage::Storage;
+
+use super::{BedMesh, LevelingConfig, SystemConfig};
+
+/// Marks the start of the settings in the storage
+const SETTINGS_MAGIC: &[u8; 4] = b"PRTY";
+/// Bumped on every change of the layout, so settings saved by another version are rejected instead of misread
+pub const SETTINGS_VERSION: u16 = 2;
+/// Magic, version and payload length
+const HEADER_SIZE: usize = 10;
+const CHECKSUM_SIZE: usize = 2;
+
+/// Reads the values of the payload in the same order they were written
+pub(crate) struct PayloadReader<'a> {
+    payload: &'a [u8],
+    position: usize,
+}
+
+impl SystemConfig {
+    /// M500. Saves the settings that survive a reboot: steps per unit, motion limits, bed leveling and PID gains.
+    /// The layout is a header with the magic, version and payload length, the payload in little endian,
+    /// and a CRC-16 of everything before it
+    pub fn save_settings(&self, storage: &mut dyn Storage) -> PrintResult<()> {
+        let payload = self.encode_settings();
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + payload.len() + CHECKSUM_SIZE);
+        data.extend_from_slice(SETTINGS_MAGIC);
+        data.extend_from_slice(&SETTINGS_VERSION.to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&checksum(&data).to_le_bytes());
+
+        storage.write(&data)
+    }
+
+    /// M501. Restores the saved settings. The config is left untouched if they can't be used
+    pub fn load_settings(&mut self, storage: &mut dyn Storage) -> PrintResult<()> {
+        let data = storage.read()?;
+        if data.len() < HEADER_SIZE || &data[..4] != SETTINGS_MAGIC {
+            return Err(Error::NoStoredSettings);
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != SETTINGS_VERSION {
+            return Err(Error::SettingsVersionMismatch(version));
+        }
+
+        let payload_length = u32::from_le_bytes([data[6], data[7], data[8], data[9]]) as usize;
+        let checksum_start = HEADER_SIZE + payload_length;
+        if data.len() < checksum_start + CHECKSUM_SIZE {
+            return Err(Error::CorruptSettings);
+        }
+        let stored_checksum = u16::from_le_bytes([data[checksum_start], data[checksum_start + 1]]);
+        if stored_checksum != checksum(&data[..checksum_start]) {
+            return Err(Error::CorruptSettings);
+        }
+
+        self.decode_settings(&data[HEADER_SIZE..checksum_start])
+    }
+
+    /// M502. Goes back to the factory defaults of every saved setting, without touching the storage
+    pub fn reset_settings(&mut self) {
+        self.motion_config.steps_per_unit = StepsPerUnit::default();
+        self.motion_config.limits = MotionLimits::default();
+        self.leveling_config = LevelingConfig::default();
+        for heater in [HeaterKind::Hotend, HeaterKind::Bed] {
+            self.set_pid_gains(heater, heater.default_gains());
+        }
+    }
+
+    /// M503. Every saved setting as the gcode that sets it, so the report can be run to restore them.
+    /// Mesh points can only be restored on a machine that already has a mesh with the same grid
+    pub fn report_settings(&self) -> String {
+        let steps_per_unit = self.steps_per_unit();
+        let limits = self.motion_limits();
+        let mut report = String::new();
+
+        report.push_str("; Steps per unit\n");
+        report.push_str(&format!(
+            "M92{}\n",
+            axis_values(|axis| steps_per_unit.get(axis))
+        ));
+        report.push_str("; Max acceleration (mm/s²)\n");
+        report.push_str(&format!(
+            "M201{}\n",
+            axis_values(|axis| limits.max_acceleration(axis))
+        ));
+        report.push_str("; Max feedrate (mm/s)\n");
+        report.push_str(&format!(
+            "M203{}\n",
+            axis_values(|axis| limits.max_feedrate(axis))
+        ));
+        report.push_str("; Print, retract and travel acceleration (mm/s²)\n");
+        report.push_str(&format!(
+            "M204 P{} R{} T{}\n",
+            limits.print_acceleration(),
+            limits.retract_acceleration(),
+            limits.travel_acceleration()
+        ));
+        report.push_str("; Jerk (mm/s), junction deviation (mm) and min feedrates (mm/s)\n");
+        report.push_str(&format!(
+            "M205{} J{} S{} T{}\n",
+            axis_values(|axis| limits.jerk(axis)),
+            limits.junction_deviation(),
+            limits.min_feedrate(),
+            limits.min_travel_feedrate()
+        ));
+
+        if let Some(mesh) = self.bed_mesh() {
+            report.push_str(&format!(
+                "; Bed mesh of {}x{} points, from X{} Y{} to X{} Y{}\n",
+                mesh.columns(),
+                mesh.rows(),
+                mesh.min_x(),
+                mesh.min_y(),
+                mesh.max_x(),
+                mesh.max_y()
+            ));
+            for row in 0..mesh.rows() {
+                for column in 0..mesh.columns() {
+                    let z_offset = mesh.point(column, row).unwrap_or_default();
+                    report.push_str(&format!("M421 I{column} J{row} Z{z_offset}\n"));
+                }
+            }
+        }
+
+        //  Leveling goes last, since it can't be turned on before the mesh is set
+        report.push_str("; Bed leveling\n");
+        report.push_str(&format!(
+            "M420 S{} Z{}\n",
+            self.is_leveling_active() as u8,
+            self.fade_height().unwrap_or_default()
+        ));
+        for (heater, title, name) in [
+            (HeaterKind::Hotend, "Hotend", "M301"),
+            (HeaterKind::Bed, "Bed", "M304"),
+        ] {
+            let gains = self.pid_gains(heater);
+            report.push_str(&format!("; {title} PID\n"));
+            report.push_str(&format!(
+                "{name} P{} I{} D{}\n",
+                gains.proportional(),
+                gains.integral(),
+                gains.derivative()
+            ));
+        }
+
+        report
+    }
+
+    fn encode_settings(&self) -> Vec<u8> {
+        let steps_per_unit = self.steps_per_unit();
+        let limits = self.motion_limits();
+        let mut payload = vec![];
+
+        for axis in Axis::ALL {
+            push_f32(&mut payload, steps_per_unit.get(axis));
+        }
+        for axis in Axis::ALL {
+            push_f32(&mut payload, limits.max_acceleration(axis));
+        }
+        for axis in Axis::ALL {
+            push_f32(&mut payload, limits.max_feedrate(axis));
+        }
+        push_f32(&mut payload, limits.print_acceleration());
+        push_f32(&mut payload, limits.retract_acceleration());
+        push_f32(&mut payload, limits.travel_acceleration());
+        for axis in Axis::ALL {
+            push_f32(&mut payload, limits.jerk(axis));
+        }
+        push_f32(&mut payload, limits.junction_deviation());
+        push_f32(&mut payload, limits.min_feedrate());
+        push_f32(&mut payload, limits.min_travel_feedrate());
+
+        payload.push(self.leveling_config.enabled as u8);
+        push_f32(
+            &mut payload,
+            self.leveling_config.fade_height.unwrap_or_default(),
+        );
+        match &self.leveling_config.mesh {
+            None => payload.push(0),
+            Some(mesh) => {
+                payload.push(1);
+                for value in [mesh.min_x(), mesh.min_y(), mesh.max_x(), mesh.max_y()] {
+                    push_f32(&mut payload, value);
+                }
+                payload.extend_from_slice(&(mesh.columns() as u16).to_le_bytes());
+                payload.extend_from_slice(&(mesh.rows() as u16).to_le_bytes());
+                for row in 0..mesh.rows() {
+                    for column in 0..mesh.columns() {
+                        push_f32(&mut payload, mesh.point(column, row).unwrap_or_default());
+                    }
+                }
+            }
+        }
+        for heater in [HeaterKind::Hotend, HeaterKind::Bed] {
+            let gains = self.pid_gains(heater);
+            push_f32(&mut payload, gains.proportional());
+            push_f32(&mut payload, gains.integral());
+            push_f32(&mut payload, gains.derivative());
+        }
+
+        payload
+    }
+
+    /// Reads every setting before applying any of them, so a short payload doesn't leave them half loaded
+    fn decode_settings(&mut self, payload: &[u8]) -> PrintResult<()> {
+        let mut reader = PayloadReader::new(payload);
+
+        let mut steps_per_unit = StepsPerUnit::default();
+        for axis in Axis::ALL {
+            steps_per_unit.set(axis, reader.read_f32()?);
+        }
+
+        let mut limits = MotionLimits::default();
+        limits.set_max_acceleration(&reader.read_axis_parameters()?);
+        limits.set_max_feedrate(&reader.read_axis_parameters()?);
+        limits.set_acceleration(&M204Acceleration {
+            print: Some(reader.read_f32()?),
+            retract: Some(reader.read_f32()?),
+            travel: Some(reader.read_f32()?),
+            legacy: None,
+        });
+        limits.set_advanced_settings(&M205AdvancedSettings {
+            jerk: reader.read_axis_parameters()?,
+            junction_deviation: Some(reader.read_f32()?),
+            min_feedrate: Some(reader.read_f32()?),
+            min_travel_feedrate: Some(reader.read_f32()?),
+        });
+
+        let enabled = reader.read_u8()? != 0;
+        let fade_height = reader.read_f32()?;
+        let mesh = match reader.read_u8()? {
+            0 => None,
+            _ => {
+                let [min_x, min_y, max_x, max_y] = [
+                    reader.read_f32()?,
+                    reader.read_f32()?,
+                    reader.read_f32()?,
+                    reader.read_f32()?,
+                ];
+                let columns = reader.read_u16()? as usize;
+                let rows = reader.read_u16()? as usize;
+                let mut mesh = BedMesh::new(min_x, min_y, max_x, max_y, columns, rows)
+                    .map_err(|_| Error::CorruptSettings)?;
+                for row in 0..rows {
+                    for column in 0..columns {
+                        mesh.set_point(column, row, reader.read_f32()?)?;
+                    }
+                }
+                Some(mesh)
+            }
+        };
+        let mut pid_gains = vec![];
+        for _ in [HeaterKind::Hotend, HeaterKind::Bed] {
+            pid_gains.push(PidGains::new(
+                reader.read_f32()?,
+                reader.read_f32()?,
+                reader.read_f32()?,
+            ));
+        }
+
+        if !reader.is_finished() {
+            return Err(Error::CorruptSettings);
+        }
+
+        self.motion_config.steps_per_unit = steps_per_unit;
+        self.motion_config.limits = limits;
+        self.leveling_config = LevelingConfig {
+            enabled: enabled && mesh.is_some(),
+            fade_height: (fade_height > 0.0).then_some(fade_height),
+            mesh,
+        };
+        for (heater, gains) in [HeaterKind::Hotend, HeaterKind::Bed]
+            .into_iter()
+            .zip(pid_gains)
+        {
+            self.set_pid_gains(heater, gains);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> PayloadReader<'a> {
+    pub(crate) fn new(payload: &'a [u8]) -> Self {
+        Self {
+            payload,
+            position: 0,
+        }
+    }
+
+    /// True once every byte of the payload was read
+    pub(crate) fn is_finished(&self) -> bool {
+        self.position == self.payload.len()
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> PrintResult<[u8; N]> {
+        let bytes = self
+            .payload
+            .get(self.position..self.position + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(Error::CorruptSettings)?;
+        self.position += N;
+
+        Ok(bytes)
+    }
+
+    /// Bytes of a value whose length is only known while reading, like a text
+    pub(crate) fn read_slice(&mut self, length: usize) -> PrintResult<&'a [u8]> {
+        let bytes = self
+            .payload
+            .get(self.position..self.position + length)
+            .ok_or(Error::CorruptSettings)?;
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> PrintResult<u8> {
+        Ok(self.read_bytes::<1>()?[0])
+    }
+
+    pub(crate) fn read_u16(&mut self) -> PrintResult<u16> {
+        Ok(u16::from_le_bytes(self.read_bytes()?))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> PrintResult<u64> {
+        Ok(u64::from_le_bytes(self.read_bytes()?))
+    }
+
+    pub(crate) fn read_f32(&mut self) -> PrintResult<f32> {
+        Ok(f32::from_le_bytes(self.read_bytes()?))
+    }
+
+    /// One value for each axis, in the order X, Y, Z and E
+    fn read_axis_parameters(&mut self) -> PrintResult<AxisParameters> {
+        Ok(AxisParameters {
+            x: Some(self.read_f32()?),
+            y: Some(self.read_f32()?),
+            z: Some(self.read_f32()?),
+            e: Some(self.read_f32()?),
+        })
+    }
+}
+
+pub(crate) fn push_f32(payload: &mut Vec<u8>, value: f32) {
+    payload.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Parameters of a command setting a value for each axis. Ex: ` X80 Y80 Z400 E93`
+fn axis_values(value: impl Fn(Axis) -> f32) -> String {
+    Axis::ALL
+        .iter()
+        .map(|axis| format!(" {:?}{}", axis, value(*axis)))
+        .collect()
+}
+
+/// CRC-16/CCITT, the same checksum Marlin uses for its EEPROM
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, byte| {
+        let mut crc = crc ^ ((*byte as u16) << 8);
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::gcode::GcodeReader;
+    use crate::heater::{HeaterKind, PidGains};
+    use crate::motion::Axis;
+    use crate::storage::{MemoryEeprom, Storage};
+    use crate::system::{BedMesh, SystemConfig};
+
+    fn apply_source(config: &mut SystemConfig, source: &str) {
+        for line in GcodeReader::new(source.as_bytes()) {
+            if let Some(command) = line.unwrap().into_command() {
+                config.apply_command(&command).unwrap();
+            }
+        }
+    }
+
+    /// Config with every saved setting changed from its default
+    fn tuned_config() -> SystemConfig {
+        let mut config = SystemConfig::default();
+        config.set_bed_mesh(BedMesh::new(10.0, 10.0, 190.0, 190.0, 3, 2).unwrap());
+        apply_source(
+            &mut config,
+            "M92 X100.5 E415\nM201 Z50\nM203 X250\nM204 P1500 R800 T2000\n\
+             M205 X8 J0.02 S1\nM421 I2 J1 Z-0.125\nM420 S1 Z10\nM301 P30.5 I2 D80\nM304 P60\n",
+        );
+        config
+    }
+
+    #[test]
+    fn save_and_load_settings() {
+        let config = tuned_config();
+        let mut eeprom = MemoryEeprom::default();
+        config.save_settings(&mut eeprom).unwrap();
+
+        let mut loaded = SystemConfig::default();
+        loaded.load_settings(&mut eeprom).unwrap();
+
+        assert_eq!(loaded.steps_per_unit().get(Axis::X), 100.5);
+        assert_eq!(loaded.motion_limits(), config.motion_limits());
+        assert_eq!(loaded.bed_mesh(), config.bed_mesh());
+        assert!(loaded.is_leveling_active());
+        assert_eq!(loaded.fade_height(), Some(10.0));
+        assert_eq!(
+            loaded.pid_gains(HeaterKind::Hotend),
+            PidGains::new(30.5, 2.0, 80.0)
+        );
+        assert_eq!(loaded.pid_gains(HeaterKind::Bed).proportional(), 60.0);
+
+        loaded.reset_settings();
+        assert_eq!(loaded.steps_per_unit().get(Axis::X), 80.0);
+        assert!(loaded.bed_mesh().is_none());
+        assert_eq!(
+            loaded.pid_gains(HeaterKind::Hotend),
+            HeaterKind::Hotend.default_gains()
+        );
+    }
+
+    #[test]
+    fn stale_or_corrupt_settings_are_rejected() {
+        let mut config = SystemConfig::default();
+        let mut eeprom = MemoryEeprom::default();
+        assert!(matches!(
+            config.load_settings(&mut eeprom),
+            Err(Error::NoStoredSettings)
+        ));
+
+        tuned_config().save_settings(&mut eeprom).unwrap();
+        let saved = eeprom.read().unwrap();
+
+        let mut corrupt = saved.clone();
+        corrupt[20] ^= 0x01;
+        eeprom.write(&corrupt).unwrap();
+        assert!(matches!(
+            config.load_settings(&mut eeprom),
+            Err(Error::CorruptSettings)
+        ));
+
+        let mut stale = saved.clone();
+        stale[4] = 0;
+        eeprom.write(&stale).unwrap();
+        assert!(matches!(
+            config.load_settings(&mut eeprom),
+            Err(Error::SettingsVersionMismatch(0))
+        ));
+
+        //  Nothing was loaded from the rejected saves
+        assert_eq!(config.steps_per_unit().get(Axis::X), 80.0);
+    }
+
+    #[test]
+    fn report_restores_settings() {
+        let config = tuned_config();
+        let report = config.report_settings();
+        assert!(report.contains("M92 X100.5 Y80 Z400 E415\n"));
+        assert!(report.contains("M301 P30.5 I2 D80\n"));
+
+        let mut restored = SystemConfig::default();
+        restored.set_bed_mesh(BedMesh::new(10.0, 10.0, 190.0, 190.0, 3, 2).unwrap());
+        apply_source(&mut restored, &report);
+
+        assert_eq!(restored.report_settings(), report);
+        assert_eq!(restored.bed_mesh(), config.bed_mesh());
+    }
+}
@@ -0,0 +1,591 @@
+use std::f32::consts::TAU;
+
+use crate::error::{Error, PrintResult};
+use crate::gcode::{
+    G1Move, G2ArcMove, G28Home, G92SetPosition, GcodeCommand, M92StepsPerUnit, M301PidGains,
+    M900PressureAdvance,
+};
+use crate::heater::HeaterKind;
+use crate::motion::Axis;
+use crate::types::{ExtrudeAmountType, FeedrateAmountType, LocationType};
+
+use super::{CoordinatesConfig, Location, SystemConfig, UnitsConfig};
+
+const MILLIMETERS_PER_INCH: f32 = 25.4;
+/// Feedrate used for moves read before any F parameter was set, in millimeters per minute
+const DEFAULT_FEEDRATE: FeedrateAmountType = 1500.0;
+/// Longest straight segment used to follow an arc, in millimeters
+const ARC_SEGMENT_LENGTH: LocationType = 1.0;
+
+/// Move with every coordinate already resolved into absolute millimeters,
+/// after applying the units and positioning modes active when it was read
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedMove {
+    start: Location,
+    end: Location,
+    /// Filament pushed along the move. Negative for retractions
+    extrusion: ExtrudeAmountType,
+    /// Millimeters per minute
+    feedrate: FeedrateAmountType,
+}
+
+impl SystemConfig {
+    /// Updates the machine state with a command, working as the state tracker for the job.
+    /// Returns the resolved moves if the command moves either the toolhead or the extruder.
+    /// Lines give a single move, and arcs are split into short straight moves following them
+    pub fn apply_command(&mut self, command: &GcodeCommand) -> PrintResult<Vec<ResolvedMove>> {
+        match command {
+            GcodeCommand::G0(linear_move) | GcodeCommand::G1(linear_move) => {
+                return Ok(self.resolve_move(linear_move).into_iter().collect());
+            }
+            GcodeCommand::G2(arc_move) => return self.resolve_arc(arc_move, true),
+            GcodeCommand::G3(arc_move) => return self.resolve_arc(arc_move, false),
+            GcodeCommand::G20 => self.global.units_config = UnitsConfig::Inches,
+            GcodeCommand::G28(home) => self.home(home),
+            //  Probing needs the hardware, so the executor takes care of it
+            GcodeCommand::G29(_) => {}
+            GcodeCommand::G21 => self.global.units_config = UnitsConfig::Millimeters,
+            GcodeCommand::G90 => {
+                self.global.coordinates_config = CoordinatesConfig::Absolute;
+                self.global.extruder_coordinates_config = CoordinatesConfig::Absolute;
+            }
+            GcodeCommand::G91 => {
+                self.global.coordinates_config = CoordinatesConfig::Relative;
+                self.global.extruder_coordinates_config = CoordinatesConfig::Relative;
+            }
+            GcodeCommand::G92(set_position) => self.set_position(set_position),
+            GcodeCommand::M82 => {
+                self.global.extruder_coordinates_config = CoordinatesConfig::Absolute
+            }
+            GcodeCommand::M83 => {
+                self.global.extruder_coordinates_config = CoordinatesConfig::Relative
+            }
+            GcodeCommand::M92(steps_per_unit) => self.set_steps_per_unit(steps_per_unit),
+            GcodeCommand::M201(max_acceleration) => self
+                .motion_config
+                .limits
+                .set_max_acceleration(max_acceleration),
+            GcodeCommand::M203(max_feedrate) => {
+                self.motion_config.limits.set_max_feedrate(max_feedrate)
+            }
+            GcodeCommand::M204(acceleration) => {
+                self.motion_config.limits.set_acceleration(acceleration)
+            }
+            GcodeCommand::M205(settings) => {
+                self.motion_config.limits.set_advanced_settings(settings)
+            }
+            GcodeCommand::M420(leveling_state) => self.set_leveling_state(leveling_state),
+            GcodeCommand::M421(mesh_point) => self.set_mesh_point(mesh_point)?,
+            GcodeCommand::M301(gains) => self.merge_pid_gains(HeaterKind::Hotend, gains),
+            //  Tuning needs the heaters, so the executor takes care of it
+            GcodeCommand::M303(_) => {}
+            GcodeCommand::M304(gains) => self.merge_pid_gains(HeaterKind::Bed, gains),
+            GcodeCommand::M502 => self.reset_settings(),
+            //  Saving, loading and reporting need the storage, so the executor takes care of them
+            GcodeCommand::M500 | GcodeCommand::M501 | GcodeCommand::M503 => {}
+            GcodeCommand::M900(pressure_advance) => {
+                self.apply_pressure_advance(pressure_advance)?
+            }
+            //  Tool changes to extruders the machine doesn't have are left for the firmware macros
+            GcodeCommand::Passthrough(name, _) => {
+                if let Some(tool) = command.tool_change()
+                    && tool < self.extruder_config.count
+                {
+                    self.extruder_config.active = tool;
+                }
+                match name.as_str() {
+                    "M106" => {
+                        let speed = command.parameter_value('S').unwrap_or(255.0);
+                        self.extruder_config.fan_enabled = speed > 0.0;
+                    }
+                    "M107" => self.extruder_config.fan_enabled = false,
+                    _ => {}
+                }
+            }
+            GcodeCommand::M112 | GcodeCommand::M410 => {}
+            //  Handlers registered in the executor decide what extended commands do
+            GcodeCommand::Extended(_) => {}
+        }
+
+        Ok(vec![])
+    }
+
+    /// Resolves the targets of a move against the current state, and moves the state to the end of it.
+    /// Returns None if the move doesn't go anywhere, like a line setting only the feedrate
+    fn resolve_move(&mut self, linear_move: &G1Move) -> Option<ResolvedMove> {
+        if let Some(feedrate) = linear_move.feedrate_per_minute {
+            self.extruder_config.feedrate = self.to_millimeters(feedrate);
+        }
+
+        let start = self.extruder_config.current_location;
+        let end = Location {
+            x: self.resolve_coordinate(start.x, linear_move.x_target),
+            y: self.resolve_coordinate(start.y, linear_move.y_target),
+            z: self.resolve_coordinate(start.z, linear_move.z_target),
+        };
+
+        let extrusion = match linear_move
+            .amount_to_extrude
+            .map(|e| self.to_millimeters(e))
+        {
+            None => 0.0,
+            Some(e) => match self.global.extruder_coordinates_config {
+                CoordinatesConfig::Absolute => e - self.extruder_config.extruder_position,
+                CoordinatesConfig::Relative => e,
+            },
+        };
+
+        self.extruder_config.current_location = end;
+        self.extruder_config.extruder_position += extrusion;
+
+        if start == end && extrusion == 0.0 {
+            return None;
+        }
+
+        Some(ResolvedMove {
+            start,
+            end,
+            extrusion,
+            feedrate: self.next_feedrate(),
+        })
+    }
+
+    /// Feedrate of the next move, in millimeters per minute
+    fn next_feedrate(&self) -> FeedrateAmountType {
+        match self.extruder_config.feedrate {
+            feedrate if feedrate > 0.0 => feedrate,
+            _ => DEFAULT_FEEDRATE,
+        }
+    }
+
+    /// Resolves an arc on the XY plane into straight moves no longer than `ARC_SEGMENT_LENGTH`.
+    /// Z and the extruder move evenly along the arc. Arcs ending where they start are full circles
+    fn resolve_arc(
+        &mut self,
+        arc_move: &G2ArcMove,
+        clockwise: bool,
+    ) -> PrintResult<Vec<ResolvedMove>> {
+        let start = self.extruder_config.current_location;
+        let end = Location {
+            x: self.resolve_coordinate(start.x, arc_move.x_target),
+            y: self.resolve_coordinate(start.y, arc_move.y_target),
+            z: self.resolve_coordinate(start.z, arc_move.z_target),
+        };
+
+        let (i, j) = match arc_move.radius.map(|radius| self.to_millimeters(radius)) {
+            //  The state doesn't know the line, so the caller places the error at it
+            Some(radius) => arc_center_offsets(start, end, radius, clockwise)
+                .ok_or_else(|| Error::InvalidParameterInLine(format!("R{radius}"), None))?,
+            None => (
+                self.to_millimeters(arc_move.i_offset.unwrap_or_default()),
+                self.to_millimeters(arc_move.j_offset.unwrap_or_default()),
+            ),
+        };
+
+        //  The arc is resolved as a single straight move first, to share the feedrate and extrusion handling
+        let linear_move = G1Move {
+            x_target: arc_move.x_target,
+            y_target: arc_move.y_target,
+            z_target: arc_move.z_target,
+            amount_to_extrude: arc_move.amount_to_extrude,
+            feedrate_per_minute: arc_move.feedrate_per_minute,
+            laser_power: None,
+        };
+        //  Full circles end where they start, so they go somewhere even without extruding
+        let (extrusion, feedrate) = match self.resolve_move(&linear_move) {
+            Some(chord) => (chord.extrusion, chord.feedrate),
+            None if i != 0.0 || j != 0.0 => (0.0, self.next_feedrate()),
+            None => return Ok(vec![]),
+        };
+
+        let center_x = start.x + i;
+        let center_y = start.y + j;
+        let radius = i.hypot(j);
+        let start_angle = (-j).atan2(-i);
+        let mut travel = (end.y - center_y).atan2(end.x - center_x) - start_angle;
+        if clockwise && travel >= 0.0 {
+            travel -= TAU;
+        } else if !clockwise && travel <= 0.0 {
+            travel += TAU;
+        }
+
+        let arc_length = (travel * radius).hypot(end.z - start.z);
+        let segments = (arc_length / ARC_SEGMENT_LENGTH).ceil().max(1.0) as usize;
+        let mut moves = Vec::with_capacity(segments);
+        let mut previous = start;
+
+        for segment in 1..=segments {
+            let fraction = segment as f32 / segments as f32;
+            let location = match segment == segments {
+                true => end,
+                false => {
+                    let angle = start_angle + travel * fraction;
+                    Location {
+                        x: center_x + radius * angle.cos(),
+                        y: center_y + radius * angle.sin(),
+                        z: start.z + (end.z - start.z) * fraction,
+                    }
+                }
+            };
+
+            moves.push(ResolvedMove {
+                start: previous,
+                end: location,
+                extrusion: extrusion / segments as f32,
+                feedrate,
+            });
+            previous = location;
+        }
+
+        Ok(moves)
+    }
+
+    fn resolve_coordinate(
+        &self,
+        current: LocationType,
+        target: Option<LocationType>,
+    ) -> LocationType {
+        match target.map(|target| self.to_millimeters(target)) {
+            None => current,
+            Some(target) => match self.global.coordinates_config {
+                CoordinatesConfig::Absolute => target,
+                CoordinatesConfig::Relative => current + target,
+            },
+        }
+    }
+
+    /// Homed axes go back to the bed origin, or to zero if the bed isn't configured yet
+    fn home(&mut self, home: &G28Home) {
+        let origin = self.bed_config.origin.unwrap_or_default();
+        let location = &mut self.extruder_config.current_location;
+
+        for axis in home.axes() {
+            match axis {
+                Axis::X => location.x = origin.x,
+                Axis::Y => location.y = origin.y,
+                Axis::Z => location.z = origin.z,
+                Axis::E => {}
+            }
+        }
+    }
+
+    /// G92 always takes absolute values, regardless of the positioning mode
+    fn set_position(&mut self, set_position: &G92SetPosition) {
+        let location = &mut self.extruder_config.current_location;
+        let factor = self.global.units_config.millimeters_factor();
+
+        if let Some(x) = set_position.x {
+            location.x = x * factor;
+        }
+        if let Some(y) = set_position.y {
+            location.y = y * factor;
+        }
+        if let Some(z) = set_position.z {
+            location.z = z * factor;
+        }
+        if let Some(e) = set_position.e {
+            self.extruder_config.extruder_position = e * factor;
+        }
+    }
+
+    fn set_steps_per_unit(&mut self, steps_per_unit: &M92StepsPerUnit) {
+        for (axis, value) in steps_per_unit.by_axis() {
+            if let Some(value) = value {
+                self.motion_config.steps_per_unit.set(axis, value);
+            }
+        }
+    }
+
+    /// M900. Without T, the factor is for the active extruder
+    fn apply_pressure_advance(
+        &mut self,
+        pressure_advance: &M900PressureAdvance,
+    ) -> PrintResult<()> {
+        let tool = pressure_advance.tool.unwrap_or(self.extruder_config.active);
+        //  Placed at its line by the caller, like the arcs with a radius too small
+        let extruder = self
+            .extruder_config
+            .pressure_advance
+            .get_mut(tool as usize)
+            .ok_or_else(|| Error::InvalidParameterInLine(format!("T{tool}"), None))?;
+
+        if let Some(factor) = pressure_advance.factor {
+            extruder.set_factor(factor);
+        }
+        Ok(())
+    }
+
+    /// M301 and M304. Gains left out keep their value
+    fn merge_pid_gains(&mut self, heater: HeaterKind, gains: &M301PidGains) {
+        let merged = self.pid_gains(heater).with_values(
+            gains.proportional,
+            gains.integral,
+            gains.derivative,
+        );
+        self.set_pid_gains(heater, merged);
+    }
+
+    pub(crate) fn to_millimeters(&self, value: f32) -> f32 {
+        value * self.global.units_config.millimeters_factor()
+    }
+
+    /// True if the values of the commands are read as inches
+    pub(crate) fn is_inches(&self) -> bool {
+        matches!(self.global.units_config, UnitsConfig::Inches)
+    }
+
+    /// True if the targets of the moves are added to the current location
+    pub(crate) fn is_relative_positioning(&self) -> bool {
+        matches!(self.global.coordinates_config, CoordinatesConfig::Relative)
+    }
+
+    /// True if the extrusion of the moves is added to the extruder position
+    pub(crate) fn is_relative_extrusion(&self) -> bool {
+        matches!(
+            self.global.extruder_coordinates_config,
+            CoordinatesConfig::Relative
+        )
+    }
+}
+
+/// Offsets from the start to the center of an arc given by its radius, found like Marlin does.
+/// Returns None if the ends are too far apart for the radius
+fn arc_center_offsets(
+    start: Location,
+    end: Location,
+    radius: LocationType,
+    clockwise: bool,
+) -> Option<(LocationType, LocationType)> {
+    let dx = end.x - start.x;
+    let dy = end.y - start.y;
+    let distance = dx.hypot(dy);
+    let half_distance = distance / 2.0;
+    let height_squared = (radius.abs() - half_distance) * (radius.abs() + half_distance);
+    if distance == 0.0 || height_squared < 0.0 {
+        return None;
+    }
+
+    //  The center lies on the bisector of the chord, on the side given by the direction and the sign of the radius
+    let side = if clockwise ^ (radius < 0.0) {
+        -1.0
+    } else {
+        1.0
+    };
+    let height = side * height_squared.sqrt();
+    Some((
+        dx / 2.0 - height * dy / distance,
+        dy / 2.0 + height * dx / distance,
+    ))
+}
+
+impl UnitsConfig {
+    fn millimeters_factor(&self) -> f32 {
+        match self {
+            UnitsConfig::Millimeters => 1.0,
+            UnitsConfig::Inches => MILLIMETERS_PER_INCH,
+        }
+    }
+}
+
+impl ResolvedMove {
+    pub fn start(&self) -> Location {
+        self.start
+    }
+
+    pub fn end(&self) -> Location {
+        self.end
+    }
+
+    pub fn extrusion(&self) -> ExtrudeAmountType {
+        self.extrusion
+    }
+
+    /// Millimeters per minute
+    pub fn feedrate(&self) -> FeedrateAmountType {
+        self.feedrate
+    }
+
+    /// True if the move extrudes while going somewhere on the XY plane, unlike primes and Z moves
+    pub(crate) fn prints(&self) -> bool {
+        self.extrusion > 0.0 && (self.start.x, self.start.y) != (self.end.x, self.end.y)
+    }
+
+    /// Length of the toolhead path. Moves of the extruder alone, like retractions, measure the filament moved instead
+    pub fn length(&self) -> f32 {
+        let length = self.start.distance_to(&self.end);
+        if length > 0.0 {
+            length
+        } else {
+            self.extrusion.abs()
+        }
+    }
+
+    /// Part of the move between two fractions of its length, going from 0 at the start to 1 at the end
+    pub(crate) fn portion(&self, from: f32, to: f32) -> ResolvedMove {
+        ResolvedMove {
+            start: self.location_at(from),
+            end: self.location_at(to),
+            extrusion: self.extrusion_at(to) - self.extrusion_at(from),
+            feedrate: self.feedrate,
+        }
+    }
+
+    /// Same move, taking a different path between its start and end. Used to compensate the bed mesh
+    pub(crate) fn with_ends(&self, start: Location, end: Location) -> ResolvedMove {
+        ResolvedMove {
+            start,
+            end,
+            ..self.clone()
+        }
+    }
+
+    /// Same path, pushing a different amount of filament. Used to add the pressure advance
+    pub(crate) fn with_extrusion(&self, extrusion: ExtrudeAmountType) -> ResolvedMove {
+        ResolvedMove {
+            extrusion,
+            ..self.clone()
+        }
+    }
+
+    /// Location at a fraction of the move. Both ends are returned exactly, without rounding errors
+    fn location_at(&self, fraction: f32) -> Location {
+        if fraction <= 0.0 {
+            return self.start;
+        }
+        if fraction >= 1.0 {
+            return self.end;
+        }
+
+        Location {
+            x: self.start.x + (self.end.x - self.start.x) * fraction,
+            y: self.start.y + (self.end.y - self.start.y) * fraction,
+            z: self.start.z + (self.end.z - self.start.z) * fraction,
+        }
+    }
+
+    fn extrusion_at(&self, fraction: f32) -> ExtrudeAmountType {
+        self.extrusion * fraction.clamp(0.0, 1.0)
+    }
+
+    /// Time it takes to complete the move at its nominal feedrate, ignoring accelerations
+    pub fn nominal_duration(&self) -> f32 {
+        self.length() / (self.feedrate / 60.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gcode::GcodeReader;
+    use crate::heater::HeaterKind;
+    use crate::system::{Location, SystemConfig};
+
+    use super::ResolvedMove;
+
+    fn resolve_source(config: &mut SystemConfig, source: &str) -> Vec<ResolvedMove> {
+        GcodeReader::new(source.as_bytes())
+            .filter_map(|line| line.unwrap().into_command())
+            .flat_map(|command| config.apply_command(&command).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn resolve_absolute_and_relative_moves() {
+        let mut config = SystemConfig::default();
+        let moves = resolve_source(
+            &mut config,
+            "G1 X10 Y10 E1 F600\nG91\nG1 X5 E0.5\nG90\nM83\nG1 Y0 E0.25\nG1 F1200",
+        );
+
+        assert_eq!(moves.len(), 3);
+        assert_eq!(moves[0].end(), Location::new(10.0, 10.0, 0.0));
+        assert_eq!(moves[0].feedrate(), 600.0);
+        assert_eq!(moves[1].end(), Location::new(15.0, 10.0, 0.0));
+        assert_eq!(moves[1].extrusion(), 0.5);
+        assert_eq!(moves[2].end(), Location::new(15.0, 0.0, 0.0));
+        assert_eq!(moves[2].extrusion(), 0.25);
+        assert_eq!(config.extruder_position(), 1.75);
+    }
+
+    #[test]
+    fn resolve_inches_and_set_position() {
+        let mut config = SystemConfig::default();
+        let moves = resolve_source(&mut config, "G20\nG1 X1 E1\nG92 X0 E0\nG21\nG1 X10 E2");
+
+        assert_eq!(moves[0].end(), Location::new(25.4, 0.0, 0.0));
+        assert_eq!(moves[0].extrusion(), 25.4);
+        assert_eq!(moves[1].start(), Location::new(0.0, 0.0, 0.0));
+        assert_eq!(moves[1].extrusion(), 2.0);
+    }
+
+    #[test]
+    fn resolve_arcs_into_segments() {
+        let mut config = SystemConfig::default();
+        //  Half circle of radius 10 going over the top, then a full circle back to the same point
+        let moves = resolve_source(
+            &mut config,
+            "G1 X10 Y0\nG2 X30 Y0 I10 J0 E3\nG3 X30 Y0 I-10 J0",
+        );
+        let half_circle = &moves[1..33];
+
+        assert_eq!(half_circle.len(), 32);
+        assert_eq!(half_circle[31].end(), Location::new(30.0, 0.0, 0.0));
+        assert!(half_circle.iter().all(|segment| segment.length() <= 1.0));
+        assert!((half_circle[15].end().y() - 10.0).abs() < 0.01);
+        let extrusion: f32 = half_circle.iter().map(ResolvedMove::extrusion).sum();
+        assert!((extrusion - 3.0).abs() < 1e-4);
+
+        let full_circle = &moves[33..];
+        let center = Location::new(20.0, 0.0, 0.0);
+        assert_eq!(full_circle.len(), 63);
+        assert!(
+            full_circle
+                .iter()
+                .all(|segment| (segment.end().distance_to(&center) - 10.0).abs() < 0.01)
+        );
+
+        //  Radius too short to join both ends
+        let command = GcodeReader::new("G2 X0 Y0 R5".as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+            .into_command()
+            .unwrap();
+        assert!(config.apply_command(&command).is_err());
+    }
+
+    #[test]
+    fn pressure_advance_per_extruder() {
+        let mut config = SystemConfig::from_profile("[extruder]\ncount = 2\n").unwrap();
+        resolve_source(&mut config, "M900 K0.05\nT1\nM900 K0.08\nM900 T0 L1");
+
+        assert_eq!(config.active_extruder(), 1);
+        assert_eq!(config.pressure_advance(0).factor(), 0.05);
+        assert_eq!(config.pressure_advance(1).factor(), 0.08);
+
+        let command = GcodeReader::new("M900 K0.1 T2".as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+            .into_command()
+            .unwrap();
+        assert!(config.apply_command(&command).is_err());
+    }
+
+    #[test]
+    fn fan_and_pid_gains() {
+        let mut config = SystemConfig::default();
+        resolve_source(&mut config, "M106 S128\nM301 P30 D90\nM304 I0.05");
+
+        assert!(config.is_fan_enabled());
+        let hotend = config.pid_gains(HeaterKind::Hotend);
+        assert_eq!(hotend.proportional(), 30.0);
+        assert_eq!(hotend.integral(), 1.08);
+        assert_eq!(hotend.derivative(), 90.0);
+        assert_eq!(config.pid_gains(HeaterKind::Bed).integral(), 0.05);
+
+        resolve_source(&mut config, "M106 S0");
+        assert!(!config.is_fan_enabled());
+        resolve_source(&mut config, "M106\nM107");
+        assert!(!config.is_fan_enabled());
+    }
+}
@@ -0,0 +1,494 @@
+use std::io::Write;
+
+use crate::error::{Error, PrintResult};
+use crate::gcode::{G1Move, G2ArcMove, G92SetPosition, GcodeCommand, GcodeLine, GcodeWriter};
+use crate::system::{Location, SystemConfig};
+use crate::types::{LineNumberType, LocationType};
+
+/// Differences in length below this are rounding errors, and don't rescale the extrusion
+const SCALE_TOLERANCE: f32 = 1e-5;
+
+/// Affine transformation of the XY plane, plus an offset along Z. Points are mapped as
+/// `(x, y) -> (a * x + b * y + tx, c * x + d * y + ty)`, and every step added with the
+/// methods below is applied after the ones already in the transform
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    a: f32,
+    b: f32,
+    c: f32,
+    d: f32,
+    tx: LocationType,
+    ty: LocationType,
+    z_offset: LocationType,
+}
+
+/// Rewrites the moves of a job one by one, tracking the state of the original job
+/// to fill in the coordinates a transformed move needs but the original one didn't give
+struct JobTransformer {
+    transform: Transform,
+    /// State of the original job
+    state: SystemConfig,
+    /// Millimeters the transformed extruder is ahead of the original one, after rescaling extrusions
+    extrusion_offset: f32,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    /// Transform leaving every point where it is
+    pub fn identity() -> Self {
+        Self {
+            a: 1.0,
+            b: 0.0,
+            c: 0.0,
+            d: 1.0,
+            tx: 0.0,
+            ty: 0.0,
+            z_offset: 0.0,
+        }
+    }
+
+    pub fn translated(self, x: LocationType, y: LocationType) -> Self {
+        Self {
+            tx: self.tx + x,
+            ty: self.ty + y,
+            ..self
+        }
+    }
+
+    /// Scales around the origin. Negative factors mirror the axis
+    pub fn scaled(self, x: f32, y: f32) -> Self {
+        self.then_linear(x, 0.0, 0.0, y)
+    }
+
+    /// Rotates counterclockwise around the origin
+    pub fn rotated(self, degrees: f32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        self.then_linear(cos, -sin, sin, cos)
+    }
+
+    /// Mirrors along X, across the vertical line going through `x`
+    pub fn mirrored_x(self, x: LocationType) -> Self {
+        self.translated(-x, 0.0)
+            .scaled(-1.0, 1.0)
+            .translated(x, 0.0)
+    }
+
+    /// Mirrors along Y, across the horizontal line going through `y`
+    pub fn mirrored_y(self, y: LocationType) -> Self {
+        self.translated(0.0, -y)
+            .scaled(1.0, -1.0)
+            .translated(0.0, y)
+    }
+
+    pub fn with_z_offset(self, z_offset: LocationType) -> Self {
+        Self { z_offset, ..self }
+    }
+
+    /// Where the transform takes a point of the XY plane
+    pub fn apply(&self, x: LocationType, y: LocationType) -> (LocationType, LocationType) {
+        let (x, y) = self.apply_vector(x, y);
+        (x + self.tx, y + self.ty)
+    }
+
+    /// True if the transform turns clockwise paths into counterclockwise ones
+    pub fn is_mirroring(&self) -> bool {
+        self.determinant() < 0.0
+    }
+
+    /// Factor every length is scaled by, or None if lengths along X and Y are scaled differently,
+    /// or the transform skews the plane
+    pub fn uniform_scale(&self) -> Option<f32> {
+        let x_length = self.a.hypot(self.c);
+        let y_length = self.b.hypot(self.d);
+        let skew = self.a * self.b + self.c * self.d;
+
+        match (x_length - y_length).abs() < SCALE_TOLERANCE && skew.abs() < SCALE_TOLERANCE {
+            true => Some(x_length),
+            false => None,
+        }
+    }
+
+    /// Applies the linear part of the transform alone. Used for offsets and relative moves
+    fn apply_vector(&self, x: LocationType, y: LocationType) -> (LocationType, LocationType) {
+        (self.a * x + self.b * y, self.c * x + self.d * y)
+    }
+
+    /// Applies the linear map `[a b; c d]` after the current transform, translation included
+    fn then_linear(self, a: f32, b: f32, c: f32, d: f32) -> Self {
+        Self {
+            a: a * self.a + b * self.c,
+            b: a * self.b + b * self.d,
+            c: c * self.a + d * self.c,
+            d: c * self.b + d * self.d,
+            tx: a * self.tx + b * self.ty,
+            ty: c * self.tx + d * self.ty,
+            z_offset: self.z_offset,
+        }
+    }
+
+    fn determinant(&self) -> f32 {
+        self.a * self.d - self.b * self.c
+    }
+
+    /// Whether the transformed X and Y depend on the original X and Y, as `(x_from_y, y_from_x)`.
+    /// A move giving only one of them needs the other one written too when it does
+    fn mixes_axes(&self) -> (bool, bool) {
+        (self.b != 0.0, self.c != 0.0)
+    }
+}
+
+/// Transforms every move of a job and writes the result. G0, G1, G2, G3 and G92 are rewritten,
+/// arcs swap their direction when the transform mirrors them, and extrusions are rescaled
+/// to the new length of their moves. Any other line is written as it was read
+pub fn transform_job<W: Write>(
+    lines: impl IntoIterator<Item = PrintResult<GcodeLine>>,
+    writer: &mut GcodeWriter<W>,
+    transform: &Transform,
+) -> PrintResult<()> {
+    let mut transformer = JobTransformer::new(*transform);
+
+    for line in lines {
+        let line = line?;
+        match line.command() {
+            Some(command) => {
+                let transformed = transformer.transform_command(command, line.line_number())?;
+                writer.write_command(&transformed, line.comment())?;
+            }
+            None => writer.write_line(&line)?,
+        }
+    }
+
+    Ok(())
+}
+
+impl JobTransformer {
+    fn new(transform: Transform) -> Self {
+        Self {
+            transform,
+            state: SystemConfig::default(),
+            extrusion_offset: 0.0,
+        }
+    }
+
+    fn transform_command(
+        &mut self,
+        command: &GcodeCommand,
+        line_number: LineNumberType,
+    ) -> PrintResult<GcodeCommand> {
+        let start = self.state.current_location();
+        let start_extrusion = self.state.extruder_position();
+        //  Values are written in the units the command was read in, so it's taken before applying G20 and G21
+        let factor = self.state.to_millimeters(1.0);
+
+        if command.changes_position() {
+            self.state
+                .apply_command(command)
+                .map_err(|error| error.in_line(line_number))?;
+        }
+        let end = self.state.current_location();
+        let extrusion = self.state.extruder_position() - start_extrusion;
+
+        let transformed = match command {
+            GcodeCommand::G0(linear_move) => {
+                GcodeCommand::G0(self.transform_linear(linear_move, start, end, extrusion, factor))
+            }
+            GcodeCommand::G1(linear_move) => {
+                GcodeCommand::G1(self.transform_linear(linear_move, start, end, extrusion, factor))
+            }
+            GcodeCommand::G2(arc_move) | GcodeCommand::G3(arc_move) => {
+                let Some(scale) = self.transform.uniform_scale() else {
+                    return Err(Error::NonUniformArcScale(line_number));
+                };
+                let arc_move = self.transform_arc(arc_move, start, end, extrusion, scale, factor);
+                let clockwise = matches!(command, GcodeCommand::G2(_));
+                match clockwise != self.transform.is_mirroring() {
+                    true => GcodeCommand::G2(arc_move),
+                    false => GcodeCommand::G3(arc_move),
+                }
+            }
+            GcodeCommand::G92(set_position) => {
+                GcodeCommand::G92(self.transform_set_position(set_position, end, factor))
+            }
+            command => command.clone(),
+        };
+
+        Ok(transformed)
+    }
+
+    fn transform_linear(
+        &mut self,
+        linear_move: &G1Move,
+        start: Location,
+        end: Location,
+        extrusion: f32,
+        factor: f32,
+    ) -> G1Move {
+        let (x_target, y_target) = self.xy_targets(
+            linear_move.x_target,
+            linear_move.y_target,
+            start,
+            end,
+            factor,
+        );
+        let (dx, dy) = (end.x - start.x, end.y - start.y);
+        let (new_dx, new_dy) = self.transform.apply_vector(dx, dy);
+        let length = dx.hypot(dy);
+        let scale = match length > 0.0 {
+            true => new_dx.hypot(new_dy) / length,
+            false => 1.0,
+        };
+
+        G1Move {
+            x_target,
+            y_target,
+            z_target: self.z_target(linear_move.z_target, end, factor),
+            amount_to_extrude: self.extrude_amount(
+                linear_move.amount_to_extrude,
+                extrusion,
+                scale,
+                factor,
+            ),
+            ..linear_move.clone()
+        }
+    }
+
+    /// Offsets to the center are turned like any other vector, and radiuses keep their sign,
+    /// since mirrored arcs also swap their direction
+    fn transform_arc(
+        &mut self,
+        arc_move: &G2ArcMove,
+        start: Location,
+        end: Location,
+        extrusion: f32,
+        scale: f32,
+        factor: f32,
+    ) -> G2ArcMove {
+        let (x_target, y_target) =
+            self.xy_targets(arc_move.x_target, arc_move.y_target, start, end, factor);
+        let (i_offset, j_offset) = match (arc_move.i_offset, arc_move.j_offset) {
+            (None, None) => (None, None),
+            (i, j) => {
+                let (new_i, new_j) = self
+                    .transform
+                    .apply_vector(i.unwrap_or_default(), j.unwrap_or_default());
+                let (i_from_j, j_from_i) = self.transform.mixes_axes();
+                (
+                    (i.is_some() || (j.is_some() && i_from_j)).then_some(new_i),
+                    (j.is_some() || (i.is_some() && j_from_i)).then_some(new_j),
+                )
+            }
+        };
+
+        G2ArcMove {
+            x_target,
+            y_target,
+            z_target: self.z_target(arc_move.z_target, end, factor),
+            amount_to_extrude: self.extrude_amount(
+                arc_move.amount_to_extrude,
+                extrusion,
+                scale,
+                factor,
+            ),
+            feedrate_per_minute: arc_move.feedrate_per_minute,
+            i_offset,
+            j_offset,
+            radius: arc_move.radius.map(|radius| radius * scale),
+        }
+    }
+
+    /// G92 takes absolute values in any positioning mode, and restarts the count of the extruder
+    fn transform_set_position(
+        &mut self,
+        set_position: &G92SetPosition,
+        end: Location,
+        factor: f32,
+    ) -> G92SetPosition {
+        let (x, y) = self.absolute_xy(set_position.x, set_position.y, end, factor);
+        if set_position.e.is_some() {
+            self.extrusion_offset = 0.0;
+        }
+
+        G92SetPosition {
+            x,
+            y,
+            z: set_position
+                .z
+                .map(|_| (end.z + self.transform.z_offset) / factor),
+            e: set_position.e,
+        }
+    }
+
+    /// Targets of a move along X and Y. Only the axes changed by the transform are written
+    fn xy_targets(
+        &self,
+        x_target: Option<LocationType>,
+        y_target: Option<LocationType>,
+        start: Location,
+        end: Location,
+        factor: f32,
+    ) -> (Option<LocationType>, Option<LocationType>) {
+        if !self.state.is_relative_positioning() {
+            return self.absolute_xy(x_target, y_target, end, factor);
+        }
+
+        let (dx, dy) = self
+            .transform
+            .apply_vector(end.x - start.x, end.y - start.y);
+        self.written_axes(x_target, y_target, dx / factor, dy / factor)
+    }
+
+    fn absolute_xy(
+        &self,
+        x_target: Option<LocationType>,
+        y_target: Option<LocationType>,
+        end: Location,
+        factor: f32,
+    ) -> (Option<LocationType>, Option<LocationType>) {
+        let (x, y) = self.transform.apply(end.x, end.y);
+        self.written_axes(x_target, y_target, x / factor, y / factor)
+    }
+
+    fn written_axes(
+        &self,
+        x_target: Option<LocationType>,
+        y_target: Option<LocationType>,
+        x: LocationType,
+        y: LocationType,
+    ) -> (Option<LocationType>, Option<LocationType>) {
+        let (x_from_y, y_from_x) = self.transform.mixes_axes();
+        (
+            (x_target.is_some() || (y_target.is_some() && x_from_y)).then_some(x),
+            (y_target.is_some() || (x_target.is_some() && y_from_x)).then_some(y),
+        )
+    }
+
+    /// Relative Z targets are left as they are, since the offset only moves the absolute ones
+    fn z_target(
+        &self,
+        z_target: Option<LocationType>,
+        end: Location,
+        factor: f32,
+    ) -> Option<LocationType> {
+        match self.state.is_relative_positioning() {
+            true => z_target,
+            false => z_target.map(|_| (end.z + self.transform.z_offset) / factor),
+        }
+    }
+
+    /// Extrusion of a move whose length was multiplied by `scale`. Values are copied as they were read
+    /// while the lengths don't change, so jobs that are only moved around keep every digit of their E
+    fn extrude_amount(
+        &mut self,
+        amount: Option<f32>,
+        extrusion: f32,
+        scale: f32,
+        factor: f32,
+    ) -> Option<f32> {
+        let amount = amount?;
+        if (scale - 1.0).abs() >= SCALE_TOLERANCE {
+            self.extrusion_offset += extrusion * (scale - 1.0);
+            if self.state.is_relative_extrusion() {
+                return Some(amount * scale);
+            }
+        }
+
+        match self.state.is_relative_extrusion() || self.extrusion_offset == 0.0 {
+            true => Some(amount),
+            false => Some(amount + self.extrusion_offset / factor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::{Error, PrintResult};
+    use crate::gcode::{GcodeReader, rewrite_source};
+    use crate::system::{Location, SystemConfig};
+    use crate::transform::{Transform, transform_job};
+
+    fn transform_source(source: &str, transform: &Transform) -> PrintResult<String> {
+        let (transformed, _) = rewrite_source(source, |lines, writer| {
+            transform_job(lines, writer, transform)
+        })?;
+        Ok(transformed)
+    }
+
+    fn final_location(source: &str) -> Location {
+        let mut config = SystemConfig::default();
+        for line in GcodeReader::new(source.as_bytes()) {
+            if let Some(command) = line.unwrap().into_command() {
+                config.apply_command(&command).unwrap();
+            }
+        }
+        config.current_location()
+    }
+
+    #[test]
+    fn translate_and_offset_z() {
+        let transform = Transform::identity()
+            .translated(10.0, -5.0)
+            .with_z_offset(0.1);
+        let source = "; start\nG1 Z0.2 F600\nG1 X20 Y30 E1.23456 ; skirt\nG91\nG1 X5 Z1\nG92 X0\nM104 S200\n";
+
+        assert_eq!(
+            transform_source(source, &transform).unwrap(),
+            "; start\nG1 Z0.3 F600\nG1 X30 Y25 E1.23456 ; skirt\nG91\nG1 X5 Z1\nG92 X10\nM104 S200\n"
+        );
+    }
+
+    #[test]
+    fn rotation_keeps_the_path() {
+        let transform = Transform::identity().rotated(90.0).translated(100.0, 0.0);
+        let source = "G1 X10 Y0\nG1 X20\nG2 X30 Y10 I0 J10 E2\nG91\nG1 Y-5\n";
+        let transformed = transform_source(source, &transform).unwrap();
+
+        let original = final_location(source);
+        let expected = transform.apply(original.x(), original.y());
+        let location = final_location(&transformed);
+        assert!((location.x() - expected.0).abs() < 1e-3);
+        assert!((location.y() - expected.1).abs() < 1e-3);
+        //  Moving along X alone becomes moving along Y, so both are written
+        assert!(transformed.contains("G1 X100 Y20\n"));
+        assert!(transformed.contains("G2 X90 Y30 I-10 J0 E2\n"));
+    }
+
+    #[test]
+    fn mirror_swaps_arcs_and_scale_rescales_extrusion() {
+        let mirror = Transform::identity().mirrored_x(50.0);
+        let source = "G1 X10 Y10 E1\nG2 X20 Y10 R5 E3\n";
+        assert_eq!(
+            transform_source(source, &mirror).unwrap(),
+            "G1 X90 Y10 E1\nG3 X80 Y10 R5 E3\n"
+        );
+
+        let double = Transform::identity().scaled(2.0, 2.0);
+        assert_eq!(
+            transform_source(
+                "M82\nG1 X10 E1\nG1 X20 E2\nG1 E1.5\nG92 E0\nG1 X30 E1\n",
+                &double
+            )
+            .unwrap(),
+            "M82\nG1 X20 E2\nG1 X40 E4\nG1 E3.5\nG92 E0\nG1 X60 E2\n"
+        );
+        assert_eq!(
+            transform_source("M83\nG3 X10 Y10 I5 J5 E1\n", &double).unwrap(),
+            "M83\nG3 X20 Y20 I10 J10 E2\n"
+        );
+    }
+
+    #[test]
+    fn arcs_need_uniform_scale() {
+        let stretch = Transform::identity().scaled(2.0, 1.0);
+
+        assert!(transform_source("G1 X10 E1\n", &stretch).is_ok());
+        assert!(matches!(
+            transform_source("G1 X10\nG2 X20 I5 J0\n", &stretch),
+            Err(Error::NonUniformArcScale(2))
+        ));
+    }
+}
@@ -1,6 +1,7 @@
-pub(crate) type LocationType = i16;
+pub(crate) type LocationType = f32;
 pub(crate) type TemperatureType = u16;
-pub(crate) type ExtrudeAmountType = u16;
-pub(crate) type FeedrateAmountType = u16;
+pub(crate) type ExtrudeAmountType = f32;
+pub(crate) type FeedrateAmountType = f32;
 pub(crate) type PowerType = u16;
 pub(crate) type LineNumberType = usize;
+pub(crate) type StepCountType = i32;